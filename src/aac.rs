@@ -0,0 +1,406 @@
+//! A pure-Rust AAC-LC encoder.
+//!
+//! Interleaved f32 PCM goes in, raw AAC access units (one per 1024 frames)
+//! come out, ready to be stored in an MP4 container. Only long windows are
+//! used, and the quantizer shapes its noise to a fixed ratio below each
+//! band's energy, with the ratio searched per frame to meet the bitrate.
+//!
+//! ref
+//!   ISO/IEC 14496-3 subpart 4 (General Audio coding)
+
+// Signal processing code converts freely between sample counts, indices and
+// floating point values, all of which are small enough for these casts.
+#![allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_precision_loss,
+    clippy::cast_sign_loss,
+    clippy::cast_possible_wrap
+)]
+
+mod bits;
+mod mdct;
+mod quantize;
+mod tables;
+
+use thiserror::Error as ThisError;
+
+use self::{
+    bits::BitWriter,
+    mdct::{Mdct, BLOCK_LEN, SPECTRUM_LEN},
+    quantize::{BandAnalysis, ChannelCoding},
+    tables::{SAMPLE_RATES, SWB_OFFSETS_LONG},
+};
+
+/// Number of PCM frames coded by each access unit.
+pub const FRAME_LEN: usize = SPECTRUM_LEN;
+
+/// Audio object type of AAC Low Complexity.
+const AOT_AAC_LC: u32 = 2;
+
+/// Syntactic element identifiers.
+const ID_SCE: u32 = 0;
+const ID_CPE: u32 = 1;
+const ID_END: u32 = 7;
+
+/// Largest element the decoder input buffer is required to hold, per channel.
+const MAX_CHANNEL_BITS: usize = 6144;
+
+/// Gain applied to f32 input samples ahead of the MDCT: decoders expect
+/// spectra on a 16-bit sample scale, and their inverse transform halves the
+/// amplitude of this unnormalized forward transform.
+const INPUT_GAIN: f32 = 2.0 * 32768.0;
+
+/// Energy of a full-scale sine in the MDCT domain, used to place the
+/// absolute threshold of hearing relative to the input level.
+const FULL_SCALE_ENERGY: f32 = 1.6e15;
+
+/// Search range and iterations of the per-frame noise-to-signal target.
+const SNR_RANGE_DB: (f32, f32) = (-10.0, 70.0);
+const SNR_SEARCH_STEPS: usize = 7;
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+    #[error("Unsupported sample rate for AAC: {0}Hz")]
+    UnsupportedSampleRate(u32),
+    #[error("Unsupported channel count for AAC: {0} (only mono and stereo are supported)")]
+    UnsupportedChannels(u16),
+    #[error(
+        "Bitrate of {bitrate}bps is out of range for {channels} channel(s) at {sample_rate}Hz"
+    )]
+    UnsupportedBitrate {
+        bitrate: u32,
+        channels: u16,
+        sample_rate: u32,
+    },
+}
+
+/// Whether AAC can be encoded at `sample_rate`.
+#[must_use]
+pub fn supports_sample_rate(sample_rate: u32) -> bool {
+    SAMPLE_RATES.contains(&sample_rate)
+}
+
+/// Settings for an [`AacEncoder`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AacConfig {
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Target bitrate of the whole stream in bits per second.
+    pub bitrate: u32,
+}
+
+/// Encodes interleaved PCM into AAC-LC access units.
+pub struct AacEncoder {
+    config: AacConfig,
+    sample_rate_index: usize,
+    swb: &'static [u16],
+    /// Number of scalefactor bands below the encoder's bandwidth.
+    num_bands: usize,
+    ath: Vec<f32>,
+    mdct: Mdct,
+    /// Per channel, the previous and the current (partially filled) frame.
+    blocks: Vec<Vec<f32>>,
+    /// Frames buffered in the current frame of each block.
+    buffered: usize,
+    /// Average bits available per access unit, and the unused bits carried
+    /// over from earlier access units.
+    frame_bits: f32,
+    reservoir: f32,
+    frames_in: u64,
+    spectra: Vec<Vec<f32>>,
+}
+
+impl AacEncoder {
+    /// Creates an encoder for the given configuration.
+    ///
+    /// # Errors
+    /// Returns an error if the sample rate has no AAC sampling frequency
+    /// index, the channel count is not mono or stereo, or the bitrate is
+    /// beyond what the format can carry.
+    pub fn new(config: AacConfig) -> Result<Self, Error> {
+        let sample_rate_index = SAMPLE_RATES
+            .iter()
+            .position(|&r| r == config.sample_rate)
+            .ok_or(Error::UnsupportedSampleRate(config.sample_rate))?;
+        if !(1..=2).contains(&config.channels) {
+            return Err(Error::UnsupportedChannels(config.channels));
+        }
+
+        let channels = usize::from(config.channels);
+        let frame_bits = config.bitrate as f32 * FRAME_LEN as f32 / config.sample_rate as f32;
+        if config.bitrate < 8000 || frame_bits > (MAX_CHANNEL_BITS * channels) as f32 {
+            return Err(Error::UnsupportedBitrate {
+                bitrate: config.bitrate,
+                channels: config.channels,
+                sample_rate: config.sample_rate,
+            });
+        }
+
+        let swb = SWB_OFFSETS_LONG[sample_rate_index];
+        let bandwidth =
+            bandwidth(config.bitrate / u32::from(config.channels)).min(config.sample_rate / 2);
+        // Coefficient k is centered on (k + 0.5) * rate / 2048 Hz.
+        let max_bin = usize::try_from(u64::from(bandwidth) * 2048 / u64::from(config.sample_rate))
+            .unwrap_or(SPECTRUM_LEN);
+        let num_bands = swb
+            .iter()
+            .skip(1)
+            .position(|&offset| usize::from(offset) >= max_bin)
+            .map_or(swb.len() - 1, |b| b + 1);
+
+        let ath = swb
+            .windows(2)
+            .take(num_bands)
+            .map(|band| {
+                let min = (band[0]..band[1])
+                    .map(|k| {
+                        let freq = (f32::from(k) + 0.5) * config.sample_rate as f32 / 2048.0;
+                        ath_db(freq)
+                    })
+                    .fold(f32::INFINITY, f32::min);
+                FULL_SCALE_ENERGY * 10f32.powf((min - 96.0) / 10.0) * f32::from(band[1] - band[0])
+            })
+            .collect();
+
+        Ok(Self {
+            config,
+            sample_rate_index,
+            swb,
+            num_bands,
+            ath,
+            mdct: Mdct::new(),
+            blocks: vec![vec![0.0; BLOCK_LEN]; channels],
+            buffered: 0,
+            frame_bits,
+            reservoir: 0.0,
+            frames_in: 0,
+            spectra: vec![vec![0.0; SPECTRUM_LEN]; channels],
+        })
+    }
+
+    #[must_use]
+    pub fn config(&self) -> &AacConfig {
+        &self.config
+    }
+
+    /// The `AudioSpecificConfig` describing the stream, for the container.
+    #[must_use]
+    pub fn audio_specific_config(&self) -> [u8; 2] {
+        let asc = (AOT_AAC_LC << 11)
+            | ((self.sample_rate_index as u32) << 7)
+            | (u32::from(self.config.channels) << 3);
+        // frameLengthFlag, dependsOnCoreCoder and extensionFlag are all zero.
+        [(asc >> 8) as u8, asc as u8]
+    }
+
+    /// Number of frames of silence the decoder outputs before the first
+    /// input frame (the MDCT overlap).
+    #[must_use]
+    pub fn priming(&self) -> u32 {
+        FRAME_LEN as u32
+    }
+
+    /// Number of input frames consumed so far.
+    #[must_use]
+    pub fn frames_in(&self) -> u64 {
+        self.frames_in
+    }
+
+    /// Encodes interleaved `samples`, appending every completed access unit to
+    /// `out`.
+    pub fn encode(&mut self, samples: &[f32], out: &mut Vec<Vec<u8>>) {
+        let channels = self.blocks.len();
+        debug_assert_eq!(samples.len() % channels, 0);
+        for frame in samples.chunks_exact(channels) {
+            for (block, &s) in self.blocks.iter_mut().zip(frame) {
+                block[FRAME_LEN + self.buffered] = s;
+            }
+            self.buffered += 1;
+            if self.buffered == FRAME_LEN {
+                out.push(self.encode_frame());
+            }
+        }
+        self.frames_in += (samples.len() / channels) as u64;
+    }
+
+    /// Encodes any buffered input padded with silence, followed by the final
+    /// access unit needed to complete the overlap of the last block.
+    ///
+    /// Returns the number of padding frames the decoder will output after the
+    /// last input frame.
+    pub fn finish(&mut self, out: &mut Vec<Vec<u8>>) -> u32 {
+        let mut padding = 0;
+        if self.buffered > 0 {
+            padding = FRAME_LEN - self.buffered;
+            for block in &mut self.blocks {
+                block[FRAME_LEN + self.buffered..].fill(0.0);
+            }
+            self.buffered = FRAME_LEN;
+            out.push(self.encode_frame());
+        }
+        for block in &mut self.blocks {
+            block[FRAME_LEN..].fill(0.0);
+        }
+        self.buffered = FRAME_LEN;
+        out.push(self.encode_frame());
+        (padding + FRAME_LEN) as u32
+    }
+
+    fn encode_frame(&mut self) -> Vec<u8> {
+        for (block, spectrum) in self.blocks.iter_mut().zip(&mut self.spectra) {
+            self.mdct.forward(block, INPUT_GAIN, spectrum);
+            spectrum[usize::from(self.swb[self.num_bands])..].fill(0.0);
+            block.copy_within(FRAME_LEN.., 0);
+        }
+        self.buffered = 0;
+
+        let ms_used = if self.spectra.len() == 2 {
+            self.mid_side()
+        } else {
+            Vec::new()
+        };
+
+        let analyses: Vec<BandAnalysis> = self
+            .spectra
+            .iter()
+            .map(|s| BandAnalysis::new(s, self.swb, self.num_bands))
+            .collect();
+
+        let max_bits = (MAX_CHANNEL_BITS * self.spectra.len()) as f32;
+        let target = (self.frame_bits + 0.3 * self.reservoir).min(max_bits);
+
+        // Find the highest signal-to-noise target that fits in the budget.
+        let (mut lo, mut hi) = SNR_RANGE_DB;
+        let mut best = None;
+        for _ in 0..SNR_SEARCH_STEPS {
+            let snr = f32::midpoint(lo, hi);
+            let codings = self.quantize(&analyses, snr);
+            let bits = self.frame_bit_count(&codings);
+            if bits as f32 <= target {
+                best = Some(codings);
+                lo = snr;
+            } else {
+                hi = snr;
+            }
+        }
+        let codings = best.unwrap_or_else(|| self.quantize(&analyses, SNR_RANGE_DB.0));
+
+        let data = self.write_frame(&codings, &ms_used);
+        let used = (data.len() * 8) as f32;
+        self.reservoir =
+            (self.reservoir + self.frame_bits - used).clamp(0.0, max_bits - self.frame_bits);
+        data
+    }
+
+    fn quantize(&self, analyses: &[BandAnalysis], snr: f32) -> Vec<ChannelCoding> {
+        self.spectra
+            .iter()
+            .zip(analyses)
+            .map(|(s, a)| ChannelCoding::quantize(s, a, self.swb, &self.ath, snr))
+            .collect()
+    }
+
+    /// Decides per band whether to code a stereo pair as mid/side, and
+    /// converts the spectra of those bands in place.
+    fn mid_side(&mut self) -> Vec<bool> {
+        let (left, right) = self.spectra.split_at_mut(1);
+        let (left, right) = (&mut left[0], &mut right[0]);
+        self.swb
+            .windows(2)
+            .take(self.num_bands)
+            .map(|band| {
+                let range = usize::from(band[0])..usize::from(band[1]);
+                let (mut l, mut r, mut m, mut s) = (0.0f32, 0.0f32, 0.0f32, 0.0f32);
+                for i in range.clone() {
+                    let mid = f32::midpoint(left[i], right[i]);
+                    let side = (left[i] - right[i]) / 2.0;
+                    l += left[i] * left[i];
+                    r += right[i] * right[i];
+                    m += mid * mid;
+                    s += side * side;
+                }
+                let used = m.min(s) < 0.5 * l.min(r);
+                if used {
+                    for i in range {
+                        let (l, r) = (left[i], right[i]);
+                        left[i] = f32::midpoint(l, r);
+                        right[i] = (l - r) / 2.0;
+                    }
+                }
+                used
+            })
+            .collect()
+    }
+
+    fn max_sfb(codings: &[ChannelCoding]) -> usize {
+        codings
+            .iter()
+            .map(ChannelCoding::used_bands)
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn frame_bit_count(&self, codings: &[ChannelCoding]) -> usize {
+        let max_sfb = Self::max_sfb(codings);
+        let mut bits = 3 + 4 + 11; // element id, instance tag, ics_info
+        if codings.len() == 2 {
+            bits += 1 + 2 + max_sfb; // common_window, ms_mask_present, ms_used
+        }
+        bits += codings
+            .iter()
+            .map(|c| c.bit_count(self.swb, max_sfb))
+            .sum::<usize>();
+        bits + 3 + 7 // ID_END and byte alignment
+    }
+
+    fn write_frame(&self, codings: &[ChannelCoding], ms_used: &[bool]) -> Vec<u8> {
+        let max_sfb = Self::max_sfb(codings);
+        let ics_info = |w: &mut BitWriter| {
+            w.put_bool(false); // ics_reserved_bit
+            w.put(0, 2); // window_sequence: ONLY_LONG_SEQUENCE
+            w.put(0, 1); // window_shape: sine
+            w.put(max_sfb as u32, 6);
+            w.put_bool(false); // predictor_data_present
+        };
+
+        let mut w = BitWriter::default();
+        if let [mono] = codings {
+            w.put(ID_SCE, 3);
+            w.put(0, 4);
+            mono.write(&mut w, self.swb, max_sfb, ics_info);
+        } else {
+            w.put(ID_CPE, 3);
+            w.put(0, 4);
+            w.put_bool(true); // common_window
+            ics_info(&mut w);
+            w.put(1, 2); // ms_mask_present: per band
+            for &used in &ms_used[..max_sfb] {
+                w.put_bool(used);
+            }
+            for coding in codings {
+                coding.write(&mut w, self.swb, max_sfb, |_| {});
+            }
+        }
+        w.put(ID_END, 3);
+        debug_assert!(w.len() <= MAX_CHANNEL_BITS * codings.len());
+        w.finish()
+    }
+}
+
+/// Audio bandwidth to code for a given per-channel bitrate.
+fn bandwidth(bitrate_per_channel: u32) -> u32 {
+    match bitrate_per_channel {
+        0..=19_999 => 6_000,
+        20_000..=27_999 => 9_000,
+        28_000..=39_999 => 12_000,
+        40_000..=55_999 => 15_000,
+        56_000..=79_999 => 17_000,
+        _ => 20_000,
+    }
+}
+
+/// Absolute threshold of hearing in dB SPL (Terhardt's approximation).
+fn ath_db(freq: f32) -> f32 {
+    let f = (freq / 1000.0).max(0.02);
+    3.64 * f.powf(-0.8) - 6.5 * (-0.6 * (f - 3.3).powi(2)).exp() + 1e-3 * f.powi(4)
+}
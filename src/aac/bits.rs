@@ -0,0 +1,43 @@
+//! MSB-first bit writer for raw AAC bitstream elements.
+
+#[derive(Debug, Default)]
+pub(super) struct BitWriter {
+    buf: Vec<u8>,
+    acc: u64,
+    n_bits: u32,
+}
+
+impl BitWriter {
+    /// Writes the low `bits` bits of `value`, most significant bit first.
+    pub(super) fn put(&mut self, value: u32, bits: u32) {
+        debug_assert!(bits <= 32);
+        if bits == 0 {
+            return;
+        }
+        let mask = (1u64 << bits) - 1;
+        self.acc = (self.acc << bits) | (u64::from(value) & mask);
+        self.n_bits += bits;
+        while self.n_bits >= 8 {
+            self.n_bits -= 8;
+            self.buf.push((self.acc >> self.n_bits) as u8);
+        }
+    }
+
+    pub(super) fn put_bool(&mut self, value: bool) {
+        self.put(u32::from(value), 1);
+    }
+
+    /// Number of bits written so far.
+    pub(super) fn len(&self) -> usize {
+        self.buf.len() * 8 + self.n_bits as usize
+    }
+
+    /// Pads to a byte boundary with zero bits and returns the written bytes.
+    pub(super) fn finish(mut self) -> Vec<u8> {
+        if self.n_bits > 0 {
+            let pad = 8 - self.n_bits;
+            self.put(0, pad);
+        }
+        self.buf
+    }
+}
@@ -0,0 +1,133 @@
+//! Windowed MDCT for 2048-sample long blocks.
+//!
+//! The transform folds the windowed block into a DCT-IV of half the size,
+//! which is in turn computed with a complex FFT of a quarter of the size.
+use std::f64::consts::PI;
+
+/// Number of input samples per transform.
+pub(super) const BLOCK_LEN: usize = 2048;
+
+/// Number of spectral coefficients produced per transform.
+pub(super) const SPECTRUM_LEN: usize = BLOCK_LEN / 2;
+
+const QUARTER: usize = BLOCK_LEN / 4;
+const FFT_LEN: usize = SPECTRUM_LEN / 2;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Complex {
+    re: f32,
+    im: f32,
+}
+
+impl Complex {
+    fn mul(self, other: Self) -> Self {
+        Self {
+            re: self.re * other.re - self.im * other.im,
+            im: self.re * other.im + self.im * other.re,
+        }
+    }
+
+    fn from_angle(angle: f64) -> Self {
+        Self {
+            re: angle.cos() as f32,
+            im: angle.sin() as f32,
+        }
+    }
+}
+
+/// Precomputed window, twiddles and scratch space for the transform.
+pub(super) struct Mdct {
+    window: Vec<f32>,
+    /// Twiddles applied before and after the FFT.
+    rotation: Vec<Complex>,
+    /// Twiddles for the FFT butterflies.
+    roots: Vec<Complex>,
+    bit_reverse: Vec<usize>,
+    folded: Vec<f32>,
+    fft: Vec<Complex>,
+}
+
+impl Mdct {
+    pub(super) fn new() -> Self {
+        let window = (0..BLOCK_LEN)
+            .map(|n| (PI * (n as f64 + 0.5) / BLOCK_LEN as f64).sin() as f32)
+            .collect();
+
+        let rotation = (0..FFT_LEN)
+            .map(|n| Complex::from_angle(-PI * (n as f64 + 0.125) / SPECTRUM_LEN as f64))
+            .collect();
+
+        let roots = (0..FFT_LEN / 2)
+            .map(|k| Complex::from_angle(-2.0 * PI * k as f64 / FFT_LEN as f64))
+            .collect();
+
+        let bits = FFT_LEN.trailing_zeros();
+        let bit_reverse = (0..FFT_LEN)
+            .map(|i| i.reverse_bits() >> (usize::BITS - bits))
+            .collect();
+
+        Self {
+            window,
+            rotation,
+            roots,
+            bit_reverse,
+            folded: vec![0.0; SPECTRUM_LEN],
+            fft: vec![Complex::default(); FFT_LEN],
+        }
+    }
+
+    /// Applies the sine window to `block` (scaled by `gain`) and writes the
+    /// MDCT of the result to `out`.
+    pub(super) fn forward(&mut self, block: &[f32], gain: f32, out: &mut [f32]) {
+        debug_assert_eq!(block.len(), BLOCK_LEN);
+        debug_assert_eq!(out.len(), SPECTRUM_LEN);
+
+        let x = |n: usize| block[n] * self.window[n] * gain;
+
+        // Fold the four quarters (a, b, c, d) of the block into (-c_r - d, a - b_r).
+        for n in 0..QUARTER {
+            self.folded[n] = -x(3 * QUARTER - 1 - n) - x(3 * QUARTER + n);
+            self.folded[QUARTER + n] = x(n) - x(2 * QUARTER - 1 - n);
+        }
+
+        // DCT-IV of the folded block via a half-length complex FFT.
+        for n in 0..FFT_LEN {
+            let v = Complex {
+                re: self.folded[2 * n],
+                im: self.folded[SPECTRUM_LEN - 1 - 2 * n],
+            };
+            self.fft[self.bit_reverse[n]] = v.mul(self.rotation[n]);
+        }
+        self.fft_in_place();
+        for k in 0..FFT_LEN {
+            let y = self.fft[k].mul(self.rotation[k]);
+            out[2 * k] = y.re;
+            out[SPECTRUM_LEN - 1 - 2 * k] = -y.im;
+        }
+    }
+
+    /// Iterative radix-2 FFT over `self.fft`, which must already be in
+    /// bit-reversed order.
+    fn fft_in_place(&mut self) {
+        let mut size = 2;
+        while size <= FFT_LEN {
+            let half = size / 2;
+            let step = FFT_LEN / size;
+            for start in (0..FFT_LEN).step_by(size) {
+                for j in 0..half {
+                    let t = self.fft[start + j + half].mul(self.roots[j * step]);
+                    let u = self.fft[start + j];
+                    self.fft[start + j] = Complex {
+                        re: u.re + t.re,
+                        im: u.im + t.im,
+                    };
+                    self.fft[start + j + half] = Complex {
+                        re: u.re - t.re,
+                        im: u.im - t.im,
+                    };
+                }
+            }
+            size *= 2;
+        }
+    }
+}
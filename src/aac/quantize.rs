@@ -0,0 +1,416 @@
+//! Quantization, noiseless coding and bitstream layout of a single channel's
+//! spectrum (an `individual_channel_stream`, long windows only).
+use super::{
+    bits::BitWriter,
+    mdct::SPECTRUM_LEN,
+    tables::{SCALEFACTOR_CODES, SCALEFACTOR_LENS, SPECTRUM_CODEBOOKS},
+};
+
+const ZERO_HCB: u8 = 0;
+const ESC_HCB: u8 = 11;
+
+/// Largest magnitude representable by each codebook (index 11 escapes).
+const CODEBOOK_LAV: [u32; 12] = [0, 1, 1, 2, 2, 4, 4, 7, 7, 12, 12, 16];
+
+/// Largest quantized magnitude the bitstream can carry.
+const MAX_QUANT: u32 = 8191;
+
+/// Offset applied to scalefactors by the decoder's gain computation.
+const SF_OFFSET: i32 = 100;
+
+/// Rounding offset of the quantizer; slightly below 0.5 to account for the
+/// non-uniform dequantization.
+const MAGIC_NUMBER: f32 = 0.4054;
+
+/// How far (in dB) the noise allowance of a band moves from the target for
+/// purely tonal and purely noise-like content: noise masks quantization noise
+/// far better than tones do.
+const TONAL_MARGIN_DB: f32 = 6.0;
+const NOISE_MARGIN_DB: f32 = 6.0;
+
+/// Section length field width for long windows, and its escape value.
+const SECT_BITS: u32 = 5;
+const SECT_ESC: usize = (1 << SECT_BITS) - 1;
+
+/// Per-band measurements of one channel's spectrum, computed once per frame
+/// and reused for every quantizer pass.
+pub(super) struct BandAnalysis {
+    energy: Vec<f32>,
+    /// Noise allowance of each band relative to the frame's target, derived
+    /// from its spectral flatness.
+    margin: Vec<f32>,
+    sum_sqrt: Vec<f32>,
+    max_abs: Vec<f32>,
+    /// `|x|^(3/4)` for every coefficient.
+    x34: Vec<f32>,
+}
+
+impl BandAnalysis {
+    pub(super) fn new(spectrum: &[f32], swb: &[u16], max_sfb: usize) -> Self {
+        let mut energy = Vec::with_capacity(max_sfb);
+        let mut margin = Vec::with_capacity(max_sfb);
+        let mut sum_sqrt = Vec::with_capacity(max_sfb);
+        let mut max_abs = Vec::with_capacity(max_sfb);
+        for band in swb.windows(2).take(max_sfb) {
+            let coefs = &spectrum[usize::from(band[0])..usize::from(band[1])];
+            let e: f32 = coefs.iter().map(|x| x * x).sum();
+            energy.push(e);
+            margin.push(noise_margin(coefs, e));
+            sum_sqrt.push(coefs.iter().map(|x| x.abs().sqrt()).sum());
+            max_abs.push(coefs.iter().fold(0.0f32, |m, x| m.max(x.abs())));
+        }
+        let x34 = spectrum.iter().map(|x| x.abs().powf(0.75)).collect();
+        Self {
+            energy,
+            margin,
+            sum_sqrt,
+            max_abs,
+            x34,
+        }
+    }
+}
+
+/// A quantized channel spectrum along with its side information.
+#[derive(Clone)]
+pub(super) struct ChannelCoding {
+    scalefactors: Vec<i32>,
+    codebooks: Vec<u8>,
+    quant: Vec<i32>,
+}
+
+impl ChannelCoding {
+    /// Quantizes `spectrum` so that the noise in each band is roughly
+    /// `snr_db` below the band's energy, but never below the absolute
+    /// threshold of hearing `ath`.
+    pub(super) fn quantize(
+        spectrum: &[f32],
+        analysis: &BandAnalysis,
+        swb: &[u16],
+        ath: &[f32],
+        snr_db: f32,
+    ) -> Self {
+        let max_sfb = analysis.energy.len();
+        let noise_ratio = 10f32.powf(-snr_db / 10.0);
+        let max_quant = (MAX_QUANT as f32 - 1.0).log2();
+
+        let mut coding = Self {
+            scalefactors: vec![0; max_sfb],
+            codebooks: vec![ZERO_HCB; max_sfb],
+            quant: vec![0; SPECTRUM_LEN],
+        };
+
+        let mut active = vec![false; max_sfb];
+        for b in 0..max_sfb {
+            let energy = analysis.energy[b];
+            let allowed = (energy * noise_ratio * analysis.margin[b]).max(ath[b]);
+            if energy <= allowed || analysis.sum_sqrt[b] <= 0.0 {
+                continue;
+            }
+            // Noise of the power-law quantizer in a band is approximately
+            // 4/27 * A^(3/2) * sum(sqrt|x|), where A = 2^((sf - 100) / 4).
+            let sf = SF_OFFSET as f32
+                + 8.0 / 3.0 * (allowed * 27.0 / (4.0 * analysis.sum_sqrt[b])).log2();
+            // The largest coefficient must still fit in the quantizer range.
+            let sf_min =
+                SF_OFFSET as f32 + 4.0 * (analysis.max_abs[b].log2() - 4.0 / 3.0 * max_quant);
+            let sf = sf.max(sf_min).ceil().clamp(0.0, 255.0) as i32;
+            coding.scalefactors[b] = sf;
+            active[b] = true;
+        }
+
+        // Scalefactors are coded as differences limited to +-60, so clamp the
+        // chain of active bands, requantizing until it is stable since bands
+        // may quantize to all zeros and drop out of the chain.
+        for (b, active) in active.iter_mut().enumerate() {
+            if *active {
+                *active = coding.quantize_band(spectrum, analysis, swb, b);
+            }
+        }
+        loop {
+            let mut changed = false;
+            let mut prev = None;
+            for (b, active) in active.iter_mut().enumerate() {
+                if !*active {
+                    continue;
+                }
+                if let Some(prev) = prev {
+                    let sf = coding.scalefactors[b].clamp(prev - 60, prev + 60);
+                    if sf != coding.scalefactors[b] {
+                        coding.scalefactors[b] = sf;
+                        *active = coding.quantize_band(spectrum, analysis, swb, b);
+                        changed = true;
+                    }
+                }
+                if *active {
+                    prev = Some(coding.scalefactors[b]);
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        coding
+    }
+
+    /// Quantizes band `b` with its current scalefactor, picks a codebook and
+    /// returns whether any coefficient is non-zero.
+    fn quantize_band(
+        &mut self,
+        spectrum: &[f32],
+        analysis: &BandAnalysis,
+        swb: &[u16],
+        b: usize,
+    ) -> bool {
+        let range = usize::from(swb[b])..usize::from(swb[b + 1]);
+        let inv_step = 2f32.powf(-0.1875 * (self.scalefactors[b] - SF_OFFSET) as f32);
+        let mut max = 0;
+        for i in range.clone() {
+            let q = ((analysis.x34[i] * inv_step + MAGIC_NUMBER) as u32).min(MAX_QUANT);
+            max = max.max(q);
+            let q = q as i32;
+            self.quant[i] = if spectrum[i] < 0.0 { -q } else { q };
+        }
+        self.codebooks[b] = best_codebook(&self.quant[range], max).0;
+        max > 0
+    }
+
+    /// The scalefactor transmitted as `global_gain`: that of the first
+    /// non-zero band.
+    fn global_gain(&self) -> i32 {
+        self.codebooks
+            .iter()
+            .position(|&cb| cb != ZERO_HCB)
+            .map_or(SF_OFFSET, |b| self.scalefactors[b])
+    }
+
+    /// Index of the band after the last non-zero band.
+    pub(super) fn used_bands(&self) -> usize {
+        self.codebooks
+            .iter()
+            .rposition(|&cb| cb != ZERO_HCB)
+            .map_or(0, |b| b + 1)
+    }
+
+    /// Number of bits `write` will produce for the first `max_sfb` bands.
+    pub(super) fn bit_count(&self, swb: &[u16], max_sfb: usize) -> usize {
+        let mut bits = 8 + 3; // global_gain, pulse/tns/gain control flags
+
+        for (_, len) in sections(&self.codebooks[..max_sfb]) {
+            bits += 4 + SECT_BITS as usize * (len / SECT_ESC + 1);
+        }
+
+        let mut last_sf = self.global_gain();
+        for b in 0..max_sfb {
+            let cb = self.codebooks[b];
+            if cb == ZERO_HCB {
+                continue;
+            }
+            let sf = self.scalefactors[b];
+            bits += usize::from(SCALEFACTOR_LENS[sf_index(sf - last_sf)]);
+            last_sf = sf;
+            let range = usize::from(swb[b])..usize::from(swb[b + 1]);
+            bits += codebook_bits(cb, &self.quant[range]);
+        }
+
+        bits
+    }
+
+    /// Writes an `individual_channel_stream`. The `ics_info` closure writes
+    /// the window information following `global_gain`, and writes nothing
+    /// when the element shares a common window.
+    pub(super) fn write(
+        &self,
+        w: &mut BitWriter,
+        swb: &[u16],
+        max_sfb: usize,
+        ics_info: impl Fn(&mut BitWriter),
+    ) {
+        let global_gain = self.global_gain();
+        w.put(global_gain as u32, 8);
+        ics_info(w);
+
+        // section_data
+        for (cb, mut len) in sections(&self.codebooks[..max_sfb]) {
+            w.put(u32::from(cb), 4);
+            while len >= SECT_ESC {
+                w.put(SECT_ESC as u32, SECT_BITS);
+                len -= SECT_ESC;
+            }
+            w.put(len as u32, SECT_BITS);
+        }
+
+        // scale_factor_data
+        let mut last_sf = global_gain;
+        for b in 0..max_sfb {
+            if self.codebooks[b] == ZERO_HCB {
+                continue;
+            }
+            let sf = self.scalefactors[b];
+            let index = sf_index(sf - last_sf);
+            w.put(SCALEFACTOR_CODES[index], u32::from(SCALEFACTOR_LENS[index]));
+            last_sf = sf;
+        }
+
+        w.put_bool(false); // pulse_data_present
+        w.put_bool(false); // tns_data_present
+        w.put_bool(false); // gain_control_data_present
+
+        // spectral_data
+        for b in 0..max_sfb {
+            let cb = self.codebooks[b];
+            if cb != ZERO_HCB {
+                let range = usize::from(swb[b])..usize::from(swb[b + 1]);
+                write_codebook(w, cb, &self.quant[range]);
+            }
+        }
+    }
+}
+
+/// Scales the noise allowed in a band by how noise-like its coefficients
+/// are, using the spectral flatness measure (geometric over arithmetic mean
+/// of the energies). Gaussian noise measures around -5dB, tones far below.
+fn noise_margin(coefs: &[f32], energy: f32) -> f32 {
+    if energy <= 0.0 {
+        return 1.0;
+    }
+    let n = coefs.len() as f32;
+    let log_mean = coefs.iter().map(|x| (x * x + 1e-9).ln()).sum::<f32>() / n;
+    let flatness_db = 10.0 * (log_mean.exp() / (energy / n)).log10();
+    let tonality = ((-flatness_db - 6.0) / 14.0).clamp(0.0, 1.0);
+    let margin_db = NOISE_MARGIN_DB * (1.0 - tonality) - TONAL_MARGIN_DB * tonality;
+    10f32.powf(margin_db / 10.0)
+}
+
+fn sf_index(diff: i32) -> usize {
+    debug_assert!((-60..=60).contains(&diff));
+    (diff + 60) as usize
+}
+
+/// Groups consecutive bands that use the same codebook into sections.
+fn sections(codebooks: &[u8]) -> Vec<(u8, usize)> {
+    let mut sections: Vec<(u8, usize)> = Vec::new();
+    for &cb in codebooks {
+        match sections.last_mut() {
+            Some((last, len)) if *last == cb => *len += 1,
+            _ => sections.push((cb, 1)),
+        }
+    }
+    sections
+}
+
+/// Picks the cheapest codebook able to represent a band whose largest
+/// quantized magnitude is `max`, returning it along with its bit cost.
+fn best_codebook(quant: &[i32], max: u32) -> (u8, usize) {
+    let candidates: &[u8] = match max {
+        0 => return (ZERO_HCB, 0),
+        1 => &[1, 2],
+        2 => &[3, 4],
+        3..=4 => &[5, 6],
+        5..=7 => &[7, 8],
+        8..=12 => &[9, 10],
+        _ => &[ESC_HCB],
+    };
+    candidates
+        .iter()
+        .map(|&cb| (cb, codebook_bits(cb, quant)))
+        .min_by_key(|&(_, bits)| bits)
+        .unwrap_or((ESC_HCB, codebook_bits(ESC_HCB, quant)))
+}
+
+/// Calls `f` with the codeword index, the magnitudes that need sign bits
+/// and the escaped values of every codeword needed to code `quant` with
+/// codebook `cb`.
+fn for_each_codeword(cb: u8, quant: &[i32], mut f: impl FnMut(usize, &[i32])) {
+    debug_assert!(quant
+        .iter()
+        .all(|q| q.unsigned_abs() <= CODEBOOK_LAV[usize::from(cb)] || cb == ESC_HCB));
+    match cb {
+        1 | 2 => {
+            for q in quant.chunks_exact(4) {
+                let index = q.iter().fold(0, |acc, &v| acc * 3 + (v + 1) as usize);
+                f(index, &[]);
+            }
+        }
+        3 | 4 => {
+            for q in quant.chunks_exact(4) {
+                let index = q
+                    .iter()
+                    .fold(0, |acc, &v| acc * 3 + v.unsigned_abs() as usize);
+                f(index, q);
+            }
+        }
+        5 | 6 => {
+            for q in quant.chunks_exact(2) {
+                f(9 * (q[0] + 4) as usize + (q[1] + 4) as usize, &[]);
+            }
+        }
+        7 | 8 => {
+            for q in quant.chunks_exact(2) {
+                f(
+                    8 * q[0].unsigned_abs() as usize + q[1].unsigned_abs() as usize,
+                    q,
+                );
+            }
+        }
+        9 | 10 => {
+            for q in quant.chunks_exact(2) {
+                f(
+                    13 * q[0].unsigned_abs() as usize + q[1].unsigned_abs() as usize,
+                    q,
+                );
+            }
+        }
+        _ => {
+            for q in quant.chunks_exact(2) {
+                let y = q[0].unsigned_abs().min(16) as usize;
+                let z = q[1].unsigned_abs().min(16) as usize;
+                f(17 * y + z, q);
+            }
+        }
+    }
+}
+
+/// Bits needed for the escape sequence of a magnitude of at least 16.
+fn escape_bits(v: u32) -> usize {
+    let n = v.ilog2() as usize;
+    2 * n - 3
+}
+
+fn codebook_bits(cb: u8, quant: &[i32]) -> usize {
+    let lens = SPECTRUM_CODEBOOKS[usize::from(cb) - 1].1;
+    let mut bits = 0;
+    for_each_codeword(cb, quant, |index, signed| {
+        bits += usize::from(lens[index]);
+        for &v in signed {
+            if v != 0 {
+                bits += 1;
+            }
+            if cb == ESC_HCB && v.unsigned_abs() >= 16 {
+                bits += escape_bits(v.unsigned_abs());
+            }
+        }
+    });
+    bits
+}
+
+fn write_codebook(w: &mut BitWriter, cb: u8, quant: &[i32]) {
+    let (codes, lens) = SPECTRUM_CODEBOOKS[usize::from(cb) - 1];
+    for_each_codeword(cb, quant, |index, signed| {
+        w.put(codes[index], u32::from(lens[index]));
+        for &v in signed {
+            if v != 0 {
+                w.put_bool(v < 0);
+            }
+        }
+        if cb == ESC_HCB {
+            for v in signed.iter().map(|v| v.unsigned_abs()).filter(|&v| v >= 16) {
+                let n = v.ilog2();
+                // escape_prefix: (n - 4) ones and a terminating zero
+                w.put((1 << (n - 4)) - 1, n - 4);
+                w.put(0, 1);
+                w.put(v - (1 << n), n);
+            }
+        }
+    });
+}
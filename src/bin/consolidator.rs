@@ -1,19 +1,523 @@
-use clap::Parser;
-use consolidator::processor;
+use std::{
+    io::{IsTerminal, Write},
+    num::NonZeroUsize,
+    path::Path,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use clap::{error::ErrorKind, CommandFactory, Parser, ValueEnum};
+use consolidator::{
+    cancel::CancellationToken,
+    channels::{Layout, TargetLayout},
+    cover::{CoverOptions, CoverSource},
+    decode::ErrorMode,
+    loudness::{NormalizeMode, NormalizeOptions, NormalizeScope},
+    ordering::OrderStrategy,
+    processor::{self, ChapterOptions, ConsolidationJob, EncodingOptions, LoudnessOptions},
+    progress::{Progress, ProgressSink},
+    resample::{Quality, ResampleOptions, TargetRate},
+    silence::{SilenceOptions, SplitOptions},
+};
 use thiserror::Error;
-use tracing::{info, level_filters::LevelFilter};
-use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+use tracing::{info, level_filters::LevelFilter, warn};
+use tracing_subscriber::{fmt, fmt::MakeWriter, prelude::*, EnvFilter};
+
+/// How often the bars are redrawn.
+const DRAW_INTERVAL: Duration = Duration::from_millis(100);
+/// How often progress is logged when stderr isn't a terminal.
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+/// Width of the progress bars, in characters.
+const BAR_WIDTH: usize = 30;
+/// Room left for the name of the file being written.
+const NAME_WIDTH: usize = 36;
 
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Processing Error: {0}")]
     Processing(#[from] processor::Error),
+    #[error("Report Error: {0}")]
+    Report(#[from] std::io::Error),
+    #[error("{0} file(s) failed")]
+    FilesFailed(usize),
+    #[error("Signal Error: {0}")]
+    Signal(#[from] ctrlc::Error),
 }
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Consolidator {
     target_path: std::path::PathBuf,
+    /// How to order the input files, and so the chapters
+    #[arg(long, value_enum, default_value_t = Order::Name)]
+    order: Order,
+    /// Image to use as the cover art, instead of one from the tags or the directory
+    #[arg(long, value_name = "PATH", conflicts_with = "no_cover")]
+    cover: Option<std::path::PathBuf>,
+    /// Leave out cover art
+    #[arg(long)]
+    no_cover: bool,
+    /// Scale the cover art down so neither side exceeds this many pixels
+    #[arg(long, value_name = "PIXELS")]
+    cover_max_dimension: Option<u32>,
+    /// Re-encode the cover art as a JPEG of at most this many bytes
+    #[arg(long, value_name = "BYTES")]
+    cover_max_bytes: Option<usize>,
+    /// Sample rate of the output in Hz, or "most-common" or "highest" to pick
+    /// it from the inputs; inputs at other rates are resampled
+    #[arg(long, value_name = "RATE", default_value = "most-common", value_parser = parse_rate)]
+    sample_rate: TargetRate,
+    /// Accuracy of the resampling, traded for speed
+    #[arg(long, value_enum, default_value_t = ResampleQuality::Balanced)]
+    resample_quality: ResampleQuality,
+    /// Channel layout of the output; inputs with other layouts are downmixed
+    /// or upmixed
+    #[arg(long, value_enum, default_value_t = ChannelLayout::Auto)]
+    channels: ChannelLayout,
+    /// Fail the whole book on any error, rather than replacing packets that
+    /// can't be decoded with silence and leaving out files that can't be read
+    #[arg(long)]
+    strict: bool,
+    /// Number of files to decode at once [default: one per CPU]
+    #[arg(long, short, value_name = "N")]
+    jobs: Option<NonZeroUsize>,
+    /// Trim the silence at the start and end of each file, leaving the same
+    /// gap between every chapter
+    #[arg(long)]
+    trim_silence: bool,
+    /// Level below which audio counts as silence, in dBFS [default: -50]
+    #[arg(
+        long,
+        value_name = "DB",
+        allow_negative_numbers = true,
+        requires = "trim_silence"
+    )]
+    silence_threshold: Option<f32>,
+    /// Shortest silence that's trimmed, in seconds [default: 1]
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds, requires = "trim_silence")]
+    silence_min: Option<Duration>,
+    /// Silence left between chapters, in seconds, half of which is left at the
+    /// start and end of the book [default: 1]
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds, requires = "trim_silence")]
+    silence_gap: Option<Duration>,
+    /// Keep each file named in a cue sheet as one chapter, rather than
+    /// splitting it at the sheet's tracks
+    #[arg(long)]
+    no_cue_sheets: bool,
+    /// Split each file into chapters at its longest pauses
+    #[arg(long)]
+    split_chapters: bool,
+    /// Level below which audio counts as a pause, in dBFS [default: -50]
+    #[arg(
+        long,
+        value_name = "DB",
+        allow_negative_numbers = true,
+        requires = "split_chapters"
+    )]
+    pause_threshold: Option<f32>,
+    /// Shortest silence that can separate chapters, in seconds [default: 2]
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds, requires = "split_chapters")]
+    min_pause: Option<Duration>,
+    /// Shortest chapter to split off, in seconds [default: 300]
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds, requires = "split_chapters")]
+    min_chapter: Option<Duration>,
+    /// Number of chapters to split each file into, if it has enough pauses
+    /// [default: one for each pause]
+    #[arg(long, value_name = "N", requires = "split_chapters")]
+    target_chapters: Option<NonZeroUsize>,
+    /// Measure the loudness of each file for the report
+    #[arg(long)]
+    measure_loudness: bool,
+    /// Bring each file, or the book as a whole, to the target loudness
+    #[arg(long, value_enum, value_name = "SCOPE")]
+    normalize: Option<Normalize>,
+    /// Loudness to normalize to, in LUFS [default: -18]
+    #[arg(
+        long,
+        value_name = "LUFS",
+        allow_negative_numbers = true,
+        requires = "normalize"
+    )]
+    target_loudness: Option<f64>,
+    /// Highest true peak to allow when normalizing, in dBTP [default: -1]
+    #[arg(
+        long,
+        value_name = "DBTP",
+        allow_negative_numbers = true,
+        requires = "normalize"
+    )]
+    true_peak: Option<f64>,
+    /// Whether normalizing applies the gain to the audio, or only tags the
+    /// book with it for players to apply (which is only for the book as a
+    /// whole)
+    #[arg(long, value_enum, default_value_t = NormalizeBy::Gain, requires = "normalize")]
+    normalize_by: NormalizeBy,
+    /// Write the chapters of the output to this file for review, or to stdout
+    /// if it's "-", one to a line as start time and title
+    #[arg(long, value_name = "PATH", conflicts_with = "dry_run")]
+    export_chapters: Option<std::path::PathBuf>,
+    /// Write a JSON report of the run to this file, or to stdout if it's "-"
+    #[arg(long, value_name = "PATH")]
+    report: Option<std::path::PathBuf>,
+    /// Only probe the inputs, and print what would be produced
+    #[arg(long, conflicts_with = "report")]
+    dry_run: bool,
+    /// How to print the plan of a dry run
+    #[arg(long, value_enum, default_value_t = PlanFormat::Table, requires = "dry_run")]
+    plan_format: PlanFormat,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Order {
+    /// Natural sort by file name ("2 - x.mp3" before "10 - x.mp3")
+    Name,
+    /// Sort by disc and track number tags, failing if they're missing or duplicated
+    Tags,
+}
+
+impl From<Order> for OrderStrategy {
+    fn from(order: Order) -> Self {
+        match order {
+            Order::Name => OrderStrategy::Name,
+            Order::Tags => OrderStrategy::Tags,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Normalize {
+    /// Each file on its own, so every chapter is as loud as the others
+    File,
+    /// The book as a whole, keeping the differences between the files
+    Book,
+}
+
+impl From<Normalize> for NormalizeScope {
+    fn from(normalize: Normalize) -> Self {
+        match normalize {
+            Normalize::File => NormalizeScope::File,
+            Normalize::Book => NormalizeScope::Book,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum NormalizeBy {
+    /// Change the level of the audio
+    Gain,
+    /// Leave the audio as it is, and write ReplayGain and iTunes Sound Check
+    /// (iTunNORM) tags with the gain for the book as a whole, so only with
+    /// `--normalize book`. The loudness of each chapter is only measured for
+    /// the report
+    Tags,
+}
+
+impl From<NormalizeBy> for NormalizeMode {
+    fn from(normalize_by: NormalizeBy) -> Self {
+        match normalize_by {
+            NormalizeBy::Gain => NormalizeMode::Apply,
+            NormalizeBy::Tags => NormalizeMode::Tags,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ResampleQuality {
+    /// Short filter, about 60dB of alias rejection
+    Fast,
+    /// About 90dB of alias rejection
+    Balanced,
+    /// Long filter, about 120dB of alias rejection
+    Best,
+}
+
+impl From<ResampleQuality> for Quality {
+    fn from(quality: ResampleQuality) -> Self {
+        match quality {
+            ResampleQuality::Fast => Quality::Fast,
+            ResampleQuality::Balanced => Quality::Balanced,
+            ResampleQuality::Best => Quality::Best,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ChannelLayout {
+    /// Stereo if any input has distinct left and right channels, otherwise
+    /// mono (so inputs with the same audio in both channels become mono)
+    Auto,
+    Mono,
+    Stereo,
+}
+
+impl From<ChannelLayout> for TargetLayout {
+    fn from(layout: ChannelLayout) -> Self {
+        match layout {
+            ChannelLayout::Auto => TargetLayout::Auto,
+            ChannelLayout::Mono => TargetLayout::Fixed(Layout::Mono),
+            ChannelLayout::Stereo => TargetLayout::Fixed(Layout::Stereo),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum PlanFormat {
+    Table,
+    Json,
+}
+
+/// Shows the progress of a job on stderr: when stderr is a terminal, as bars
+/// for the file being written and for the whole job, below the log, otherwise
+/// as a log line every `LOG_INTERVAL`.
+struct ProgressDisplay {
+    terminal: bool,
+    state: Mutex<ProgressState>,
+}
+
+#[derive(Default)]
+struct ProgressState {
+    started: Option<Instant>,
+    /// The name of each input and how much of it has been decoded, as a
+    /// fraction of its estimated duration.
+    files: Vec<(String, f64)>,
+    /// The input being written, which is the first that isn't finished.
+    current: usize,
+    /// Seconds of the output encoded so far.
+    encoded: f64,
+    finalizing: bool,
+    /// Whether the bars are on the screen.
+    drawn: bool,
+    last_update: Option<Instant>,
+}
+
+impl ProgressDisplay {
+    fn new() -> Self {
+        Self {
+            terminal: std::io::stderr().is_terminal(),
+            state: Mutex::new(ProgressState::default()),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, ProgressState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl ProgressSink for ProgressDisplay {
+    fn progress(&self, event: Progress<'_>) {
+        let mut state = self.state();
+        let now = Instant::now();
+        let mut urgent = true;
+        match event {
+            Progress::Started { files } => {
+                state.started = Some(now);
+                state.last_update = Some(now);
+                state.files = vec![(String::new(), 0.0); files];
+            }
+            Progress::FileStarted { index, path, .. } => {
+                if let Some(file) = state.files.get_mut(index) {
+                    file.0 = file_name(path);
+                }
+            }
+            Progress::Decoded {
+                index,
+                seconds,
+                duration,
+            } => {
+                if let (Some(file), Some(duration)) = (state.files.get_mut(index), duration) {
+                    file.1 = (seconds / duration).clamp(0.0, 1.0);
+                }
+                urgent = false;
+            }
+            Progress::Encoded { seconds, .. } => {
+                state.encoded = seconds;
+                urgent = false;
+            }
+            Progress::FileFinished { index, .. } => {
+                if let Some(file) = state.files.get_mut(index) {
+                    file.1 = 1.0;
+                }
+                state.current = index + 1;
+            }
+            Progress::Finalizing => state.finalizing = true,
+            Progress::Finished => {
+                if state.drawn {
+                    clear(&mut std::io::stderr().lock());
+                    state.drawn = false;
+                }
+                return;
+            }
+        }
+
+        let interval = if self.terminal {
+            DRAW_INTERVAL
+        } else {
+            LOG_INTERVAL
+        };
+        let due = state.last_update.is_none_or(|last| now - last >= interval);
+        if self.terminal && (due || urgent) {
+            state.last_update = Some(now);
+            let mut stderr = std::io::stderr().lock();
+            if state.drawn {
+                clear(&mut stderr);
+            }
+            state.draw(&mut stderr);
+            state.drawn = true;
+        } else if !self.terminal && due {
+            state.last_update = Some(now);
+            let line = state.summary();
+            // Not logged under the lock, which the log writer can take.
+            drop(state);
+            info!("{line}");
+        }
+    }
+}
+
+impl ProgressState {
+    /// How much of the job is done, as a fraction.
+    #[allow(clippy::cast_precision_loss)]
+    fn done(&self) -> f64 {
+        let total: f64 = self.files.iter().map(|&(_, done)| done).sum();
+        total / self.files.len().max(1) as f64
+    }
+
+    /// The estimated time left.
+    fn eta(&self) -> Option<Duration> {
+        let elapsed = self.started?.elapsed().as_secs_f64();
+        let done = self.done();
+        (done > 0.0).then(|| Duration::from_secs_f64(elapsed * (1.0 - done) / done))
+    }
+
+    fn summary(&self) -> String {
+        let finished = self.current.min(self.files.len());
+        let mut line = format!(
+            "{:3.0}% done, {finished}/{} file(s), {} encoded",
+            self.done() * 100.0,
+            self.files.len(),
+            clock(self.encoded),
+        );
+        if self.finalizing {
+            line.push_str(", finishing the output");
+        } else if let Some(eta) = self.eta() {
+            line.push_str(&format!(", ETA {}", clock(eta.as_secs_f64())));
+        }
+        line
+    }
+
+    /// Draws the bars on two lines, leaving the cursor at the end of them.
+    fn draw(&self, out: &mut impl Write) {
+        let (name, done) = self
+            .files
+            .get(self.current)
+            .map_or(("", 1.0), |(name, done)| (name.as_str(), *done));
+        let name: String = if name.chars().count() > NAME_WIDTH {
+            let start = name.chars().count() - (NAME_WIDTH - 3);
+            format!("...{}", name.chars().skip(start).collect::<String>())
+        } else {
+            name.to_owned()
+        };
+        let _ = write!(
+            out,
+            "{name:<NAME_WIDTH$} {} {:3.0}%\n{:<NAME_WIDTH$} {} {}",
+            bar(done),
+            done * 100.0,
+            "Total",
+            bar(self.done()),
+            self.summary(),
+        );
+        let _ = out.flush();
+    }
+}
+
+/// Erases the bars, leaving the cursor where they started.
+fn clear(out: &mut impl Write) {
+    let _ = write!(out, "\r\x1b[K\x1b[1A\r\x1b[K");
+    let _ = out.flush();
+}
+
+fn bar(done: f64) -> String {
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let filled = ((done * BAR_WIDTH as f64) as usize).min(BAR_WIDTH);
+    format!("[{}{}]", "#".repeat(filled), "-".repeat(BAR_WIDTH - filled))
+}
+
+/// Seconds as h:mm:ss.
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn clock(seconds: f64) -> String {
+    let seconds = seconds.max(0.0).round() as u64;
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or(path.as_os_str())
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Writes log lines to stderr, above the progress bars if they're shown.
+#[derive(Clone)]
+struct LogWriter(Arc<ProgressDisplay>);
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        let display = &self.0;
+        if !display.terminal {
+            return std::io::stderr().write_all(buf);
+        }
+        let state = display.state();
+        let mut stderr = std::io::stderr().lock();
+        if state.drawn {
+            clear(&mut stderr);
+        }
+        stderr.write_all(buf)?;
+        if state.drawn {
+            state.draw(&mut stderr);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogWriter {
+    type Writer = Self;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    s.parse()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| format!("expected a number of seconds, not \"{s}\""))
+}
+
+fn parse_rate(s: &str) -> Result<TargetRate, String> {
+    match s {
+        "most-common" => Ok(TargetRate::MostCommon),
+        "highest" => Ok(TargetRate::Highest),
+        _ => match s.parse() {
+            Ok(rate) if rate > 0 => Ok(TargetRate::Fixed(rate)),
+            _ => Err(format!(
+                "expected a rate in Hz, \"most-common\" or \"highest\", not \"{s}\""
+            )),
+        },
+    }
 }
 
 fn main() -> Result<(), Error> {
@@ -21,16 +525,134 @@ fn main() -> Result<(), Error> {
         .with_default_directive(LevelFilter::INFO.into())
         .from_env_lossy();
 
+    let progress = Arc::new(ProgressDisplay::new());
     tracing_subscriber::registry()
-        .with(fmt::layer())
+        .with(fmt::layer().with_writer(LogWriter(progress.clone())))
         .with(env_filter)
         .init();
 
     info!("Hello, world!");
 
     let args = Consolidator::parse();
+    // The book is a single track, so there are no per-file tags to write.
+    if matches!(
+        (args.normalize, args.normalize_by),
+        (Some(Normalize::File), NormalizeBy::Tags)
+    ) {
+        Consolidator::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "'--normalize file' can't be used with '--normalize-by tags', \
+                 which tags the book as a whole; use '--normalize book'",
+            )
+            .exit();
+    }
+
+    let source = match (args.cover, args.no_cover) {
+        (_, true) => CoverSource::None,
+        (Some(path), false) => CoverSource::File(path),
+        (None, false) => CoverSource::Auto,
+    };
+    let options = processor::Options {
+        order: args.order.into(),
+        cover: CoverOptions {
+            source,
+            max_dimension: args.cover_max_dimension,
+            max_bytes: args.cover_max_bytes,
+        },
+        encoding: EncodingOptions {
+            resample: ResampleOptions {
+                rate: args.sample_rate,
+                quality: args.resample_quality.into(),
+            },
+            channels: args.channels.into(),
+            ..EncodingOptions::default()
+        },
+        errors: if args.strict {
+            ErrorMode::Strict
+        } else {
+            ErrorMode::Lenient
+        },
+        jobs: args.jobs,
+        chapters: ChapterOptions {
+            cue_sheets: !args.no_cue_sheets,
+            ..ChapterOptions::default()
+        },
+        silence: args.trim_silence.then(|| {
+            let default = SilenceOptions::default();
+            SilenceOptions {
+                threshold: args.silence_threshold.unwrap_or(default.threshold),
+                min_duration: args.silence_min.unwrap_or(default.min_duration),
+                gap: args.silence_gap.unwrap_or(default.gap),
+            }
+        }),
+        split: args.split_chapters.then(|| {
+            let default = SplitOptions::default();
+            SplitOptions {
+                threshold: args.pause_threshold.unwrap_or(default.threshold),
+                min_pause: args.min_pause.unwrap_or(default.min_pause),
+                min_chapter: args.min_chapter.unwrap_or(default.min_chapter),
+                target_chapters: args.target_chapters,
+            }
+        }),
+        loudness: LoudnessOptions {
+            measure: args.measure_loudness,
+            normalize: args.normalize.map(|scope| {
+                let default = NormalizeOptions::default();
+                NormalizeOptions {
+                    target: args.target_loudness.unwrap_or(default.target),
+                    true_peak: args.true_peak.unwrap_or(default.true_peak),
+                    scope: scope.into(),
+                    mode: args.normalize_by.into(),
+                }
+            }),
+        },
+        ..processor::Options::default()
+    };
+    // Ctrl-C stops the job cleanly, rather than leaving half an output.
+    let cancel = CancellationToken::new();
+    ctrlc::set_handler({
+        let cancel = cancel.clone();
+        move || {
+            warn!("Cancelling");
+            cancel.cancel();
+        }
+    })?;
+    let job = ConsolidationJob::directory(&args.target_path, &options)?.cancellation(cancel);
+
+    if args.dry_run {
+        info!("Planning path: {}", args.target_path.display());
+        let plan = job.plan()?;
+        match args.plan_format {
+            PlanFormat::Table => print!("{plan}"),
+            PlanFormat::Json => println!(
+                "{}",
+                serde_json::to_string_pretty(&plan).map_err(std::io::Error::from)?
+            ),
+        }
+        return Ok(());
+    }
 
-    processor::process(&args.target_path)?;
+    info!("Processing path: {}", args.target_path.display());
+    let report = job.progress(progress).run()?;
 
-    Ok(())
+    if let Some(path) = args.report {
+        let json = serde_json::to_string_pretty(&report).map_err(std::io::Error::from)?;
+        if path.as_os_str() == "-" {
+            println!("{json}");
+        } else {
+            std::fs::write(&path, json + "\n")?;
+        }
+    }
+    if let Some(path) = args.export_chapters {
+        if path.as_os_str() == "-" {
+            report.write_chapters(std::io::stdout().lock())?;
+        } else {
+            report.write_chapters(std::fs::File::create(&path)?)?;
+        }
+    }
+    match report.failures().count() {
+        0 => Ok(()),
+        failed => Err(Error::FilesFailed(failed)),
+    }
 }
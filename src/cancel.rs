@@ -0,0 +1,30 @@
+//! Cancelling a job from another thread.
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// A flag that asks a job to stop. Clones share the flag, so one can be kept
+/// to cancel the job with while another is given to it. Decoding, encoding
+/// and muxing each check it as they go, and fail with a `Cancelled` error
+/// once it's set.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Creates a token that hasn't been cancelled.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks whatever has the token to stop.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    #[must_use]
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,197 @@
+//! Channel mapping, so that inputs with different channel layouts can be
+//! joined into a single track.
+//!
+//! The output is mono or stereo, as those are the layouts the encoder
+//! supports. Mono inputs are copied to both channels of stereo output, and
+//! inputs with more channels are downmixed: left and right channels go to
+//! their own side, centre channels to both sides at -3dB, surround channels
+//! to their side at -3dB, and LFE channels are dropped. Each side is scaled
+//! so that it can't clip. Mono output is the average of the stereo downmix.
+//!
+//! ref
+//!   ITU-R BS.775 (Multichannel stereophonic sound system with and without
+//!   accompanying picture)
+use symphonia::core::audio::Channels;
+
+/// Gain of the centre and surround channels in a downmix.
+const MINUS_3DB: f32 = std::f32::consts::FRAC_1_SQRT_2;
+/// Stereo with less energy than this in the difference of its channels, as a
+/// fraction of the energy in their sum, is taken to be mono (-60dB).
+const FAKE_STEREO_RATIO: f64 = 1e-6;
+
+/// Channels that are on the left, at the front.
+const FRONT_LEFT: Channels = Channels::FRONT_LEFT
+    .union(Channels::FRONT_LEFT_CENTRE)
+    .union(Channels::FRONT_LEFT_WIDE)
+    .union(Channels::FRONT_LEFT_HIGH)
+    .union(Channels::TOP_FRONT_LEFT);
+/// Channels that are on the right, at the front.
+const FRONT_RIGHT: Channels = Channels::FRONT_RIGHT
+    .union(Channels::FRONT_RIGHT_CENTRE)
+    .union(Channels::FRONT_RIGHT_WIDE)
+    .union(Channels::FRONT_RIGHT_HIGH)
+    .union(Channels::TOP_FRONT_RIGHT);
+/// Channels that are on the left, at the side or behind.
+const SURROUND_LEFT: Channels = Channels::REAR_LEFT
+    .union(Channels::SIDE_LEFT)
+    .union(Channels::REAR_LEFT_CENTRE)
+    .union(Channels::TOP_REAR_LEFT);
+/// Channels that are on the right, at the side or behind.
+const SURROUND_RIGHT: Channels = Channels::REAR_RIGHT
+    .union(Channels::SIDE_RIGHT)
+    .union(Channels::REAR_RIGHT_CENTRE)
+    .union(Channels::TOP_REAR_RIGHT);
+const LFE: Channels = Channels::LFE1.union(Channels::LFE2);
+
+/// A channel layout of the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Mono,
+    Stereo,
+}
+
+impl Layout {
+    /// The number of channels.
+    #[must_use]
+    pub fn count(self) -> usize {
+        match self {
+            Self::Mono => 1,
+            Self::Stereo => 2,
+        }
+    }
+
+    /// The layout that keeps the most of audio with `count` channels.
+    #[must_use]
+    pub fn for_count(count: usize) -> Self {
+        if count == 1 {
+            Self::Mono
+        } else {
+            Self::Stereo
+        }
+    }
+}
+
+/// The channel layout of the output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TargetLayout {
+    /// Stereo if any input has distinct left and right channels (or more
+    /// channels than that), otherwise mono. Stereo inputs whose channels are
+    /// the same are counted as mono.
+    #[default]
+    Auto,
+    /// Every input is converted to the given layout.
+    Fixed(Layout),
+}
+
+/// Converts interleaved audio from an input's channels to an output layout.
+pub struct ChannelMapper {
+    input: Channels,
+    output: Layout,
+    /// Gain of each input channel in each output channel, one output channel
+    /// at a time.
+    matrix: Vec<f32>,
+}
+
+impl ChannelMapper {
+    /// Creates a mapper from audio with the `input` channels to `output`.
+    #[must_use]
+    pub fn new(input: Channels, output: Layout) -> Self {
+        let count = input.count();
+        let (mut left, mut right): (Vec<f32>, Vec<f32>) = if count == 1 {
+            // Whatever its position, a single channel is mono.
+            (vec![1.0], vec![1.0])
+        } else {
+            input
+                .iter()
+                .map(|channel| {
+                    if FRONT_LEFT.contains(channel) {
+                        (1.0, 0.0)
+                    } else if FRONT_RIGHT.contains(channel) {
+                        (0.0, 1.0)
+                    } else if SURROUND_LEFT.contains(channel) {
+                        (MINUS_3DB, 0.0)
+                    } else if SURROUND_RIGHT.contains(channel) {
+                        (0.0, MINUS_3DB)
+                    } else if LFE.contains(channel) {
+                        (0.0, 0.0)
+                    } else {
+                        // Centre channels, and any others.
+                        (MINUS_3DB, MINUS_3DB)
+                    }
+                })
+                .unzip()
+        };
+        for side in [&mut left, &mut right] {
+            let total: f32 = side.iter().sum();
+            if total > 1.0 {
+                for gain in side.iter_mut() {
+                    *gain /= total;
+                }
+            }
+        }
+
+        let matrix = match output {
+            Layout::Mono => left
+                .iter()
+                .zip(&right)
+                .map(|(l, r)| (l + r) / 2.0)
+                .collect(),
+            Layout::Stereo => [left, right].concat(),
+        };
+        Self {
+            input,
+            output,
+            matrix,
+        }
+    }
+
+    /// The channels of the input.
+    #[must_use]
+    pub fn input(&self) -> Channels {
+        self.input
+    }
+
+    /// The layout of the output.
+    #[must_use]
+    pub fn output(&self) -> Layout {
+        self.output
+    }
+
+    /// Maps the interleaved `input`, appending the result to `output`.
+    pub fn map(&self, input: &[f32], output: &mut Vec<f32>) {
+        let channels = self.input.count();
+        output.reserve(input.len() / channels * self.output.count());
+        for frame in input.chunks_exact(channels) {
+            for gains in self.matrix.chunks_exact(channels) {
+                output.push(gains.iter().zip(frame).map(|(g, x)| g * x).sum());
+            }
+        }
+    }
+}
+
+/// Tells whether stereo audio is "fake stereo": the same signal in both
+/// channels, which might as well be mono.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StereoCheck {
+    /// Energy of the sum and of the difference of the channels.
+    sum: f64,
+    difference: f64,
+}
+
+impl StereoCheck {
+    /// Takes interleaved stereo `samples` into account.
+    pub fn add(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(2) {
+            let (l, r) = (f64::from(frame[0]), f64::from(frame[1]));
+            self.sum += (l + r) * (l + r);
+            self.difference += (l - r) * (l - r);
+        }
+    }
+
+    /// Whether the channels of the audio so far are the same, to within
+    /// -60dB. Silence counts as the same.
+    #[must_use]
+    pub fn is_fake(&self) -> bool {
+        self.difference <= self.sum * FAKE_STEREO_RATIO
+    }
+}
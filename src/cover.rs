@@ -0,0 +1,221 @@
+//! Cover art for the output: taken from the input tags or from an image file
+//! next to the inputs, and fitted to size limits before it is embedded.
+//!
+//! Images are only decoded when they need to be resized or re-encoded, in
+//! which case the result is always a baseline JPEG.
+
+// Image codecs convert freely between pixel coordinates, coefficients and
+// sample values, whose ranges are bounded by the formats.
+#![allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_precision_loss,
+    clippy::cast_sign_loss,
+    clippy::cast_possible_wrap
+)]
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error as ThisError;
+use tracing::info;
+
+use crate::mp4::{CoverArt, ImageFormat};
+
+mod inflate;
+mod jpeg;
+mod png;
+mod resize;
+
+/// Images with more pixels than this are refused rather than decoded.
+const MAX_PIXELS: usize = 8192 * 8192;
+
+/// File names (without extension) recognized as cover art, most preferred
+/// first.
+const COVER_NAMES: [&str; 3] = ["cover", "folder", "front"];
+/// Extensions of the images that are recognized as cover art.
+const COVER_EXTENSIONS: [&str; 3] = ["jpg", "jpeg", "png"];
+/// Extensions of files that are images rather than audio.
+const IMAGE_EXTENSIONS: [&str; 8] = ["jpg", "jpeg", "png", "gif", "bmp", "webp", "tif", "tiff"];
+
+/// JPEG qualities tried in turn when re-encoding to fit `max_bytes`.
+const QUALITIES: [u8; 5] = [90, 80, 70, 60, 50];
+/// Images are shrunk by this factor each time no quality fits `max_bytes`.
+const SHRINK: f64 = 0.75;
+/// Re-encoding gives up rather than shrink an image below this size.
+const MIN_DIMENSION: u32 = 64;
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+    #[error("IO Error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Not a JPEG or PNG image")]
+    UnknownFormat,
+    #[error("Unsupported image: {0}")]
+    Unsupported(&'static str),
+    #[error("Malformed image: {0}")]
+    Malformed(&'static str),
+    #[error("Image can't be made smaller than {max_bytes} bytes")]
+    TooLarge { max_bytes: usize },
+}
+
+/// Where the cover art comes from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum CoverSource {
+    /// The first cover embedded in the input tags, or failing that a
+    /// `cover`, `folder` or `front` image next to the inputs.
+    #[default]
+    Auto,
+    /// The given image file.
+    File(PathBuf),
+    /// No cover art.
+    None,
+}
+
+/// How to choose the cover art and fit it to the limits of players.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CoverOptions {
+    pub source: CoverSource,
+    /// Larger images are scaled down so that neither side exceeds this.
+    pub max_dimension: Option<u32>,
+    /// Larger images are re-encoded as JPEG, at decreasing quality and then
+    /// decreasing size, until they fit.
+    pub max_bytes: Option<usize>,
+}
+
+/// A decoded image, as 8-bit RGB.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    /// Red, green and blue of each pixel, a row at a time from the top.
+    pub pixels: Vec<u8>,
+}
+
+/// Identifies the format of encoded image `data`.
+#[must_use]
+pub fn format(data: &[u8]) -> Option<ImageFormat> {
+    if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some(ImageFormat::Jpeg)
+    } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(ImageFormat::Png)
+    } else {
+        None
+    }
+}
+
+/// Wraps encoded image `data` as cover art.
+///
+/// # Errors
+/// Returns an error if the data isn't a JPEG or PNG image.
+pub fn from_bytes(data: Vec<u8>) -> Result<CoverArt, Error> {
+    let format = format(&data).ok_or(Error::UnknownFormat)?;
+    Ok(CoverArt { format, data })
+}
+
+/// Reads cover art from the image file at `path`.
+///
+/// # Errors
+/// Returns an error if the file can't be read or isn't a JPEG or PNG image.
+pub fn load(path: &Path) -> Result<CoverArt, Error> {
+    from_bytes(fs::read(path)?)
+}
+
+/// Whether `path` looks like an image, judging by its extension.
+#[must_use]
+pub fn is_image(path: &Path) -> bool {
+    has_extension(path, &IMAGE_EXTENSIONS)
+}
+
+/// Finds the image among `paths` that is most likely the cover art, going by
+/// the file names, such as `cover.jpg` or `Folder.png`.
+#[must_use]
+pub fn find(paths: &[PathBuf]) -> Option<&Path> {
+    paths
+        .iter()
+        .filter(|path| has_extension(path, &COVER_EXTENSIONS))
+        .filter_map(|path| {
+            let stem = path.file_stem()?.to_str()?.to_ascii_lowercase();
+            let rank = COVER_NAMES.iter().position(|&name| name == stem)?;
+            Some((rank, path.as_path()))
+        })
+        .min_by_key(|&(rank, _)| rank)
+        .map(|(_, path)| path)
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| extensions.iter().any(|x| e.eq_ignore_ascii_case(x)))
+}
+
+/// Decodes `art` to RGB.
+///
+/// # Errors
+/// Returns an error if the image is malformed, or uses a feature that isn't
+/// supported, such as lossless or arithmetic-coded JPEG.
+pub fn decode(art: &CoverArt) -> Result<Image, Error> {
+    match art.format {
+        ImageFormat::Jpeg => jpeg::decode(&art.data),
+        ImageFormat::Png => png::decode(&art.data),
+    }
+}
+
+/// Fits `art` to the limits in `options`, scaling it down to at most
+/// `max_dimension` on each side and re-encoding it to at most `max_bytes`.
+/// Art within the limits is returned unchanged.
+///
+/// # Errors
+/// Returns an error if the image needs to be re-encoded but can't be decoded,
+/// or can't be made small enough.
+pub fn fit(art: CoverArt, options: &CoverOptions) -> Result<CoverArt, Error> {
+    let (width, height) = match art.format {
+        ImageFormat::Jpeg => jpeg::dimensions(&art.data),
+        ImageFormat::Png => png::dimensions(&art.data),
+    }
+    .ok_or(Error::Malformed("no image dimensions"))?;
+
+    let too_wide = options
+        .max_dimension
+        .is_some_and(|max| width.max(height) > max);
+    let too_long = options.max_bytes.is_some_and(|max| art.data.len() > max);
+    if !too_wide && !too_long {
+        return Ok(art);
+    }
+
+    let mut image = decode(&art)?;
+    if let Some(max) = options.max_dimension.filter(|_| too_wide) {
+        let scale = f64::from(max) / f64::from(width.max(height));
+        image = scaled(&image, scale);
+    }
+
+    let max_bytes = options.max_bytes.unwrap_or(usize::MAX);
+    loop {
+        for quality in QUALITIES {
+            let data = jpeg::encode(&image, quality);
+            if data.len() <= max_bytes {
+                info!(
+                    "Re-encoded {width}x{height} cover art ({} bytes) as {}x{} JPEG ({} bytes)",
+                    art.data.len(),
+                    image.width,
+                    image.height,
+                    data.len()
+                );
+                return Ok(CoverArt {
+                    format: ImageFormat::Jpeg,
+                    data,
+                });
+            }
+        }
+        if image.width.min(image.height) <= MIN_DIMENSION {
+            return Err(Error::TooLarge { max_bytes });
+        }
+        image = scaled(&image, SHRINK);
+    }
+}
+
+/// Scales `image` by `scale` (at most 1), keeping at least one pixel.
+fn scaled(image: &Image, scale: f64) -> Image {
+    let size = |side: u32| ((f64::from(side) * scale).round() as u32).clamp(1, side);
+    resize::resize(image, size(image.width), size(image.height))
+}
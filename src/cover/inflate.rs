@@ -0,0 +1,261 @@
+//! DEFLATE decompression of zlib streams, as used by PNG.
+//!
+//! ref
+//!   RFC 1950 (zlib), RFC 1951 (DEFLATE)
+use super::Error;
+
+const MAX_BITS: usize = 15;
+
+/// Base lengths and extra bits for length codes 257..=285.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+/// Base distances and extra bits for distance codes 0..=29.
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Order in which code length code lengths are stored in a dynamic block.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// LSB-first bit reader.
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    acc: u32,
+    len: u32,
+}
+
+impl Bits<'_> {
+    fn bits(&mut self, n: u32) -> Result<u32, Error> {
+        while self.len < n {
+            let byte = *self
+                .data
+                .get(self.pos)
+                .ok_or(Error::Malformed("truncated deflate stream"))?;
+            self.pos += 1;
+            self.acc |= u32::from(byte) << self.len;
+            self.len += 8;
+        }
+        let value = self.acc & ((1u32 << n) - 1);
+        self.acc >>= n;
+        self.len -= n;
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        self.acc = 0;
+        self.len = 0;
+    }
+}
+
+/// A canonical Huffman code, decoded a bit at a time.
+struct Huffman {
+    /// Number of codes of each length.
+    counts: [u16; MAX_BITS + 1],
+    /// Symbols ordered by code.
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &len in lengths {
+            counts[usize::from(len)] += 1;
+        }
+        counts[0] = 0;
+
+        let mut offsets = [0u16; MAX_BITS + 2];
+        for len in 1..=MAX_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; usize::from(offsets[MAX_BITS + 1])];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                let offset = &mut offsets[usize::from(len)];
+                symbols[usize::from(*offset)] = u16::try_from(symbol).unwrap_or(u16::MAX);
+                *offset += 1;
+            }
+        }
+        Self { counts, symbols }
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16, Error> {
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for &count in &self.counts[1..] {
+            code |= i32::try_from(bits.bits(1)?).unwrap_or(0);
+            let count = i32::from(count);
+            if code - first < count {
+                let i = usize::try_from(index + code - first).unwrap_or(usize::MAX);
+                return self
+                    .symbols
+                    .get(i)
+                    .copied()
+                    .ok_or(Error::Malformed("bad deflate code"));
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(Error::Malformed("bad deflate code"))
+    }
+}
+
+/// Decompresses a zlib stream. The checksum is not verified.
+pub(super) fn zlib_decompress(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let [cmf, flg, ..] = *data else {
+        return Err(Error::Malformed("truncated zlib stream"));
+    };
+    if cmf & 0x0f != 8 || (u16::from(cmf) << 8 | u16::from(flg)) % 31 != 0 {
+        return Err(Error::Malformed("bad zlib header"));
+    }
+    if flg & 0x20 != 0 {
+        return Err(Error::Unsupported("zlib preset dictionary"));
+    }
+
+    let mut bits = Bits {
+        data: &data[2..],
+        pos: 0,
+        acc: 0,
+        len: 0,
+    };
+    let mut out = Vec::new();
+    loop {
+        let last = bits.bits(1)? == 1;
+        match bits.bits(2)? {
+            0 => stored(&mut bits, &mut out)?,
+            1 => {
+                let mut lengths = [0u8; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                let lit = Huffman::new(&lengths);
+                let dist = Huffman::new(&[5; 30]);
+                codes(&mut bits, &mut out, &lit, &dist)?;
+            }
+            2 => {
+                let (lit, dist) = dynamic_tables(&mut bits)?;
+                codes(&mut bits, &mut out, &lit, &dist)?;
+            }
+            _ => return Err(Error::Malformed("bad deflate block type")),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+fn stored(bits: &mut Bits, out: &mut Vec<u8>) -> Result<(), Error> {
+    bits.align();
+    let header = bits
+        .data
+        .get(bits.pos..bits.pos + 4)
+        .ok_or(Error::Malformed("truncated deflate stream"))?;
+    let len = usize::from(u16::from_le_bytes([header[0], header[1]]));
+    let nlen = u16::from_le_bytes([header[2], header[3]]);
+    if len != usize::from(!nlen) {
+        return Err(Error::Malformed("bad stored block length"));
+    }
+    bits.pos += 4;
+    let block = bits
+        .data
+        .get(bits.pos..bits.pos + len)
+        .ok_or(Error::Malformed("truncated deflate stream"))?;
+    out.extend_from_slice(block);
+    bits.pos += len;
+    Ok(())
+}
+
+fn dynamic_tables(bits: &mut Bits) -> Result<(Huffman, Huffman), Error> {
+    let n_lit = bits.bits(5)? as usize + 257;
+    let n_dist = bits.bits(5)? as usize + 1;
+    let n_code = bits.bits(4)? as usize + 4;
+    if n_lit > 286 || n_dist > 30 {
+        return Err(Error::Malformed("bad deflate table counts"));
+    }
+
+    let mut code_lengths = [0u8; 19];
+    for &i in &CODE_LENGTH_ORDER[..n_code] {
+        code_lengths[i] = u8::try_from(bits.bits(3)?).unwrap_or(0);
+    }
+    let code_length = Huffman::new(&code_lengths);
+
+    let mut lengths = vec![0u8; n_lit + n_dist];
+    let mut i = 0;
+    while i < lengths.len() {
+        let symbol = code_length.decode(bits)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (u8::try_from(symbol).unwrap_or(0), 1),
+            16 => {
+                let previous = *i
+                    .checked_sub(1)
+                    .and_then(|p| lengths.get(p))
+                    .ok_or(Error::Malformed("repeat with no previous length"))?;
+                (previous, 3 + bits.bits(2)? as usize)
+            }
+            17 => (0, 3 + bits.bits(3)? as usize),
+            _ => (0, 11 + bits.bits(7)? as usize),
+        };
+        let run = lengths
+            .get_mut(i..i + repeat)
+            .ok_or(Error::Malformed("too many code lengths"))?;
+        run.fill(value);
+        i += repeat;
+    }
+    if lengths[256] == 0 {
+        return Err(Error::Malformed("no end of block code"));
+    }
+    Ok((
+        Huffman::new(&lengths[..n_lit]),
+        Huffman::new(&lengths[n_lit..]),
+    ))
+}
+
+fn codes(bits: &mut Bits, out: &mut Vec<u8>, lit: &Huffman, dist: &Huffman) -> Result<(), Error> {
+    loop {
+        let symbol = lit.decode(bits)?;
+        match symbol {
+            0..=255 => out.push(u8::try_from(symbol).unwrap_or(0)),
+            256 => return Ok(()),
+            _ => {
+                let i = usize::from(symbol - 257);
+                let (&base, &extra) = LENGTH_BASE
+                    .get(i)
+                    .zip(LENGTH_EXTRA.get(i))
+                    .ok_or(Error::Malformed("bad length code"))?;
+                let len = usize::from(base) + bits.bits(u32::from(extra))? as usize;
+
+                let i = usize::from(dist.decode(bits)?);
+                let (&base, &extra) = DIST_BASE
+                    .get(i)
+                    .zip(DIST_EXTRA.get(i))
+                    .ok_or(Error::Malformed("bad distance code"))?;
+                let distance = usize::from(base) + bits.bits(u32::from(extra))? as usize;
+
+                let start = out
+                    .len()
+                    .checked_sub(distance)
+                    .ok_or(Error::Malformed("distance too far back"))?;
+                // The copy may overlap the bytes it produces.
+                for j in 0..len {
+                    out.push(out[start + j]);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,1022 @@
+//! JPEG decoding (baseline and progressive Huffman) to 8-bit RGB, and
+//! baseline JPEG encoding with 4:2:0 chroma subsampling.
+//!
+//! ref
+//!   ITU-T T.81 (ISO/IEC 10918-1)
+use std::f32::consts::{FRAC_1_SQRT_2, PI};
+
+use super::{Error, Image};
+
+/// Natural (row-major) index of each coefficient, in zigzag order.
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20,
+    13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59,
+    52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// Example quantization tables from Annex K, in natural order.
+const LUMA_QUANT: [u16; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81, 104, 113,
+    92, 49, 64, 78, 87, 103, 121, 120, 101, 72, 92, 95, 98, 112, 100, 103, 99,
+];
+const CHROMA_QUANT: [u16; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99, 18, 21, 26, 66, 99, 99, 99, 99, 24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+/// Example Huffman tables from Annex K: the number of codes of each length
+/// and the symbols in code order.
+const DC_LUMA_BITS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+const DC_CHROMA_BITS: [u8; 16] = [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0];
+const DC_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+const AC_LUMA_BITS: [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7d];
+const AC_LUMA_VALUES: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+    0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xa1, 0x08, 0x23, 0x42, 0xb1, 0xc1, 0x15, 0x52, 0xd1, 0xf0,
+    0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0a, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x25, 0x26, 0x27, 0x28,
+    0x29, 0x2a, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+    0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7,
+    0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5,
+    0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe1, 0xe2,
+    0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+];
+const AC_CHROMA_BITS: [u8; 16] = [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77];
+const AC_CHROMA_VALUES: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+    0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xa1, 0xb1, 0xc1, 0x09, 0x23, 0x33, 0x52, 0xf0,
+    0x15, 0x62, 0x72, 0xd1, 0x0a, 0x16, 0x24, 0x34, 0xe1, 0x25, 0xf1, 0x17, 0x18, 0x19, 0x1a, 0x26,
+    0x27, 0x28, 0x29, 0x2a, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+    0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5,
+    0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3,
+    0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda,
+    0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+];
+
+/// Number of bits resolved by a single lookup when decoding Huffman codes.
+const LOOKUP_BITS: u32 = 9;
+
+/// `BASIS[x][u]` is the DCT basis function `C(u)/2 * cos((2x+1)uπ/16)`; the
+/// 2D transforms apply it along rows and then columns.
+fn basis() -> [[f32; 8]; 8] {
+    let mut basis = [[0.0; 8]; 8];
+    for (x, row) in basis.iter_mut().enumerate() {
+        for (u, value) in row.iter_mut().enumerate() {
+            let c = if u == 0 { FRAC_1_SQRT_2 } else { 1.0 };
+            *value = c / 2.0 * ((2 * x + 1) as f32 * u as f32 * PI / 16.0).cos();
+        }
+    }
+    basis
+}
+
+/// Reads the width and height from the first start-of-frame segment.
+pub(super) fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let mut pos = 2;
+    loop {
+        if *data.get(pos)? != 0xff {
+            return None;
+        }
+        let marker = *data.get(pos + 1)?;
+        let len = usize::from(u16::from_be_bytes([
+            *data.get(pos + 2)?,
+            *data.get(pos + 3)?,
+        ]));
+        if matches!(marker, 0xc0..=0xcf) && !matches!(marker, 0xc4 | 0xc8 | 0xcc) {
+            let height = u16::from_be_bytes([*data.get(pos + 5)?, *data.get(pos + 6)?]);
+            let width = u16::from_be_bytes([*data.get(pos + 7)?, *data.get(pos + 8)?]);
+            return Some((width.into(), height.into()));
+        }
+        pos += 2 + len;
+    }
+}
+
+// Decoding
+
+/// A Huffman table prepared for decoding.
+struct HuffmanTable {
+    /// `(length, symbol)` of the code starting with each `LOOKUP_BITS` bit
+    /// pattern, for codes no longer than that; a length of 0 means the code
+    /// is longer.
+    lookup: Vec<(u8, u8)>,
+    /// Largest code of each length, or -1 if there are none.
+    max_code: [i32; 17],
+    /// Offset from a code of each length to the index of its symbol.
+    offset: [i32; 17],
+    symbols: Vec<u8>,
+}
+
+impl HuffmanTable {
+    fn new(bits: &[u8; 16], symbols: &[u8]) -> Result<Self, Error> {
+        let mut table = Self {
+            lookup: vec![(0, 0); 1 << LOOKUP_BITS],
+            max_code: [-1; 17],
+            offset: [0; 17],
+            symbols: symbols.to_vec(),
+        };
+        let mut code: i32 = 0;
+        let mut index: i32 = 0;
+        for len in 1..=16 {
+            let count = i32::from(bits[len - 1]);
+            // More codes than there are of this length overflow the table.
+            if code + count > 1 << len {
+                return Err(Error::Malformed("overfull Huffman table"));
+            }
+            if count > 0 {
+                table.offset[len] = index - code;
+                for i in 0..count {
+                    let c = code + i;
+                    if len <= LOOKUP_BITS as usize {
+                        let shift = LOOKUP_BITS as usize - len;
+                        let symbol = *symbols
+                            .get((index + i) as usize)
+                            .ok_or(Error::Malformed("short Huffman table"))?;
+                        for fill in 0..1 << shift {
+                            table.lookup[((c << shift) | fill) as usize] = (len as u8, symbol);
+                        }
+                    }
+                }
+                code += count;
+                index += count;
+                table.max_code[len] = code - 1;
+            }
+            code <<= 1;
+        }
+        if index as usize > symbols.len() {
+            return Err(Error::Malformed("short Huffman table"));
+        }
+        Ok(table)
+    }
+
+    fn decode(&self, bits: &mut BitReader) -> Result<u8, Error> {
+        let peek = bits.peek(16);
+        let (len, symbol) = self.lookup[(peek >> (16 - LOOKUP_BITS)) as usize];
+        if len > 0 {
+            bits.consume(u32::from(len));
+            return Ok(symbol);
+        }
+        for len in LOOKUP_BITS as usize + 1..=16 {
+            let code = (peek >> (16 - len)) as i32;
+            if code <= self.max_code[len] {
+                bits.consume(len as u32);
+                return self
+                    .symbols
+                    .get((code + self.offset[len]) as usize)
+                    .copied()
+                    .ok_or(Error::Malformed("bad Huffman code"));
+            }
+        }
+        Err(Error::Malformed("bad Huffman code"))
+    }
+}
+
+/// MSB-first reader of entropy-coded data, which removes stuffed zero bytes
+/// and stops (supplying zeros) at the next marker.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    acc: u64,
+    n_bits: u32,
+    at_marker: bool,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Self {
+            data,
+            pos,
+            acc: 0,
+            n_bits: 0,
+            at_marker: false,
+        }
+    }
+
+    fn fill(&mut self) {
+        while self.n_bits <= 56 {
+            let mut byte = 0;
+            if !self.at_marker {
+                match self.data.get(self.pos..self.pos + 2) {
+                    Some(&[0xff, 0x00]) => {
+                        byte = 0xff;
+                        self.pos += 2;
+                    }
+                    Some(&[0xff, _]) | None => self.at_marker = true,
+                    Some(_) => {
+                        byte = self.data[self.pos];
+                        self.pos += 1;
+                    }
+                }
+            }
+            self.acc |= u64::from(byte) << (56 - self.n_bits);
+            self.n_bits += 8;
+        }
+    }
+
+    fn peek(&mut self, n: u32) -> u32 {
+        self.fill();
+        (self.acc >> (64 - n)) as u32
+    }
+
+    fn consume(&mut self, n: u32) {
+        self.acc <<= n;
+        self.n_bits -= n;
+    }
+
+    fn bits(&mut self, n: u32) -> u32 {
+        if n == 0 {
+            return 0;
+        }
+        let value = self.peek(n);
+        self.consume(n);
+        value
+    }
+
+    fn bit(&mut self) -> bool {
+        self.bits(1) == 1
+    }
+
+    /// Reads an `n` bit magnitude category value and sign-extends it. `n` is
+    /// at most 15: DC categories are checked, and AC sizes are four bits.
+    fn receive_extend(&mut self, n: u32) -> i32 {
+        if n == 0 {
+            return 0;
+        }
+        let value = self.bits(n) as i32;
+        if value < 1 << (n - 1) {
+            value - (1 << n) + 1
+        } else {
+            value
+        }
+    }
+
+    /// Discards buffered bits and skips the restart marker that should follow.
+    fn restart(&mut self) {
+        self.acc = 0;
+        self.n_bits = 0;
+        self.at_marker = false;
+        while self.data.get(self.pos) == Some(&0xff) {
+            match self.data.get(self.pos + 1) {
+                Some(0xd0..=0xd7) => {
+                    self.pos += 2;
+                    return;
+                }
+                Some(0xff) => self.pos += 1,
+                _ => return,
+            }
+        }
+    }
+}
+
+struct Component {
+    id: u8,
+    h: usize,
+    v: usize,
+    quant: usize,
+    /// Blocks per row and column, padded to whole MCUs.
+    blocks_w: usize,
+    blocks_h: usize,
+    /// Blocks per row and column that hold image data.
+    used_w: usize,
+    used_h: usize,
+    /// Coefficients of every block, in natural order.
+    coefs: Vec<[i16; 64]>,
+    dc_pred: i32,
+    dc_table: usize,
+    ac_table: usize,
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    quant: [[u16; 64]; 4],
+    dc_tables: [Option<HuffmanTable>; 4],
+    ac_tables: [Option<HuffmanTable>; 4],
+    components: Vec<Component>,
+    width: usize,
+    height: usize,
+    h_max: usize,
+    v_max: usize,
+    progressive: bool,
+    restart_interval: usize,
+    eob_run: u32,
+}
+
+pub(super) fn decode(data: &[u8]) -> Result<Image, Error> {
+    if data.get(..2) != Some(&[0xff, 0xd8]) {
+        return Err(Error::Malformed("missing JPEG start of image"));
+    }
+    let mut decoder = Decoder {
+        data,
+        quant: [[1; 64]; 4],
+        dc_tables: Default::default(),
+        ac_tables: Default::default(),
+        components: Vec::new(),
+        width: 0,
+        height: 0,
+        h_max: 1,
+        v_max: 1,
+        progressive: false,
+        restart_interval: 0,
+        eob_run: 0,
+    };
+
+    let mut pos = 2;
+    loop {
+        // Skip any fill bytes before the marker.
+        while data.get(pos) == Some(&0xff) && data.get(pos + 1) == Some(&0xff) {
+            pos += 1;
+        }
+        let Some(&[0xff, marker]) = data.get(pos..pos + 2) else {
+            return Err(Error::Malformed("expected a JPEG marker"));
+        };
+        pos += 2;
+        if marker == 0xd9 {
+            break;
+        }
+        if matches!(marker, 0xd0..=0xd7 | 0x01) {
+            continue;
+        }
+        let len = usize::from(u16::from_be_bytes([
+            *data.get(pos).ok_or(Error::Malformed("truncated JPEG"))?,
+            *data
+                .get(pos + 1)
+                .ok_or(Error::Malformed("truncated JPEG"))?,
+        ]));
+        let segment = data
+            .get(pos + 2..pos + len)
+            .ok_or(Error::Malformed("truncated JPEG segment"))?;
+        match marker {
+            0xc0..=0xc2 => decoder.read_frame(segment, marker == 0xc2)?,
+            0xc3 | 0xc5..=0xc7 | 0xc9..=0xcb | 0xcd..=0xcf => {
+                return Err(Error::Unsupported(
+                    "lossless, hierarchical or arithmetic JPEG",
+                ))
+            }
+            0xc4 => decoder.read_huffman(segment)?,
+            0xdb => decoder.read_quant(segment)?,
+            0xdd => {
+                decoder.restart_interval = usize::from(u16::from_be_bytes([
+                    *segment.first().unwrap_or(&0),
+                    *segment.get(1).unwrap_or(&0),
+                ]));
+            }
+            0xda => {
+                pos = decoder.read_scan(segment, pos + len)?;
+                continue;
+            }
+            _ => {}
+        }
+        pos += len;
+    }
+
+    if decoder.components.is_empty() {
+        return Err(Error::Malformed("JPEG has no frame"));
+    }
+    decoder.output()
+}
+
+impl Decoder<'_> {
+    fn read_frame(&mut self, segment: &[u8], progressive: bool) -> Result<(), Error> {
+        if segment.len() < 6 || segment[0] != 8 {
+            return Err(Error::Unsupported("JPEG sample precision"));
+        }
+        self.progressive = progressive;
+        self.height = usize::from(u16::from_be_bytes([segment[1], segment[2]]));
+        self.width = usize::from(u16::from_be_bytes([segment[3], segment[4]]));
+        let count = usize::from(segment[5]);
+        if self.width == 0 || self.height == 0 {
+            return Err(Error::Unsupported("JPEG without explicit height"));
+        }
+        if self.width * self.height > super::MAX_PIXELS {
+            return Err(Error::Unsupported("JPEG dimensions"));
+        }
+        if count != 1 && count != 3 {
+            return Err(Error::Unsupported("JPEG color space"));
+        }
+        let specs = segment
+            .get(6..6 + 3 * count)
+            .ok_or(Error::Malformed("short JPEG frame header"))?;
+
+        for spec in specs.chunks_exact(3) {
+            let (h, v) = (usize::from(spec[1] >> 4), usize::from(spec[1] & 0x0f));
+            if !(1..=4).contains(&h) || !(1..=4).contains(&v) || spec[2] > 3 {
+                return Err(Error::Malformed("bad JPEG component"));
+            }
+            self.components.push(Component {
+                id: spec[0],
+                h,
+                v,
+                quant: usize::from(spec[2]),
+                blocks_w: 0,
+                blocks_h: 0,
+                used_w: 0,
+                used_h: 0,
+                coefs: Vec::new(),
+                dc_pred: 0,
+                dc_table: 0,
+                ac_table: 0,
+            });
+        }
+        self.h_max = self.components.iter().map(|c| c.h).max().unwrap_or(1);
+        self.v_max = self.components.iter().map(|c| c.v).max().unwrap_or(1);
+        let mcus_x = self.width.div_ceil(8 * self.h_max);
+        let mcus_y = self.height.div_ceil(8 * self.v_max);
+        for c in &mut self.components {
+            c.blocks_w = mcus_x * c.h;
+            c.blocks_h = mcus_y * c.v;
+            c.used_w = (self.width * c.h).div_ceil(self.h_max).div_ceil(8);
+            c.used_h = (self.height * c.v).div_ceil(self.v_max).div_ceil(8);
+            c.coefs = vec![[0; 64]; c.blocks_w * c.blocks_h];
+        }
+        Ok(())
+    }
+
+    fn read_huffman(&mut self, mut segment: &[u8]) -> Result<(), Error> {
+        while !segment.is_empty() {
+            let class = segment[0] >> 4;
+            let id = usize::from(segment[0] & 0x0f);
+            let bits: [u8; 16] = segment
+                .get(1..17)
+                .and_then(|b| b.try_into().ok())
+                .ok_or(Error::Malformed("short JPEG Huffman table"))?;
+            let count: usize = bits.iter().map(|&b| usize::from(b)).sum();
+            let symbols = segment
+                .get(17..17 + count)
+                .ok_or(Error::Malformed("short JPEG Huffman table"))?;
+            let table = HuffmanTable::new(&bits, symbols)?;
+            match (class, id) {
+                (0, 0..=3) => self.dc_tables[id] = Some(table),
+                (1, 0..=3) => self.ac_tables[id] = Some(table),
+                _ => return Err(Error::Malformed("bad JPEG Huffman table id")),
+            }
+            segment = &segment[17 + count..];
+        }
+        Ok(())
+    }
+
+    fn read_quant(&mut self, mut segment: &[u8]) -> Result<(), Error> {
+        while !segment.is_empty() {
+            let precision = segment[0] >> 4;
+            let id = usize::from(segment[0] & 0x0f);
+            if id > 3 {
+                return Err(Error::Malformed("bad JPEG quantization table id"));
+            }
+            let size = if precision == 0 { 64 } else { 128 };
+            let values = segment
+                .get(1..=size)
+                .ok_or(Error::Malformed("short JPEG quantization table"))?;
+            for (k, &natural) in ZIGZAG.iter().enumerate() {
+                self.quant[id][natural] = if precision == 0 {
+                    u16::from(values[k])
+                } else {
+                    u16::from_be_bytes([values[2 * k], values[2 * k + 1]])
+                };
+            }
+            segment = &segment[1 + size..];
+        }
+        Ok(())
+    }
+
+    /// Decodes the scan whose header is `segment` and whose entropy-coded
+    /// data starts at `start`, returning the position of the next marker.
+    fn read_scan(&mut self, segment: &[u8], start: usize) -> Result<usize, Error> {
+        let count = usize::from(*segment.first().ok_or(Error::Malformed("empty JPEG scan"))?);
+        let specs = segment
+            .get(1..1 + 2 * count)
+            .ok_or(Error::Malformed("short JPEG scan header"))?;
+        let tail = segment
+            .get(1 + 2 * count..4 + 2 * count)
+            .ok_or(Error::Malformed("short JPEG scan header"))?;
+        let (ss, se, ah, al) = (
+            usize::from(tail[0]),
+            usize::from(tail[1]),
+            u32::from(tail[2] >> 4),
+            u32::from(tail[2] & 0x0f),
+        );
+        if se > 63 || ss > se || (!self.progressive && (ss != 0 || se != 63)) {
+            return Err(Error::Malformed("bad JPEG spectral selection"));
+        }
+
+        let mut scan = Vec::with_capacity(count);
+        for spec in specs.chunks_exact(2) {
+            let index = self
+                .components
+                .iter()
+                .position(|c| c.id == spec[0])
+                .ok_or(Error::Malformed("JPEG scan of unknown component"))?;
+            let component = &mut self.components[index];
+            component.dc_table = usize::from(spec[1] >> 4) & 3;
+            component.ac_table = usize::from(spec[1] & 0x0f) & 3;
+            component.dc_pred = 0;
+            scan.push(index);
+        }
+        self.eob_run = 0;
+
+        let mut bits = BitReader::new(self.data, start);
+        let mcus = if let [index] = scan[..] {
+            let c = &self.components[index];
+            c.used_w * c.used_h
+        } else {
+            self.width.div_ceil(8 * self.h_max) * self.height.div_ceil(8 * self.v_max)
+        };
+        let mcus_x = self.width.div_ceil(8 * self.h_max);
+        for mcu in 0..mcus {
+            if self.restart_interval > 0 && mcu > 0 && mcu % self.restart_interval == 0 {
+                bits.restart();
+                self.eob_run = 0;
+                for &index in &scan {
+                    self.components[index].dc_pred = 0;
+                }
+            }
+            if let [index] = scan[..] {
+                let used_w = self.components[index].used_w;
+                let (x, y) = (mcu % used_w, mcu / used_w);
+                let block = y * self.components[index].blocks_w + x;
+                self.decode_block(&mut bits, index, block, (ss, se, ah, al))?;
+            } else {
+                let (mx, my) = (mcu % mcus_x, mcu / mcus_x);
+                for &index in &scan {
+                    let c = &self.components[index];
+                    let (h, v, blocks_w) = (c.h, c.v, c.blocks_w);
+                    for by in 0..v {
+                        for bx in 0..h {
+                            let block = (my * v + by) * blocks_w + mx * h + bx;
+                            self.decode_block(&mut bits, index, block, (ss, se, ah, al))?;
+                        }
+                    }
+                }
+            }
+        }
+
+        // Find the marker that ends the scan, skipping restart markers.
+        let mut pos = bits.pos;
+        while pos + 1 < self.data.len() {
+            if self.data[pos] == 0xff && !matches!(self.data[pos + 1], 0x00 | 0xd0..=0xd7 | 0xff) {
+                return Ok(pos);
+            }
+            pos += 1;
+        }
+        Ok(self.data.len().saturating_sub(2).max(pos))
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn decode_block(
+        &mut self,
+        bits: &mut BitReader,
+        index: usize,
+        block: usize,
+        (ss, se, ah, al): (usize, usize, u32, u32),
+    ) -> Result<(), Error> {
+        let c = &mut self.components[index];
+        let coefs = &mut c.coefs[block];
+
+        if ss == 0 {
+            if ah == 0 {
+                let category = table(&self.dc_tables, c.dc_table)?.decode(bits)?;
+                // Differences of 8-bit samples need at most 11 bits.
+                if category > 11 {
+                    return Err(Error::Malformed("JPEG DC difference out of range"));
+                }
+                c.dc_pred = c
+                    .dc_pred
+                    .wrapping_add(bits.receive_extend(u32::from(category)));
+                coefs[0] = (c.dc_pred << al) as i16;
+            } else if bits.bit() {
+                coefs[0] |= 1 << al;
+            }
+            if !self.progressive {
+                let ac = table(&self.ac_tables, c.ac_table)?;
+                let mut k = 1;
+                while k < 64 {
+                    let rs = ac.decode(bits)?;
+                    let (run, size) = (usize::from(rs >> 4), u32::from(rs & 0x0f));
+                    if size == 0 {
+                        if run != 15 {
+                            break;
+                        }
+                        k += 16;
+                        continue;
+                    }
+                    k += run;
+                    if k > 63 {
+                        return Err(Error::Malformed("JPEG coefficient out of range"));
+                    }
+                    coefs[ZIGZAG[k]] = bits.receive_extend(size) as i16;
+                    k += 1;
+                }
+            }
+            return Ok(());
+        }
+
+        let ac = table(&self.ac_tables, c.ac_table)?;
+        if ah == 0 {
+            // First AC scan of a band.
+            if self.eob_run > 0 {
+                self.eob_run -= 1;
+                return Ok(());
+            }
+            let mut k = ss;
+            while k <= se {
+                let rs = ac.decode(bits)?;
+                let (run, size) = (u32::from(rs >> 4), u32::from(rs & 0x0f));
+                if size == 0 {
+                    if run < 15 {
+                        self.eob_run = (1 << run) - 1 + bits.bits(run);
+                        break;
+                    }
+                    k += 16;
+                    continue;
+                }
+                k += run as usize;
+                if k > 63 {
+                    return Err(Error::Malformed("JPEG coefficient out of range"));
+                }
+                coefs[ZIGZAG[k]] = (bits.receive_extend(size) << al) as i16;
+                k += 1;
+            }
+            return Ok(());
+        }
+
+        // Refinement AC scan: one more bit of every non-zero coefficient in
+        // the band, and newly non-zero coefficients of magnitude one.
+        let p1 = 1i16 << al;
+        let m1 = -1i16 << al;
+        let refine = |bits: &mut BitReader, coef: &mut i16| {
+            if bits.bit() && *coef & p1 == 0 {
+                *coef += if *coef >= 0 { p1 } else { m1 };
+            }
+        };
+        let mut k = ss;
+        if self.eob_run == 0 {
+            while k <= se {
+                let rs = ac.decode(bits)?;
+                let (mut run, size) = (i32::from(rs >> 4), rs & 0x0f);
+                let mut value = 0;
+                if size != 0 {
+                    value = if bits.bit() { p1 } else { m1 };
+                } else if run != 15 {
+                    self.eob_run = (1 << run) + bits.bits(run as u32);
+                    break;
+                }
+                while k <= se {
+                    let coef = &mut coefs[ZIGZAG[k]];
+                    if *coef != 0 {
+                        refine(bits, coef);
+                    } else {
+                        if run == 0 {
+                            break;
+                        }
+                        run -= 1;
+                    }
+                    k += 1;
+                }
+                if value != 0 && k <= se {
+                    coefs[ZIGZAG[k]] = value;
+                }
+                k += 1;
+            }
+        }
+        if self.eob_run > 0 {
+            while k <= se {
+                let coef = &mut coefs[ZIGZAG[k]];
+                if *coef != 0 {
+                    refine(bits, coef);
+                }
+                k += 1;
+            }
+            self.eob_run -= 1;
+        }
+        Ok(())
+    }
+
+    /// Dequantizes and inverse transforms every block, then upsamples and
+    /// converts the components to RGB.
+    fn output(&self) -> Result<Image, Error> {
+        let basis = basis();
+        let planes: Vec<Vec<u8>> = self
+            .components
+            .iter()
+            .map(|c| {
+                let quant = &self.quant[c.quant];
+                let stride = c.blocks_w * 8;
+                let mut plane = vec![0u8; stride * c.blocks_h * 8];
+                for (i, coefs) in c.coefs.iter().enumerate() {
+                    let (bx, by) = (i % c.blocks_w, i / c.blocks_w);
+                    let mut block = [0.0f32; 64];
+                    for (j, value) in block.iter_mut().enumerate() {
+                        *value = f32::from(coefs[j]) * f32::from(quant[j]);
+                    }
+                    let samples = idct(&basis, &block);
+                    for y in 0..8 {
+                        let row = (by * 8 + y) * stride + bx * 8;
+                        for x in 0..8 {
+                            plane[row + x] =
+                                (samples[y * 8 + x] + 128.0).round().clamp(0.0, 255.0) as u8;
+                        }
+                    }
+                }
+                plane
+            })
+            .collect();
+
+        let mut pixels = Vec::with_capacity(self.width * self.height * 3);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let sample = |i: usize| {
+                    let c = &self.components[i];
+                    let sx = x * c.h / self.h_max;
+                    let sy = y * c.v / self.v_max;
+                    f32::from(planes[i][sy * c.blocks_w * 8 + sx])
+                };
+                if self.components.len() == 1 {
+                    let gray = sample(0) as u8;
+                    pixels.extend_from_slice(&[gray; 3]);
+                } else {
+                    let (luma, cb, cr) = (sample(0), sample(1) - 128.0, sample(2) - 128.0);
+                    let to_u8 = |v: f32| v.round().clamp(0.0, 255.0) as u8;
+                    pixels.push(to_u8(luma + 1.402 * cr));
+                    pixels.push(to_u8(luma - 0.344_136 * cb - 0.714_136 * cr));
+                    pixels.push(to_u8(luma + 1.772 * cb));
+                }
+            }
+        }
+
+        Ok(Image {
+            width: u32::try_from(self.width).map_err(|_| Error::Unsupported("JPEG dimensions"))?,
+            height: u32::try_from(self.height)
+                .map_err(|_| Error::Unsupported("JPEG dimensions"))?,
+            pixels,
+        })
+    }
+}
+
+fn table(tables: &[Option<HuffmanTable>; 4], id: usize) -> Result<&HuffmanTable, Error> {
+    tables[id]
+        .as_ref()
+        .ok_or(Error::Malformed("missing JPEG Huffman table"))
+}
+
+fn idct(basis: &[[f32; 8]; 8], coefs: &[f32; 64]) -> [f32; 64] {
+    let mut rows = [0.0f32; 64];
+    for v in 0..8 {
+        for x in 0..8 {
+            rows[v * 8 + x] = (0..8).map(|u| basis[x][u] * coefs[v * 8 + u]).sum();
+        }
+    }
+    let mut out = [0.0f32; 64];
+    for y in 0..8 {
+        for x in 0..8 {
+            out[y * 8 + x] = (0..8).map(|v| basis[y][v] * rows[v * 8 + x]).sum();
+        }
+    }
+    out
+}
+
+fn fdct(basis: &[[f32; 8]; 8], samples: &[f32; 64]) -> [f32; 64] {
+    let mut rows = [0.0f32; 64];
+    for y in 0..8 {
+        for u in 0..8 {
+            rows[y * 8 + u] = (0..8).map(|x| basis[x][u] * samples[y * 8 + x]).sum();
+        }
+    }
+    let mut out = [0.0f32; 64];
+    for v in 0..8 {
+        for u in 0..8 {
+            out[v * 8 + u] = (0..8).map(|y| basis[y][v] * rows[y * 8 + u]).sum();
+        }
+    }
+    out
+}
+
+// Encoding
+
+/// A Huffman table prepared for encoding: the code and length of each symbol.
+struct HuffmanCodes {
+    codes: [(u16, u8); 256],
+}
+
+impl HuffmanCodes {
+    fn new(bits: &[u8; 16], symbols: &[u8]) -> Self {
+        let mut codes = [(0, 0); 256];
+        let mut code = 0u16;
+        let mut symbols = symbols.iter();
+        for (len, &count) in (1u8..).zip(bits) {
+            for _ in 0..count {
+                if let Some(&symbol) = symbols.next() {
+                    codes[usize::from(symbol)] = (code, len);
+                }
+                code += 1;
+            }
+            code <<= 1;
+        }
+        Self { codes }
+    }
+}
+
+/// MSB-first bit writer that stuffs a zero byte after every 0xff.
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u32,
+    n_bits: u32,
+}
+
+impl BitWriter {
+    fn put(&mut self, value: u32, bits: u32) {
+        self.acc = (self.acc << bits) | (value & ((1 << bits) - 1));
+        self.n_bits += bits;
+        while self.n_bits >= 8 {
+            self.n_bits -= 8;
+            let byte = (self.acc >> self.n_bits) as u8;
+            self.out.push(byte);
+            if byte == 0xff {
+                self.out.push(0);
+            }
+        }
+    }
+
+    fn put_code(&mut self, table: &HuffmanCodes, symbol: u8) {
+        let (code, len) = table.codes[usize::from(symbol)];
+        self.put(u32::from(code), u32::from(len));
+    }
+
+    /// Pads the final byte with one bits.
+    fn flush(&mut self) {
+        if self.n_bits > 0 {
+            self.put(0x7f, 8 - self.n_bits);
+        }
+    }
+}
+
+/// The magnitude category of a coefficient and its value bits.
+fn category(value: i32) -> (u8, u32) {
+    let magnitude = value.unsigned_abs();
+    let size = 32 - magnitude.leading_zeros();
+    let bits = if value < 0 {
+        (value - 1) as u32 & ((1 << size) - 1)
+    } else {
+        value as u32
+    };
+    (size as u8, bits)
+}
+
+fn scaled_quant(base: &[u16; 64], quality: u8) -> [u16; 64] {
+    let quality = u32::from(quality.clamp(1, 100));
+    let scale = if quality < 50 {
+        5000 / quality
+    } else {
+        200 - 2 * quality
+    };
+    base.map(|q| ((u32::from(q) * scale + 50) / 100).clamp(1, 255) as u16)
+}
+
+/// Encodes `image` as a baseline JFIF JPEG at `quality` (1 to 100), with
+/// chroma subsampled by two in each direction.
+#[allow(clippy::too_many_lines)]
+pub(super) fn encode(image: &Image, quality: u8) -> Vec<u8> {
+    let width = image.width as usize;
+    let height = image.height as usize;
+    let luma_quant = scaled_quant(&LUMA_QUANT, quality);
+    let chroma_quant = scaled_quant(&CHROMA_QUANT, quality);
+
+    let mut out = vec![0xff, 0xd8];
+    let mut segment = |marker: u8, body: &[u8]| {
+        out.extend_from_slice(&[0xff, marker]);
+        out.extend_from_slice(
+            &u16::try_from(body.len() + 2)
+                .unwrap_or(u16::MAX)
+                .to_be_bytes(),
+        );
+        out.extend_from_slice(body);
+    };
+    segment(0xe0, b"JFIF\0\x01\x01\x00\x00\x01\x00\x01\x00\x00");
+
+    let mut dqt = Vec::with_capacity(130);
+    for (id, quant) in [(0u8, &luma_quant), (1, &chroma_quant)] {
+        dqt.push(id);
+        dqt.extend(ZIGZAG.iter().map(|&i| quant[i] as u8));
+    }
+    segment(0xdb, &dqt);
+
+    let mut sof = vec![8];
+    sof.extend_from_slice(&(height as u16).to_be_bytes());
+    sof.extend_from_slice(&(width as u16).to_be_bytes());
+    sof.extend_from_slice(&[3, 1, 0x22, 0, 2, 0x11, 1, 3, 0x11, 1]);
+    segment(0xc0, &sof);
+
+    let mut dht = Vec::new();
+    for (class_id, bits, values) in [
+        (0x00, &DC_LUMA_BITS, &DC_VALUES[..]),
+        (0x10, &AC_LUMA_BITS, &AC_LUMA_VALUES[..]),
+        (0x01, &DC_CHROMA_BITS, &DC_VALUES[..]),
+        (0x11, &AC_CHROMA_BITS, &AC_CHROMA_VALUES[..]),
+    ] {
+        dht.push(class_id);
+        dht.extend_from_slice(bits);
+        dht.extend_from_slice(values);
+    }
+    segment(0xc4, &dht);
+    segment(0xda, &[3, 1, 0x00, 2, 0x11, 3, 0x11, 0, 63, 0]);
+
+    // Color convert into planes padded (by repeating the edges) to whole
+    // 16x16 MCUs.
+    let padded_w = width.div_ceil(16) * 16;
+    let padded_h = height.div_ceil(16) * 16;
+    let mut planes = [
+        vec![0.0f32; padded_w * padded_h],
+        vec![0.0f32; padded_w * padded_h],
+        vec![0.0f32; padded_w * padded_h],
+    ];
+    for y in 0..padded_h {
+        for x in 0..padded_w {
+            let src = (y.min(height - 1) * width + x.min(width - 1)) * 3;
+            let [r, g, b] = [0, 1, 2].map(|c| f32::from(image.pixels[src + c]));
+            let j = y * padded_w + x;
+            planes[0][j] = 0.299 * r + 0.587 * g + 0.114 * b - 128.0;
+            planes[1][j] = -0.168_736 * r - 0.331_264 * g + 0.5 * b;
+            planes[2][j] = 0.5 * r - 0.418_688 * g - 0.081_312 * b;
+        }
+    }
+
+    let basis = basis();
+    let tables = [
+        (
+            HuffmanCodes::new(&DC_LUMA_BITS, &DC_VALUES),
+            HuffmanCodes::new(&AC_LUMA_BITS, &AC_LUMA_VALUES),
+        ),
+        (
+            HuffmanCodes::new(&DC_CHROMA_BITS, &DC_VALUES),
+            HuffmanCodes::new(&AC_CHROMA_BITS, &AC_CHROMA_VALUES),
+        ),
+    ];
+    let mut bits = BitWriter {
+        out,
+        acc: 0,
+        n_bits: 0,
+    };
+    let mut dc_pred = [0i32; 3];
+    let mut encode_block = |bits: &mut BitWriter, component: usize, samples: &[f32; 64]| {
+        let quant = if component == 0 {
+            &luma_quant
+        } else {
+            &chroma_quant
+        };
+        let (dc_table, ac_table) = &tables[component.min(1)];
+        let coefs = fdct(&basis, samples);
+        let quantized: [i32; 64] = std::array::from_fn(|k| {
+            (coefs[ZIGZAG[k]] / f32::from(quant[ZIGZAG[k]])).round() as i32
+        });
+
+        let (size, value) = category(quantized[0] - dc_pred[component]);
+        dc_pred[component] = quantized[0];
+        bits.put_code(dc_table, size);
+        bits.put(value, u32::from(size));
+
+        let mut run = 0;
+        for &coef in &quantized[1..] {
+            if coef == 0 {
+                run += 1;
+                continue;
+            }
+            while run > 15 {
+                bits.put_code(ac_table, 0xf0);
+                run -= 16;
+            }
+            let (size, value) = category(coef);
+            bits.put_code(ac_table, (run << 4) | size);
+            bits.put(value, u32::from(size));
+            run = 0;
+        }
+        if run > 0 {
+            bits.put_code(ac_table, 0x00);
+        }
+    };
+
+    for my in (0..padded_h).step_by(16) {
+        for mx in (0..padded_w).step_by(16) {
+            for (by, bx) in [(0, 0), (0, 8), (8, 0), (8, 8)] {
+                let samples: [f32; 64] = std::array::from_fn(|i| {
+                    planes[0][(my + by + i / 8) * padded_w + mx + bx + i % 8]
+                });
+                encode_block(&mut bits, 0, &samples);
+            }
+            for (component, plane) in planes.iter().enumerate().skip(1) {
+                let samples: [f32; 64] = std::array::from_fn(|i| {
+                    let (x, y) = (mx + 2 * (i % 8), my + 2 * (i / 8));
+                    let at = |dx: usize, dy: usize| plane[(y + dy) * padded_w + x + dx];
+                    (at(0, 0) + at(1, 0) + at(0, 1) + at(1, 1)) / 4.0
+                });
+                encode_block(&mut bits, component, &samples);
+            }
+        }
+    }
+    bits.flush();
+
+    let mut out = bits.out;
+    out.extend_from_slice(&[0xff, 0xd9]);
+    out
+}
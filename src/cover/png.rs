@@ -0,0 +1,238 @@
+//! PNG decoding to 8-bit RGB. Alpha is dropped.
+//!
+//! ref
+//!   <https://www.w3.org/TR/png/>
+use super::{inflate, Error, Image};
+
+const SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+/// Starting column and row, and column and row steps, of each Adam7 pass.
+const ADAM7: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+struct Header {
+    width: usize,
+    height: usize,
+    bit_depth: u8,
+    color_type: u8,
+    interlaced: bool,
+}
+
+impl Header {
+    fn channels(&self) -> usize {
+        match self.color_type {
+            2 => 3,
+            4 => 2,
+            6 => 4,
+            _ => 1,
+        }
+    }
+
+    fn bits_per_pixel(&self) -> usize {
+        self.channels() * usize::from(self.bit_depth)
+    }
+
+    /// Bytes per row of a (sub)image `width` pixels wide, excluding the
+    /// filter type byte.
+    fn stride(&self, width: usize) -> usize {
+        (width * self.bits_per_pixel()).div_ceil(8)
+    }
+}
+
+/// Reads the width and height from the `IHDR` chunk.
+pub(super) fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    if data.get(..8)? != SIGNATURE || data.get(12..16)? != b"IHDR" {
+        return None;
+    }
+    let width = u32::from_be_bytes(data.get(16..20)?.try_into().ok()?);
+    let height = u32::from_be_bytes(data.get(20..24)?.try_into().ok()?);
+    Some((width, height))
+}
+
+pub(super) fn decode(data: &[u8]) -> Result<Image, Error> {
+    if data.get(..8) != Some(SIGNATURE) {
+        return Err(Error::Malformed("missing PNG signature"));
+    }
+
+    let mut header = None;
+    let mut palette: &[u8] = &[];
+    let mut compressed = Vec::new();
+    let mut pos = 8;
+    while pos + 8 <= data.len() {
+        let len = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]);
+        let kind = &data[pos + 4..pos + 8];
+        let body = data
+            .get(pos + 8..pos + 8 + len as usize)
+            .ok_or(Error::Malformed("truncated PNG chunk"))?;
+        match kind {
+            b"IHDR" => header = Some(parse_header(body)?),
+            b"PLTE" => palette = body,
+            b"IDAT" => compressed.extend_from_slice(body),
+            b"IEND" => break,
+            _ => {}
+        }
+        // Skip the body and its CRC.
+        pos += 12 + len as usize;
+    }
+
+    let header = header.ok_or(Error::Malformed("missing PNG header"))?;
+    if header.color_type == 3 && palette.is_empty() {
+        return Err(Error::Malformed("missing PNG palette"));
+    }
+    let raw = inflate::zlib_decompress(&compressed)?;
+
+    let mut image = Image {
+        width: u32::try_from(header.width).map_err(|_| Error::Unsupported("image too large"))?,
+        height: u32::try_from(header.height).map_err(|_| Error::Unsupported("image too large"))?,
+        pixels: vec![0; header.width * header.height * 3],
+    };
+
+    let passes: &[(usize, usize, usize, usize)] = if header.interlaced {
+        &ADAM7
+    } else {
+        &[(0, 0, 1, 1)]
+    };
+    let mut raw = raw.as_slice();
+    for &(x0, y0, dx, dy) in passes {
+        let width = header.width.saturating_sub(x0).div_ceil(dx);
+        let height = header.height.saturating_sub(y0).div_ceil(dy);
+        if width == 0 || height == 0 {
+            continue;
+        }
+        let len = (header.stride(width) + 1) * height;
+        let pass = raw
+            .get(..len)
+            .ok_or(Error::Malformed("truncated PNG image data"))?;
+        raw = &raw[len..];
+
+        let rows = unfilter(&header, width, height, pass)?;
+        let stride = header.stride(width);
+        for (row, y) in rows.chunks_exact(stride).zip((y0..).step_by(dy)) {
+            for (i, x) in (x0..).step_by(dx).take(width).enumerate() {
+                let rgb = pixel(&header, palette, row, i);
+                let offset = (y * header.width + x) * 3;
+                image.pixels[offset..offset + 3].copy_from_slice(&rgb);
+            }
+        }
+    }
+    Ok(image)
+}
+
+fn parse_header(body: &[u8]) -> Result<Header, Error> {
+    if body.len() < 13 {
+        return Err(Error::Malformed("short PNG header"));
+    }
+    let width = u32::from_be_bytes([body[0], body[1], body[2], body[3]]) as usize;
+    let height = u32::from_be_bytes([body[4], body[5], body[6], body[7]]) as usize;
+    let header = Header {
+        width,
+        height,
+        bit_depth: body[8],
+        color_type: body[9],
+        interlaced: body[12] == 1,
+    };
+    let valid_depth = match header.color_type {
+        0 => matches!(header.bit_depth, 1 | 2 | 4 | 8 | 16),
+        3 => matches!(header.bit_depth, 1 | 2 | 4 | 8),
+        2 | 4 | 6 => matches!(header.bit_depth, 8 | 16),
+        _ => false,
+    };
+    if !valid_depth || body[10] != 0 || body[11] != 0 {
+        return Err(Error::Unsupported("PNG color type or bit depth"));
+    }
+    if width == 0 || height == 0 || width.saturating_mul(height) > super::MAX_PIXELS {
+        return Err(Error::Unsupported("PNG dimensions"));
+    }
+    Ok(header)
+}
+
+/// Reverses the per-row filters, returning the rows without their filter
+/// type bytes.
+fn unfilter(header: &Header, width: usize, height: usize, data: &[u8]) -> Result<Vec<u8>, Error> {
+    let stride = header.stride(width);
+    // Filters work on whole bytes, looking back one pixel (at least a byte).
+    let bpp = header.bits_per_pixel().div_ceil(8);
+    let mut out = vec![0u8; stride * height];
+    for y in 0..height {
+        let filter = data[y * (stride + 1)];
+        let line = &data[y * (stride + 1) + 1..(y + 1) * (stride + 1)];
+        let (before, rest) = out.split_at_mut(y * stride);
+        let prior = if y == 0 {
+            None
+        } else {
+            Some(&before[(y - 1) * stride..])
+        };
+        let row = &mut rest[..stride];
+        for i in 0..stride {
+            let a = if i >= bpp { row[i - bpp] } else { 0 };
+            let b = prior.map_or(0, |p| p[i]);
+            let c = if i >= bpp {
+                prior.map_or(0, |p| p[i - bpp])
+            } else {
+                0
+            };
+            let predicted = match filter {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => u16::midpoint(u16::from(a), u16::from(b)) as u8,
+                4 => paeth(a, b, c),
+                _ => return Err(Error::Malformed("bad PNG filter type")),
+            };
+            row[i] = line[i].wrapping_add(predicted);
+        }
+    }
+    Ok(out)
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = i16::from(a) + i16::from(b) - i16::from(c);
+    let pa = (p - i16::from(a)).abs();
+    let pb = (p - i16::from(b)).abs();
+    let pc = (p - i16::from(c)).abs();
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// The RGB value of pixel `i` of an unfiltered row.
+fn pixel(header: &Header, palette: &[u8], row: &[u8], i: usize) -> [u8; 3] {
+    let depth = usize::from(header.bit_depth);
+    // The most significant byte of sample `n` of the pixel, for 8 and 16 bit
+    // samples.
+    let sample = |n: usize| row[(i * header.channels() + n) * depth / 8];
+    match header.color_type {
+        0 | 3 if depth < 8 => {
+            let bit = i * depth;
+            let shift = 8 - depth - bit % 8;
+            let value = (row[bit / 8] >> shift) & ((1 << depth) - 1);
+            if header.color_type == 3 {
+                palette_entry(palette, value)
+            } else {
+                let gray = (u16::from(value) * 255 / ((1 << depth) - 1)) as u8;
+                [gray; 3]
+            }
+        }
+        3 => palette_entry(palette, row[i]),
+        0 | 4 => [sample(0); 3],
+        _ => [sample(0), sample(1), sample(2)],
+    }
+}
+
+fn palette_entry(palette: &[u8], index: u8) -> [u8; 3] {
+    let i = usize::from(index) * 3;
+    palette
+        .get(i..i + 3)
+        .map_or([0; 3], |rgb| [rgb[0], rgb[1], rgb[2]])
+}
@@ -0,0 +1,44 @@
+//! Downscaling by averaging the source pixels that each output pixel covers.
+use super::Image;
+
+/// Resizes `image` to `width` by `height`, which should be no larger than it.
+pub(super) fn resize(image: &Image, width: u32, height: u32) -> Image {
+    let (src_w, src_h) = (image.width as usize, image.height as usize);
+    let (dst_w, dst_h) = (width as usize, height as usize);
+    let columns = spans(src_w, dst_w);
+
+    let mut pixels = Vec::with_capacity(dst_w * dst_h * 3);
+    for (y0, y1) in spans(src_h, dst_h) {
+        for &(x0, x1) in &columns {
+            let mut sum = [0u32; 3];
+            for y in y0..y1 {
+                let row = &image.pixels[(y * src_w + x0) * 3..(y * src_w + x1) * 3];
+                for rgb in row.chunks_exact(3) {
+                    for (total, &value) in sum.iter_mut().zip(rgb) {
+                        *total += u32::from(value);
+                    }
+                }
+            }
+            let count = u32::try_from((y1 - y0) * (x1 - x0)).unwrap_or(u32::MAX);
+            pixels.extend(sum.map(|total| ((total + count / 2) / count) as u8));
+        }
+    }
+
+    Image {
+        width,
+        height,
+        pixels,
+    }
+}
+
+/// The range of source pixels covered by each of `dst` output pixels, each
+/// covering at least one.
+fn spans(src: usize, dst: usize) -> Vec<(usize, usize)> {
+    (0..dst)
+        .map(|i| {
+            let start = i * src / dst;
+            let end = ((i + 1) * src / dst).max(start + 1);
+            (start, end)
+        })
+        .collect()
+}
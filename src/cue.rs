@@ -0,0 +1,323 @@
+//! Cue sheets, which describe the tracks in one or more audio files, as CD
+//! rippers often write them next to a single file for the whole disc.
+//!
+//! Only what marks chapters is read: each `FILE`, and in them each `TRACK`
+//! with its `TITLE`, `PERFORMER` and `INDEX 01`, the start of the track proper.
+//! A track's pregap (its `INDEX 00`) is left to the track before, so a track
+//! whose `INDEX 01` falls in the next file starts there, and the start of that
+//! file still belongs to the track before. Sheets are read as UTF-8 if they
+//! are valid UTF-8, and as Windows-1252 otherwise, as older rippers wrote them.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use thiserror::Error as ThisError;
+use tracing::warn;
+
+/// Frames to the second in cue sheet times (the sectors of a CD).
+const FRAMES_PER_SECOND: u64 = 75;
+
+/// Characters of Windows-1252 from 0x80 to 0x9F, where it differs from
+/// Latin-1; its five undefined bytes map to the control characters Latin-1
+/// has there.
+const CP1252_HIGH: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8D}', 'Ž', '\u{8F}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9D}', 'ž', 'Ÿ',
+];
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+    #[error("IO Error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Malformed cue sheet at line {line}: {message}")]
+    Malformed { line: usize, message: &'static str },
+}
+
+/// A cue sheet: the audio files it describes, and the tracks in each.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CueSheet {
+    /// The title of the whole disc.
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub files: Vec<CueFile>,
+}
+
+/// An audio file named in a cue sheet, with the tracks that start in it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CueFile {
+    /// The file's name, relative to the cue sheet.
+    pub name: String,
+    pub tracks: Vec<Track>,
+}
+
+/// A track, which starts at its `INDEX 01`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Track {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// Where the track starts in its file.
+    pub start: Duration,
+}
+
+impl Track {
+    /// The title of the track's chapter: its title, or failing that its
+    /// performer, or failing that its number.
+    #[must_use]
+    pub fn chapter_title(&self) -> String {
+        self.title
+            .clone()
+            .or_else(|| self.performer.clone())
+            .unwrap_or_else(|| format!("Track {:02}", self.number))
+    }
+}
+
+/// The tracks a cue sheet has in one audio file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileTracks {
+    /// The title and performer of the sheet as a whole.
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub tracks: Vec<Track>,
+    /// Whether the file follows another in the sheet, so that any audio
+    /// before its first track belongs to the last track of that file.
+    pub continues: bool,
+}
+
+impl CueSheet {
+    /// Reads the cue sheet at `path`.
+    ///
+    /// # Errors
+    /// Returns an error if the file can't be read or isn't a cue sheet.
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        Self::parse(&decode(&fs::read(path)?))
+    }
+
+    /// Parses the text of a cue sheet. Commands other than those describing
+    /// files and tracks are ignored, as are tracks without an `INDEX 01`.
+    ///
+    /// # Errors
+    /// Returns an error if a track comes before any file, or a track number or
+    /// index is malformed.
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut sheet = Self::default();
+        // The track being read, which is added to the file it starts in, and
+        // whether it has been added, when it's the last track of the last
+        // file until the next track or file.
+        let mut track: Option<Track> = None;
+        let mut added = false;
+        for (number, line) in text.lines().enumerate() {
+            let malformed = |message| Error::Malformed {
+                line: number + 1,
+                message,
+            };
+            let mut words = Words(line.trim());
+            let Some(command) = words.next() else {
+                continue;
+            };
+            match command.to_ascii_uppercase().as_str() {
+                "FILE" => {
+                    let name = words
+                        .next()
+                        .ok_or_else(|| malformed("FILE without a name"))?;
+                    sheet.files.push(CueFile {
+                        name,
+                        tracks: Vec::new(),
+                    });
+                    added = false;
+                }
+                "TRACK" => {
+                    if sheet.files.is_empty() {
+                        return Err(malformed("TRACK before any FILE"));
+                    }
+                    let number = words
+                        .next()
+                        .and_then(|number| number.parse().ok())
+                        .ok_or_else(|| malformed("TRACK without a number"))?;
+                    track = Some(Track {
+                        number,
+                        title: None,
+                        performer: None,
+                        start: Duration::MAX,
+                    });
+                    added = false;
+                }
+                "INDEX" => {
+                    let index: u32 = words
+                        .next()
+                        .and_then(|index| index.parse().ok())
+                        .ok_or_else(|| malformed("INDEX without a number"))?;
+                    let start = words
+                        .next()
+                        .as_deref()
+                        .and_then(parse_time)
+                        .ok_or_else(|| malformed("INDEX without a time as mm:ss:ff"))?;
+                    if index != 1 {
+                        continue;
+                    }
+                    if let (Some(mut track), Some(file)) = (track.take(), sheet.files.last_mut()) {
+                        track.start = start;
+                        file.tracks.push(track);
+                        added = true;
+                    }
+                }
+                command @ ("TITLE" | "PERFORMER") => {
+                    let value = words.next().filter(|value| !value.is_empty());
+                    let before_tracks = sheet.files.iter().all(|file| file.tracks.is_empty());
+                    let current = match track.as_mut() {
+                        Some(track) => Some(track),
+                        None if added => sheet
+                            .files
+                            .last_mut()
+                            .and_then(|file| file.tracks.last_mut()),
+                        None => None,
+                    };
+                    let (title, performer) = match current {
+                        Some(track) => (&mut track.title, &mut track.performer),
+                        // Before any track, the sheet's own.
+                        None if before_tracks => (&mut sheet.title, &mut sheet.performer),
+                        None => continue,
+                    };
+                    *(if command == "TITLE" { title } else { performer }) = value;
+                }
+                _ => {}
+            }
+        }
+        Ok(sheet)
+    }
+
+    /// The tracks in the file named `name`, by its name in the sheet, or
+    /// failing that by its name without the extension, since the audio is
+    /// often converted after it's ripped. Returns `None` if the sheet has no
+    /// such file, or no tracks in it.
+    #[must_use]
+    pub fn tracks_in(&self, name: &str) -> Option<FileTracks> {
+        let file_name = |file: &CueFile| {
+            // Names may be relative paths, written with either separator.
+            let name = file.name.rsplit(['/', '\\']).next().unwrap_or_default();
+            name.to_owned()
+        };
+        let stem = |name: &str| {
+            let stem = Path::new(name).file_stem()?.to_str()?;
+            Some(stem.to_lowercase())
+        };
+        let position = self
+            .files
+            .iter()
+            .position(|file| file_name(file).eq_ignore_ascii_case(name))
+            .or_else(|| {
+                let matching: Vec<usize> = (0..self.files.len())
+                    .filter(|&i| stem(&file_name(&self.files[i])) == stem(name))
+                    .collect();
+                // Stems alone don't tell apart "a.flac" and "a.wav".
+                (matching.len() == 1).then(|| matching[0])
+            })?;
+        let file = &self.files[position];
+        (!file.tracks.is_empty()).then(|| FileTracks {
+            title: self.title.clone(),
+            performer: self.performer.clone(),
+            tracks: file.tracks.clone(),
+            continues: position > 0,
+        })
+    }
+}
+
+/// Whether `path` looks like a cue sheet, judging by its extension.
+#[must_use]
+pub fn is_cue_sheet(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("cue"))
+}
+
+/// The tracks of each of `inputs`, from the first of the cue sheets `sheets`
+/// in the same directory that names it. Sheets that can't be read are logged
+/// and left out.
+#[must_use]
+pub fn find(inputs: &[PathBuf], sheets: &[PathBuf]) -> Vec<Option<FileTracks>> {
+    let sheets: Vec<(&Path, CueSheet)> = sheets
+        .iter()
+        .filter_map(|path| match CueSheet::read(path) {
+            Ok(sheet) => Some((directory(path), sheet)),
+            Err(e) => {
+                warn!("Ignoring cue sheet '{}': {e}", path.display());
+                None
+            }
+        })
+        .collect();
+    inputs
+        .iter()
+        .map(|input| {
+            let name = input.file_name()?.to_str()?;
+            sheets
+                .iter()
+                .filter(|(dir, _)| *dir == directory(input))
+                .find_map(|(_, sheet)| sheet.tracks_in(name))
+        })
+        .collect()
+}
+
+/// The directory of `path`, with a bare file name in the current directory.
+fn directory(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// The text of a cue sheet: UTF-8, with or without a byte order mark, or
+/// failing that Windows-1252.
+fn decode(bytes: &[u8]) -> String {
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_owned(),
+        Err(_) => bytes
+            .iter()
+            .map(|&byte| match byte {
+                0x80..=0x9F => CP1252_HIGH[usize::from(byte - 0x80)],
+                _ => char::from(byte),
+            })
+            .collect(),
+    }
+}
+
+/// Parses a time as minutes, seconds and frames, `mm:ss:ff`.
+fn parse_time(time: &str) -> Option<Duration> {
+    let mut parts = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (Some(Some(minutes)), Some(Some(seconds)), Some(Some(frames)), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
+    if seconds >= 60 || frames >= FRAMES_PER_SECOND {
+        return None;
+    }
+    let frames = (minutes * 60 + seconds) * FRAMES_PER_SECOND + frames;
+    Some(Duration::from_nanos(
+        frames * 1_000_000_000 / FRAMES_PER_SECOND,
+    ))
+}
+
+/// The words of a line of a cue sheet, where a quoted string is one word.
+struct Words<'a>(&'a str);
+
+impl Iterator for Words<'_> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        let rest = self.0.trim_start();
+        if rest.is_empty() {
+            return None;
+        }
+        let (word, rest) = match rest.strip_prefix('"') {
+            // An unterminated quote runs to the end of the line.
+            Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+            None => rest.split_once(char::is_whitespace).unwrap_or((rest, "")),
+        };
+        self.0 = rest;
+        Some(word.to_owned())
+    }
+}
@@ -0,0 +1,707 @@
+//! Streaming decoding of a single input file.
+//!
+//! Packets are decoded one at a time into a reusable interleaved buffer, so
+//! only a single packet's worth of audio is held in memory no matter how long
+//! the file is.
+//!
+//! Formats are identified by sniffing the start of the file before falling
+//! back to symphonia's probe.
+use std::{
+    fmt,
+    fs::File,
+    io::{Seek, SeekFrom},
+};
+
+use serde::Serialize;
+use symphonia::{
+    core::{
+        audio::{Channels, SampleBuffer, SignalSpec},
+        codecs::{
+            Decoder, DecoderOptions, CODEC_TYPE_MP1, CODEC_TYPE_MP2, CODEC_TYPE_MP3,
+            CODEC_TYPE_NULL,
+        },
+        errors::Error as SymphoniaError,
+        formats::{FormatOptions, FormatReader, Packet, Track},
+        io::{MediaSourceStream, MediaSourceStreamOptions, ReadBytes},
+        meta::{MetadataLog, MetadataOptions},
+        probe::{Hint, Instantiate},
+        units::TimeBase,
+    },
+    default::formats::MpaReader,
+};
+use thiserror::Error as ThisError;
+use tracing::{debug, info, warn};
+
+use crate::{
+    cancel::CancellationToken,
+    tags::{self, Tags},
+};
+
+mod adts;
+mod duration;
+mod sniff;
+
+use adts::AdtsReader;
+pub use duration::{Confidence, DurationSource, ProbedDuration};
+
+/// Extensions of the formats symphonia can read, by the short name of the
+/// format.
+const EXTENSIONS: [(&str, &[&str]); 9] = [
+    ("wav", &["wav", "wave"]),
+    ("flac", &["flac"]),
+    ("ogg", &["ogg", "oga", "opus"]),
+    ("isomp4", &["mp4", "m4a", "m4b", "m4p", "mov", "3gp"]),
+    ("mkv", &["mkv", "mka", "webm"]),
+    ("aac", &["aac", "adts"]),
+    ("mp1", &["mp1"]),
+    ("mp2", &["mp2", "mpa"]),
+    ("mp3", &["mp3"]),
+];
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+    #[error("Unsupported format: {0}")]
+    UnsupportedFormat(SymphoniaError),
+    #[error("No audio track found")]
+    NoAudioTrack,
+    #[error("Unsupported codec: {0}")]
+    UnsupportedCodec(String),
+    #[error("Failed to read packet {packet}: {source}")]
+    Demux {
+        packet: u64,
+        #[source]
+        source: SymphoniaError,
+    },
+    #[error("Failed to decode packet {packet} (timestamp {ts}, {seconds:.3}s): {source}")]
+    Decode {
+        packet: u64,
+        /// Timestamp of the packet in the track's time base.
+        ts: u64,
+        /// Position of the packet in seconds, counting all the audio decoded
+        /// before it, including that of any earlier chained streams.
+        seconds: f64,
+        #[source]
+        source: SymphoniaError,
+    },
+    #[error("Cancelled")]
+    Cancelled,
+}
+
+/// What to do about packets that can't be decoded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorMode {
+    /// Fail with an error.
+    Strict,
+    /// Replace the packet with silence of the same length, and carry on.
+    #[default]
+    Lenient,
+}
+
+/// How the format of a file was identified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DetectionMethod {
+    /// By the magic number of its container, then read by symphonia.
+    Magic,
+    /// By a run of ADTS or MPEG audio frame headers, then read with the
+    /// matching reader from where the frames start.
+    FrameSync,
+    /// By symphonia's probe, as sniffing didn't recognize it.
+    Probe,
+}
+
+/// The format identified for an input file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Detection {
+    /// Short name of the format, such as `mp3` or `isomp4`, or `None` if only
+    /// symphonia's probe recognized it.
+    pub format: Option<&'static str>,
+    pub method: DetectionMethod,
+    /// Extension of the file, which is given to the probe as a hint.
+    pub extension: Option<String>,
+    /// Bytes of junk skipped between the tags and the audio.
+    pub skipped: u64,
+}
+
+impl Detection {
+    /// Whether the file's extension is one used for the detected format, or
+    /// `None` if either is unknown.
+    #[must_use]
+    pub fn extension_matches(&self) -> Option<bool> {
+        let extension = self.extension.as_deref()?;
+        let (_, extensions) = EXTENSIONS.iter().find(|(f, _)| Some(*f) == self.format)?;
+        Some(extensions.iter().any(|e| e.eq_ignore_ascii_case(extension)))
+    }
+}
+
+impl fmt::Display for Detection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.format.unwrap_or("unknown"))?;
+        match self.method {
+            DetectionMethod::Magic => write!(f, " (magic number)")?,
+            DetectionMethod::FrameSync => write!(f, " (frame sync)")?,
+            DetectionMethod::Probe => write!(f, " (probe)")?,
+        }
+        if self.skipped > 0 {
+            write!(f, ", skipped {} bytes of junk", self.skipped)?;
+        }
+        Ok(())
+    }
+}
+
+/// The result of probing a file.
+pub struct Probed {
+    pub format: Box<dyn FormatReader>,
+    /// Metadata that precedes the container, such as `ID3v2` tags.
+    pub metadata: Option<MetadataLog>,
+    pub detection: Detection,
+    /// The Xing or VBRI header of an MPEG audio stream.
+    vbr: Option<sniff::VbrHeader>,
+}
+
+/// Whether `extension` is one used by a format that symphonia can read.
+fn is_audio_extension(extension: &str) -> bool {
+    EXTENSIONS
+        .iter()
+        .flat_map(|(_, extensions)| *extensions)
+        .any(|e| e.eq_ignore_ascii_case(extension))
+}
+
+/// Probes the format of `file`, whose name has the extension `extension`,
+/// reading any metadata that precedes the container.
+///
+/// The start of the file is sniffed first. Raw ADTS and MPEG audio streams
+/// are read from the first run of valid frames, skipping any junk in front of
+/// them; anything else is left to symphonia's probe, unless neither the
+/// content nor the extension looks like audio.
+///
+/// # Errors
+/// Returns an error if the file's format isn't recognized.
+pub fn probe(mut file: File, extension: Option<&str>) -> Result<Probed, Error> {
+    let sniffed = sniff::sniff(&mut file).map_err(|e| Error::UnsupportedFormat(e.into()))?;
+    let mut detection = Detection {
+        format: None,
+        method: DetectionMethod::Probe,
+        extension: extension.map(ToOwned::to_owned),
+        skipped: sniffed.offset - sniffed.tags_len,
+    };
+    let mut mss = MediaSourceStream::new(Box::new(file), MediaSourceStreamOptions::default());
+    let format_options = FormatOptions::default();
+
+    let (format, metadata) = match sniffed.kind {
+        sniff::Kind::Adts | sniff::Kind::Mpeg(_) => {
+            let metadata = read_tags(&mut mss, sniffed.tags_len);
+            mss.seek(SeekFrom::Start(sniffed.offset))
+                .map_err(|e| Error::UnsupportedFormat(e.into()))?;
+            let format: Box<dyn FormatReader> = if let sniff::Kind::Mpeg(name) = sniffed.kind {
+                detection.format = Some(name);
+                // Trim the encoder delay and padding a LAME tag gives, as the
+                // duration from the Xing header does. Trimming also cuts the
+                // stream to its length as the reader has it, so it's only
+                // done where the Xing header's count can be trusted.
+                let options = FormatOptions {
+                    enable_gapless: sniffed.vbr.is_some_and(|vbr| vbr.encoder_tag),
+                    ..format_options
+                };
+                Box::new(MpaReader::try_new(mss, &options).map_err(Error::UnsupportedFormat)?)
+            } else {
+                detection.format = Some("aac");
+                Box::new(
+                    AdtsReader::try_new(mss, &format_options).map_err(Error::UnsupportedFormat)?,
+                )
+            };
+            detection.method = DetectionMethod::FrameSync;
+            (format, metadata)
+        }
+        sniff::Kind::Unknown if !extension.is_some_and(is_audio_extension) => {
+            return Err(Error::UnsupportedFormat(SymphoniaError::Unsupported(
+                "no audio format recognized",
+            )));
+        }
+        kind => {
+            if let sniff::Kind::Container(name) = kind {
+                detection.format = Some(name);
+                detection.method = DetectionMethod::Magic;
+            }
+            let mut hint = Hint::new();
+            if let Some(extension) = extension {
+                hint.with_extension(extension);
+            }
+            let probed = symphonia::default::get_probe()
+                .format(&hint, mss, &format_options, &MetadataOptions::default())
+                .map_err(Error::UnsupportedFormat)?;
+            (probed.format, probed.metadata.into_inner())
+        }
+    };
+
+    if detection.extension_matches() == Some(false) {
+        warn!(
+            "File has a '{}' extension, but looks like {detection}",
+            extension.unwrap_or_default()
+        );
+    }
+    Ok(Probed {
+        format,
+        metadata,
+        detection,
+        vbr: sniffed.vbr,
+    })
+}
+
+/// Reads the `ID3v2` tags in the first `len` bytes of `mss`.
+fn read_tags(mss: &mut MediaSourceStream, len: u64) -> Option<MetadataLog> {
+    let mut log = None;
+    while mss.pos() < len {
+        match symphonia::default::get_probe().next(mss) {
+            Ok(Instantiate::Metadata(reader)) => {
+                match reader(&MetadataOptions::default()).read_all(mss) {
+                    Ok(revision) => log.get_or_insert_with(MetadataLog::default).push(revision),
+                    Err(e) => {
+                        warn!("Ignoring unreadable ID3v2 tag: {e}");
+                        break;
+                    }
+                }
+            }
+            Ok(Instantiate::Format(_)) | Err(_) => break,
+        }
+    }
+    log
+}
+
+/// The audio decoded from one packet, as interleaved samples.
+pub struct Chunk<'a> {
+    pub spec: SignalSpec,
+    pub samples: &'a [f32],
+}
+
+/// Decodes the default track of a file, a packet at a time.
+///
+/// Where the stream changes part way through, as at the boundary between the
+/// streams of a chained Ogg file or where an MP3 changes sample rate, the
+/// track is selected again and the decoder rebuilt, so decoding carries on
+/// across the boundary. The chunks after it may have a different spec.
+pub struct FileDecoder {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    spec: Option<SignalSpec>,
+    sample_buf: Option<SampleBuffer<f32>>,
+    /// Number of packets of the track read so far.
+    packets: u64,
+    frames: u64,
+    /// Duration of the audio decoded so far, in seconds.
+    seconds: f64,
+    /// Number of times the stream changed and the decoder was rebuilt.
+    resets: u64,
+    mode: ErrorMode,
+    /// Number of packets that couldn't be decoded and were replaced with
+    /// silence.
+    skipped: u64,
+    /// Frames in the last packet decoded, the length given to silence for a
+    /// packet whose duration isn't known.
+    last_frames: usize,
+    /// The interleaved silence that replaces the last skipped packet.
+    silence: Vec<f32>,
+    tags: Tags,
+    detection: Detection,
+    vbr: Option<sniff::VbrHeader>,
+    cancel: Option<CancellationToken>,
+}
+
+/// What decoding a packet produced.
+#[derive(Clone, Copy)]
+enum Decoded {
+    /// Audio, in `sample_buf`.
+    Audio(SignalSpec),
+    /// Silence in place of a packet that couldn't be decoded, in `silence`.
+    Silence(SignalSpec),
+    /// Nothing, as a packet that couldn't be decoded was skipped.
+    Nothing,
+}
+
+impl FileDecoder {
+    /// Probes `file` (using its `extension` as a hint), reading its tags, and
+    /// prepares a decoder for its first audio track.
+    ///
+    /// # Errors
+    /// Returns an error if the file's format isn't recognized, it has no audio
+    /// track, or the track's codec isn't supported.
+    pub fn open(file: File, extension: Option<&str>) -> Result<Self, Error> {
+        let (probed, tags) = tags::probe(file, extension)?;
+        let format = probed.format;
+        let track = select_track(format.as_ref())?;
+        Ok(Self {
+            track_id: track.id,
+            decoder: make_decoder(track)?,
+            format,
+            spec: None,
+            sample_buf: None,
+            packets: 0,
+            frames: 0,
+            seconds: 0.0,
+            resets: 0,
+            mode: ErrorMode::default(),
+            skipped: 0,
+            last_frames: 0,
+            silence: Vec::new(),
+            tags,
+            detection: probed.detection,
+            vbr: probed.vbr,
+            cancel: None,
+        })
+    }
+
+    /// Sets what to do about packets that can't be decoded, which by default
+    /// are replaced with silence.
+    pub fn set_error_mode(&mut self, mode: ErrorMode) {
+        self.mode = mode;
+    }
+
+    /// Sets a token that, once cancelled, makes reading the next packet fail
+    /// with [`Error::Cancelled`].
+    pub fn set_cancellation(&mut self, token: CancellationToken) {
+        self.cancel = Some(token);
+    }
+
+    /// How the file's format was identified.
+    #[must_use]
+    pub fn detection(&self) -> &Detection {
+        &self.detection
+    }
+
+    /// The tags read from the file.
+    #[must_use]
+    pub fn tags(&self) -> &Tags {
+        &self.tags
+    }
+
+    /// Sample rate of the track being decoded, as given by the container.
+    #[must_use]
+    pub fn sample_rate(&self) -> Option<u32> {
+        self.track()?.codec_params.sample_rate
+    }
+
+    /// Number of channels of the track being decoded, as given by the
+    /// container.
+    #[must_use]
+    pub fn channels(&self) -> Option<usize> {
+        self.track()?.codec_params.channels.map(Channels::count)
+    }
+
+    /// Duration of the track being decoded in seconds, as given by the
+    /// container.
+    #[must_use]
+    pub fn duration(&self) -> Option<f64> {
+        let frames = self.track()?.codec_params.n_frames?;
+        let time = self.time_base()?.calc_time(frames);
+        #[allow(clippy::cast_precision_loss)]
+        let seconds = time.seconds as f64 + time.frac;
+        Some(seconds)
+    }
+
+    /// Short name of the codec of the track being decoded, such as `mp3`.
+    #[must_use]
+    pub fn codec(&self) -> Option<&'static str> {
+        symphonia::default::get_codecs()
+            .get_codec(self.decoder.codec_params().codec)
+            .map(|d| d.short_name)
+    }
+
+    /// Number of frames decoded so far.
+    #[must_use]
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Duration of the audio decoded so far, in seconds, which unlike
+    /// [`frames`](Self::frames) allows for changes of sample rate.
+    #[must_use]
+    pub fn seconds(&self) -> f64 {
+        self.seconds
+    }
+
+    /// Number of packets so far that couldn't be decoded and were replaced
+    /// with silence.
+    #[must_use]
+    pub fn skipped_packets(&self) -> u64 {
+        self.skipped
+    }
+
+    /// Number of times so far that the stream changed, such as at the start
+    /// of each chained stream after the first, and decoding was restarted.
+    #[must_use]
+    pub fn resets(&self) -> u64 {
+        self.resets
+    }
+
+    /// Decodes the next packet of the track, returning its audio, or `None`
+    /// at the end of the stream. The returned chunk borrows a buffer that is
+    /// reused by the next call.
+    ///
+    /// In [`ErrorMode::Lenient`], a packet that can't be decoded is returned as
+    /// a chunk of silence of the same length instead.
+    ///
+    /// # Errors
+    /// Returns an error, identifying the packet, if a packet can't be read (or
+    /// in [`ErrorMode::Strict`], decoded), or if the stream changes to one
+    /// that can't be decoded.
+    pub fn next_chunk(&mut self) -> Result<Option<Chunk<'_>>, Error> {
+        loop {
+            let Some(packet) = self.next_packet()? else {
+                return Ok(None);
+            };
+            match self.decode(&packet)? {
+                Decoded::Audio(spec) => {
+                    let Some(buf) = self.sample_buf.as_ref() else {
+                        unreachable!("audio is decoded into the sample buffer");
+                    };
+                    return Ok(Some(Chunk {
+                        spec,
+                        samples: buf.samples(),
+                    }));
+                }
+                Decoded::Silence(spec) => {
+                    return Ok(Some(Chunk {
+                        spec,
+                        samples: &self.silence,
+                    }));
+                }
+                Decoded::Nothing => {}
+            }
+        }
+    }
+
+    /// Reads the next packet of the track, or `None` at the end of the
+    /// stream, selecting the track again and rebuilding the decoder when a
+    /// new stream starts.
+    fn next_packet(&mut self) -> Result<Option<Packet>, Error> {
+        loop {
+            if self
+                .cancel
+                .as_ref()
+                .is_some_and(CancellationToken::is_cancelled)
+            {
+                return Err(Error::Cancelled);
+            }
+            // Get the next packet from the format reader.
+            let packet = match self.format.next_packet() {
+                Ok(p) => p,
+                Err(SymphoniaError::ResetRequired) => {
+                    // A new stream has started, with its own tracks.
+                    let track = select_track(self.format.as_ref())?;
+                    self.track_id = track.id;
+                    self.decoder = make_decoder(track)?;
+                    self.reset("a new stream started");
+                    continue;
+                }
+                Err(SymphoniaError::IoError(e))
+                    if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    // The format reader signals end-of-stream with an unexpected EOF.
+                    return Ok(None);
+                }
+                Err(source) => {
+                    return Err(Error::Demux {
+                        packet: self.packets,
+                        source,
+                    })
+                }
+            };
+
+            // If the packet does not belong to the selected track, skip it.
+            if packet.track_id() == self.track_id {
+                self.packets += 1;
+                return Ok(Some(packet));
+            }
+        }
+    }
+
+    /// Decodes `packet`, which was the last to be read, replacing it with
+    /// silence if it can't be decoded and the mode allows it.
+    fn decode(&mut self, packet: &Packet) -> Result<Decoded, Error> {
+        // The decoded buffer is fetched afterwards with `last_decoded`, so
+        // that the decoder can be rebuilt and the packet retried.
+        let decoded = match self.decoder.decode(packet).map(|_| ()) {
+            Err(SymphoniaError::ResetRequired) => {
+                self.rebuild_decoder()?;
+                self.reset("the decoder asked to be reset");
+                self.decoder.decode(packet).map(|_| ())
+            }
+            // MPEG audio decoders refuse frames whose spec differs from the
+            // first they decoded, so one that changes needs a new decoder.
+            Err(e @ SymphoniaError::DecodeError(_)) => match self.mpeg_spec_change(packet) {
+                Some(change) => {
+                    self.rebuild_decoder()?;
+                    self.reset(&change);
+                    self.decoder.decode(packet).map(|_| ())
+                }
+                None => Err(e),
+            },
+            decoded => decoded,
+        };
+        if let Err(source) = decoded {
+            // A packet that seems to use an unsupported feature once others
+            // have decoded is more likely corrupt.
+            let corrupt = match source {
+                SymphoniaError::DecodeError(_) | SymphoniaError::IoError(_) => true,
+                SymphoniaError::Unsupported(_) => self.spec.is_some(),
+                _ => false,
+            };
+            let error = Error::Decode {
+                packet: self.packets - 1,
+                ts: packet.ts(),
+                seconds: self.seconds,
+                source,
+            };
+            if self.mode == ErrorMode::Strict || !corrupt {
+                return Err(error);
+            }
+            self.skipped += 1;
+            // Without a spec there's nothing to base the silence on, so skip
+            // the packet altogether.
+            let Some(spec) = self.spec else {
+                warn!("Skipping packet: {error}");
+                return Ok(Decoded::Nothing);
+            };
+            let frames = self.packet_frames(packet, spec.rate);
+            warn!("Replacing packet with {frames} frames of silence: {error}");
+            self.silence.clear();
+            self.silence.resize(frames * spec.channels.count(), 0.0);
+            self.frames += frames as u64;
+            self.seconds += seconds(frames, spec.rate);
+            return Ok(Decoded::Silence(spec));
+        }
+        let audio_buf = self.decoder.last_decoded();
+
+        // Copy the decoded buffer into the interleaved sample buffer, which is
+        // (re)created whenever the spec changes or a packet decodes to more
+        // frames than it can hold.
+        let buf_spec = *audio_buf.spec();
+        let needed = audio_buf.capacity();
+        let fits = self.sample_buf.as_ref().is_some_and(|buf| {
+            self.spec == Some(buf_spec) && buf.capacity() >= needed * buf_spec.channels.count()
+        });
+        if !fits {
+            self.spec = Some(buf_spec);
+            self.sample_buf = Some(SampleBuffer::<f32>::new(needed as u64, buf_spec));
+        }
+        let Some(buf) = self.sample_buf.as_mut() else {
+            unreachable!("sample buffer was just created");
+        };
+        buf.copy_interleaved_ref(audio_buf);
+        let frames = buf.len() / buf_spec.channels.count();
+        self.last_frames = frames;
+        self.frames += frames as u64;
+        self.seconds += seconds(frames, buf_spec.rate);
+        debug!("Decoded {} frames", self.frames);
+        Ok(Decoded::Audio(buf_spec))
+    }
+
+    /// The number of frames at `rate` that `packet` would have decoded to,
+    /// going by its duration if that's known, otherwise by the last packet.
+    fn packet_frames(&self, packet: &Packet, rate: u32) -> usize {
+        match self.time_base() {
+            Some(time_base) if packet.dur() > 0 => {
+                let time = time_base.calc_time(packet.dur());
+                #[allow(
+                    clippy::cast_precision_loss,
+                    clippy::cast_possible_truncation,
+                    clippy::cast_sign_loss
+                )]
+                let frames = ((time.seconds as f64 + time.frac) * f64::from(rate)).round() as usize;
+                frames
+            }
+            _ => self.last_frames,
+        }
+    }
+
+    /// The track being decoded.
+    fn track(&self) -> Option<&Track> {
+        self.format.tracks().iter().find(|t| t.id == self.track_id)
+    }
+
+    /// The time base of the track's timestamps and durations.
+    fn time_base(&self) -> Option<TimeBase> {
+        let params = &self.track()?.codec_params;
+        params
+            .time_base
+            .or_else(|| params.sample_rate.map(|r| TimeBase::new(1, r)))
+    }
+
+    /// Replaces the decoder with a new one for the current track.
+    fn rebuild_decoder(&mut self) -> Result<(), Error> {
+        let track = self
+            .format
+            .tracks()
+            .iter()
+            .find(|t| t.id == self.track_id)
+            .ok_or(Error::NoAudioTrack)?;
+        self.decoder = make_decoder(track)?;
+        Ok(())
+    }
+
+    /// Notes that decoding restarted at the current position because of
+    /// `reason`.
+    fn reset(&mut self, reason: &str) {
+        self.resets += 1;
+        info!(
+            "Restarted decoding at {:.3}s (packet {}), as {reason}",
+            self.seconds, self.packets
+        );
+    }
+
+    /// Describes how the spec of the MPEG audio frame in `packet` differs
+    /// from that of the audio decoded so far, if it does.
+    fn mpeg_spec_change(&self, packet: &Packet) -> Option<String> {
+        let codec = self.decoder.codec_params().codec;
+        if ![CODEC_TYPE_MP1, CODEC_TYPE_MP2, CODEC_TYPE_MP3].contains(&codec) {
+            return None;
+        }
+        let spec = self.spec?;
+        let header = sniff::MpegHeader::parse(packet.buf())?;
+        let channels = spec.channels.count();
+        (header.sample_rate != spec.rate || header.channels != channels).then(|| {
+            format!(
+                "the stream changed from {}Hz/{channels}ch to {}Hz/{}ch",
+                spec.rate, header.sample_rate, header.channels
+            )
+        })
+    }
+}
+
+/// Selects the track of `format` to decode: the default track, unless it's a
+/// data track (such as the chapter track of an m4b) with no codec, in which
+/// case the first track with a codec.
+fn select_track(format: &dyn FormatReader) -> Result<&Track, Error> {
+    format
+        .default_track()
+        .filter(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .or_else(|| {
+            format
+                .tracks()
+                .iter()
+                .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        })
+        .ok_or(Error::NoAudioTrack)
+}
+
+/// Makes a decoder for `track`.
+fn make_decoder(track: &Track) -> Result<Box<dyn Decoder>, Error> {
+    let codecs = symphonia::default::get_codecs();
+    codecs
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(|_| {
+            let codec = track.codec_params.codec;
+            Error::UnsupportedCodec(
+                codecs
+                    .get_codec(codec)
+                    .map_or_else(|| codec.to_string(), |d| d.short_name.to_owned()),
+            )
+        })
+}
+
+/// The duration of `frames` frames at `rate`, in seconds.
+#[allow(clippy::cast_precision_loss)]
+fn seconds(frames: usize, rate: u32) -> f64 {
+    frames as f64 / f64::from(rate)
+}
@@ -0,0 +1,171 @@
+//! A reader for raw ADTS AAC streams.
+//!
+//! Symphonia's own ADTS reader only syncs to MPEG-4 headers without a CRC
+//! (`FF F1`), so streams written with the MPEG-2 id or with CRCs can't be
+//! read with it. This one accepts any ADTS header, and resyncs past junk
+//! between frames.
+//!
+//! ref
+//!   ISO/IEC 14496-3 1.A.2 (Audio Data Transport Stream)
+use symphonia::core::{
+    codecs::{CodecParameters, CODEC_TYPE_AAC},
+    errors::{Error, Result, SeekErrorKind},
+    formats::{Cue, FormatOptions, FormatReader, Packet, SeekMode, SeekTo, SeekedTo, Track},
+    io::{MediaSourceStream, ReadBytes, SeekBuffered},
+    meta::{Metadata, MetadataLog},
+    units::TimeBase,
+};
+
+/// Length of a header without a CRC.
+pub(super) const HEADER_LEN: usize = 7;
+/// Frames of audio in each AAC raw data block.
+const FRAMES_PER_BLOCK: u64 = 1024;
+const SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+/// The fields of an ADTS frame header that are needed to read the stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Header {
+    /// Audio object type minus one.
+    pub profile: u8,
+    pub sample_rate_index: u8,
+    pub channel_config: u8,
+    /// Length of the header, which is longer when it has a CRC.
+    pub len: usize,
+    /// Length of the frame, including the header.
+    pub frame_len: usize,
+}
+
+impl Header {
+    /// Parses the header at the start of `bytes`.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let b: &[u8; HEADER_LEN] = bytes.first_chunk()?;
+        // 12 sync bits, then the id, and a layer that is always 0.
+        if b[0] != 0xff || b[1] & 0xf6 != 0xf0 {
+            return None;
+        }
+        let protection_absent = b[1] & 1 == 1;
+        let header = Self {
+            profile: b[2] >> 6,
+            sample_rate_index: (b[2] >> 2) & 0x0f,
+            channel_config: ((b[2] & 1) << 2) | (b[3] >> 6),
+            len: if protection_absent { 7 } else { 9 },
+            frame_len: (usize::from(b[3] & 3) << 11)
+                | (usize::from(b[4]) << 3)
+                | usize::from(b[5] >> 5),
+        };
+        let raw_blocks = b[6] & 3;
+        (usize::from(header.sample_rate_index) < SAMPLE_RATES.len()
+            && header.frame_len > header.len
+            && raw_blocks == 0)
+            .then_some(header)
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        SAMPLE_RATES[usize::from(self.sample_rate_index)]
+    }
+
+    /// Whether `other` could be a later frame of the same stream.
+    pub fn same_stream(&self, other: &Self) -> bool {
+        self.profile == other.profile
+            && self.sample_rate_index == other.sample_rate_index
+            && self.channel_config == other.channel_config
+    }
+
+    /// The `AudioSpecificConfig` describing the stream.
+    fn audio_specific_config(&self) -> [u8; 2] {
+        let object_type = self.profile + 1;
+        [
+            (object_type << 3) | (self.sample_rate_index >> 1),
+            ((self.sample_rate_index & 1) << 7) | (self.channel_config << 3),
+        ]
+    }
+}
+
+/// Reads the AAC frames of an ADTS stream as packets of a single track.
+pub struct AdtsReader {
+    reader: MediaSourceStream,
+    tracks: Vec<Track>,
+    cues: Vec<Cue>,
+    metadata: MetadataLog,
+    first: Header,
+    next_ts: u64,
+}
+
+impl AdtsReader {
+    /// Reads the next header that belongs to the stream, skipping any junk
+    /// before it.
+    fn read_header(&mut self) -> Result<Header> {
+        loop {
+            let mut bytes = [0; HEADER_LEN];
+            self.reader.read_buf_exact(&mut bytes)?;
+            match Header::parse(&bytes) {
+                Some(header) if header.same_stream(&self.first) => {
+                    self.reader.ignore_bytes((header.len - HEADER_LEN) as u64)?;
+                    return Ok(header);
+                }
+                // Try again from the next byte.
+                _ => self.reader.seek_buffered_rev(HEADER_LEN - 1),
+            }
+        }
+    }
+}
+
+impl FormatReader for AdtsReader {
+    fn try_new(mut source: MediaSourceStream, _options: &FormatOptions) -> Result<Self> {
+        let mut bytes = [0; HEADER_LEN];
+        source.read_buf_exact(&mut bytes)?;
+        let Some(first) = Header::parse(&bytes) else {
+            return Err(Error::Unsupported("adts: no frame header"));
+        };
+        source.seek_buffered_rev(HEADER_LEN);
+
+        let mut params = CodecParameters::new();
+        params
+            .for_codec(CODEC_TYPE_AAC)
+            .with_sample_rate(first.sample_rate())
+            .with_time_base(TimeBase::new(1, first.sample_rate()))
+            .with_max_frames_per_packet(FRAMES_PER_BLOCK)
+            .with_extra_data(Box::new(first.audio_specific_config()));
+
+        Ok(Self {
+            reader: source,
+            tracks: vec![Track::new(0, params)],
+            cues: Vec::new(),
+            metadata: MetadataLog::default(),
+            first,
+            next_ts: 0,
+        })
+    }
+
+    fn cues(&self) -> &[Cue] {
+        &self.cues
+    }
+
+    fn metadata(&mut self) -> Metadata<'_> {
+        self.metadata.metadata()
+    }
+
+    fn seek(&mut self, _mode: SeekMode, _to: SeekTo) -> Result<SeekedTo> {
+        Err(Error::SeekError(SeekErrorKind::Unseekable))
+    }
+
+    fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    fn next_packet(&mut self) -> Result<Packet> {
+        let header = self.read_header()?;
+        let data = self
+            .reader
+            .read_boxed_slice_exact(header.frame_len - header.len)?;
+        let ts = self.next_ts;
+        self.next_ts += FRAMES_PER_BLOCK;
+        Ok(Packet::new_from_boxed_slice(0, ts, FRAMES_PER_BLOCK, data))
+    }
+
+    fn into_inner(self: Box<Self>) -> MediaSourceStream {
+        self.reader
+    }
+}
@@ -0,0 +1,118 @@
+//! Finding the duration of a file without decoding it, where that can be
+//! done reliably.
+//!
+//! Containers mostly state the length of their streams. Raw MPEG audio
+//! streams don't, but those written by VBR-aware encoders start with a Xing
+//! (or Info) or VBRI header that counts their frames, less the encoder delay
+//! and padding of any LAME tag, which decoding trims; for others, symphonia
+//! estimates the length from the bitrate, which can be far out, so it isn't
+//! used. Failing those, the packets are read through without decoding them,
+//! as each one's duration is usually known; any whose isn't is decoded.
+use serde::Serialize;
+use symphonia::core::codecs::{CODEC_TYPE_MP1, CODEC_TYPE_MP2, CODEC_TYPE_MP3};
+
+use super::{Error, FileDecoder};
+
+/// Where a duration came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DurationSource {
+    /// The length of the stream given by the container.
+    Container,
+    /// The frame count in a Xing or Info header.
+    Xing,
+    /// The frame count in a VBRI header.
+    Vbri,
+    /// The durations of the packets, read without decoding them.
+    PacketScan,
+    /// The packets, some of which had to be decoded to find their durations.
+    Decode,
+}
+
+/// How far a duration can be trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Confidence {
+    /// The duration of the audio that decoding gives, to the frame.
+    Exact,
+    /// Given by a header that may be out by a frame or two of audio, as
+    /// encoders that don't tag themselves differ in what they count.
+    Approximate,
+}
+
+/// The duration of a file, with where it came from.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ProbedDuration {
+    /// The duration in seconds.
+    pub seconds: f64,
+    pub source: DurationSource,
+    pub confidence: Confidence,
+}
+
+impl FileDecoder {
+    /// Finds the duration of the track, as cheaply as it can be found
+    /// reliably: from the container or a Xing or VBRI header if there is one,
+    /// otherwise by reading the packets. This reads through the file, so it
+    /// takes the decoder.
+    ///
+    /// # Errors
+    /// Returns an error if the packets have to be read and one can't be (or
+    /// has to be decoded and, in [`ErrorMode::Strict`](super::ErrorMode),
+    /// can't be).
+    pub fn into_duration(mut self) -> Result<ProbedDuration, Error> {
+        if let Some(vbr) = self.vbr {
+            let rate = self.sample_rate().unwrap_or(1);
+            // Set by the reader from a LAME tag, if the stream has one.
+            let trimmed = self.track().map_or(0, |track| {
+                let params = &track.codec_params;
+                u64::from(params.delay.unwrap_or(0)) + u64::from(params.padding.unwrap_or(0))
+            });
+            #[allow(clippy::cast_precision_loss)]
+            let seconds = vbr.frames.saturating_sub(trimmed) as f64 / f64::from(rate);
+            return Ok(ProbedDuration {
+                seconds,
+                source: if vbr.vbri {
+                    DurationSource::Vbri
+                } else {
+                    DurationSource::Xing
+                },
+                confidence: if vbr.encoder_tag {
+                    Confidence::Exact
+                } else {
+                    Confidence::Approximate
+                },
+            });
+        }
+        let codec = self.decoder.codec_params().codec;
+        let mpeg = [CODEC_TYPE_MP1, CODEC_TYPE_MP2, CODEC_TYPE_MP3].contains(&codec);
+        if let Some(seconds) = self.duration().filter(|_| !mpeg) {
+            return Ok(ProbedDuration {
+                seconds,
+                source: DurationSource::Container,
+                confidence: Confidence::Exact,
+            });
+        }
+
+        let mut source = DurationSource::PacketScan;
+        while let Some(packet) = self.next_packet()? {
+            match self.time_base() {
+                Some(time_base) if packet.dur() > 0 => {
+                    let time = time_base.calc_time(packet.dur());
+                    #[allow(clippy::cast_precision_loss)]
+                    let seconds = time.seconds as f64 + time.frac;
+                    self.seconds += seconds;
+                }
+                _ => {
+                    // Decoding counts the packet's audio.
+                    source = DurationSource::Decode;
+                    self.decode(&packet)?;
+                }
+            }
+        }
+        Ok(ProbedDuration {
+            seconds: self.seconds,
+            source,
+            confidence: Confidence::Exact,
+        })
+    }
+}
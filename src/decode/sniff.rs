@@ -0,0 +1,331 @@
+//! Identifies the format of a file from its first bytes.
+//!
+//! Symphonia's probe takes the first registered marker it finds anywhere in
+//! the first megabyte, so a stray frame sync in the junk in front of a
+//! headerless MP3 derails it, and a file that isn't audio at all is scanned
+//! to the limit before it gives up. Here containers are only recognized by
+//! the magic number at the start of the file (after any `ID3v2` tags), and
+//! frame-based streams only where several frame headers chain together.
+//!
+//! The first frame of an MPEG audio stream is also checked for a Xing (or
+//! Info) or VBRI header, which gives the number of frames in the stream.
+//!
+//! ref
+//!   <http://www.mp3-tech.org/programmer/frame_header.html>
+//!   <http://gabriel.mp3-tech.org/mp3infotag.html>
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+};
+
+use super::adts;
+
+/// Bytes searched for the start of the audio.
+const SNIFF_LEN: usize = 64 * 1024;
+/// Consecutive frame headers needed before a frame sync is believed.
+const MIN_FRAMES: usize = 3;
+const ID3V2_HEADER_LEN: usize = 10;
+
+/// Bitrates in kbit/s, by bitrate index, for MPEG-1 layers I, II and III.
+const MPEG1_BITRATES: [[u32; 15]; 3] = [
+    [
+        0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+    ],
+    [
+        0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+    ],
+    [
+        0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ],
+];
+/// Bitrates in kbit/s, by bitrate index, for MPEG-2 and 2.5 layer I, and
+/// layers II and III.
+const MPEG2_BITRATES: [[u32; 15]; 2] = [
+    [
+        0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
+    ],
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+];
+const MPEG1_SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+/// What the first bytes of a file look like.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Kind {
+    /// A container with the given short name, recognized by its magic number.
+    Container(&'static str),
+    /// A raw ADTS AAC stream.
+    Adts,
+    /// An MPEG audio stream, with the short name of its layer.
+    Mpeg(&'static str),
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Sniffed {
+    pub kind: Kind,
+    /// Length of the `ID3v2` tags at the start of the file.
+    pub tags_len: u64,
+    /// Where the audio (or the container) starts.
+    pub offset: u64,
+    /// The header of an MPEG audio stream that counts its frames.
+    pub vbr: Option<VbrHeader>,
+}
+
+/// A header in the first frame of an MPEG audio stream, written by the
+/// encoder, that gives the length of the stream. The frame itself holds no
+/// audio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct VbrHeader {
+    /// Whether it's a VBRI header (written by Fraunhofer encoders) rather
+    /// than a Xing or Info header.
+    pub vbri: bool,
+    /// Frames of audio in the stream, not counting the header's frame.
+    pub frames: u64,
+    /// Whether a Xing header has an encoder's extension (such as LAME's)
+    /// after it, which means it was written after encoding the whole stream.
+    pub encoder_tag: bool,
+}
+
+/// Sniffs the format of `file`, leaving it positioned at its start.
+pub(super) fn sniff(file: &mut File) -> io::Result<Sniffed> {
+    let tags_len = id3v2_len(file)?;
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    file.seek(SeekFrom::Start(tags_len))?;
+    file.take(SNIFF_LEN as u64).read_to_end(&mut head)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    let at_eof = head.len() < SNIFF_LEN;
+    let (kind, skipped) = if let Some(name) = container(&head) {
+        (Kind::Container(name), 0)
+    } else {
+        frames(&head, at_eof).unwrap_or((Kind::Unknown, 0))
+    };
+    let vbr = match kind {
+        Kind::Mpeg(_) => vbr_header(&head[skipped..]),
+        _ => None,
+    };
+    Ok(Sniffed {
+        kind,
+        tags_len,
+        offset: tags_len + skipped as u64,
+        vbr,
+    })
+}
+
+/// The total length of the (possibly several) `ID3v2` tags at the start of
+/// `file`.
+fn id3v2_len(file: &mut File) -> io::Result<u64> {
+    let mut len = 0;
+    loop {
+        let mut header = [0; ID3V2_HEADER_LEN];
+        file.seek(SeekFrom::Start(len))?;
+        if file.read_exact(&mut header).is_err() || &header[..3] != b"ID3" {
+            return Ok(len);
+        }
+        // The size is "syncsafe", seven bits to a byte, and excludes the
+        // header and any footer.
+        let size = header[6..]
+            .iter()
+            .fold(0, |size, &b| (size << 7) | u64::from(b & 0x7f));
+        let footer = if header[5] & 0x10 == 0 { 0 } else { 10 };
+        len += ID3V2_HEADER_LEN as u64 + size + footer;
+    }
+}
+
+fn container(head: &[u8]) -> Option<&'static str> {
+    let at = |offset: usize, magic: &[u8]| head.get(offset..offset + magic.len()) == Some(magic);
+    Some(if at(0, b"RIFF") && at(8, b"WAVE") {
+        "wav"
+    } else if at(0, b"fLaC") {
+        "flac"
+    } else if at(0, b"OggS") {
+        "ogg"
+    } else if at(4, b"ftyp") {
+        "isomp4"
+    } else if at(0, &[0x1a, 0x45, 0xdf, 0xa3]) {
+        "mkv"
+    } else {
+        return None;
+    })
+}
+
+/// Finds the first run of chained ADTS or MPEG audio frames in `head`,
+/// returning its kind and offset.
+fn frames(head: &[u8], at_eof: bool) -> Option<(Kind, usize)> {
+    (0..head.len().saturating_sub(adts::HEADER_LEN)).find_map(|i| {
+        if head[i] != 0xff {
+            return None;
+        }
+        if let Some(first) = adts::Header::parse(&head[i..]) {
+            let chained = chain(head, i, at_eof, |bytes| {
+                let header = adts::Header::parse(bytes)?;
+                header.same_stream(&first).then_some(header.frame_len)
+            });
+            return chained.then_some((Kind::Adts, i));
+        }
+        let first = MpegHeader::parse(&head[i..])?;
+        let chained = chain(head, i, at_eof, |bytes| {
+            let header = MpegHeader::parse(bytes)?;
+            header.same_stream(&first).then_some(header.frame_len)
+        });
+        chained.then_some((Kind::Mpeg(first.name()), i))
+    })
+}
+
+/// Whether frames whose lengths are given by `frame_len` follow each other
+/// from `start`, either `MIN_FRAMES` of them or up to the end of the file.
+fn chain(
+    head: &[u8],
+    start: usize,
+    at_eof: bool,
+    frame_len: impl Fn(&[u8]) -> Option<usize>,
+) -> bool {
+    let mut pos = start;
+    for _ in 0..MIN_FRAMES {
+        if at_eof && pos == head.len() {
+            return true;
+        }
+        match head.get(pos..).and_then(&frame_len) {
+            Some(len) => pos += len,
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Reads the Xing, Info or VBRI header in the MPEG audio `frame`, if it has
+/// one.
+fn vbr_header(frame: &[u8]) -> Option<VbrHeader> {
+    let header = MpegHeader::parse(frame)?;
+    let frame = frame.get(..header.frame_len)?;
+    let be32 = |offset: usize| {
+        let bytes = frame.get(offset..offset + 4)?;
+        Some(u32::from_be_bytes(bytes.try_into().ok()?))
+    };
+
+    // A Xing header follows the side information.
+    let crc = if frame[1] & 1 == 0 { 2 } else { 0 };
+    let side_info = match (header.version, header.channels) {
+        (1, 1) => 17,
+        (1, _) => 32,
+        (_, 1) => 9,
+        _ => 17,
+    };
+    let xing = 4 + crc + side_info;
+    if matches!(frame.get(xing..xing + 4), Some(b"Xing" | b"Info")) {
+        let flags = be32(xing + 4)?;
+        if flags & 1 == 0 {
+            return None;
+        }
+        // The frame count, byte count, table of contents and quality fields
+        // are each present if their flag is set.
+        let extension = xing
+            + 8
+            + [(1, 4), (2, 4), (4, 100), (8, 4)]
+                .iter()
+                .filter(|&&(flag, _)| flags & flag != 0)
+                .map(|&(_, len)| len)
+                .sum::<usize>();
+        let encoder_tag = frame
+            .get(extension..extension + 4)
+            .is_some_and(|tag| tag == b"LAME" || tag.starts_with(b"Lav"));
+        return Some(VbrHeader {
+            vbri: false,
+            frames: u64::from(be32(xing + 8)?) * header.samples_per_frame(),
+            encoder_tag,
+        });
+    }
+
+    // A VBRI header is always 32 bytes after the frame header.
+    if frame.get(36..40) == Some(b"VBRI") {
+        return Some(VbrHeader {
+            vbri: true,
+            frames: u64::from(be32(36 + 14)?) * header.samples_per_frame(),
+            encoder_tag: false,
+        });
+    }
+    None
+}
+
+/// The fields of an MPEG audio frame header that are needed to find the next
+/// frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct MpegHeader {
+    /// 1 for MPEG-1, 2 for MPEG-2 and MPEG-2.5.
+    version: u8,
+    layer: u8,
+    pub sample_rate: u32,
+    pub channels: usize,
+    frame_len: usize,
+}
+
+impl MpegHeader {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let b: &[u8; 4] = bytes.first_chunk()?;
+        if b[0] != 0xff || b[1] & 0xe0 != 0xe0 {
+            return None;
+        }
+        let (version, rate_divisor) = match (b[1] >> 3) & 3 {
+            0 => (2, 4),
+            2 => (2, 2),
+            3 => (1, 1),
+            _ => return None,
+        };
+        let layer = match (b[1] >> 1) & 3 {
+            1 => 3,
+            2 => 2,
+            3 => 1,
+            _ => return None,
+        };
+        let bitrate_index = usize::from(b[2] >> 4);
+        let rate_index = usize::from((b[2] >> 2) & 3);
+        // Free format streams (bitrate index 0) can't be chained.
+        if bitrate_index == 0 || bitrate_index == 15 || rate_index == 3 {
+            return None;
+        }
+        let bitrate = 1000
+            * if version == 1 {
+                MPEG1_BITRATES[usize::from(layer - 1)][bitrate_index]
+            } else {
+                MPEG2_BITRATES[usize::from(layer.min(2) - 1)][bitrate_index]
+            };
+        let sample_rate = MPEG1_SAMPLE_RATES[rate_index] / rate_divisor;
+        let padding = u32::from((b[2] >> 1) & 1);
+        let frame_len = match (layer, version) {
+            (1, _) => (12 * bitrate / sample_rate + padding) * 4,
+            (3, 2) => 72 * bitrate / sample_rate + padding,
+            _ => 144 * bitrate / sample_rate + padding,
+        };
+        Some(Self {
+            version,
+            layer,
+            sample_rate,
+            // Every channel mode but mono (3) has two channels.
+            channels: if b[3] >> 6 == 3 { 1 } else { 2 },
+            frame_len: frame_len as usize,
+        })
+    }
+
+    /// Frames of audio in each MPEG frame.
+    fn samples_per_frame(&self) -> u64 {
+        match (self.layer, self.version) {
+            (1, _) => 384,
+            (3, 2) => 576,
+            _ => 1152,
+        }
+    }
+
+    fn same_stream(&self, other: &Self) -> bool {
+        self.version == other.version
+            && self.layer == other.layer
+            && self.sample_rate == other.sample_rate
+    }
+
+    fn name(&self) -> &'static str {
+        match self.layer {
+            1 => "mp1",
+            2 => "mp2",
+            _ => "mp3",
+        }
+    }
+}
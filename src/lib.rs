@@ -1,3 +1,16 @@
 #![warn(clippy::pedantic)]
 
+pub mod aac;
+pub mod cancel;
+pub mod channels;
+pub mod cover;
+pub mod cue;
+pub mod decode;
+pub mod loudness;
+pub mod mp4;
+pub mod ordering;
 pub mod processor;
+pub mod progress;
+pub mod resample;
+pub mod silence;
+pub mod tags;
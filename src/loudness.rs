@@ -0,0 +1,398 @@
+//! Loudness as EBU R128 measures it, so that inputs from different sources
+//! can be brought to the same level.
+//!
+//! Audio is K-weighted (a high shelf for the head, then a high pass) and its
+//! mean square taken over blocks of 400ms, overlapping by 75%. The integrated
+//! loudness is the mean of the blocks above an absolute gate of -70 LUFS and a
+//! relative gate 10 LU below the mean of those, so that silence and quiet
+//! passages don't drag it down. Gating works on the blocks, so measurements
+//! of several inputs combine into the loudness of them all.
+//!
+//! Rather than applying gain, a book can be tagged with it, as `ReplayGain`
+//! and iTunes Sound Check (`iTunNORM`) items, for players to apply.
+//!
+//! The true peak is the highest sample after upsampling by four with a
+//! windowed sinc filter, which catches the peaks between samples that a
+//! reconstruction filter (or a lossy encoder) produces.
+//!
+//! ref
+//!   <https://www.itu.int/rec/R-REC-BS.1770>
+//!   <https://tech.ebu.ch/docs/r/r128.pdf>
+
+// Filter design and block counting convert between sample counts and
+// floating point values, all of which are small enough for these casts.
+#![allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_precision_loss,
+    clippy::cast_sign_loss
+)]
+use std::{f64::consts::PI, fmt::Write};
+
+use serde::Serialize;
+
+/// Blocks that gating works on, in steps of a quarter of a block.
+const BLOCK_STEPS: usize = 4;
+const STEP_SECONDS: f64 = 0.1;
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+/// Phases of the true-peak interpolator, and its taps at each phase.
+const PEAK_PHASES: usize = 4;
+const PEAK_TAPS: usize = 12;
+
+/// How loud some audio is.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS, or negative infinity (`null` when
+    /// serialized) for audio that's silent throughout.
+    pub integrated: f64,
+    /// Highest true peak in dB relative to full scale (dBTP).
+    pub true_peak: f64,
+}
+
+/// How inputs are brought to the same loudness.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NormalizeOptions {
+    /// Integrated loudness to bring the audio to, in LUFS.
+    pub target: f64,
+    /// Highest true peak to allow, in dBTP, which limits the gain of audio
+    /// with peaks that would otherwise go over it.
+    pub true_peak: f64,
+    /// What's brought to the target when the gain is applied. Tags are
+    /// always for the book as a whole.
+    pub scope: NormalizeScope,
+    pub mode: NormalizeMode,
+}
+
+impl Default for NormalizeOptions {
+    fn default() -> Self {
+        Self {
+            target: -18.0,
+            true_peak: -1.0,
+            scope: NormalizeScope::default(),
+            mode: NormalizeMode::default(),
+        }
+    }
+}
+
+impl NormalizeOptions {
+    /// The gain in dB that brings audio as loud as `loudness` to the target,
+    /// or as near as the true-peak ceiling allows. Silence is left alone.
+    #[must_use]
+    pub fn gain(&self, loudness: &Loudness) -> f64 {
+        if !loudness.integrated.is_finite() {
+            return 0.0;
+        }
+        (self.target - loudness.integrated).min(self.true_peak - loudness.true_peak)
+    }
+}
+
+/// What's brought to the target loudness.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NormalizeScope {
+    /// Each input on its own, so each chapter is as loud as the others.
+    #[default]
+    File,
+    /// The book as a whole, with the same gain throughout, which keeps the
+    /// differences between the inputs.
+    Book,
+}
+
+/// Whether normalizing changes the audio, or tags it with the gain.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NormalizeMode {
+    /// The gain is applied to the audio.
+    #[default]
+    Apply,
+    /// The audio is left as it is, and the book tagged with the gain that
+    /// brings it as a whole to the target, for players to apply. The scope
+    /// is ignored, as the book is a single track: the same gain goes in the
+    /// track and album tags. Each chapter's loudness is measured for the
+    /// report, but not tagged.
+    Tags,
+}
+
+/// Tags telling players to apply `gain` in dB to audio of `loudness`: the
+/// `ReplayGain` track and album gain and peak, and the iTunes Sound Check
+/// `iTunNORM`, as freeform item names and values.
+#[must_use]
+pub fn gain_tags(gain: f64, loudness: &Loudness) -> Vec<(&'static str, String)> {
+    let peak = 10f64.powf(loudness.true_peak / 20.0);
+    let replay_gain = format!("{gain:.2} dB");
+    let replay_peak = format!("{peak:.6}");
+    // Sound Check gives the gain as the level, relative to 1/1000W and then
+    // 1/2500W, that it brings the left and right channels to, along with their
+    // peaks as 16-bit samples. The rest is statistics players don't need.
+    let level = |reference: f64| {
+        (reference * 10f64.powf(-gain / 10.0))
+            .round()
+            .min(f64::from(u32::MAX)) as u32
+    };
+    let peak_sample = (peak * 32768.0).round().min(f64::from(u32::MAX)) as u32;
+    let mut sound_check = String::new();
+    for value in [
+        level(1000.0),
+        level(1000.0),
+        level(2500.0),
+        level(2500.0),
+        0,
+        0,
+        peak_sample,
+        peak_sample,
+        0,
+        0,
+    ] {
+        let _ = write!(sound_check, " {value:08X}");
+    }
+    vec![
+        ("REPLAYGAIN_TRACK_GAIN", replay_gain.clone()),
+        ("REPLAYGAIN_TRACK_PEAK", replay_peak.clone()),
+        ("REPLAYGAIN_ALBUM_GAIN", replay_gain),
+        ("REPLAYGAIN_ALBUM_PEAK", replay_peak),
+        ("iTunNORM", sound_check),
+    ]
+}
+
+/// What a [`LoudnessMeter`] measured, which can be combined with other
+/// measurements into the loudness of them all.
+#[derive(Debug, Clone, Default)]
+pub struct Measurement {
+    /// Mean square of each block, weighted.
+    blocks: Vec<f64>,
+    /// Highest true peak, as an amplitude.
+    peak: f64,
+}
+
+impl Measurement {
+    #[must_use]
+    pub fn loudness(&self) -> Loudness {
+        Loudness {
+            integrated: integrated(&self.blocks),
+            true_peak: 20.0 * self.peak.log10(),
+        }
+    }
+
+    /// Adds the audio of `other` to what's measured.
+    pub fn merge(&mut self, other: &Self) {
+        self.blocks.extend(&other.blocks);
+        self.peak = self.peak.max(other.peak);
+    }
+}
+
+/// Measures the loudness of interleaved audio, keeping enough of what it
+/// measured to give the loudness of any part of it.
+pub struct LoudnessMeter {
+    channels: usize,
+    /// K-weighting filters for each channel.
+    filters: Vec<[Biquad; 2]>,
+    peaks: TruePeak,
+    step_frames: usize,
+    /// The step so far, its length in frames, and the steps before it.
+    step: Step,
+    step_len: usize,
+    steps: Vec<Step>,
+}
+
+/// A quarter of a block of audio.
+#[derive(Debug, Clone, Copy, Default)]
+struct Step {
+    /// Weighted sum of the squares of the samples.
+    energy: f64,
+    peak: f64,
+}
+
+impl LoudnessMeter {
+    /// Creates a meter for audio at `rate` with `channels` channels, each
+    /// weighted the same.
+    #[must_use]
+    pub fn new(rate: u32, channels: usize) -> Self {
+        let rate = f64::from(rate);
+        Self {
+            channels,
+            filters: vec![[Biquad::shelf(rate), Biquad::high_pass(rate)]; channels],
+            peaks: TruePeak::new(channels),
+            step_frames: (rate * STEP_SECONDS).round() as usize,
+            step: Step::default(),
+            step_len: 0,
+            steps: Vec::new(),
+        }
+    }
+
+    /// Adds interleaved `samples` to the measurement.
+    pub fn add(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.channels) {
+            for (channel, (sample, [shelf, high_pass])) in
+                frame.iter().zip(&mut self.filters).enumerate()
+            {
+                let weighted = high_pass.process(shelf.process(f64::from(*sample)));
+                self.step.energy += weighted * weighted;
+                self.step.peak = self.step.peak.max(self.peaks.add(channel, *sample));
+            }
+            self.step_len += 1;
+            if self.step_len == self.step_frames {
+                self.steps.push(std::mem::take(&mut self.step));
+                self.step_len = 0;
+            }
+        }
+    }
+
+    /// The measurement of the audio added from frame `start` up to frame
+    /// `end`, to the nearest step of 100ms within them.
+    #[must_use]
+    pub fn measure(&self, start: u64, end: u64) -> Measurement {
+        let step_frames = self.step_frames as u64;
+        let first = start.div_ceil(step_frames) as usize;
+        let last = ((end / step_frames) as usize).min(self.steps.len());
+        let steps = self.steps.get(first..last).unwrap_or_default();
+        Measurement {
+            blocks: steps
+                .windows(BLOCK_STEPS)
+                .map(|block| {
+                    let energy: f64 = block.iter().map(|step| step.energy).sum();
+                    energy / (BLOCK_STEPS * self.step_frames) as f64
+                })
+                .collect(),
+            peak: steps.iter().map(|step| step.peak).fold(0.0, f64::max),
+        }
+    }
+
+    /// The measurement of all the audio added. A block left incomplete at the
+    /// end isn't counted, other than for its peak.
+    #[must_use]
+    pub fn finish(self) -> Measurement {
+        let mut measurement = self.measure(0, u64::MAX);
+        measurement.peak = measurement.peak.max(self.step.peak);
+        measurement
+    }
+}
+
+/// Integrated loudness of blocks with the weighted mean squares `blocks`.
+fn integrated(blocks: &[f64]) -> f64 {
+    let loudness = |mean_square: f64| -0.691 + 10.0 * mean_square.log10();
+    let mean = |blocks: &[f64]| blocks.iter().sum::<f64>() / blocks.len() as f64;
+    let audible: Vec<f64> = blocks
+        .iter()
+        .copied()
+        .filter(|&block| loudness(block) > ABSOLUTE_GATE)
+        .collect();
+    if audible.is_empty() {
+        return f64::NEG_INFINITY;
+    }
+    let gate = loudness(mean(&audible)) + RELATIVE_GATE;
+    let gated: Vec<f64> = audible
+        .into_iter()
+        .filter(|&block| loudness(block) > gate)
+        .collect();
+    loudness(mean(&gated))
+}
+
+/// A second-order filter section.
+#[derive(Debug, Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    /// The last two inputs and outputs.
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self {
+            b,
+            a,
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    /// The first stage of K-weighting, a high shelf of about +4dB, at `rate`.
+    fn shelf(rate: f64) -> Self {
+        let (f0, gain, q) = (
+            1_681.974_450_955_533,
+            3.999_843_853_973_347,
+            0.707_175_236_955_419_6,
+        );
+        let k = (PI * f0 / rate).tan();
+        let vh = 10f64.powf(gain / 20.0);
+        let vb = vh.powf(0.499_666_774_154_541_6);
+        let a0 = 1.0 + k / q + k * k;
+        Self::new(
+            [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        )
+    }
+
+    /// The second stage of K-weighting, a high pass at about 38Hz, at `rate`.
+    fn high_pass(rate: f64) -> Self {
+        let (f0, q) = (38.135_470_876_024_44, 0.500_327_037_323_877_3);
+        let k = (PI * f0 / rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        Self::new(
+            [1.0, -2.0, 1.0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        )
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// Finds the true peaks of audio, upsampling it by four.
+struct TruePeak {
+    /// The interpolation filter at each phase.
+    phases: [[f64; PEAK_TAPS]; PEAK_PHASES],
+    /// The last samples of each channel, newest first.
+    history: Vec<[f64; PEAK_TAPS]>,
+}
+
+impl TruePeak {
+    fn new(channels: usize) -> Self {
+        // Each phase interpolates a point between the middle two of the
+        // samples it covers, with a Hann-windowed sinc normalized to unity
+        // gain.
+        let delay = (PEAK_TAPS - 1) as f64 / 2.0;
+        let half_width = PEAK_TAPS as f64 / 2.0 + 0.5;
+        let mut phases = [[0.0; PEAK_TAPS]; PEAK_PHASES];
+        for (phase, taps) in phases.iter_mut().enumerate() {
+            for (tap, coefficient) in taps.iter_mut().enumerate() {
+                let t = tap as f64 - delay + phase as f64 / PEAK_PHASES as f64;
+                let sinc = if t == 0.0 {
+                    1.0
+                } else {
+                    (PI * t).sin() / (PI * t)
+                };
+                *coefficient = sinc * 0.5 * (1.0 + (PI * t / half_width).cos());
+            }
+            let sum: f64 = taps.iter().sum();
+            for coefficient in taps.iter_mut() {
+                *coefficient /= sum;
+            }
+        }
+        Self {
+            phases,
+            history: vec![[0.0; PEAK_TAPS]; channels],
+        }
+    }
+
+    /// Adds the next `sample` of `channel`, returning the highest amplitude
+    /// of it and the points interpolated just before it.
+    fn add(&mut self, channel: usize, sample: f32) -> f64 {
+        let history = &mut self.history[channel];
+        history.copy_within(..PEAK_TAPS - 1, 1);
+        history[0] = f64::from(sample);
+        self.phases.iter().fold(history[0].abs(), |peak, taps| {
+            let value: f64 = taps.iter().zip(history.iter()).map(|(c, x)| c * x).sum();
+            peak.max(value.abs())
+        })
+    }
+}
@@ -0,0 +1,609 @@
+//! A small, streaming MP4/M4B container writer.
+//!
+//! The layout produced is `ftyp`, `mdat`, `moov`: media data is appended to
+//! the `mdat` box as it arrives and only the (compact) sample tables are kept
+//! in memory. When the writer is finished, the `mdat` size is patched and the
+//! `moov` box describing the audio track (and its chapters and metadata, if
+//! any) is appended.
+//!
+//! ref
+//!   ISO/IEC 14496-12 (ISO base media file format)
+//!   <https://developer.apple.com/library/archive/documentation/QuickTime/QTFF/>
+use std::io::{self, Seek, SeekFrom, Write};
+
+use tracing::debug;
+
+mod chapters;
+mod metadata;
+
+pub use chapters::Chapter;
+pub use metadata::{CoverArt, ImageFormat, Metadata};
+
+/// Timescale used for the movie header and track headers.
+const MOVIE_TIMESCALE: u32 = 1000;
+
+/// Maximum number of packets grouped into a single chunk.
+const PACKETS_PER_CHUNK: u32 = 64;
+
+/// Size of the `mdat` header, which always uses the 64-bit `largesize` form so
+/// that outputs larger than 4GiB do not need the header to be rewritten.
+const MDAT_HEADER_LEN: u64 = 16;
+
+const AUDIO_TRACK_ID: u32 = 1;
+
+/// The encoding of the audio samples stored in the track.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioFormat {
+    /// AAC access units, described by the stream's `AudioSpecificConfig`.
+    Aac { audio_specific_config: [u8; 2] },
+}
+
+/// Describes the single audio track stored in the container.
+#[derive(Debug, Clone, Copy)]
+pub struct AudioTrackConfig {
+    pub format: AudioFormat,
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Frames at the start of the decoded stream that are not part of the
+    /// audio (the encoder delay), hidden from playback with an edit list.
+    pub priming: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Chunk {
+    offset: u64,
+    /// Packets in the chunk, each a sample.
+    packets: u32,
+    len: u64,
+}
+
+/// In-memory sample table for a track; everything needed to emit `stbl`.
+#[derive(Debug)]
+struct SampleTable {
+    /// Size of every sample, `stsz` style.
+    sizes: Vec<u32>,
+    /// Run-length encoded sample durations, `(count, delta)`.
+    durations: Vec<(u32, u32)>,
+    chunks: Vec<Chunk>,
+}
+
+impl SampleTable {
+    fn new() -> Self {
+        Self {
+            sizes: Vec::new(),
+            durations: Vec::new(),
+            chunks: Vec::new(),
+        }
+    }
+
+    fn push_durations(&mut self, count: u32, delta: u32) {
+        match self.durations.last_mut() {
+            Some((n, d)) if *d == delta => *n += count,
+            _ => self.durations.push((count, delta)),
+        }
+    }
+
+    /// Records a packet of `len` bytes starting at `offset` that holds
+    /// `frames` PCM frames.
+    fn push_packet(&mut self, offset: u64, len: u64, frames: u32) {
+        self.sizes.push(u32::try_from(len).unwrap_or(u32::MAX));
+        self.push_durations(1, frames);
+
+        match self.chunks.last_mut() {
+            Some(chunk)
+                if chunk.offset + chunk.len == offset && chunk.packets < PACKETS_PER_CHUNK =>
+            {
+                chunk.packets += 1;
+                chunk.len += len;
+            }
+            _ => self.chunks.push(Chunk {
+                offset,
+                packets: 1,
+                len,
+            }),
+        }
+    }
+
+    fn max_sample_size(&self) -> u32 {
+        self.sizes.iter().copied().max().unwrap_or(0)
+    }
+
+    /// Total duration in media timescale units.
+    fn duration(&self) -> u64 {
+        self.durations
+            .iter()
+            .map(|&(count, delta)| u64::from(count) * u64::from(delta))
+            .sum()
+    }
+
+    /// Writes the `stts`, `stsc`, `stsz` and `stco` (or `co64`) boxes.
+    #[allow(clippy::similar_names)]
+    fn write(&self, b: &mut BoxBuf) {
+        let stts = b.open_full(b"stts", 0, 0);
+        b.u32(u32::try_from(self.durations.len()).unwrap_or(u32::MAX));
+        for &(count, delta) in &self.durations {
+            b.u32(count);
+            b.u32(delta);
+        }
+        b.close(stts);
+
+        let stsc = b.open_full(b"stsc", 0, 0);
+        let count_pos = b.buf.len();
+        b.u32(0);
+        let mut entries = 0u32;
+        let mut last = None;
+        for (i, chunk) in self.chunks.iter().enumerate() {
+            if last != Some(chunk.packets) {
+                b.u32(u32::try_from(i + 1).unwrap_or(u32::MAX));
+                b.u32(chunk.packets);
+                b.u32(1); // sample description index
+                entries += 1;
+                last = Some(chunk.packets);
+            }
+        }
+        b.buf[count_pos..count_pos + 4].copy_from_slice(&entries.to_be_bytes());
+        b.close(stsc);
+
+        let stsz = b.open_full(b"stsz", 0, 0);
+        b.u32(0);
+        b.u32(u32::try_from(self.sizes.len()).unwrap_or(u32::MAX));
+        for &size in &self.sizes {
+            b.u32(size);
+        }
+        b.close(stsz);
+
+        let chunk_count = u32::try_from(self.chunks.len()).unwrap_or(u32::MAX);
+        if self
+            .chunks
+            .last()
+            .is_some_and(|c| u32::try_from(c.offset).is_err())
+        {
+            let co64 = b.open_full(b"co64", 0, 0);
+            b.u32(chunk_count);
+            for chunk in &self.chunks {
+                b.u64(chunk.offset);
+            }
+            b.close(co64);
+        } else {
+            let stco = b.open_full(b"stco", 0, 0);
+            b.u32(chunk_count);
+            for chunk in &self.chunks {
+                b.u32(u32::try_from(chunk.offset).unwrap_or(u32::MAX));
+            }
+            b.close(stco);
+        }
+    }
+}
+
+/// Builds nested boxes into a byte buffer, patching in sizes once a box is
+/// closed.
+#[derive(Default)]
+struct BoxBuf {
+    buf: Vec<u8>,
+}
+
+impl BoxBuf {
+    fn open(&mut self, fourcc: &[u8]) -> usize {
+        debug_assert_eq!(fourcc.len(), 4);
+        let start = self.buf.len();
+        self.u32(0);
+        self.buf.extend_from_slice(fourcc);
+        start
+    }
+
+    fn open_full(&mut self, fourcc: &[u8], version: u8, flags: u32) -> usize {
+        let start = self.open(fourcc);
+        self.u32((u32::from(version) << 24) | (flags & 0x00ff_ffff));
+        start
+    }
+
+    fn close(&mut self, start: usize) {
+        let len = u32::try_from(self.buf.len() - start).expect("box larger than 4GiB");
+        self.buf[start..start + 4].copy_from_slice(&len.to_be_bytes());
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn bytes(&mut self, v: &[u8]) {
+        self.buf.extend_from_slice(v);
+    }
+
+    fn zeros(&mut self, n: usize) {
+        self.buf.resize(self.buf.len() + n, 0);
+    }
+
+    /// The unity transformation matrix used by `mvhd` and `tkhd`.
+    fn matrix(&mut self) {
+        for v in [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000] {
+            self.u32(v);
+        }
+    }
+
+    /// Writes zeroed creation and modification times, using 64-bit fields
+    /// when `version` is 1.
+    fn times(&mut self, version: u8) {
+        if version == 1 {
+            self.u64(0);
+            self.u64(0);
+        } else {
+            self.u32(0);
+            self.u32(0);
+        }
+    }
+
+    fn duration(&mut self, version: u8, duration: u64) {
+        if version == 1 {
+            self.u64(duration);
+        } else {
+            // Callers only select version 0 when the duration fits.
+            self.u32(u32::try_from(duration).unwrap_or(u32::MAX));
+        }
+    }
+
+    /// Writes a track header; `flags` says whether the track is enabled and
+    /// used in the movie, `duration` is in the movie timescale.
+    fn tkhd(&mut self, track_id: u32, flags: u32, duration: u64, volume: u16) {
+        let version = version_for(duration);
+        let tkhd = self.open_full(b"tkhd", version, flags);
+        self.times(version);
+        self.u32(track_id);
+        self.u32(0);
+        self.duration(version, duration);
+        self.zeros(8);
+        self.u16(0); // layer
+        self.u16(0); // alternate group
+        self.u16(volume);
+        self.u16(0);
+        self.matrix();
+        self.u32(0); // width
+        self.u32(0); // height
+        self.close(tkhd);
+    }
+
+    /// Writes a media header with an undetermined language.
+    fn mdhd(&mut self, timescale: u32, duration: u64) {
+        let version = version_for(duration);
+        let mdhd = self.open_full(b"mdhd", version, 0);
+        self.times(version);
+        self.u32(timescale);
+        self.duration(version, duration);
+        self.u16(0x55c4); // language: und
+        self.u16(0);
+        self.close(mdhd);
+    }
+
+    fn hdlr(&mut self, handler: &[u8], name: &str) {
+        let hdlr = self.open_full(b"hdlr", 0, 0);
+        self.u32(0);
+        self.bytes(handler);
+        self.zeros(12);
+        self.bytes(name.as_bytes());
+        self.bytes(&[0]);
+        self.close(hdlr);
+    }
+
+    /// Writes a data information box saying the media is in this file.
+    fn dinf(&mut self) {
+        let dinf = self.open(b"dinf");
+        let dref = self.open_full(b"dref", 0, 0);
+        self.u32(1);
+        // flags: media data is in the same file
+        let url = self.open_full(b"url ", 0, 1);
+        self.close(url);
+        self.close(dref);
+        self.close(dinf);
+    }
+}
+
+fn version_for(duration: u64) -> u8 {
+    u8::from(u32::try_from(duration).is_err())
+}
+
+/// Rescales `value` from timescale `from` to timescale `to`, rounding down.
+fn rescale(value: u64, from: u32, to: u32) -> u64 {
+    let scaled = u128::from(value) * u128::from(to) / u128::from(from);
+    u64::try_from(scaled).unwrap_or(u64::MAX)
+}
+
+/// Streaming writer for a single-audio-track M4B file.
+pub struct Mp4Writer<W: Write + Seek> {
+    out: W,
+    config: AudioTrackConfig,
+    table: SampleTable,
+    mdat_start: u64,
+    pos: u64,
+    padding: u32,
+    chapters: Vec<Chapter>,
+    chapter_table: Option<SampleTable>,
+    metadata: Metadata,
+}
+
+impl<W: Write + Seek> Mp4Writer<W> {
+    /// Starts a new file by writing the `ftyp` box and an open `mdat` header.
+    ///
+    /// # Errors
+    /// Returns any error from the underlying writer.
+    pub fn new(mut out: W, config: AudioTrackConfig) -> io::Result<Self> {
+        let mut ftyp = BoxBuf::default();
+        let start = ftyp.open(b"ftyp");
+        ftyp.bytes(b"M4B ");
+        ftyp.u32(0);
+        for brand in [b"M4B ", b"M4A ", b"mp42", b"isom"] {
+            ftyp.bytes(brand);
+        }
+        ftyp.close(start);
+        out.write_all(&ftyp.buf)?;
+
+        let mdat_start = ftyp.buf.len() as u64;
+        out.write_all(&1u32.to_be_bytes())?;
+        out.write_all(b"mdat")?;
+        out.write_all(&0u64.to_be_bytes())?;
+
+        Ok(Self {
+            out,
+            config,
+            table: SampleTable::new(),
+            mdat_start,
+            pos: mdat_start + MDAT_HEADER_LEN,
+            padding: 0,
+            chapters: Vec::new(),
+            chapter_table: None,
+            metadata: Metadata::default(),
+        })
+    }
+
+    /// The configuration of the audio track being written.
+    pub fn config(&self) -> &AudioTrackConfig {
+        &self.config
+    }
+
+    /// Appends one packet of encoded audio holding `frames` frames.
+    ///
+    /// # Errors
+    /// Returns any error from the underlying writer.
+    pub fn write_packet(&mut self, data: &[u8], frames: u32) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.out.write_all(data)?;
+        self.table.push_packet(self.pos, data.len() as u64, frames);
+        self.pos += data.len() as u64;
+        Ok(())
+    }
+
+    /// Sets the number of frames at the end of the stream that are encoder
+    /// padding and should not be played.
+    pub fn set_padding(&mut self, frames: u32) {
+        self.padding = frames;
+    }
+
+    /// Number of frames that will be presented, excluding priming and padding.
+    pub fn presented_frames(&self) -> u64 {
+        self.table
+            .duration()
+            .saturating_sub(u64::from(self.config.priming) + u64::from(self.padding))
+    }
+
+    /// Writes any chapter titles, patches the `mdat` size and writes the
+    /// `moov` box, returning the underlying writer.
+    ///
+    /// # Errors
+    /// Returns any error from the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_chapter_samples()?;
+
+        let mdat_len = self.pos - self.mdat_start;
+        self.out.seek(SeekFrom::Start(self.mdat_start + 8))?;
+        self.out.write_all(&mdat_len.to_be_bytes())?;
+        self.out.seek(SeekFrom::Start(self.pos))?;
+
+        let moov = self.moov();
+        debug!(
+            "Writing moov box of {} bytes after {} bytes of media data",
+            moov.len(),
+            mdat_len
+        );
+        self.out.write_all(&moov)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn moov(&self) -> Vec<u8> {
+        let media_duration = self.table.duration();
+        let duration = rescale(
+            self.presented_frames(),
+            self.config.sample_rate,
+            MOVIE_TIMESCALE,
+        );
+
+        let mut b = BoxBuf::default();
+        let moov = b.open(b"moov");
+
+        let version = version_for(duration);
+        let mvhd = b.open_full(b"mvhd", version, 0);
+        b.times(version);
+        b.u32(MOVIE_TIMESCALE);
+        b.duration(version, duration);
+        b.u32(0x0001_0000); // rate 1.0
+        b.u16(0x0100); // volume 1.0
+        b.zeros(10);
+        b.matrix();
+        b.zeros(24);
+        let next_track_id = if self.chapter_table.is_some() {
+            chapters::CHAPTER_TRACK_ID + 1
+        } else {
+            AUDIO_TRACK_ID + 1
+        };
+        b.u32(next_track_id);
+        b.close(mvhd);
+
+        self.trak(&mut b, duration, media_duration);
+        if let Some(table) = &self.chapter_table {
+            self.chapter_trak(&mut b, table, duration);
+        }
+
+        if !self.chapters.is_empty() || !self.metadata.is_empty() {
+            let udta = b.open(b"udta");
+            if !self.metadata.is_empty() {
+                self.meta(&mut b);
+            }
+            if !self.chapters.is_empty() {
+                self.chpl(&mut b);
+            }
+            b.close(udta);
+        }
+
+        b.close(moov);
+        b.buf
+    }
+
+    fn trak(&self, b: &mut BoxBuf, duration: u64, media_duration: u64) {
+        let trak = b.open(b"trak");
+
+        // flags: enabled | in movie | in preview
+        b.tkhd(AUDIO_TRACK_ID, 0x7, duration, 0x0100);
+
+        if self.config.priming > 0 || self.padding > 0 {
+            let edts = b.open(b"edts");
+            let media_time = u64::from(self.config.priming);
+            let version = version_for(duration.max(media_time));
+            let elst = b.open_full(b"elst", version, 0);
+            b.u32(1);
+            b.duration(version, duration);
+            b.duration(version, media_time);
+            b.u32(0x0001_0000); // media rate 1.0
+            b.close(elst);
+            b.close(edts);
+        }
+
+        if self.chapter_table.is_some() {
+            chapters::chapter_tref(b);
+        }
+
+        let mdia = b.open(b"mdia");
+        b.mdhd(self.config.sample_rate, media_duration);
+        b.hdlr(b"soun", "SoundHandler");
+
+        let minf = b.open(b"minf");
+        let smhd = b.open_full(b"smhd", 0, 0);
+        b.u16(0); // balance
+        b.u16(0);
+        b.close(smhd);
+
+        b.dinf();
+
+        self.stbl(b);
+
+        b.close(minf);
+        b.close(mdia);
+        b.close(trak);
+    }
+
+    fn stbl(&self, b: &mut BoxBuf) {
+        let stbl = b.open(b"stbl");
+
+        let stsd = b.open_full(b"stsd", 0, 0);
+        b.u32(1);
+        self.sample_entry(b);
+        b.close(stsd);
+
+        self.table.write(b);
+
+        b.close(stbl);
+    }
+
+    fn sample_entry(&self, b: &mut BoxBuf) {
+        let AudioFormat::Aac {
+            audio_specific_config,
+        } = self.config.format;
+        let entry = b.open(b"mp4a");
+        b.zeros(6);
+        b.u16(1); // data reference index
+        b.u16(0); // version
+        b.u16(0); // revision
+        b.u32(0); // vendor
+        b.u16(self.config.channels);
+        b.u16(16); // sample size
+        b.u16(0); // compression id
+        b.u16(0); // packet size
+
+        // A 16.16 fixed point rate; higher rates are left to the decoder
+        // config and the media timescale, rather than written wrongly.
+        b.u32(if self.config.sample_rate <= 0xffff {
+            self.config.sample_rate << 16
+        } else {
+            0
+        });
+        self.esds(b, &audio_specific_config);
+        b.close(entry);
+    }
+
+    /// Writes the MPEG-4 elementary stream descriptor for an AAC track.
+    fn esds(&self, b: &mut BoxBuf, audio_specific_config: &[u8]) {
+        let (max_bitrate, avg_bitrate) = self.bitrates();
+        let esds = b.open_full(b"esds", 0, 0);
+
+        // Descriptors use a one byte tag followed by a (here, single byte)
+        // length; all of these are well under 128 bytes.
+        let dsi_len = audio_specific_config.len();
+        let dcd_len = 13 + 2 + dsi_len;
+        let es_len = 3 + 2 + dcd_len + 3;
+        let descriptor = |b: &mut BoxBuf, tag: u8, len: usize| {
+            b.bytes(&[tag, u8::try_from(len).unwrap_or(u8::MAX)]);
+        };
+
+        descriptor(b, 0x03, es_len); // ES_Descriptor
+        b.u16(1); // ES_ID
+        b.bytes(&[0]); // flags
+
+        descriptor(b, 0x04, dcd_len); // DecoderConfigDescriptor
+        b.bytes(&[0x40]); // objectTypeIndication: MPEG-4 audio
+        b.bytes(&[0x15]); // streamType: audio, upstream 0, reserved 1
+        let buffer_size = self.table.max_sample_size();
+        b.bytes(&buffer_size.to_be_bytes()[1..]);
+        b.u32(max_bitrate);
+        b.u32(avg_bitrate);
+
+        descriptor(b, 0x05, dsi_len); // DecoderSpecificInfo
+        b.bytes(audio_specific_config);
+
+        descriptor(b, 0x06, 1); // SLConfigDescriptor
+        b.bytes(&[0x02]);
+
+        b.close(esds);
+    }
+
+    /// Peak (over one second) and average bitrates of the track.
+    fn bitrates(&self) -> (u32, u32) {
+        let sizes = &self.table.sizes;
+        let frames = self.table.duration();
+        if sizes.is_empty() || frames == 0 {
+            return (0, 0);
+        }
+        let rate = u64::from(self.config.sample_rate);
+        let total: u64 = sizes.iter().map(|&s| u64::from(s)).sum();
+        let avg = total * 8 * rate / frames;
+
+        let per_second = usize::try_from(rate * sizes.len() as u64 / frames)
+            .unwrap_or(1)
+            .max(1);
+        let mut window: u64 = sizes.iter().take(per_second).map(|&s| u64::from(s)).sum();
+        let mut max = window;
+        for i in per_second..sizes.len() {
+            window = window + u64::from(sizes[i]) - u64::from(sizes[i - per_second]);
+            max = max.max(window);
+        }
+        let to_u32 = |v: u64| u32::try_from(v).unwrap_or(u32::MAX);
+        (to_u32(max * 8), to_u32(avg))
+    }
+}
@@ -0,0 +1,189 @@
+//! Chapter markers, written both as a Nero `chpl` box (read by most players
+//! on Windows and Linux) and as a `QuickTime` text track that the audio track
+//! references with `tref/chap` (read by Apple's players).
+use std::io::{self, Seek, Write};
+
+use super::{BoxBuf, Mp4Writer, SampleTable};
+
+/// Track id of the chapter text track; the audio track is always track 1.
+pub(super) const CHAPTER_TRACK_ID: u32 = 2;
+
+/// The Nero `chpl` box counts chapters with a single byte.
+const MAX_NERO_CHAPTERS: usize = 255;
+
+/// Nero chapter start times are in units of 100 nanoseconds.
+const NERO_TIMESCALE: u32 = 10_000_000;
+
+/// A named section of the output audio.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chapter {
+    pub title: String,
+    /// First frame of the chapter, counted from the start of the presented
+    /// audio (that is, excluding any encoder priming).
+    pub start: u64,
+    /// Length of the chapter in frames.
+    pub frames: u64,
+}
+
+impl<W: Write + Seek> Mp4Writer<W> {
+    /// Sets the chapters to write when the file is finished. Chapters must be
+    /// ordered by their start frame.
+    pub fn set_chapters(&mut self, chapters: Vec<Chapter>) {
+        debug_assert!(chapters.windows(2).all(|w| w[0].start <= w[1].start));
+        self.chapters = chapters;
+    }
+
+    /// The chapters that will be written when the file is finished.
+    pub fn chapters(&self) -> &[Chapter] {
+        &self.chapters
+    }
+
+    /// Appends one text sample per chapter to the media data, recording them
+    /// in the chapter track's sample table.
+    ///
+    /// Each sample spans from the start of its chapter to the start of the
+    /// next one, so the track covers the whole of the presented audio even if
+    /// the chapters leave gaps.
+    pub(super) fn write_chapter_samples(&mut self) -> io::Result<()> {
+        if self.chapters.is_empty() {
+            return Ok(());
+        }
+
+        let end = self.presented_frames();
+        let mut table = SampleTable::new();
+        for (i, chapter) in self.chapters.iter().enumerate() {
+            let start = if i == 0 { 0 } else { chapter.start.min(end) };
+            let next = self.chapters.get(i + 1).map_or(end, |c| c.start.min(end));
+            let sample = text_sample(&chapter.title);
+            self.out.write_all(&sample)?;
+
+            // Chapters longer than the 32-bit sample duration are split into
+            // repeated samples.
+            let mut remaining = next.saturating_sub(start);
+            loop {
+                let duration = u32::try_from(remaining).unwrap_or(u32::MAX);
+                table.push_packet(self.pos, sample.len() as u64, duration);
+                remaining -= u64::from(duration);
+                if remaining == 0 {
+                    break;
+                }
+                self.out.write_all(&sample)?;
+                self.pos += sample.len() as u64;
+            }
+            self.pos += sample.len() as u64;
+        }
+        self.chapter_table = Some(table);
+        Ok(())
+    }
+
+    /// Writes the `trak` box for the chapter text track.
+    pub(super) fn chapter_trak(&self, b: &mut BoxBuf, table: &SampleTable, duration: u64) {
+        let trak = b.open(b"trak");
+
+        // flags: in movie; chapter tracks are disabled so they aren't played
+        // as subtitles.
+        b.tkhd(CHAPTER_TRACK_ID, 0x2, duration, 0);
+
+        let mdia = b.open(b"mdia");
+        b.mdhd(self.config.sample_rate, table.duration());
+        b.hdlr(b"text", "ChapterHandler");
+
+        let minf = b.open(b"minf");
+        let gmhd = b.open(b"gmhd");
+        let gmin = b.open_full(b"gmin", 0, 0);
+        b.u16(0x0040); // graphics mode: copy
+        b.zeros(6); // opcolor
+        b.u16(0); // balance
+        b.u16(0);
+        b.close(gmin);
+        let text = b.open(b"text");
+        b.matrix();
+        b.close(text);
+        b.close(gmhd);
+        b.dinf();
+
+        let stbl = b.open(b"stbl");
+        let stsd = b.open_full(b"stsd", 0, 0);
+        b.u32(1);
+        text_sample_entry(b);
+        b.close(stsd);
+        table.write(b);
+        b.close(stbl);
+
+        b.close(minf);
+        b.close(mdia);
+        b.close(trak);
+    }
+
+    /// Writes the Nero `chpl` box. Only the first 255 chapters can be stored.
+    pub(super) fn chpl(&self, b: &mut BoxBuf) {
+        let chpl = b.open_full(b"chpl", 1, 0);
+        b.u32(0);
+        let chapters = &self.chapters[..self.chapters.len().min(MAX_NERO_CHAPTERS)];
+        b.bytes(&[u8::try_from(chapters.len()).unwrap_or(u8::MAX)]);
+        for chapter in chapters {
+            b.u64(super::rescale(
+                chapter.start,
+                self.config.sample_rate,
+                NERO_TIMESCALE,
+            ));
+            let title = truncate(&chapter.title, u8::MAX.into());
+            b.bytes(&[u8::try_from(title.len()).unwrap_or(u8::MAX)]);
+            b.bytes(title.as_bytes());
+        }
+        b.close(chpl);
+    }
+}
+
+/// Writes the `tref` box pointing the audio track at its chapters.
+pub(super) fn chapter_tref(b: &mut BoxBuf) {
+    let tref = b.open(b"tref");
+    let chap = b.open(b"chap");
+    b.u32(CHAPTER_TRACK_ID);
+    b.close(chap);
+    b.close(tref);
+}
+
+/// The `QuickTime` text sample description used for chapter titles.
+fn text_sample_entry(b: &mut BoxBuf) {
+    let entry = b.open(b"text");
+    b.zeros(6);
+    b.u16(1); // data reference index
+    b.u32(0); // display flags
+    b.u32(1); // text justification: centered
+    b.zeros(6); // background color
+    b.zeros(8); // default text box
+    b.zeros(8);
+    b.u16(0); // font number
+    b.u16(0); // font face
+    b.bytes(&[0]);
+    b.u16(0);
+    b.zeros(6); // foreground color
+    b.bytes(&[0]); // font name (empty pascal string)
+    b.close(entry);
+}
+
+/// A text sample holding `title`, marked as UTF-8 with an `encd` box.
+fn text_sample(title: &str) -> Vec<u8> {
+    let title = truncate(title, usize::from(u16::MAX));
+    let mut sample = Vec::with_capacity(2 + title.len() + 12);
+    sample.extend_from_slice(&u16::try_from(title.len()).unwrap_or(u16::MAX).to_be_bytes());
+    sample.extend_from_slice(title.as_bytes());
+    sample.extend_from_slice(&12u32.to_be_bytes());
+    sample.extend_from_slice(b"encd");
+    sample.extend_from_slice(&0x0000_0100u32.to_be_bytes());
+    sample
+}
+
+/// The longest prefix of `s` that is at most `max` bytes and ends on a
+/// character boundary.
+fn truncate(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
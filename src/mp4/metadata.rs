@@ -0,0 +1,149 @@
+//! Book-level metadata, written as iTunes-style `ilst` items in
+//! `moov/udta/meta`.
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{Seek, Write},
+};
+
+use serde::Serialize;
+
+use super::{BoxBuf, Mp4Writer};
+
+/// Well-known type of a `data` box holding UTF-8 text.
+const DATA_TYPE_UTF8: u32 = 1;
+/// Well-known types of `data` boxes holding images.
+const DATA_TYPE_JPEG: u32 = 13;
+const DATA_TYPE_PNG: u32 = 14;
+/// Namespace of the freeform (`----`) items that iTunes and most other
+/// players read.
+const FREEFORM_MEAN: &[u8] = b"com.apple.iTunes";
+
+/// Encoding of a cover image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+}
+
+/// An encoded cover image, written as the `covr` item.
+#[derive(Clone, PartialEq, Eq)]
+pub struct CoverArt {
+    pub format: ImageFormat,
+    pub data: Vec<u8>,
+}
+
+impl fmt::Debug for CoverArt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CoverArt")
+            .field("format", &self.format)
+            .field("len", &self.data.len())
+            .finish()
+    }
+}
+
+/// Descriptive metadata for the whole output file. Serialized without the
+/// cover art.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Metadata {
+    /// Written as both the name and the album.
+    pub title: Option<String>,
+    /// Written as both the artist and the album artist.
+    pub author: Option<String>,
+    /// Written as the composer, which is where audiobook players look for it.
+    pub narrator: Option<String>,
+    pub year: Option<String>,
+    pub genre: Option<String>,
+    pub comment: Option<String>,
+    /// Freeform items in the iTunes namespace, such as `iTunNORM`, by name.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub freeform: BTreeMap<String, String>,
+    #[serde(skip)]
+    pub cover: Option<CoverArt>,
+}
+
+impl Metadata {
+    fn text_items(&self) -> Vec<(&[u8; 4], &str)> {
+        let items: [(&[u8; 4], &Option<String>); 8] = [
+            (b"\xa9nam", &self.title),
+            (b"\xa9alb", &self.title),
+            (b"\xa9ART", &self.author),
+            (b"aART", &self.author),
+            (b"\xa9wrt", &self.narrator),
+            (b"\xa9day", &self.year),
+            (b"\xa9gen", &self.genre),
+            (b"\xa9cmt", &self.comment),
+        ];
+        items
+            .into_iter()
+            .filter_map(|(fourcc, value)| Some((fourcc, value.as_deref()?)))
+            .filter(|(_, value)| !value.is_empty())
+            .collect()
+    }
+
+    /// Whether there is nothing to write.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.text_items().is_empty() && self.freeform.is_empty() && self.cover.is_none()
+    }
+}
+
+impl<W: Write + Seek> Mp4Writer<W> {
+    /// Sets the metadata to write when the file is finished.
+    pub fn set_metadata(&mut self, metadata: Metadata) {
+        self.metadata = metadata;
+    }
+
+    /// Writes the `meta` box holding the `ilst` items.
+    pub(super) fn meta(&self, b: &mut BoxBuf) {
+        let meta = b.open_full(b"meta", 0, 0);
+        let hdlr = b.open_full(b"hdlr", 0, 0);
+        b.u32(0);
+        b.bytes(b"mdir");
+        b.bytes(b"appl");
+        b.zeros(8);
+        b.bytes(&[0]);
+        b.close(hdlr);
+
+        let ilst = b.open(b"ilst");
+        for (fourcc, value) in self.metadata.text_items() {
+            let item = b.open(fourcc);
+            text_data(b, value);
+            b.close(item);
+        }
+        for (name, value) in &self.metadata.freeform {
+            let item = b.open(b"----");
+            let mean = b.open_full(b"mean", 0, 0);
+            b.bytes(FREEFORM_MEAN);
+            b.close(mean);
+            let name_box = b.open_full(b"name", 0, 0);
+            b.bytes(name.as_bytes());
+            b.close(name_box);
+            text_data(b, value);
+            b.close(item);
+        }
+        if let Some(cover) = &self.metadata.cover {
+            let item = b.open(b"covr");
+            let data = b.open(b"data");
+            b.u32(match cover.format {
+                ImageFormat::Jpeg => DATA_TYPE_JPEG,
+                ImageFormat::Png => DATA_TYPE_PNG,
+            });
+            b.u32(0); // locale
+            b.bytes(&cover.data);
+            b.close(data);
+            b.close(item);
+        }
+        b.close(ilst);
+        b.close(meta);
+    }
+}
+
+/// Writes a `data` box holding `value` as text.
+fn text_data(b: &mut BoxBuf, value: &str) {
+    let data = b.open(b"data");
+    b.u32(DATA_TYPE_UTF8);
+    b.u32(0); // locale
+    b.bytes(value.as_bytes());
+    b.close(data);
+}
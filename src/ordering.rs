@@ -0,0 +1,162 @@
+//! Ordering of the input files, which determines the order of the chapters in
+//! the output.
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    ffi::OsStr,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error as ThisError;
+use tracing::{debug, warn};
+
+use crate::tags;
+
+/// How input files are ordered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OrderStrategy {
+    /// Natural sort by file name, so that `2 - x.mp3` comes before
+    /// `10 - x.mp3`.
+    #[default]
+    Name,
+    /// Sort by disc number and then track number tags.
+    Tags,
+    /// Keep the order the files are given in.
+    Given,
+}
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+    #[error("'{0}' has no track number tag")]
+    MissingTrackNumber(PathBuf),
+    #[error("'{0}' has no disc number tag, but other files do")]
+    MissingDiscNumber(PathBuf),
+    #[error("'{first}' and '{second}' are both disc {disc} track {track}")]
+    DuplicateTrack {
+        disc: u32,
+        track: u32,
+        first: PathBuf,
+        second: PathBuf,
+    },
+}
+
+/// Sorts `paths` according to `strategy`.
+///
+/// With [`OrderStrategy::Tags`], every audio file must have a track number,
+/// and either all or none of them must have a disc number; files that can't
+/// be read as audio are put last, in name order.
+///
+/// # Errors
+/// Returns an error if the tags don't give a single unambiguous order.
+pub fn sort(paths: &mut Vec<PathBuf>, strategy: OrderStrategy) -> Result<(), Error> {
+    if strategy == OrderStrategy::Given {
+        return Ok(());
+    }
+    paths.sort_by(|a, b| natural_cmp(&file_name(a), &file_name(b)));
+    if strategy == OrderStrategy::Name {
+        return Ok(());
+    }
+
+    let mut tagged = Vec::new();
+    let mut untagged = Vec::new();
+    for path in paths.drain(..) {
+        let extension = path.extension().and_then(OsStr::to_str);
+        match File::open(&path).map(|file| tags::read(file, extension)) {
+            Ok(Ok(tags)) => tagged.push((path, tags)),
+            Ok(Err(e)) => {
+                debug!("Not ordering '{}' by tags: {e}", path.display());
+                untagged.push(path);
+            }
+            Err(e) => {
+                warn!("Couldn't read tags of '{}': {e}", path.display());
+                untagged.push(path);
+            }
+        }
+    }
+
+    let with_disc = tagged.iter().filter(|(_, t)| t.disc.is_some()).count();
+    let mut keyed = Vec::with_capacity(tagged.len());
+    let mut seen: HashMap<(u32, u32), PathBuf> = HashMap::new();
+    for (path, tags) in tagged {
+        let Some(track) = tags.track else {
+            return Err(Error::MissingTrackNumber(path));
+        };
+        let disc = match tags.disc {
+            Some(disc) => disc,
+            None if with_disc == 0 => 1,
+            None => return Err(Error::MissingDiscNumber(path)),
+        };
+        if let Some(first) = seen.insert((disc, track), path.clone()) {
+            return Err(Error::DuplicateTrack {
+                disc,
+                track,
+                first,
+                second: path,
+            });
+        }
+        keyed.push(((disc, track), path));
+    }
+
+    keyed.sort_by_key(|&(key, _)| key);
+    paths.extend(keyed.into_iter().map(|(_, path)| path));
+    paths.extend(untagged);
+    Ok(())
+}
+
+/// Compares two strings so that runs of digits are compared by their numeric
+/// value and everything else is compared case-insensitively. Strings that
+/// compare equal this way (`"01"` and `"1"`) are ordered by their bytes, so
+/// the order is always total.
+#[must_use]
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a_chunks = chunks(a);
+    let mut b_chunks = chunks(b);
+    loop {
+        let ordering = match (a_chunks.next(), b_chunks.next()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) => {
+                if is_number(x) && is_number(y) {
+                    let x = x.trim_start_matches('0');
+                    let y = y.trim_start_matches('0');
+                    x.len().cmp(&y.len()).then_with(|| x.cmp(y))
+                } else {
+                    let x = x.chars().flat_map(char::to_lowercase);
+                    let y = y.chars().flat_map(char::to_lowercase);
+                    x.cmp(y)
+                }
+            }
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+/// Splits `s` into alternating runs of ASCII digits and other characters.
+fn chunks(s: &str) -> impl Iterator<Item = &str> {
+    let mut rest = s;
+    std::iter::from_fn(move || {
+        let first = rest.chars().next()?;
+        let digits = first.is_ascii_digit();
+        let end = rest
+            .find(|c: char| c.is_ascii_digit() != digits)
+            .unwrap_or(rest.len());
+        let (chunk, tail) = rest.split_at(end);
+        rest = tail;
+        Some(chunk)
+    })
+}
+
+fn is_number(chunk: &str) -> bool {
+    chunk.starts_with(|c: char| c.is_ascii_digit())
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or(path.as_os_str())
+        .to_string_lossy()
+        .into_owned()
+}
@@ -1,177 +1,1688 @@
 use core::result::Result;
-use std::{fs::File, path::Path};
-
-use symphonia::core::{
-    audio::SampleBuffer,
-    codecs::DecoderOptions,
-    errors::Error as SymphoniaError,
-    formats::FormatOptions,
-    io::{MediaSourceStream, MediaSourceStreamOptions},
-    meta::MetadataOptions,
-    probe::Hint,
+use std::{
+    collections::BTreeSet,
+    ffi::OsStr,
+    fmt,
+    fs::File,
+    io::{self, BufWriter},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{Receiver, SyncSender},
+        Arc, OnceLock,
+    },
 };
+
+use serde::{Serialize, Serializer};
 use thiserror::Error as ThisError;
-use tracing::{debug, error, info};
+use tracing::{error, info, warn};
+
+use crate::{
+    aac::{self, AacConfig, AacEncoder},
+    cancel::CancellationToken,
+    channels::{ChannelMapper, Layout, StereoCheck, TargetLayout},
+    cover::{self, CoverOptions, CoverSource},
+    cue::{self, FileTracks},
+    decode::{self, Detection, ErrorMode, FileDecoder},
+    loudness::{self, Loudness, LoudnessMeter, NormalizeMode, NormalizeOptions},
+    mp4::{AudioFormat, AudioTrackConfig, Chapter, CoverArt, Metadata, Mp4Writer},
+    ordering::{self, OrderStrategy},
+    progress::{Progress, ProgressSink},
+    resample::{ResampleOptions, Resampler, TargetRate},
+    silence::{self, Pause, PauseDetector, Segment, SilenceOptions, SilenceSplitter, SplitOptions},
+    tags::{self, Tags},
+};
+
+mod normalize;
+mod parallel;
+mod plan;
+
+pub use plan::{Plan, PlannedChapter, PlannedCover, PlannedFile};
+
+/// Seconds of audio at the start of each stereo input that are checked for
+/// fake stereo.
+const STEREO_CHECK_SECONDS: u64 = 60;
+/// Chunks of decoded audio (a packet's worth each) that can wait to be
+/// encoded for each input, which bounds how far ahead of the encoder the
+/// decoding of later inputs gets.
+const QUEUED_CHUNKS: usize = 256;
 
 #[derive(ThisError, Debug)]
 pub enum Error {
     #[error("IO Error: {0}")]
     Io(#[from] std::io::Error),
+    #[error("Decode Error: {0}")]
+    Decode(#[from] decode::Error),
+    #[error("Encode Error: {0}")]
+    Encode(#[from] aac::Error),
+    #[error("Ordering Error: {0}")]
+    Ordering(#[from] ordering::Error),
+    #[error("Cover Error: {0}")]
+    Cover(#[from] cover::Error),
+    #[error("Mux Error: {0}")]
+    Mux(#[source] std::io::Error),
+    #[error("Cancelled")]
+    Cancelled,
+}
+
+/// How the inputs are consolidated.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Options {
+    /// How to order the input files, and so the chapters.
+    pub order: OrderStrategy,
+    pub cover: CoverOptions,
+    pub encoding: EncodingOptions,
+    pub chapters: ChapterOptions,
+    /// Metadata for the book, each field of which replaces the one taken from
+    /// the tags. A cover given here is used ahead of any other, unless
+    /// `cover.source` is [`CoverSource::None`].
+    pub metadata: Metadata,
+    /// Whether packets that can't be decoded are replaced with silence, or
+    /// fail the whole book along with any other error in a file.
+    pub errors: ErrorMode,
+    /// How many inputs are decoded at once, on as many threads, or `None` for
+    /// one per CPU. The output is the same whatever the number.
+    pub jobs: Option<NonZeroUsize>,
+    /// How silence at the start and end of each input is trimmed, or `None`
+    /// to keep all of it.
+    pub silence: Option<SilenceOptions>,
+    /// How each input's chapter is split into several at its pauses, or
+    /// `None` for one chapter to an input.
+    pub split: Option<SplitOptions>,
+    pub loudness: LoudnessOptions,
+}
+
+/// The format and quality of the output audio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncodingOptions {
+    /// Bitrate given to each channel of the AAC output, in bits per second.
+    pub bitrate_per_channel: u32,
+    /// The sample rate of the output, and how inputs are converted to it.
+    pub resample: ResampleOptions,
+    /// The channel layout of the output.
+    pub channels: TargetLayout,
 }
 
-fn get_sample_buf(file: File) -> Result<symphonia::core::audio::SampleBuffer<f32>, SymphoniaError> {
-    let file = Box::new(file);
-    // Create the media source stream using the boxed media source from above.
-    let mss = MediaSourceStream::new(file, MediaSourceStreamOptions::default());
+impl Default for EncodingOptions {
+    fn default() -> Self {
+        Self {
+            bitrate_per_channel: 64_000,
+            resample: ResampleOptions::default(),
+            channels: TargetLayout::default(),
+        }
+    }
+}
 
-    // Create a hint to help the format registry guess what format reader is appropriate. In this
-    // example we'll leave it empty.
-    let hint = Hint::new();
+/// How the loudness of the inputs is measured and evened out.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LoudnessOptions {
+    /// Whether to measure the loudness of each input and chapter for the
+    /// report, which is done anyway as far as normalizing needs it.
+    pub measure: bool,
+    /// How to bring the inputs to the same loudness, or `None` to leave them
+    /// as they are.
+    pub normalize: Option<NormalizeOptions>,
+}
 
-    // Use the default options when reading and decoding.
-    let format_opts: FormatOptions = FormatOptions::default();
-    let metadata_opts: MetadataOptions = MetadataOptions::default();
-    let decoder_opts: DecoderOptions = DecoderOptions::default();
+impl LoudnessOptions {
+    /// Whether the output is measured as it's written, for the loudness of
+    /// each chapter and for tagging the book.
+    fn meters_output(&self) -> bool {
+        self.measure
+            || self
+                .normalize
+                .is_some_and(|normalize| normalize.mode == NormalizeMode::Tags)
+    }
+}
 
-    // Probe the media source stream for a format.
-    let probed = symphonia::default::get_probe()
-        .format(&hint, mss, &format_opts, &metadata_opts)
-        .unwrap();
+/// How the chapters of the output are made.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChapterOptions {
+    pub titles: ChapterTitles,
+    /// Whether an input named in a cue sheet next to it is split into
+    /// chapters at the sheet's tracks, titled after them, rather than being
+    /// one chapter.
+    pub cue_sheets: bool,
+}
 
-    // Get the format reader yielded by the probe operation.
-    let mut format = probed.format;
+impl Default for ChapterOptions {
+    fn default() -> Self {
+        Self {
+            titles: ChapterTitles::default(),
+            cue_sheets: true,
+        }
+    }
+}
 
-    // Get the default track.
-    let track = format.default_track().unwrap();
+/// Where each chapter, one to an input, gets its title.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChapterTitles {
+    /// The input's title tag, or its file name without the extension if it
+    /// has none.
+    #[default]
+    Tags,
+    /// The input's file name without the extension.
+    FileName,
+}
 
-    // Create a decoder for the track.
-    let mut decoder = symphonia::default::get_codecs()
-        .make(&track.codec_params, &decoder_opts)
-        .unwrap();
+/// Consolidates a given list of audio files into a single m4b file.
+///
+/// ```no_run
+/// # use consolidator::processor::{ConsolidationJob, Error};
+/// # fn main() -> Result<(), Error> {
+/// let result = ConsolidationJob::new("book.m4b")
+///     .inputs(["01.mp3", "02.mp3"])
+///     .run()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ConsolidationJob {
+    inputs: Vec<PathBuf>,
+    output: PathBuf,
+    options: Options,
+    progress: Arc<dyn ProgressSink>,
+    cancel: CancellationToken,
+}
 
-    // Store the track identifier, we'll use it to filter packets.
-    let track_id = track.id;
+impl fmt::Debug for ConsolidationJob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConsolidationJob")
+            .field("inputs", &self.inputs)
+            .field("output", &self.output)
+            .field("options", &self.options)
+            .finish_non_exhaustive()
+    }
+}
 
-    let mut sample_count = 0;
-    let mut sample_buf = None;
+impl ConsolidationJob {
+    /// Creates a job that writes to `output`, with no inputs yet and the
+    /// default options, except that the inputs are kept in the order they're
+    /// given ([`OrderStrategy::Given`]).
+    #[must_use]
+    pub fn new(output: impl Into<PathBuf>) -> Self {
+        Self {
+            inputs: Vec::new(),
+            output: output.into(),
+            options: Options {
+                order: OrderStrategy::Given,
+                ..Options::default()
+            },
+            progress: Arc::new(()),
+            cancel: CancellationToken::new(),
+        }
+    }
 
-    loop {
-        // Get the next packet from the format reader.
-        let packet = match format.next_packet() {
-            Ok(p) => p,
-            Err(e) => match e {
-                symphonia::core::errors::Error::ResetRequired => {
-                    info!("Assuming reset-required marks end-of-stream, this sample buf is now complete");
-                    if let Some(sample_buf) = sample_buf {
-                        return Ok(sample_buf);
-                    }
-                    panic!("Got reset-required, but no sample buf yet");
-                }
-                e => {
-                    return Err(e);
+    /// Creates a job that consolidates the files of the directory `p`, other
+    /// than images, into an m4b file in the directory named after it.
+    ///
+    /// # Errors
+    /// Will return an IO error if something goes wrong listing the directory.
+    pub fn directory(p: &Path, options: &Options) -> Result<Self, Error> {
+        let output = output_path(p);
+        let mut paths = Vec::new();
+        for res in std::fs::read_dir(p)? {
+            let entry = res?;
+            if let Ok(file_type) = entry.file_type() {
+                let path = entry.path();
+                if file_type.is_file()
+                    && path != output
+                    && !cover::is_image(&path)
+                    && !cue::is_cue_sheet(&path)
+                {
+                    paths.push(path);
                 }
+            }
+        }
+        if paths.is_empty() {
+            warn!("No audio files found in '{}'", p.display());
+        }
+        Ok(Self::new(output).inputs(paths).options(options.clone()))
+    }
+
+    /// Adds an input file.
+    #[must_use]
+    pub fn input(mut self, path: impl Into<PathBuf>) -> Self {
+        self.inputs.push(path.into());
+        self
+    }
+
+    /// Adds input files.
+    #[must_use]
+    pub fn inputs<P: Into<PathBuf>>(mut self, paths: impl IntoIterator<Item = P>) -> Self {
+        self.inputs.extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    /// Replaces all of the options at once.
+    #[must_use]
+    pub fn options(mut self, options: Options) -> Self {
+        self.options = options;
+        self
+    }
+
+    #[must_use]
+    pub fn order(mut self, order: OrderStrategy) -> Self {
+        self.options.order = order;
+        self
+    }
+
+    #[must_use]
+    pub fn cover(mut self, cover: CoverOptions) -> Self {
+        self.options.cover = cover;
+        self
+    }
+
+    #[must_use]
+    pub fn encoding(mut self, encoding: EncodingOptions) -> Self {
+        self.options.encoding = encoding;
+        self
+    }
+
+    #[must_use]
+    pub fn chapters(mut self, chapters: ChapterOptions) -> Self {
+        self.options.chapters = chapters;
+        self
+    }
+
+    /// Sets metadata that replaces what's taken from the tags, field by field.
+    #[must_use]
+    pub fn metadata(mut self, metadata: Metadata) -> Self {
+        self.options.metadata = metadata;
+        self
+    }
+
+    #[must_use]
+    pub fn errors(mut self, errors: ErrorMode) -> Self {
+        self.options.errors = errors;
+        self
+    }
+
+    #[must_use]
+    pub fn jobs(mut self, jobs: Option<NonZeroUsize>) -> Self {
+        self.options.jobs = jobs;
+        self
+    }
+
+    #[must_use]
+    pub fn silence(mut self, silence: Option<SilenceOptions>) -> Self {
+        self.options.silence = silence;
+        self
+    }
+
+    #[must_use]
+    pub fn split(mut self, split: Option<SplitOptions>) -> Self {
+        self.options.split = split;
+        self
+    }
+
+    #[must_use]
+    pub fn loudness(mut self, loudness: LoudnessOptions) -> Self {
+        self.options.loudness = loudness;
+        self
+    }
+
+    /// Sets where the progress of [`run`](Self::run) is reported.
+    #[must_use]
+    pub fn progress(mut self, sink: Arc<dyn ProgressSink>) -> Self {
+        self.progress = sink;
+        self
+    }
+
+    /// Sets a token that stops the job once it's cancelled, with
+    /// [`Error::Cancelled`].
+    #[must_use]
+    pub fn cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancel = token;
+        self
+    }
+
+    /// Decodes the inputs, up to `jobs` of them at a time, and streams the
+    /// audio of each in turn into the output as a chapter. Normalizing the
+    /// loudness takes a first pass over the inputs to measure it. Images are
+    /// never inputs, but when [`CoverSource::Auto`] finds no cover in the
+    /// tags, one named like `cover.jpg` in the directory of an input provides
+    /// it.
+    ///
+    /// # Errors
+    /// Will return an ordering error if the inputs can't be ordered
+    /// unambiguously, a cover error if an explicitly chosen cover image can't
+    /// be read, an encode error if the options ask for a format AAC doesn't
+    /// support, or a mux error if writing the output fails.
+    /// Errors reading, decoding or encoding an individual input (such as an
+    /// unrecognized format) are recorded in its [`FileReport`] and the job
+    /// continues with the next input, and packets that can't be decoded are
+    /// replaced with silence, unless `errors` is [`ErrorMode::Strict`], in
+    /// which case any such error is returned and no output is written.
+    /// Once the job's [`CancellationToken`] is cancelled, this returns
+    /// [`Error::Cancelled`], and no output is written either.
+    pub fn run(&self) -> Result<ConsolidationReport, Error> {
+        let result = self.consolidate();
+        self.progress.progress(Progress::Finished);
+        result
+    }
+
+    fn consolidate(&self) -> Result<ConsolidationReport, Error> {
+        let options = &self.options;
+        let output = &self.output;
+        let cover = match &options.cover.source {
+            CoverSource::File(path) => Some(cover::load(path)?),
+            _ => None,
+        };
+
+        let mut paths = self.inputs.clone();
+        ordering::sort(&mut paths, options.order)?;
+        let format = output_format(&paths, options, &self.cancel)?;
+        let (levels, loudness) = normalize::levels(&paths, options, format.layout, &self.cancel)?;
+        let cues = if options.chapters.cue_sheets {
+            cue::find(&paths, &files_near(&paths, cue::is_cue_sheet))
+        } else {
+            Vec::new()
+        };
+        self.progress
+            .progress(Progress::Started { files: paths.len() });
+
+        // Inputs are decoded and converted to the output's format on worker
+        // threads, and encoded here in order. A format left to the first
+        // input with audio can only be decided by working through the inputs
+        // in order, so then there's just the one worker.
+        let threads = match format {
+            OutputFormat {
+                rate: Some(_),
+                layout: Some(_),
+            } => parallel::threads(options.jobs, paths.len()),
+            _ => 1,
+        };
+        let decoding = Decoding {
+            format,
+            first_format: OnceLock::new(),
+            levels: &levels,
+            cues: &cues,
+            options,
+            progress: &*self.progress,
+            cancel: &self.cancel,
+        };
+        let (writer, files) = parallel::stream(
+            &paths,
+            threads,
+            QUEUED_CHUNKS,
+            |index, path, events| decoding.input(index, path, events),
+            |inputs| {
+                write_inputs(
+                    output,
+                    options,
+                    &*self.progress,
+                    &self.cancel,
+                    paths.iter().zip(inputs),
+                )
             },
+        )?;
+
+        let Some(writer) = writer else {
+            warn!("None of the {} input(s) produced any audio", files.len());
+            return Ok(ConsolidationReport {
+                output: None,
+                sample_rate: None,
+                channels: None,
+                duration: 0.0,
+                loudness,
+                files,
+                chapters: Vec::new(),
+                warnings: Vec::new(),
+            });
         };
+        let config = *writer.encoder.config();
+        let chapters: Vec<ChapterReport> = writer
+            .chapters
+            .iter()
+            .map(|chapter| ChapterReport::new(chapter, config.sample_rate, writer.meter.as_ref()))
+            .collect();
+        let loudness = loudness.or_else(|| {
+            let meter = writer.meter.as_ref()?;
+            Some(meter.measure(0, u64::MAX).loudness())
+        });
+        let images = files_near(&self.inputs, cover::is_image);
+        self.progress.progress(Progress::Finalizing);
+        let warnings = match writer.finish(options, cover, cover::find(&images)) {
+            Err(Error::Cancelled) => {
+                let _ = std::fs::remove_file(output);
+                return Err(Error::Cancelled);
+            }
+            result => result?,
+        };
+        info!("Wrote '{}'", output.display());
+        Ok(ConsolidationReport {
+            output: Some(output.clone()),
+            sample_rate: Some(config.sample_rate),
+            channels: Some(usize::from(config.channels)),
+            duration: chapters.iter().map(|chapter| chapter.duration).sum(),
+            loudness,
+            files,
+            chapters,
+            warnings,
+        })
+    }
+}
 
-        // If the packet does not belong to the selected track, skip it.
-        if packet.track_id() != track_id {
-            continue;
+/// What a [`ConsolidationJob`] did, which serializes to JSON (or any other
+/// serde format) for tools to check.
+#[derive(Debug, Serialize)]
+pub struct ConsolidationReport {
+    /// The m4b file written, or `None` if no input produced any audio.
+    pub output: Option<PathBuf>,
+    /// The sample rate of the output.
+    pub sample_rate: Option<u32>,
+    /// The number of channels of the output.
+    pub channels: Option<usize>,
+    /// Duration of the output, in seconds.
+    pub duration: f64,
+    /// Loudness of all the inputs together, before any gain, if it was
+    /// measured.
+    pub loudness: Option<Loudness>,
+    /// Each input, in the order they were consolidated.
+    pub files: Vec<FileReport>,
+    pub chapters: Vec<ChapterReport>,
+    /// Problems with the output as a whole, such as cover art that had to be
+    /// left out.
+    pub warnings: Vec<String>,
+}
+
+impl ConsolidationReport {
+    /// The inputs that failed.
+    pub fn failures(&self) -> impl Iterator<Item = &FileReport> {
+        self.files
+            .iter()
+            .filter(|file| matches!(file.status, FileStatus::Failed(_)))
+    }
+
+    /// Writes the chapters to `out` for review, one to a line as the start
+    /// time (`hh:mm:ss.mmm`) and title, the format `mp4chaps` reads.
+    ///
+    /// # Errors
+    /// Will return any error writing to `out`.
+    pub fn write_chapters(&self, mut out: impl io::Write) -> io::Result<()> {
+        for chapter in &self.chapters {
+            // Books are far shorter than the times that would truncate.
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            let millis = (chapter.start * 1000.0).round() as u64;
+            writeln!(
+                out,
+                "{:02}:{:02}:{:02}.{:03} {}",
+                millis / 3_600_000,
+                millis / 60_000 % 60,
+                millis / 1000 % 60,
+                millis % 1000,
+                chapter.title
+            )?;
         }
+        Ok(())
+    }
+}
 
-        // Decode the packet into audio samples, ignoring any decode errors.
-        match decoder.decode(&packet) {
-            Ok(audio_buf) => {
-                // The decoded audio samples may now be accessed via the audio buffer if per-channel
-                // slices of samples in their native decoded format is desired. Use-cases where
-                // the samples need to be accessed in an interleaved order or converted into
-                // another sample format, or a byte buffer is required, are covered by copying the
-                // audio buffer into a sample buffer or raw sample buffer, respectively. In the
-                // example below, we will copy the audio buffer into a sample buffer in an
-                // interleaved order while also converting to a f32 sample format.
-
-                // If this is the *first* decoded packet, create a sample buffer matching the
-                // decoded audio buffer format.
-                if sample_buf.is_none() {
-                    // Get the audio buffer specification.
-                    let spec = *audio_buf.spec();
-
-                    // Get the capacity of the decoded buffer. Note: This is capacity, not length!
-                    let duration = audio_buf.capacity() as u64;
-
-                    // Create the f32 sample buffer.
-                    sample_buf = Some(SampleBuffer::<f32>::new(duration, spec));
-                }
+/// What became of one input.
+#[derive(Debug, Serialize)]
+pub struct FileReport {
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub status: FileStatus,
+    /// How the file's format was identified, or `None` if it couldn't be.
+    pub detection: Option<Detection>,
+    /// Short name of the codec, such as `mp3`.
+    pub codec: Option<&'static str>,
+    /// Sample rate and number of channels at the start of the file.
+    pub sample_rate: Option<u32>,
+    pub channels: Option<usize>,
+    /// Frames of audio decoded from the file, at its own sample rate.
+    pub frames: u64,
+    /// Duration of the audio decoded from the file, in seconds.
+    pub duration: f64,
+    /// Packets that couldn't be decoded and were replaced with silence.
+    pub skipped_packets: u64,
+    /// Seconds of silence trimmed from the start and end of the file.
+    pub trimmed_silence: f64,
+    /// Loudness of the file, as remixed to the output's channels, if it was
+    /// measured.
+    pub loudness: Option<Loudness>,
+    /// Gain in dB applied to the file to normalize its loudness.
+    pub gain: Option<f64>,
+    pub warnings: Vec<String>,
+}
+
+impl FileReport {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            status: FileStatus::Succeeded,
+            detection: None,
+            codec: None,
+            sample_rate: None,
+            channels: None,
+            frames: 0,
+            duration: 0.0,
+            skipped_packets: 0,
+            trimmed_silence: 0.0,
+            loudness: None,
+            gain: None,
+            warnings: Vec::new(),
+        }
+    }
 
-                // Copy the decoded audio buffer into the sample buffer in an interleaved format.
-                if let Some(buf) = &mut sample_buf {
-                    buf.copy_interleaved_ref(audio_buf);
+    /// Logs a warning about the file, and records it in the report.
+    fn warn(&mut self, warning: String) {
+        warn!("{warning}");
+        self.warnings.push(warning);
+    }
+}
 
-                    // The samples may now be access via the `samples()` function.
-                    sample_count += buf.samples().len();
-                    debug!("\rDecoded {} samples", sample_count);
-                }
-            }
-            Err(symphonia::core::errors::Error::ResetRequired) => {
-                panic!("Reset Error Encountered, something should be done but idk what");
-            }
-            Err(e) => {
-                break Err(e);
+/// Whether an input made it into the output, serialized as a `status` of
+/// `succeeded` or `failed` with the `error` message.
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", content = "error", rename_all = "snake_case")]
+pub enum FileStatus {
+    Succeeded,
+    /// Reading, decoding or encoding the file failed. Any audio decoded
+    /// before the error is still in the output.
+    Failed(#[serde(serialize_with = "serialize_error")] Error),
+}
+
+fn serialize_error<S: Serializer>(error: &Error, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(error)
+}
+
+/// A chapter of the output, timed in seconds.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChapterReport {
+    pub title: String,
+    pub start: f64,
+    pub duration: f64,
+    /// Loudness of the chapter as written, if it was measured.
+    pub loudness: Option<Loudness>,
+}
+
+impl ChapterReport {
+    /// The report on `chapter`, measured by `meter` if the output was.
+    // Chapters are far shorter than the 2^52 frames that f64 holds exactly.
+    #[allow(clippy::cast_precision_loss)]
+    fn new(chapter: &Chapter, sample_rate: u32, meter: Option<&LoudnessMeter>) -> Self {
+        let rate = f64::from(sample_rate);
+        let end = chapter.start + chapter.frames;
+        Self {
+            title: chapter.title.clone(),
+            start: chapter.start as f64 / rate,
+            duration: chapter.frames as f64 / rate,
+            loudness: meter.map(|meter| meter.measure(chapter.start, end).loudness()),
+        }
+    }
+}
+
+/// The m4b being written, along with the encoder producing its audio.
+struct Output {
+    writer: Mp4Writer<BufWriter<File>>,
+    encoder: AacEncoder,
+    packets: Vec<Vec<u8>>,
+    chapters: Vec<Chapter>,
+    /// Tags of every file with a chapter in the output, without their covers.
+    tags: Vec<Tags>,
+    /// The first cover found in the tags.
+    cover: Option<CoverArt>,
+    /// Title for the book if the tags don't give one.
+    default_title: String,
+    cancel: CancellationToken,
+    /// Measures the audio as it's written, if it's to be.
+    meter: Option<LoudnessMeter>,
+}
+
+impl Output {
+    fn create(
+        path: &Path,
+        sample_rate: u32,
+        layout: Layout,
+        bitrate_per_channel: u32,
+        cancel: CancellationToken,
+    ) -> Result<Self, Error> {
+        let channels = match layout {
+            Layout::Mono => 1,
+            Layout::Stereo => 2,
+        };
+        let encoder = AacEncoder::new(AacConfig {
+            sample_rate,
+            channels,
+            bitrate: bitrate_per_channel * u32::from(channels),
+        })?;
+        let config = AudioTrackConfig {
+            format: AudioFormat::Aac {
+                audio_specific_config: encoder.audio_specific_config(),
+            },
+            sample_rate,
+            channels,
+            priming: encoder.priming(),
+        };
+        let writer = File::create(path)
+            .and_then(|file| Mp4Writer::new(BufWriter::new(file), config))
+            .map_err(Error::Mux)?;
+        Ok(Self {
+            writer,
+            encoder,
+            packets: Vec::new(),
+            chapters: Vec::new(),
+            tags: Vec::new(),
+            cover: None,
+            default_title: file_stem(path),
+            cancel,
+            meter: None,
+        })
+    }
+
+    /// Starts a new chapter at the current end of the audio for a file with
+    /// the given tags.
+    fn begin_chapter(&mut self, title: String, mut tags: Tags) {
+        self.chapters.push(Chapter {
+            title,
+            start: self.encoder.frames_in(),
+            frames: 0,
+        });
+        if let Some(cover) = tags.cover.take() {
+            self.cover.get_or_insert(cover);
+        }
+        self.tags.push(tags);
+    }
+
+    /// Splits the current chapter at the middles of its longest `pauses`, as
+    /// `options` direct, into chapters titled by their number in the book.
+    fn split_chapter(&mut self, pauses: &[Pause], options: &SplitOptions) {
+        let Some(chapter) = self.chapters.pop() else {
+            return;
+        };
+        let end = chapter.start + chapter.frames;
+        let points = silence::chapter_points(
+            pauses,
+            chapter.start,
+            end,
+            options.min_chapter_frames(self.encoder.config().sample_rate),
+            options.target_chapters,
+        );
+        if points.is_empty() {
+            self.chapters.push(chapter);
+            return;
+        }
+        info!(
+            "Splitting '{}' into {} chapters at its pauses",
+            chapter.title,
+            points.len() + 1
+        );
+        let mut start = chapter.start;
+        for end in points.into_iter().chain([end]) {
+            self.chapters.push(Chapter {
+                title: format!("Chapter {}", self.chapters.len() + 1),
+                start,
+                frames: end - start,
+            });
+            start = end;
+        }
+    }
+
+    /// Splits the current chapter at the starts of the cue sheet's `tracks`
+    /// in its input, into chapters titled after them. The input's audio
+    /// starts `skipped` frames into it, after the leading silence that was
+    /// trimmed. Audio before the first track is added to the chapter before,
+    /// if the input `continues` its last track, and otherwise keeps the
+    /// chapter's title.
+    fn split_tracks(&mut self, tracks: &FileTracks, skipped: u64) {
+        let Some(chapter) = self.chapters.pop() else {
+            return;
+        };
+        let rate = self.encoder.config().sample_rate;
+        let end = chapter.start + chapter.frames;
+        let mut parts: Vec<(u64, String)> = Vec::new();
+        for track in &tracks.tracks {
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            let frames = (track.start.as_secs_f64() * f64::from(rate)).round() as u64;
+            let start = (chapter.start + frames.saturating_sub(skipped)).max(chapter.start);
+            // Tracks out of order, or past the end of the audio, are dropped.
+            if start < end && parts.last().is_none_or(|&(last, _)| start > last) {
+                parts.push((start, track.chapter_title()));
             }
         }
+        info!(
+            "Splitting '{}' into {} chapters at the tracks of its cue sheet",
+            chapter.title,
+            parts.len()
+        );
+        match parts.first() {
+            Some(&(first, _)) if first > chapter.start => match self.chapters.last_mut() {
+                Some(before) if tracks.continues => before.frames += first - chapter.start,
+                _ => parts.insert(0, (chapter.start, chapter.title)),
+            },
+            Some(_) => {}
+            None => parts.push((chapter.start, chapter.title)),
+        }
+        let ends: Vec<u64> = parts.iter().skip(1).map(|&(start, _)| start).collect();
+        for ((start, title), end) in parts.into_iter().zip(ends.into_iter().chain([end])) {
+            self.chapters.push(Chapter {
+                title,
+                start,
+                frames: end - start,
+            });
+        }
+    }
+
+    /// Encodes and muxes interleaved `samples`, extending the current chapter.
+    fn write(&mut self, samples: &[f32]) -> Result<(), Error> {
+        if self.cancel.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        let channels = u64::from(self.encoder.config().channels);
+        if let Some(chapter) = self.chapters.last_mut() {
+            chapter.frames += samples.len() as u64 / channels;
+        }
+        if let Some(meter) = self.meter.as_mut() {
+            meter.add(samples);
+        }
+        self.encoder.encode(samples, &mut self.packets);
+        self.write_packets()
+    }
+
+    fn write_packets(&mut self) -> Result<(), Error> {
+        if self.cancel.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        for packet in self.packets.drain(..) {
+            // Each access unit holds exactly one frame's worth of audio.
+            #[allow(clippy::cast_possible_truncation)]
+            self.writer
+                .write_packet(&packet, aac::FRAME_LEN as u32)
+                .map_err(Error::Mux)?;
+        }
+        Ok(())
+    }
+
+    /// Finishes the file, with the cover from `options.metadata` or `cover`
+    /// as its cover art or, if those are `None`, the first cover in the tags
+    /// (when `options.cover.source` allows it), failing that `fallback`,
+    /// and tagged with the gain that normalizes it if `options.loudness` asks
+    /// for tags. Returns warnings about the cover art.
+    fn finish(
+        mut self,
+        options: &Options,
+        cover: Option<CoverArt>,
+        fallback: Option<&Path>,
+    ) -> Result<Vec<String>, Error> {
+        let mut warnings = Vec::new();
+        let mut warn = |warning: String| {
+            warn!("{warning}");
+            warnings.push(warning);
+        };
+        let padding = self.encoder.finish(&mut self.packets);
+        self.write_packets()?;
+        self.writer.set_padding(padding);
+        self.writer.set_chapters(self.chapters);
+        let mut metadata = book_metadata(&self.tags, &options.metadata, self.default_title);
+        let tags = options
+            .loudness
+            .normalize
+            .filter(|normalize| normalize.mode == NormalizeMode::Tags);
+        if let (Some(normalize), Some(meter)) = (tags, self.meter.take()) {
+            // The book is a single track, so its gain is both the track's and
+            // the album's.
+            let loudness = meter.finish().loudness();
+            let gain = normalize.gain(&loudness);
+            info!("Tagging the book with a gain of {gain:+.1} dB");
+            metadata.freeform.extend(
+                loudness::gain_tags(gain, &loudness)
+                    .into_iter()
+                    .map(|(name, value)| (name.to_owned(), value)),
+            );
+        }
+        let cover = options.metadata.cover.clone().or(cover);
+        let cover = match options.cover.source {
+            CoverSource::Auto => cover.or(self.cover).or_else(|| {
+                let path = fallback?;
+                info!("Using cover art from '{}'", path.display());
+                cover::load(path)
+                    .inspect_err(|e| warn(format!("Ignoring cover art '{}': {e}", path.display())))
+                    .ok()
+            }),
+            CoverSource::File(_) => cover,
+            CoverSource::None => None,
+        };
+        metadata.cover = cover.and_then(|cover| {
+            cover::fit(cover, &options.cover)
+                .inspect_err(|e| warn(format!("Leaving out cover art: {e}")))
+                .ok()
+        });
+        self.writer.set_metadata(metadata);
+        self.writer.finish().map_err(Error::Mux)?;
+        Ok(warnings)
+    }
+}
+
+/// The metadata for a book made of files with `tags`, with each text field
+/// that `overrides` has replaced, and `default_title` if there's no title.
+fn book_metadata(tags: &[Tags], overrides: &Metadata, default_title: String) -> Metadata {
+    let mut metadata = tags::book_metadata(tags);
+    for (field, value) in [
+        (&mut metadata.title, &overrides.title),
+        (&mut metadata.author, &overrides.author),
+        (&mut metadata.narrator, &overrides.narrator),
+        (&mut metadata.year, &overrides.year),
+        (&mut metadata.genre, &overrides.genre),
+        (&mut metadata.comment, &overrides.comment),
+    ] {
+        if value.is_some() {
+            field.clone_from(value);
+        }
     }
+    metadata.title.get_or_insert(default_title);
+    metadata
+}
+
+/// The file name of `path` without its extension.
+fn file_stem(path: &Path) -> String {
+    path.file_stem()
+        .unwrap_or(path.as_os_str())
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// The title of the chapter for the file at `path` with `tags`.
+fn chapter_title(path: &Path, tags: &Tags, titles: ChapterTitles) -> String {
+    match titles {
+        ChapterTitles::Tags => tags.title.clone().unwrap_or_else(|| file_stem(path)),
+        ChapterTitles::FileName => file_stem(path),
+    }
+}
+
+/// Files in the directories of the `inputs` that pass `filter`, such as the
+/// images one of which might be the cover art.
+fn files_near(inputs: &[PathBuf], filter: fn(&Path) -> bool) -> Vec<PathBuf> {
+    let dirs: BTreeSet<&Path> = inputs.iter().filter_map(|path| path.parent()).collect();
+    dirs.into_iter()
+        .filter_map(|dir| {
+            // A bare file name has an empty parent: the current directory.
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            std::fs::read_dir(dir).ok()
+        })
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            (path.is_file() && filter(&path)).then_some(path)
+        })
+        .collect()
+}
+
+/// The m4b file produced for the directory `p`, named after the directory.
+fn output_path(p: &Path) -> PathBuf {
+    let name = p
+        .canonicalize()
+        .ok()
+        .and_then(|p| p.file_name().map(ToOwned::to_owned))
+        .unwrap_or_else(|| "consolidated".into());
+    let mut file_name = name;
+    file_name.push(".m4b");
+    p.join(file_name)
 }
 
 /// For each regular file in the given directory, if its an audio file,
 /// it will be consolidated into a single resulting m4b file
-/// that is written to the same directory. Each file will be its own chapter
+/// that is written to the same directory. Each file will be its own chapter,
+/// in the order given by `options.order`. Image files are skipped, though one
+/// named like `cover.jpg` provides the cover art when the inputs have none.
 ///
-/// # Errors
-/// Will return an IO error if something goes wrong reading the files or their contents.
-/// Any errors related to unrecognized/unsupported audio formats will be logged and
-/// processing will continue.
+/// This runs the job that [`ConsolidationJob::directory`] creates.
 ///
-pub fn process(p: &Path) -> Result<(), self::Error> {
+/// # Errors
+/// Will return an IO error if something goes wrong listing the directory, or
+/// any error [`ConsolidationJob::run`] returns.
+pub fn process(p: &Path, options: &Options) -> Result<ConsolidationReport, self::Error> {
     info!("Processing path: {}", p.display());
-    let entries = std::fs::read_dir(p)?;
+    ConsolidationJob::directory(p, options)?.run()
+}
 
-    for res in entries {
-        let entry = res?;
-        if let Ok(file_type) = entry.file_type() {
-            if file_type.is_file() {
-                let path = entry.path();
-                let name = path.file_name().unwrap();
-                info!("Processing file: '{path:?}'",);
-                let f = File::open(&path)?;
-                match process_impl(f) {
-                    Ok(()) => {
-                        info!(
-                            "Completed Processing for file: Successfully processed file: '{name:?}'",
-                        );
+/// Plans what [`process`] would produce for the directory `p`, without
+/// decoding the files in full.
+///
+/// # Errors
+/// Will return an IO error if something goes wrong listing the directory, or
+/// any error [`ConsolidationJob::plan`] returns.
+pub fn plan(p: &Path, options: &Options) -> Result<Plan, self::Error> {
+    info!("Planning path: {}", p.display());
+    ConsolidationJob::directory(p, options)?.plan()
+}
+
+/// The format of an input, as far as choosing the output's goes.
+struct InputFormat {
+    rate: u32,
+    channels: usize,
+    /// Whether the input is stereo with the same signal in both channels.
+    fake_stereo: bool,
+}
+
+/// The format of the output, where it's `None` taken from the first file to
+/// be decoded.
+struct OutputFormat {
+    rate: Option<u32>,
+    layout: Option<Layout>,
+}
+
+/// Decodes the start of the file at `path` to find its format, checking up to
+/// `STEREO_CHECK_SECONDS` of stereo audio for fake stereo if `check_stereo`.
+fn inspect(path: &Path, check_stereo: bool, cancel: &CancellationToken) -> Option<InputFormat> {
+    let extension = path.extension().and_then(OsStr::to_str);
+    let mut decoder = FileDecoder::open(File::open(path).ok()?, extension).ok()?;
+    decoder.set_cancellation(cancel.clone());
+    // Bad packets are reported when the file is processed; here they just
+    // end the check early.
+    decoder.set_error_mode(ErrorMode::Strict);
+    let chunk = decoder.next_chunk().ok()??;
+    let (rate, channels) = (chunk.spec.rate, chunk.spec.channels.count());
+    let mut check = (check_stereo && channels == 2).then(StereoCheck::default);
+    if let Some(check) = check.as_mut() {
+        check.add(chunk.samples);
+        let limit = u64::from(rate) * STEREO_CHECK_SECONDS;
+        while decoder.frames() < limit && check.is_fake() {
+            match decoder.next_chunk() {
+                Ok(Some(chunk)) if chunk.spec.channels.count() == 2 => check.add(chunk.samples),
+                _ => break,
+            }
+        }
+    }
+    Some(InputFormat {
+        rate,
+        channels,
+        fake_stereo: check.is_some_and(|check| check.is_fake()),
+    })
+}
+
+/// Chooses the format of the output from the formats of the inputs at
+/// `paths`, as `options` direct. Only rates that AAC supports are chosen from,
+/// as the rest have to be resampled anyway.
+///
+/// # Errors
+/// Returns an error if the options ask for a fixed rate that AAC doesn't
+/// support, or if `cancel` is cancelled.
+fn output_format(
+    paths: &[PathBuf],
+    options: &Options,
+    cancel: &CancellationToken,
+) -> Result<OutputFormat, Error> {
+    let fixed_rate = match options.encoding.resample.rate {
+        TargetRate::Fixed(rate) if !aac::supports_sample_rate(rate) => {
+            return Err(Error::Encode(aac::Error::UnsupportedSampleRate(rate)));
+        }
+        TargetRate::Fixed(rate) => Some(rate),
+        _ => None,
+    };
+    let fixed_layout = match options.encoding.channels {
+        TargetLayout::Fixed(layout) => Some(layout),
+        TargetLayout::Auto => None,
+    };
+    if fixed_rate.is_some() && fixed_layout.is_some() {
+        return Ok(OutputFormat {
+            rate: fixed_rate,
+            layout: fixed_layout,
+        });
+    }
+
+    // Files that can't be decoded are left for processing to report.
+    let threads = parallel::threads(options.jobs, paths.len());
+    let inspected = parallel::map(paths, threads, |path| {
+        inspect(path, fixed_layout.is_none(), cancel)
+    });
+    if cancel.is_cancelled() {
+        return Err(Error::Cancelled);
+    }
+    let inputs: Vec<InputFormat> = paths
+        .iter()
+        .zip(inspected)
+        .filter_map(|(path, input)| {
+            let input = input?;
+            if input.fake_stereo {
+                info!(
+                    "Treating '{}' as mono, as its channels are the same",
+                    path.display()
+                );
+            }
+            Some(input)
+        })
+        .collect();
+
+    let rate = fixed_rate.or_else(|| {
+        let rates: Vec<u32> = inputs
+            .iter()
+            .map(|input| input.rate)
+            .filter(|&rate| aac::supports_sample_rate(rate))
+            .collect();
+        let rate = options.encoding.resample.rate.choose(&rates)?;
+        let others = inputs.iter().filter(|input| input.rate != rate).count();
+        info!("Output sample rate: {rate}Hz ({others} file(s) will be resampled)");
+        Some(rate)
+    });
+    let layout = fixed_layout.or_else(|| {
+        let stereo =
+            |input: &InputFormat| input.channels > 2 || input.channels == 2 && !input.fake_stereo;
+        let layout = match inputs.iter().any(stereo) {
+            _ if inputs.is_empty() => return None,
+            true => Layout::Stereo,
+            false => Layout::Mono,
+        };
+        info!("Output channels: {layout:?}");
+        Some(layout)
+    });
+    Ok(OutputFormat { rate, layout })
+}
+
+/// Opens the input `file` for decoding, recording its format.
+fn open_input(
+    file: &mut FileReport,
+    options: &Options,
+    cancel: &CancellationToken,
+) -> Result<FileDecoder, Error> {
+    let extension = file.path.extension().and_then(OsStr::to_str);
+    let mut decoder = FileDecoder::open(File::open(&file.path)?, extension)?;
+    decoder.set_error_mode(options.errors);
+    decoder.set_cancellation(cancel.clone());
+    info!("Detected format: {}", decoder.detection());
+    let detection = decoder.detection();
+    if detection.extension_matches() == Some(false) {
+        // Already logged when the file was probed.
+        file.warnings.push(format!(
+            "File has a '{}' extension, but looks like {detection}",
+            extension.unwrap_or_default(),
+        ));
+    }
+    file.detection = Some(detection.clone());
+    file.codec = decoder.codec();
+    Ok(decoder)
+}
+
+/// What decoding an input sends to be written, in order.
+enum Event {
+    /// The input's first audio, which starts its chapter, in an output with
+    /// the given format.
+    Chapter {
+        title: String,
+        tags: Box<Tags>,
+        /// The input's tracks if a cue sheet names it, at which its chapter
+        /// is split.
+        tracks: Option<Box<FileTracks>>,
+        rate: u32,
+        layout: Layout,
+    },
+    /// Interleaved audio, converted to the output's format, and split into
+    /// the silence at either end and the rest if silence is trimmed.
+    Audio(Segment),
+    /// The end of the input, with what was decoded from it, and any error
+    /// that ended it early.
+    Done(Box<FileReport>, Result<(), Error>),
+}
+
+/// Writes the chapters of `inputs`, each a path and the events decoding it
+/// sends, into the output at `output`, which is created once an input has
+/// audio. Returns the output, unless no input had any audio, and a report on
+/// each input.
+///
+/// # Errors
+/// Returns a mux error if writing the output fails, the first error in an
+/// input if `options.errors` is [`ErrorMode::Strict`], or a cancelled error
+/// once `cancel` is cancelled, in which last two cases the output is removed.
+fn write_inputs<'a>(
+    output: &Path,
+    options: &Options,
+    progress: &dyn ProgressSink,
+    cancel: &CancellationToken,
+    inputs: impl Iterator<Item = (&'a PathBuf, Receiver<Event>)>,
+) -> Result<(Option<Output>, Vec<FileReport>), Error> {
+    let mut writing = Writing::new(output, options, cancel);
+    for (index, (path, events)) in inputs.enumerate() {
+        let name = path.file_name().unwrap_or(path.as_os_str());
+        let mut create_error = None;
+        let mut done = None;
+        for event in events {
+            let result = match event {
+                Event::Chapter { .. } | Event::Audio(_) if create_error.is_some() => Ok(()),
+                Event::Chapter {
+                    title,
+                    tags,
+                    tracks,
+                    rate,
+                    layout,
+                } => writing.chapter(title, tags, tracks, rate, layout),
+                Event::Audio(segment) => writing.audio(segment).map(|()| {
+                    if let Some(seconds) = writing.seconds() {
+                        progress.progress(Progress::Encoded { index, seconds });
                     }
-                    Err(e) => {
-                        error!("Error while processing file: '{name:?}'\n{e:?}");
+                }),
+                Event::Done(file, result) => {
+                    done = Some((file, result));
+                    Ok(())
+                }
+            };
+            match result {
+                Ok(()) => {}
+                Err(Error::Cancelled) => {
+                    discard(&mut writing.writer, output);
+                    return Err(Error::Cancelled);
+                }
+                Err(e @ Error::Mux(_)) => {
+                    error!(
+                        "Failed writing '{}' while processing '{name:?}'",
+                        output.display()
+                    );
+                    return Err(e);
+                }
+                // The output couldn't be made for this input's format, so
+                // the rest of its audio is dropped.
+                Err(e) => create_error = Some(e),
+            }
+        }
+        // Without an end, the input's worker panicked, which is passed on
+        // once the workers are joined.
+        let Some((mut file, result)) = done else {
+            continue;
+        };
+        // An input with nothing but silence still has a chapter.
+        match writing.begin() {
+            Err(Error::Cancelled) => {
+                discard(&mut writing.writer, output);
+                return Err(Error::Cancelled);
+            }
+            begun => begun?,
+        }
+        file.trimmed_silence += std::mem::take(&mut writing.trimmed);
+        match create_error.map_or(result, Err) {
+            Ok(()) => {
+                info!("Completed Processing for file: Successfully processed file: '{name:?}'",);
+            }
+            Err(Error::Cancelled) => {
+                discard(&mut writing.writer, output);
+                return Err(Error::Cancelled);
+            }
+            Err(e) if options.errors == ErrorMode::Strict => {
+                error!("Error while processing file: '{name:?}', giving up on the book");
+                discard(&mut writing.writer, output);
+                return Err(e);
+            }
+            Err(e) => {
+                error!("Error while processing file: '{name:?}'\n{e:?}");
+                file.status = FileStatus::Failed(e);
+            }
+        }
+        progress.progress(Progress::FileFinished {
+            index,
+            failed: matches!(file.status, FileStatus::Failed(_)),
+        });
+        writing.files.push(*file);
+    }
+    match writing.end() {
+        Err(Error::Cancelled) => {
+            discard(&mut writing.writer, output);
+            return Err(Error::Cancelled);
+        }
+        ended => ended?,
+    }
+    Ok((writing.writer, writing.files))
+}
+
+/// Drops the output being written to `path`, if there is one, and removes
+/// its file.
+fn discard(writer: &mut Option<Output>, path: &Path) {
+    if writer.take().is_some() {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// The output as the inputs are written into it, in order. Each chapter is
+/// begun once its first sound arrives, so that the silence either side of
+/// the join with the last chapter can be trimmed to the gap.
+struct Writing<'a> {
+    path: &'a Path,
+    options: &'a Options,
+    cancel: &'a CancellationToken,
+    writer: Option<Output>,
+    files: Vec<FileReport>,
+    /// Frames of silence left between chapters, and channels of the output.
+    gap: usize,
+    channels: usize,
+    /// The chapter of the input being written, until it's begun.
+    chapter: Option<(String, Box<Tags>, Option<Box<FileTracks>>)>,
+    /// The silence at the start of the input being written, and at the end of
+    /// the last input with any sound, along with the index of its report.
+    leading: HeldSilence,
+    trailing: HeldSilence,
+    trailing_file: usize,
+    /// Seconds of leading silence trimmed from the input being written.
+    trimmed: f64,
+    /// The pauses in the current chapter, if it's to be split at them.
+    pauses: Option<PauseDetector>,
+    /// The cue sheet's tracks in the current chapter, if it's to be split at
+    /// them, and the frames of leading silence trimmed before it.
+    tracks: Option<(Box<FileTracks>, u64)>,
+}
+
+impl<'a> Writing<'a> {
+    fn new(path: &'a Path, options: &'a Options, cancel: &'a CancellationToken) -> Self {
+        Self {
+            path,
+            options,
+            cancel,
+            writer: None,
+            files: Vec::new(),
+            gap: 0,
+            channels: 0,
+            chapter: None,
+            leading: HeldSilence::default(),
+            trailing: HeldSilence::default(),
+            trailing_file: 0,
+            trimmed: 0.0,
+            pauses: None,
+            tracks: None,
+        }
+    }
+
+    /// Starts the chapter of the next input, creating the output for the
+    /// format given if this is the first.
+    fn chapter(
+        &mut self,
+        title: String,
+        tags: Box<Tags>,
+        tracks: Option<Box<FileTracks>>,
+        rate: u32,
+        layout: Layout,
+    ) -> Result<(), Error> {
+        if self.writer.is_none() {
+            self.writer = Some(Output::create(
+                self.path,
+                rate,
+                layout,
+                self.options.encoding.bitrate_per_channel,
+                self.cancel.clone(),
+            )?);
+            self.gap = self
+                .options
+                .silence
+                .map_or(0, |silence| silence.gap_frames(rate));
+            if self.options.loudness.meters_output() {
+                if let Some(writer) = self.writer.as_mut() {
+                    writer.meter = Some(LoudnessMeter::new(rate, layout.count()));
+                }
+            }
+            self.channels = layout.count();
+        }
+        self.chapter = Some((title, tags, tracks));
+        Ok(())
+    }
+
+    fn audio(&mut self, segment: Segment) -> Result<(), Error> {
+        match segment {
+            Segment::Leading(samples) => {
+                self.leading.push_leading(&samples, self.channels, self.gap);
+            }
+            Segment::Audio(samples) => {
+                self.begin()?;
+                if let Some(writer) = self.writer.as_mut() {
+                    if let Some(pauses) = self.pauses.as_mut() {
+                        pauses.process(&samples, writer.encoder.frames_in());
                     }
-                };
+                    writer.write(&samples)?;
+                }
+            }
+            Segment::Trailing(samples) => {
+                self.begin()?;
+                self.trailing
+                    .push_trailing(&samples, self.channels, self.gap);
+                self.trailing_file = self.files.len();
             }
         }
+        Ok(())
+    }
+
+    /// Seconds of audio written so far.
+    fn seconds(&self) -> Option<f64> {
+        let writer = self.writer.as_ref()?;
+        #[allow(clippy::cast_precision_loss)]
+        Some(writer.encoder.frames_in() as f64 / f64::from(writer.encoder.config().sample_rate))
+    }
+
+    /// Begins the chapter of the input being written, if it hasn't been, after
+    /// what's kept of the silence at the end of the last chapter, and follows
+    /// it with what's kept of the input's leading silence. Half the gap is
+    /// kept at the start of the book.
+    fn begin(&mut self) -> Result<(), Error> {
+        let (Some(writer), Some((title, tags, tracks))) =
+            (self.writer.as_mut(), self.chapter.take())
+        else {
+            return Ok(());
+        };
+        let leading = std::mem::take(&mut self.leading);
+        let trailing = std::mem::take(&mut self.trailing);
+        let (keep_trailing, keep_leading) = if writer.chapters.is_empty() {
+            (0, leading.frames.min(self.gap / 2))
+        } else {
+            silence::split_gap(trailing.frames, leading.frames, self.gap)
+        };
+        writer.write(trailing.start(keep_trailing, self.channels))?;
+        if let Some((tracks, skipped)) = self.tracks.take() {
+            writer.split_tracks(&tracks, skipped);
+        }
+        if let (Some(pauses), Some(split)) = (self.pauses.take(), &self.options.split) {
+            writer.split_chapter(&pauses.finish(), split);
+        }
+        writer.begin_chapter(title, *tags);
+        writer.write(leading.end(keep_leading, self.channels))?;
+
+        // An input's cue sheet marks its chapters in place of its pauses.
+        let sample_rate = writer.encoder.config().sample_rate;
+        self.pauses = self
+            .options
+            .split
+            .filter(|_| tracks.is_none())
+            .map(|split| PauseDetector::new(&split, sample_rate, self.channels));
+        self.tracks = tracks.map(|tracks| (tracks, (leading.frames - keep_leading) as u64));
+        let rate = f64::from(sample_rate);
+        #[allow(clippy::cast_precision_loss)]
+        let seconds = |frames: usize| frames as f64 / rate;
+        if let Some(file) = self.files.get_mut(self.trailing_file) {
+            file.trimmed_silence += seconds(trailing.frames - keep_trailing);
+        }
+        self.trimmed += seconds(leading.frames - keep_leading);
+        Ok(())
     }
 
-    Ok(())
+    /// Ends the last chapter with half the gap of its trailing silence, and
+    /// splits it at its tracks or pauses if it's to be.
+    fn end(&mut self) -> Result<(), Error> {
+        let Some(writer) = self.writer.as_mut() else {
+            return Ok(());
+        };
+        let trailing = std::mem::take(&mut self.trailing);
+        let keep = trailing.frames.min(self.gap / 2);
+        writer.write(trailing.start(keep, self.channels))?;
+        if let Some((tracks, skipped)) = self.tracks.take() {
+            writer.split_tracks(&tracks, skipped);
+        }
+        if let (Some(pauses), Some(split)) = (self.pauses.take(), &self.options.split) {
+            writer.split_chapter(&pauses.finish(), split);
+        }
+        if let Some(file) = self.files.get_mut(self.trailing_file) {
+            #[allow(clippy::cast_precision_loss)]
+            let seconds =
+                (trailing.frames - keep) as f64 / f64::from(writer.encoder.config().sample_rate);
+            file.trimmed_silence += seconds;
+        }
+        Ok(())
+    }
 }
 
-//// todo
-/// - Figure out roughly what api calls to make to create an empty m4b container
-/// - figure out how that container can be filled up with audio data
-/// - read each file and process it into samples (?)
-/// - put the contents of the file into the next m4b entry
-/// - close the m4b at the end and print the path
-///
-/// ref
-///   https://github.com/pdeljanov/Symphonia/blob/master/symphonia-play/src/main.rs#L225
-fn process_impl(f: File) -> Result<(), SymphoniaError> {
-    let sample_buf = get_sample_buf(f)?;
+/// A run of silence, of which only the gap's worth next to the sound is kept.
+#[derive(Default)]
+struct HeldSilence {
+    samples: Vec<f32>,
+    /// The length of the whole run.
+    frames: usize,
+}
+
+impl HeldSilence {
+    /// Extends silence that comes before the sound with `samples`, keeping
+    /// the last `gap` frames.
+    fn push_leading(&mut self, samples: &[f32], channels: usize, gap: usize) {
+        self.frames += samples.len() / channels;
+        self.samples.extend(samples);
+        let excess = self.samples.len().saturating_sub(gap * channels);
+        self.samples.drain(..excess);
+    }
+
+    /// Extends silence that comes after the sound with `samples`, keeping the
+    /// first `gap` frames.
+    fn push_trailing(&mut self, samples: &[f32], channels: usize, gap: usize) {
+        self.frames += samples.len() / channels;
+        let room = (gap * channels).saturating_sub(self.samples.len());
+        self.samples.extend(&samples[..room.min(samples.len())]);
+    }
+
+    /// The first `frames` frames, of those kept.
+    fn start(&self, frames: usize, channels: usize) -> &[f32] {
+        &self.samples[..frames * channels]
+    }
+
+    /// The last `frames` frames, of those kept.
+    fn end(&self, frames: usize, channels: usize) -> &[f32] {
+        &self.samples[self.samples.len() - frames * channels..]
+    }
+}
+
+/// What the workers decoding the inputs share.
+struct Decoding<'a> {
+    /// The output's format, with what that leaves open taken from the first
+    /// input with audio, which `first_format` records.
+    format: OutputFormat,
+    first_format: OnceLock<(u32, Layout)>,
+    /// The loudness of each input and its gain, if they were measured.
+    levels: &'a [normalize::Level],
+    /// The tracks of each input that a cue sheet names.
+    cues: &'a [Option<FileTracks>],
+    options: &'a Options,
+    progress: &'a dyn ProgressSink,
+    cancel: &'a CancellationToken,
+}
+
+impl Decoding<'_> {
+    /// The rate and layout of the output, given the `rate` and number of
+    /// `channels` of the audio being decoded.
+    fn output_format(&self, rate: u32, channels: usize) -> (u32, Layout) {
+        *self.first_format.get_or_init(|| {
+            (
+                self.format.rate.unwrap_or(rate),
+                self.format
+                    .layout
+                    .unwrap_or_else(|| Layout::for_count(channels)),
+            )
+        })
+    }
+
+    /// Decodes the input at `path`, the `index`th, sending its audio to
+    /// `events` as a chapter titled as `options.chapters` directs, followed
+    /// by a report of what was decoded.
+    fn input(&self, index: usize, path: &Path, events: &SyncSender<Event>) {
+        info!("Processing file: '{path:?}'",);
+        let mut file = FileReport::new(path.to_owned());
+        let level = self.levels.get(index).copied().unwrap_or_default();
+        file.loudness = level.loudness;
+        file.gain = level.gain;
+        let result = match self.decode(index, &mut file, events) {
+            Err(Error::Decode(decode::Error::Cancelled)) => Err(Error::Cancelled),
+            result => result,
+        };
+        // Nothing is listening if the job has stopped.
+        let _ = events.send(Event::Done(Box::new(file), result));
+    }
+
+    /// The title, tags and cue sheet tracks of the chapter of the input at
+    /// `path`, the `index`th, with the given `tags`. The sheet's title and
+    /// performer, those of the disc, stand in for missing tags.
+    fn chapter(
+        &self,
+        index: usize,
+        path: &Path,
+        tags: &Tags,
+    ) -> (String, Box<Tags>, Option<Box<FileTracks>>) {
+        let title = chapter_title(path, tags, self.options.chapters.titles);
+        let mut tags = tags.clone();
+        let tracks = self.cues.get(index).cloned().flatten();
+        if let Some(tracks) = &tracks {
+            info!("Taking the chapters of '{path:?}' from its cue sheet");
+            tags.album = tags.album.or_else(|| tracks.title.clone());
+            tags.artist = tags.artist.or_else(|| tracks.performer.clone());
+        }
+        (title, Box::new(tags), tracks.map(Box::new))
+    }
+
+    /// Decodes the input `file`, sending its audio to `events` as a chapter,
+    /// and recording in `file` what was decoded. Stops early, without an
+    /// error, if nothing is receiving the events.
+    ///
+    /// Audio is remixed to the output's channel layout and then resampled to
+    /// its rate, as needed. Audio is sent as each packet is decoded, so a file
+    /// that fails part way through keeps the audio (and chapter) decoded
+    /// before the failure.
+    ///
+    /// ref
+    ///   <https://github.com/pdeljanov/Symphonia/blob/master/symphonia-play/src/main.rs#L225>
+    fn decode(
+        &self,
+        index: usize,
+        file: &mut FileReport,
+        events: &SyncSender<Event>,
+    ) -> Result<(), Error> {
+        let Self {
+            options, progress, ..
+        } = self;
+        let mut decoder = open_input(file, options, self.cancel)?;
+        let duration = decoder.duration();
+        progress.progress(Progress::FileStarted {
+            index,
+            path: &file.path,
+            duration,
+        });
+
+        let mut chapter = Some(self.chapter(index, &file.path, decoder.tags()));
+        let mut mapping: Option<ChannelMapper> = None;
+        let mut resampler: Option<Resampler> = None;
+        let mut splitter: Option<SilenceSplitter> = None;
+        #[allow(clippy::cast_possible_truncation)]
+        let gain = file.gain.map(|gain| 10f32.powf(gain as f32 / 20.0));
+        let result = loop {
+            let chunk = match decoder.next_chunk() {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e.into()),
+            };
+            let rate = chunk.spec.rate;
+            let channels = chunk.spec.channels;
+            file.sample_rate.get_or_insert(rate);
+            file.channels.get_or_insert(channels.count());
+
+            let (output_rate, layout) = self.output_format(rate, channels.count());
+            if let Some((title, tags, tracks)) = chapter.take() {
+                let event = Event::Chapter {
+                    title,
+                    tags,
+                    tracks,
+                    rate: output_rate,
+                    layout,
+                };
+                if events.send(event).is_err() {
+                    break Ok(());
+                }
+                splitter = options
+                    .silence
+                    .map(|silence| SilenceSplitter::new(&silence, output_rate, layout.count()));
+            }
+
+            let mut samples = if channels.count() == layout.count() {
+                chunk.samples.to_vec()
+            } else {
+                let mapper = match mapping.take() {
+                    Some(mapper) if mapper.input() == channels => mapping.insert(mapper),
+                    _ => mapping.insert({
+                        info!("Mapping {} channel(s) to {layout:?}", channels.count());
+                        ChannelMapper::new(channels, layout)
+                    }),
+                };
+                let mut remixed = Vec::new();
+                mapper.map(chunk.samples, &mut remixed);
+                remixed
+            };
+
+            // Finish off the audio at a rate the stream has changed from.
+            if let Some(mut old) = resampler.take_if(|r| r.from_rate() != rate) {
+                let mut converted = Vec::new();
+                old.finish(&mut converted);
+                if !send_audio(events, splitter.as_mut(), gain, converted) {
+                    break Ok(());
+                }
+            }
+            if rate != output_rate {
+                let resampler = resampler.get_or_insert_with(|| {
+                    info!("Resampling from {rate}Hz to {output_rate}Hz");
+                    Resampler::new(
+                        rate,
+                        output_rate,
+                        layout.count(),
+                        options.encoding.resample.quality,
+                    )
+                });
+                let mut converted = Vec::new();
+                resampler.process(&samples, &mut converted);
+                samples = converted;
+            }
+            if !send_audio(events, splitter.as_mut(), gain, samples) {
+                break Ok(());
+            }
+            progress.progress(Progress::Decoded {
+                index,
+                seconds: decoder.seconds(),
+                duration,
+            });
+        };
+
+        // Keep the audio still in the resampler, even if decoding failed.
+        if let Some(resampler) = resampler.as_mut() {
+            let mut converted = Vec::new();
+            resampler.finish(&mut converted);
+            send_audio(events, splitter.as_mut(), gain, converted);
+        }
+        if let Some(splitter) = splitter {
+            let mut segments = Vec::new();
+            splitter.finish(&mut segments);
+            send_segments(events, segments);
+        }
+        record_decoded(file, &decoder);
+        result
+    }
+}
 
-    info!("Got sample buffer with {} samples", sample_buf.len());
+/// Records in `file` what `decoder` decoded from it.
+fn record_decoded(file: &mut FileReport, decoder: &FileDecoder) {
+    file.frames = decoder.frames();
+    file.duration = decoder.seconds();
+    file.skipped_packets = decoder.skipped_packets();
+    info!("Decoded {} frames", decoder.frames());
+    if decoder.skipped_packets() > 0 {
+        file.warn(format!(
+            "Replaced {} undecodable packet(s) with silence",
+            decoder.skipped_packets()
+        ));
+    }
+}
+
+/// Sends interleaved `samples` to `events`, amplified by `gain` and split into
+/// silence and the rest by `splitter` if there is one. Returns whether
+/// anything is still receiving the events.
+fn send_audio(
+    events: &SyncSender<Event>,
+    splitter: Option<&mut SilenceSplitter>,
+    gain: Option<f32>,
+    mut samples: Vec<f32>,
+) -> bool {
+    if let Some(gain) = gain {
+        for sample in &mut samples {
+            *sample *= gain;
+        }
+    }
+    let Some(splitter) = splitter else {
+        return events.send(Event::Audio(Segment::Audio(samples))).is_ok();
+    };
+    let mut segments = Vec::new();
+    splitter.process(&samples, &mut segments);
+    send_segments(events, segments)
+}
 
-    Ok(())
+fn send_segments(events: &SyncSender<Event>, segments: Vec<Segment>) -> bool {
+    segments
+        .into_iter()
+        .all(|segment| events.send(Event::Audio(segment)).is_ok())
 }
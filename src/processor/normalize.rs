@@ -0,0 +1,144 @@
+//! Measuring the loudness of the inputs before any of their audio is written,
+//! so that the gain that normalizes each is known when it's decoded.
+use std::{ffi::OsStr, fs::File, path::Path};
+
+use tracing::info;
+
+use super::{parallel, Error, Options};
+use crate::{
+    cancel::CancellationToken,
+    channels::{ChannelMapper, Layout},
+    decode::FileDecoder,
+    loudness::{Loudness, LoudnessMeter, Measurement, NormalizeMode, NormalizeScope},
+};
+
+/// The loudness of an input, and the gain in dB it's given.
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct Level {
+    pub(super) loudness: Option<Loudness>,
+    pub(super) gain: Option<f64>,
+}
+
+/// Measures the loudness of the inputs at `paths` as remixed to `layout`, if
+/// `options.loudness` asks for it to be measured or for gain to be applied.
+/// Returns the level of each input (or none at all, if it isn't measured) and
+/// the loudness of all of them together.
+///
+/// # Errors
+/// Returns a cancelled error once `cancel` is cancelled.
+pub(super) fn levels(
+    paths: &[impl AsRef<Path> + Sync],
+    options: &Options,
+    layout: Option<Layout>,
+    cancel: &CancellationToken,
+) -> Result<(Vec<Level>, Option<Loudness>), Error> {
+    let normalize = options
+        .loudness
+        .normalize
+        .filter(|normalize| normalize.mode == NormalizeMode::Apply);
+    if !options.loudness.measure && normalize.is_none() {
+        return Ok((Vec::new(), None));
+    }
+    // Files that can't be decoded are left for processing to report.
+    let threads = parallel::threads(options.jobs, paths.len());
+    let measurements = parallel::map(paths, threads, |path| {
+        measure(path.as_ref(), options, layout, cancel)
+    });
+    if cancel.is_cancelled() {
+        return Err(Error::Cancelled);
+    }
+    let mut book = Measurement::default();
+    for measurement in measurements.iter().flatten() {
+        book.merge(measurement);
+    }
+    let book = measurements
+        .iter()
+        .any(Option::is_some)
+        .then(|| book.loudness());
+    if let Some(book) = book {
+        info!(
+            "Loudness of the book: {:.1} LUFS, true peak {:.1} dBTP",
+            book.integrated, book.true_peak
+        );
+    }
+
+    let levels = paths
+        .iter()
+        .zip(&measurements)
+        .map(|(path, measurement)| {
+            let loudness = measurement.as_ref().map(Measurement::loudness);
+            let gain = normalize.and_then(|normalize| match normalize.scope {
+                NormalizeScope::File => loudness.map(|loudness| normalize.gain(&loudness)),
+                NormalizeScope::Book => book.map(|book| normalize.gain(&book)),
+            });
+            if let Some(loudness) = loudness {
+                info!(
+                    "Loudness of '{}': {:.1} LUFS, true peak {:.1} dBTP{}",
+                    path.as_ref().display(),
+                    loudness.integrated,
+                    loudness.true_peak,
+                    gain.map(|gain| format!(", gain {gain:+.1} dB"))
+                        .unwrap_or_default(),
+                );
+            }
+            Level { loudness, gain }
+        })
+        .collect();
+    Ok((levels, book))
+}
+
+/// Decodes all of the file at `path`, remixed to `layout` (if it's known),
+/// and measures its loudness, or returns `None` if it has no audio. A file
+/// that fails part way through is measured up to the failure.
+fn measure(
+    path: &Path,
+    options: &Options,
+    layout: Option<Layout>,
+    cancel: &CancellationToken,
+) -> Option<Measurement> {
+    let extension = path.extension().and_then(OsStr::to_str);
+    let mut decoder = FileDecoder::open(File::open(path).ok()?, extension).ok()?;
+    decoder.set_error_mode(options.errors);
+    decoder.set_cancellation(cancel.clone());
+
+    let mut measurement = None;
+    // The filters of the meter depend on the rate, so each stream of a file
+    // that changes rate is measured on its own.
+    let mut meter: Option<(u32, usize, LoudnessMeter)> = None;
+    let mut mapping: Option<ChannelMapper> = None;
+    let mut remixed = Vec::new();
+    while let Ok(Some(chunk)) = decoder.next_chunk() {
+        let (rate, channels) = (chunk.spec.rate, chunk.spec.channels);
+        let count = layout.map_or(channels.count(), Layout::count);
+        let samples = match layout {
+            Some(layout) if layout.count() != channels.count() => {
+                let mapper = match mapping.take() {
+                    Some(mapper) if mapper.input() == channels => mapping.insert(mapper),
+                    _ => mapping.insert(ChannelMapper::new(channels, layout)),
+                };
+                remixed.clear();
+                mapper.map(chunk.samples, &mut remixed);
+                &remixed
+            }
+            _ => chunk.samples,
+        };
+        let (_, _, meter) = match meter.take() {
+            Some(current @ (r, c, _)) if (r, c) == (rate, count) => meter.insert(current),
+            old => {
+                if let Some((_, _, old)) = old {
+                    measurement
+                        .get_or_insert_with(Measurement::default)
+                        .merge(&old.finish());
+                }
+                meter.insert((rate, count, LoudnessMeter::new(rate, count)))
+            }
+        };
+        meter.add(samples);
+    }
+    if let Some((_, _, meter)) = meter {
+        measurement
+            .get_or_insert_with(Measurement::default)
+            .merge(&meter.finish());
+    }
+    measurement
+}
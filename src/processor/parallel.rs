@@ -0,0 +1,101 @@
+//! Working on several inputs at once, on a pool of threads, while keeping
+//! what's made of them in the order of the inputs.
+//!
+//! Workers take the inputs in order, so the input the results are waited on
+//! is always being worked on (or finished) and the wait always ends.
+use std::{
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+        Mutex, PoisonError,
+    },
+    thread,
+};
+
+/// The number of threads to work on `inputs` inputs with, given the `jobs`
+/// option: never more than there are inputs, and by default one per CPU.
+pub(super) fn threads(jobs: Option<NonZeroUsize>, inputs: usize) -> usize {
+    jobs.or_else(|| thread::available_parallelism().ok())
+        .map_or(1, NonZeroUsize::get)
+        .min(inputs)
+        .max(1)
+}
+
+/// Applies `f` to each of `items` on up to `threads` threads, returning the
+/// results in the order of the items.
+pub(super) fn map<T: Sync, R: Send>(
+    items: &[T],
+    threads: usize,
+    f: impl Fn(&T) -> R + Sync,
+) -> Vec<R> {
+    if threads <= 1 {
+        return items.iter().map(f).collect();
+    }
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, R)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(index) else {
+                            break results;
+                        };
+                        results.push((index, f(item)));
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| {
+                worker
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            })
+            .collect()
+    });
+    results.sort_unstable_by_key(|&(index, _)| index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Has `produce` work through `items`, each with its index, on `threads`
+/// threads, sending what it makes of each item to that item's channel, while
+/// `consume` receives from the channels, one per item in the order of the
+/// items, on this thread.
+///
+/// Each channel holds up to `capacity` values, after which its producer waits
+/// for them to be received, so producers get at most that far ahead. Once
+/// `consume` returns, and drops the receivers, no more items are started and
+/// sends fail, which producers should take as their cue to stop.
+pub(super) fn stream<T: Sync, V: Send, R>(
+    items: &[T],
+    threads: usize,
+    capacity: usize,
+    produce: impl Fn(usize, &T, &SyncSender<V>) + Sync,
+    consume: impl FnOnce(Vec<Receiver<V>>) -> R,
+) -> R {
+    let (senders, receivers): (Vec<_>, Vec<_>) =
+        items.iter().map(|_| mpsc::sync_channel(capacity)).unzip();
+    let queue = Mutex::new(items.iter().enumerate().zip(senders));
+    let stopped = AtomicBool::new(false);
+    thread::scope(|scope| {
+        for _ in 0..threads.max(1) {
+            scope.spawn(|| loop {
+                let next = queue.lock().unwrap_or_else(PoisonError::into_inner).next();
+                let Some(((index, item), sender)) = next else {
+                    break;
+                };
+                if stopped.load(Ordering::Relaxed) {
+                    break;
+                }
+                produce(index, item, &sender);
+            });
+        }
+        let result = consume(receivers);
+        stopped.store(true, Ordering::Relaxed);
+        result
+    })
+}
@@ -0,0 +1,467 @@
+//! Plans of what a job would produce, made by probing the inputs rather than
+//! decoding them, so they can be checked before spending the time to encode.
+
+// Sizes and durations convert between byte counts, frame counts and seconds,
+// all of which are far too small to lose anything in these casts.
+#![allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_precision_loss,
+    clippy::cast_sign_loss
+)]
+use std::{
+    ffi::OsStr,
+    fmt,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+use tracing::info;
+
+use super::{
+    book_metadata, chapter_title, file_stem, files_near, output_format, parallel, ConsolidationJob,
+    Error, Options,
+};
+use crate::{
+    aac,
+    cancel::CancellationToken,
+    channels::Layout,
+    cover::{self, CoverSource},
+    cue::{self, FileTracks},
+    decode::{Confidence, Detection, DurationSource, FileDecoder, ProbedDuration},
+    mp4::Metadata,
+    ordering,
+    tags::Tags,
+};
+
+/// Bytes of the output besides the audio and the cover art, other than the
+/// sample table: the headers, the chapter track and the metadata.
+const OVERHEAD_BYTES: u64 = 4096;
+/// Bytes of the sample table for each packet of audio.
+const SAMPLE_TABLE_BYTES_PER_PACKET: u64 = 4;
+
+/// What a [`ConsolidationJob`] would produce, which serializes to JSON (or any
+/// other serde format), and displays as a table.
+#[derive(Debug, Serialize)]
+pub struct Plan {
+    pub output: PathBuf,
+    /// The sample rate of the output, or `None` if no input could be read.
+    pub sample_rate: Option<u32>,
+    /// The number of channels of the output.
+    pub channels: Option<usize>,
+    /// The bitrate of the output's audio, in bits per second.
+    pub bitrate: Option<u32>,
+    /// Duration of the output in seconds, leaving out any input whose
+    /// duration isn't known.
+    pub duration: f64,
+    /// Whether silence at the ends of the inputs would be trimmed, which the
+    /// duration and the chapter times still include, as finding it takes
+    /// decoding.
+    pub untrimmed: bool,
+    /// Estimated size of the output in bytes, from its bitrate and duration.
+    pub estimated_size: Option<u64>,
+    /// Metadata of the book, from the tags and the options.
+    pub metadata: Metadata,
+    pub cover: Option<PlannedCover>,
+    /// Each input, in the order they would be consolidated.
+    pub files: Vec<PlannedFile>,
+    pub chapters: Vec<PlannedChapter>,
+}
+
+/// An input, as far as probing it goes.
+#[derive(Debug, Serialize)]
+pub struct PlannedFile {
+    pub path: PathBuf,
+    /// Why the file can't be read, in which case it would be left out.
+    pub error: Option<String>,
+    pub detection: Option<Detection>,
+    /// Short name of the codec, such as `mp3`.
+    pub codec: Option<&'static str>,
+    /// Sample rate and number of channels, as given by the container.
+    pub sample_rate: Option<u32>,
+    pub channels: Option<usize>,
+    /// Duration, as found by probing, or `None` if reading the file failed
+    /// part way through.
+    pub duration: Option<ProbedDuration>,
+}
+
+/// A chapter of the output, timed in seconds. Times that depend on an input
+/// whose duration isn't known are `None`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlannedChapter {
+    pub title: String,
+    pub start: Option<f64>,
+    pub duration: Option<f64>,
+    /// Whether the chapter would be split into several at its pauses, which
+    /// takes decoding to find, so the plan has it whole.
+    pub unsplit: bool,
+}
+
+/// Where the cover art would come from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "source", content = "path", rename_all = "snake_case")]
+pub enum PlannedCover {
+    /// The cover given in the metadata options.
+    Given,
+    /// The tags of the given input.
+    Tags(PathBuf),
+    /// The given image file.
+    File(PathBuf),
+}
+
+impl ConsolidationJob {
+    /// Works out what [`run`](Self::run) would produce, without writing
+    /// anything: the order of the inputs, the chapters, the metadata and the
+    /// format and estimated size of the output. Inputs are probed for their
+    /// format, tags and duration rather than decoded, except for the start of
+    /// each, which is decoded to choose the output format just as `run` does.
+    ///
+    /// # Errors
+    /// Will return an ordering error if the inputs can't be ordered
+    /// unambiguously, a cover error if an explicitly chosen cover image can't
+    /// be read, an encode error if the options ask for a format AAC doesn't
+    /// support, or [`Error::Cancelled`] if the job is cancelled.
+    pub fn plan(&self) -> Result<Plan, Error> {
+        let options = &self.options;
+        let mut paths = self.inputs.clone();
+        ordering::sort(&mut paths, options.order)?;
+        let format = output_format(&paths, options, &self.cancel)?;
+
+        let cues = if options.chapters.cue_sheets {
+            cue::find(&paths, &files_near(&paths, cue::is_cue_sheet))
+        } else {
+            Vec::new()
+        };
+
+        let threads = parallel::threads(options.jobs, paths.len());
+        let probed = parallel::map(&paths, threads, |path| probe(path, options, &self.cancel));
+        if self.cancel.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        let mut files = Vec::with_capacity(paths.len());
+        let mut chapters = Vec::with_capacity(paths.len());
+        let mut tags = Vec::new();
+        let mut tags_cover = None;
+        let mut start = Some(0.0);
+        for (index, (file, chapter)) in probed.into_iter().enumerate() {
+            let Some((title, mut file_tags)) = chapter else {
+                files.push(file);
+                continue;
+            };
+            if let Some(cover) = file_tags.cover.take() {
+                tags_cover.get_or_insert((file.path.clone(), cover.data.len()));
+            }
+            let duration = file.duration.map(|duration| duration.seconds);
+            // As when running, the sheet's title and performer stand in for
+            // missing tags, and its tracks mark the chapters in place of the
+            // input's pauses.
+            match cues.get(index).and_then(Option::as_ref) {
+                Some(tracks) => {
+                    file_tags.album = file_tags.album.or_else(|| tracks.title.clone());
+                    file_tags.artist = file_tags.artist.or_else(|| tracks.performer.clone());
+                    push_tracks(&mut chapters, title, start, duration, tracks);
+                }
+                None => chapters.push(PlannedChapter {
+                    title,
+                    start,
+                    duration,
+                    unsplit: options.split.is_some(),
+                }),
+            }
+            tags.push(file_tags);
+            start = start
+                .zip(duration)
+                .map(|(start, duration)| start + duration);
+            files.push(file);
+        }
+
+        // As when running, the first input to be read decides what the
+        // options and the survey of the inputs leave open.
+        let first = files.iter().find(|file| file.error.is_none());
+        let sample_rate = format
+            .rate
+            .or_else(|| first.and_then(|file| file.sample_rate));
+        let layout = format
+            .layout
+            .or_else(|| first.and_then(|file| file.channels).map(Layout::for_count));
+        let bitrate =
+            layout.map(|layout| options.encoding.bitrate_per_channel * layout.count() as u32);
+
+        let (cover, cover_bytes) = self.plan_cover(tags_cover)?;
+        let cover_bytes = options
+            .cover
+            .max_bytes
+            .map_or(cover_bytes, |max| cover_bytes.min(max));
+
+        let duration: f64 = files
+            .iter()
+            .filter_map(|file| file.duration)
+            .map(|duration| duration.seconds)
+            .sum();
+        let estimated_size = sample_rate.zip(bitrate).map(|(rate, bitrate)| {
+            let audio = (duration * f64::from(bitrate) / 8.0) as u64;
+            let packets = (duration * f64::from(rate) / aac::FRAME_LEN as f64).ceil() as u64;
+            audio + packets * SAMPLE_TABLE_BYTES_PER_PACKET + cover_bytes as u64 + OVERHEAD_BYTES
+        });
+
+        Ok(Plan {
+            metadata: book_metadata(&tags, &options.metadata, file_stem(&self.output)),
+            output: self.output.clone(),
+            sample_rate,
+            channels: layout.map(Layout::count),
+            bitrate,
+            duration,
+            untrimmed: options.silence.is_some(),
+            estimated_size,
+            cover,
+            files,
+            chapters,
+        })
+    }
+
+    /// Where the cover art would come from and its size in bytes, given the
+    /// first input with a cover in its tags and the size of that cover.
+    fn plan_cover(
+        &self,
+        tags_cover: Option<(PathBuf, usize)>,
+    ) -> Result<(Option<PlannedCover>, usize), Error> {
+        let options = &self.options;
+        Ok(match &options.cover.source {
+            CoverSource::None => (None, 0),
+            _ if options.metadata.cover.is_some() => (
+                Some(PlannedCover::Given),
+                options.metadata.cover.as_ref().map_or(0, |c| c.data.len()),
+            ),
+            CoverSource::File(path) => (
+                Some(PlannedCover::File(path.clone())),
+                cover::load(path)?.data.len(),
+            ),
+            CoverSource::Auto => {
+                if let Some((path, len)) = tags_cover {
+                    (Some(PlannedCover::Tags(path)), len)
+                } else {
+                    let images = files_near(&self.inputs, cover::is_image);
+                    let path = cover::find(&images);
+                    let len = path
+                        .and_then(|path| std::fs::metadata(path).ok())
+                        .map_or(0, |m| m.len() as usize);
+                    (path.map(|path| PlannedCover::File(path.to_owned())), len)
+                }
+            }
+        })
+    }
+}
+
+/// Adds the chapters of an input starting at `start` and lasting `duration`
+/// seconds, split at the starts of its cue sheet's `tracks` as running would
+/// split it: tracks out of order, or past the end of the input, are dropped,
+/// and the input before its first track is added to the chapter before if it
+/// `continues` that chapter's track, and otherwise keeps its own `title`.
+fn push_tracks(
+    chapters: &mut Vec<PlannedChapter>,
+    title: String,
+    start: Option<f64>,
+    duration: Option<f64>,
+    tracks: &FileTracks,
+) {
+    let mut parts: Vec<(f64, String)> = Vec::new();
+    for track in &tracks.tracks {
+        let offset = track.start.as_secs_f64();
+        if duration.is_none_or(|duration| offset < duration)
+            && parts.last().is_none_or(|&(last, _)| offset > last)
+        {
+            parts.push((offset, track.chapter_title()));
+        }
+    }
+    match parts.first() {
+        Some(&(first, _)) if first > 0.0 => match chapters.last_mut() {
+            Some(before) if tracks.continues => {
+                before.duration = before.duration.map(|duration| duration + first);
+            }
+            _ => parts.insert(0, (0.0, title)),
+        },
+        Some(_) => {}
+        None => parts.push((0.0, title)),
+    }
+    let ends: Vec<Option<f64>> = parts
+        .iter()
+        .skip(1)
+        .map(|&(offset, _)| Some(offset))
+        .chain([duration])
+        .collect();
+    for ((offset, title), end) in parts.into_iter().zip(ends) {
+        chapters.push(PlannedChapter {
+            title,
+            start: start.map(|start| start + offset),
+            duration: end.map(|end| end - offset),
+            unsplit: false,
+        });
+    }
+}
+
+/// Probes the input at `path`, returning what was found and, if it can be
+/// read, the title and tags of its chapter.
+fn probe(
+    path: &Path,
+    options: &Options,
+    cancel: &CancellationToken,
+) -> (PlannedFile, Option<(String, Tags)>) {
+    let extension = path.extension().and_then(OsStr::to_str);
+    let decoder = File::open(path)
+        .map_err(Error::from)
+        .and_then(|file| Ok(FileDecoder::open(file, extension)?));
+    let mut decoder = match decoder {
+        Ok(decoder) => decoder,
+        Err(e) => {
+            info!("Would leave out '{}': {e}", path.display());
+            let file = PlannedFile {
+                path: path.to_owned(),
+                error: Some(e.to_string()),
+                detection: None,
+                codec: None,
+                sample_rate: None,
+                channels: None,
+                duration: None,
+            };
+            return (file, None);
+        }
+    };
+
+    decoder.set_cancellation(cancel.clone());
+    let title = chapter_title(path, decoder.tags(), options.chapters.titles);
+    let tags = decoder.tags().clone();
+    let mut file = PlannedFile {
+        path: path.to_owned(),
+        error: None,
+        detection: Some(decoder.detection().clone()),
+        codec: decoder.codec(),
+        sample_rate: decoder.sample_rate(),
+        channels: decoder.channels(),
+        duration: None,
+    };
+    file.duration = decoder
+        .into_duration()
+        .inspect_err(|e| info!("No duration for '{}': {e}", path.display()))
+        .ok();
+    (file, Some((title, tags)))
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Output:   {}", self.output.display())?;
+        match (self.sample_rate, self.channels, self.bitrate) {
+            (Some(rate), Some(channels), Some(bitrate)) => writeln!(
+                f,
+                "Format:   AAC, {rate}Hz, {channels} channel(s), {}kbit/s",
+                bitrate / 1000
+            )?,
+            _ => writeln!(f, "Format:   none, as no input can be read")?,
+        }
+        write!(f, "Duration: {}", timestamp(self.duration))?;
+        if self.untrimmed {
+            write!(f, " (before trimming silence)")?;
+        }
+        writeln!(f)?;
+        if let Some(size) = self.estimated_size {
+            writeln!(f, "Size:     about {:.1}MB", size as f64 / 1e6)?;
+        }
+        let metadata = &self.metadata;
+        for (name, value) in [
+            ("Title", &metadata.title),
+            ("Author", &metadata.author),
+            ("Narrator", &metadata.narrator),
+            ("Year", &metadata.year),
+            ("Genre", &metadata.genre),
+            ("Comment", &metadata.comment),
+        ] {
+            if let Some(value) = value {
+                writeln!(f, "{:<10}{value}", format!("{name}:"))?;
+            }
+        }
+        match &self.cover {
+            Some(PlannedCover::Given) => writeln!(f, "Cover:    given")?,
+            Some(PlannedCover::Tags(path)) => {
+                writeln!(f, "Cover:    from the tags of '{}'", path.display())?;
+            }
+            Some(PlannedCover::File(path)) => writeln!(f, "Cover:    '{}'", path.display())?,
+            None => writeln!(f, "Cover:    none")?,
+        }
+
+        writeln!(f)?;
+        writeln!(f, "{:>3}  {:<12}  {:<12}  Title", "#", "Start", "Duration")?;
+        let time = |t: Option<f64>| t.map_or_else(|| "?".to_owned(), timestamp);
+        for (i, chapter) in self.chapters.iter().enumerate() {
+            writeln!(
+                f,
+                "{:>3}  {:<12}  {:<12}  {}{}",
+                i + 1,
+                time(chapter.start),
+                time(chapter.duration),
+                chapter.title,
+                if chapter.unsplit { " *" } else { "" }
+            )?;
+        }
+        if self.chapters.iter().any(|chapter| chapter.unsplit) {
+            writeln!(f, "* Split into more chapters at its pauses when run.")?;
+        }
+        if self.untrimmed {
+            writeln!(f, "Times are before trimming silence.")?;
+        }
+
+        writeln!(f)?;
+        for file in &self.files {
+            let name = file.path.file_name().unwrap_or(file.path.as_os_str());
+            write!(f, "{}: ", name.to_string_lossy())?;
+            match (&file.error, &file.detection) {
+                (Some(error), _) => writeln!(f, "left out, {error}")?,
+                (None, Some(detection)) => {
+                    write!(f, "{detection}")?;
+                    if let Some(codec) = file.codec {
+                        write!(f, ", {codec}")?;
+                    }
+                    if let Some(rate) = file.sample_rate {
+                        write!(f, ", {rate}Hz")?;
+                    }
+                    if let Some(channels) = file.channels {
+                        write!(f, ", {channels} channel(s)")?;
+                    }
+                    match file.duration {
+                        Some(duration) => writeln!(
+                            f,
+                            ", {} ({})",
+                            timestamp(duration.seconds),
+                            describe(duration)
+                        )?,
+                        None => writeln!(f, ", ?")?,
+                    }
+                }
+                (None, None) => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where `duration` came from, and how far it can be trusted.
+fn describe(duration: ProbedDuration) -> &'static str {
+    match (duration.source, duration.confidence) {
+        (DurationSource::Container, _) => "from the container",
+        (DurationSource::Xing, Confidence::Exact) => "from the Xing and encoder headers",
+        (DurationSource::Xing, Confidence::Approximate) => "from the Xing header, approximate",
+        (DurationSource::Vbri, _) => "from the VBRI header, approximate",
+        (DurationSource::PacketScan, _) => "from the packets",
+        (DurationSource::Decode, _) => "decoded",
+    }
+}
+
+/// `seconds` as `h:mm:ss.mmm`.
+fn timestamp(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!(
+        "{}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
@@ -0,0 +1,50 @@
+//! Progress of a consolidation job, reported as it runs so that it can be
+//! shown.
+//!
+//! Inputs are decoded on several threads at once, so events about different
+//! inputs can arrive together, from different threads. Each input is known by
+//! its index in the order of the chapters.
+use std::path::Path;
+
+/// Something that happened in a job.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Progress<'a> {
+    /// The job is starting on `files` inputs.
+    Started { files: usize },
+    /// Decoding of an input has started. Its duration, in seconds, is as the
+    /// container gives it, if it does, and may only be an estimate.
+    FileStarted {
+        index: usize,
+        path: &'a Path,
+        duration: Option<f64>,
+    },
+    /// `seconds` of an input's audio have been decoded, of an estimated
+    /// `duration`.
+    Decoded {
+        index: usize,
+        seconds: f64,
+        duration: Option<f64>,
+    },
+    /// An input's audio has been encoded, up to `seconds` into the output.
+    Encoded { index: usize, seconds: f64 },
+    /// An input is finished with, and either made it into the output or
+    /// `failed`.
+    FileFinished { index: usize, failed: bool },
+    /// Every input has been encoded, and the output is being finished: the
+    /// last of the audio, the sample tables, chapters, metadata and cover art
+    /// are written.
+    Finalizing,
+    /// The job is over, whether or not it succeeded.
+    Finished,
+}
+
+/// Receives the progress of a job. It's called from the job's threads, so it
+/// should return quickly.
+pub trait ProgressSink: Send + Sync {
+    fn progress(&self, event: Progress<'_>);
+}
+
+/// Ignores the progress.
+impl ProgressSink for () {
+    fn progress(&self, _: Progress<'_>) {}
+}
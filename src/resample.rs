@@ -0,0 +1,289 @@
+//! Sample-rate conversion, so that inputs at different rates can be joined
+//! into a single track.
+//!
+//! Each output sample is interpolated from the input with a windowed sinc
+//! filter. The filter is sampled at a fixed set of phases between input
+//! samples; where the ratio of the rates needs more phases than that, the
+//! output times are rounded to the nearest phase. The cutoff is set so the
+//! filter's stopband starts at the lower of the two Nyquist frequencies,
+//! which keeps aliases (and images) down to the stopband attenuation.
+//!
+//! ref
+//!   <https://ccrma.stanford.edu/~jos/resample/>
+
+// Filter design and indexing convert freely between sample counts, positions
+// and floating point values, all of which are small enough for these casts.
+#![allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_precision_loss,
+    clippy::cast_sign_loss
+)]
+use std::{collections::HashMap, f64::consts::PI};
+
+/// Most phases the filter is sampled at.
+const MAX_PHASES: u64 = 4096;
+
+/// The output sample rate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TargetRate {
+    /// The rate of the most inputs, preferring the higher rate on a tie.
+    #[default]
+    MostCommon,
+    /// The highest rate of any input.
+    Highest,
+    /// The given rate.
+    Fixed(u32),
+}
+
+impl TargetRate {
+    /// Chooses the output rate for inputs at `rates`, or `None` if there are
+    /// no inputs to choose from.
+    #[must_use]
+    pub fn choose(self, rates: &[u32]) -> Option<u32> {
+        match self {
+            Self::MostCommon => {
+                let mut counts = HashMap::new();
+                for &rate in rates {
+                    *counts.entry(rate).or_insert(0) += 1;
+                }
+                counts
+                    .into_iter()
+                    .max_by_key(|&(rate, count)| (count, rate))
+                    .map(|(rate, _)| rate)
+            }
+            Self::Highest => rates.iter().copied().max(),
+            Self::Fixed(rate) => Some(rate),
+        }
+    }
+}
+
+/// Trades the accuracy of the conversion for speed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Quality {
+    /// A short filter with about 60dB of stopband attenuation, flat to 77%
+    /// of the lower Nyquist frequency.
+    Fast,
+    /// About 90dB of attenuation, flat to 88% of the Nyquist frequency.
+    #[default]
+    Balanced,
+    /// About 120dB of attenuation, flat to 94% of the Nyquist frequency.
+    Best,
+}
+
+impl Quality {
+    /// Half the length of the filter, in input samples.
+    fn half_len(self) -> usize {
+        match self {
+            Self::Fast => 16,
+            Self::Balanced => 48,
+            Self::Best => 128,
+        }
+    }
+
+    /// Stopband attenuation of the filter, in dB.
+    fn attenuation(self) -> f64 {
+        match self {
+            Self::Fast => 60.0,
+            Self::Balanced => 90.0,
+            Self::Best => 120.0,
+        }
+    }
+}
+
+/// How to convert inputs to a common sample rate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResampleOptions {
+    pub rate: TargetRate,
+    pub quality: Quality,
+}
+
+/// Converts interleaved audio from one sample rate to another.
+///
+/// Output sample `n` is the input interpolated at time `n * from / to`, so the
+/// output is aligned with the input, with no delay.
+pub struct Resampler {
+    from: u32,
+    to: u32,
+    channels: usize,
+    /// The ratio of the rates in lowest terms: each output sample advances
+    /// `step` `phases`ths of an input sample.
+    step: u64,
+    phases: u64,
+    /// Phases the filter is sampled at, at most `phases`.
+    filter_phases: u64,
+    taps: usize,
+    /// The filter for each of `filter_phases + 1` phases, `taps` coefficients
+    /// at a time.
+    filter: Vec<f32>,
+    /// Interleaved input that is still needed, preceded at the start by
+    /// silence to fill the filter.
+    buf: Vec<f32>,
+    /// Time of the next output sample, in `phases`ths of an input sample from
+    /// the start of `buf`.
+    pos: u64,
+    frames_in: u64,
+    frames_out: u64,
+}
+
+impl Resampler {
+    /// Creates a resampler from rate `from` to rate `to` for audio with
+    /// `channels` channels.
+    ///
+    /// # Panics
+    /// Panics if either rate or `channels` is zero.
+    #[must_use]
+    pub fn new(from: u32, to: u32, channels: usize, quality: Quality) -> Self {
+        assert!(from > 0 && to > 0 && channels > 0);
+        let gcd = gcd(u64::from(from), u64::from(to));
+        let (step, phases) = (u64::from(from) / gcd, u64::from(to) / gcd);
+        let filter_phases = phases.min(MAX_PHASES);
+
+        let half = quality.half_len();
+        let taps = 2 * half;
+        let attenuation = quality.attenuation();
+        let beta = 0.1102 * (attenuation - 8.7);
+        // Width of the transition band of a Kaiser-windowed filter of this
+        // length, as a fraction of the input's Nyquist frequency.
+        let transition = (attenuation - 7.95) / (2.285 * taps as f64) / PI;
+        let ratio = (f64::from(to) / f64::from(from)).min(1.0);
+        let cutoff = ratio - transition / 2.0;
+
+        let mut filter = Vec::with_capacity((filter_phases as usize + 1) * taps);
+        for phase in 0..=filter_phases {
+            let offset = phase as f64 / filter_phases as f64;
+            let row: Vec<f64> = (0..taps)
+                .map(|k| {
+                    // Distance from the output time to the input sample.
+                    let x = offset + (half - 1) as f64 - k as f64;
+                    cutoff * sinc(cutoff * x) * kaiser(x / half as f64, beta)
+                })
+                .collect();
+            // Normalize each phase to unity gain at DC.
+            let sum: f64 = row.iter().sum();
+            filter.extend(row.iter().map(|h| (h / sum) as f32));
+        }
+
+        Self {
+            from,
+            to,
+            channels,
+            step,
+            phases,
+            filter_phases,
+            taps,
+            filter,
+            buf: vec![0.0; (half - 1) * channels],
+            pos: (half as u64 - 1) * phases,
+            frames_in: 0,
+            frames_out: 0,
+        }
+    }
+
+    /// The rate the input is converted from.
+    #[must_use]
+    pub fn from_rate(&self) -> u32 {
+        self.from
+    }
+
+    /// The rate the input is converted to.
+    #[must_use]
+    pub fn to_rate(&self) -> u32 {
+        self.to
+    }
+
+    #[must_use]
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Converts the interleaved `input`, appending to `output` the samples
+    /// that can be produced so far.
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        self.buf.extend_from_slice(input);
+        self.frames_in += (input.len() / self.channels) as u64;
+        self.run(u64::MAX, output);
+    }
+
+    /// Finishes the conversion, appending the rest of the output to `output`,
+    /// so that in all `round(frames in * to / from)` frames are output.
+    pub fn finish(&mut self, output: &mut Vec<f32>) {
+        let total =
+            (self.frames_in * u64::from(self.to) + u64::from(self.from) / 2) / u64::from(self.from);
+        // Follow the input with enough silence to fill the filter for the
+        // last output sample.
+        self.buf
+            .resize(self.buf.len() + (self.taps + 1) * self.channels, 0.0);
+        self.run(total, output);
+    }
+
+    /// Produces output samples until the filter runs out of input or `limit`
+    /// frames have been output in all.
+    fn run(&mut self, limit: u64, output: &mut Vec<f32>) {
+        let channels = self.channels;
+        let half = self.taps / 2;
+        let frames = self.buf.len() / channels;
+        while self.frames_out < limit {
+            let start = (self.pos / self.phases) as usize + 1 - half;
+            if start + self.taps > frames {
+                break;
+            }
+            let offset = self.pos % self.phases;
+            let phase = (offset * self.filter_phases + self.phases / 2) / self.phases;
+            let coefficients = &self.filter[phase as usize * self.taps..][..self.taps];
+            let window = &self.buf[start * channels..(start + self.taps) * channels];
+            for channel in 0..channels {
+                let sample = coefficients
+                    .iter()
+                    .zip(window[channel..].iter().step_by(channels))
+                    .map(|(h, x)| h * x)
+                    .sum();
+                output.push(sample);
+            }
+            self.pos += self.step;
+            self.frames_out += 1;
+        }
+        // Drop the input that no later output sample needs.
+        let start = ((self.pos / self.phases) as usize + 1 - half).min(frames);
+        self.buf.drain(..start * channels);
+        self.pos -= start as u64 * self.phases;
+    }
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// The Kaiser window with shape `beta`, over `-1..=1`.
+fn kaiser(x: f64, beta: f64) -> f64 {
+    if x.abs() > 1.0 {
+        0.0
+    } else {
+        bessel_i0(beta * (1.0 - x * x).sqrt()) / bessel_i0(beta)
+    }
+}
+
+/// The zeroth-order modified Bessel function of the first kind, from its
+/// power series.
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    for k in 1..64 {
+        term *= (x / (2.0 * f64::from(k))).powi(2);
+        sum += term;
+        if term < sum * 1e-17 {
+            break;
+        }
+    }
+    sum
+}
@@ -0,0 +1,324 @@
+//! Silence in the inputs: at their starts and ends, where it's trimmed so
+//! that the gap between chapters is the same throughout the book, and within
+//! them, where long pauses mark where an input can be split into chapters.
+//!
+//! Rippers often leave seconds of silence at each end of every track, so that
+//! joined as they are, chapters are separated by the sum of the silence at
+//! the end of one and the start of the next. Audio is silent where every
+//! channel is below the threshold; runs of silence at the start or end of an
+//! input that are at least the minimum duration are split off, and the
+//! silence on either side of each join is cut down to the gap, keeping the
+//! silence nearest the sound. Quieter runs within an input are left alone.
+//!
+//! A book that comes as a single file would otherwise be a single chapter.
+//! Its pauses, runs of silence within it at least a minimum duration, are
+//! candidates for chapter points; the longest are taken first, leaving every
+//! chapter at least a minimum length, until there are as many chapters as
+//! asked for, or no more pauses.
+
+// Durations convert between seconds and frame counts, which are far too small
+// to lose anything in these casts.
+#![allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_precision_loss,
+    clippy::cast_sign_loss
+)]
+use std::{num::NonZeroUsize, time::Duration};
+
+/// Longest run of silence that's held back until what follows it is known.
+/// Beyond this, silence within an input is let go as audio (so any more than
+/// this at the end of an input isn't trimmed), and silence at the start as
+/// leading silence.
+const MAX_HELD_SECONDS: u32 = 60;
+
+/// How silence at the starts and ends of the inputs is trimmed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SilenceOptions {
+    /// Level below which audio counts as silence, in dB relative to full
+    /// scale.
+    pub threshold: f32,
+    /// Shortest run of silence that's trimmed.
+    pub min_duration: Duration,
+    /// Silence left between chapters; half of it is left at the start and
+    /// end of the book.
+    pub gap: Duration,
+}
+
+impl Default for SilenceOptions {
+    fn default() -> Self {
+        Self {
+            threshold: -50.0,
+            min_duration: Duration::from_secs(1),
+            gap: Duration::from_secs(1),
+        }
+    }
+}
+
+impl SilenceOptions {
+    /// The gap in frames at `rate`.
+    #[must_use]
+    pub fn gap_frames(&self, rate: u32) -> usize {
+        frames(self.gap, rate)
+    }
+}
+
+/// How each input is split into chapters at its pauses.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SplitOptions {
+    /// Level below which audio counts as silence, in dB relative to full
+    /// scale.
+    pub threshold: f32,
+    /// Shortest run of silence that can separate chapters.
+    pub min_pause: Duration,
+    /// Shortest chapter to make.
+    pub min_chapter: Duration,
+    /// How many chapters to split each input into, if it has enough pauses,
+    /// or `None` for as many as there are pauses.
+    pub target_chapters: Option<NonZeroUsize>,
+}
+
+impl Default for SplitOptions {
+    fn default() -> Self {
+        Self {
+            threshold: -50.0,
+            min_pause: Duration::from_secs(2),
+            min_chapter: Duration::from_mins(5),
+            target_chapters: None,
+        }
+    }
+}
+
+impl SplitOptions {
+    /// The shortest chapter in frames at `rate`.
+    #[must_use]
+    pub fn min_chapter_frames(&self, rate: u32) -> u64 {
+        frames(self.min_chapter, rate) as u64
+    }
+}
+
+/// A part of an input's audio.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    /// Silence at the start of the input, long enough to be trimmed, which
+    /// may come in more than one part. An input that's silent throughout is
+    /// all leading silence.
+    Leading(Vec<f32>),
+    Audio(Vec<f32>),
+    /// Silence at the end of the input, long enough to be trimmed.
+    Trailing(Vec<f32>),
+}
+
+/// Splits the leading and trailing silence off an input's interleaved audio.
+pub struct SilenceSplitter {
+    /// Linear amplitude below which a sample is silent.
+    threshold: f32,
+    channels: usize,
+    /// Samples in the shortest run of silence that's split off.
+    min_samples: usize,
+    max_held_samples: usize,
+    /// Whether there's been any sound yet.
+    sound: bool,
+    /// Samples of leading silence already let go.
+    leading: usize,
+    /// The silence since the last sound, or since the start.
+    held: Vec<f32>,
+}
+
+impl SilenceSplitter {
+    /// Creates a splitter for audio at `rate` with `channels` channels.
+    #[must_use]
+    pub fn new(options: &SilenceOptions, rate: u32, channels: usize) -> Self {
+        Self {
+            threshold: amplitude(options.threshold),
+            channels,
+            min_samples: frames(options.min_duration, rate) * channels,
+            max_held_samples: (MAX_HELD_SECONDS * rate) as usize * channels,
+            sound: false,
+            leading: 0,
+            held: Vec::new(),
+        }
+    }
+
+    /// Takes the next interleaved `samples`, appending to `out` whatever of
+    /// the audio so far can't be trailing silence.
+    pub fn process(&mut self, samples: &[f32], out: &mut Vec<Segment>) {
+        let loud = |frame: &[f32]| is_loud(frame, self.threshold);
+        let mut frames = samples.chunks_exact(self.channels);
+        let Some(last) = frames.clone().rposition(loud) else {
+            self.hold(samples, out);
+            return;
+        };
+        let end = (last + 1) * self.channels;
+
+        let mut audio = std::mem::take(&mut self.held);
+        if self.sound {
+            audio.extend(&samples[..end]);
+        } else {
+            self.sound = true;
+            let first = frames.position(loud).unwrap_or(last) * self.channels;
+            audio.extend(&samples[..first]);
+            if self.leading + audio.len() >= self.min_samples {
+                out.push(Segment::Leading(audio));
+                audio = Vec::new();
+            }
+            audio.extend(&samples[first..end]);
+        }
+        out.push(Segment::Audio(audio));
+        self.held.extend(&samples[end..]);
+    }
+
+    /// Appends the last of the audio to `out`.
+    pub fn finish(self, out: &mut Vec<Segment>) {
+        if self.held.is_empty() {
+            return;
+        }
+        let long = self.leading + self.held.len() >= self.min_samples;
+        out.push(match (self.sound, long) {
+            (_, false) => Segment::Audio(self.held),
+            (true, true) => Segment::Trailing(self.held),
+            (false, true) => Segment::Leading(self.held),
+        });
+    }
+
+    /// Holds back silent `samples`, letting go of any silence beyond what's
+    /// held at most.
+    fn hold(&mut self, samples: &[f32], out: &mut Vec<Segment>) {
+        self.held.extend(samples);
+        if self.held.len() > self.max_held_samples {
+            let excess = self.held.len() - self.max_held_samples;
+            let excess: Vec<f32> = self.held.drain(..excess - excess % self.channels).collect();
+            if self.sound {
+                out.push(Segment::Audio(excess));
+            } else {
+                self.leading += excess.len();
+                out.push(Segment::Leading(excess));
+            }
+        }
+    }
+}
+
+/// A run of silence after some sound, in frames from the start of the audio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pause {
+    pub start: u64,
+    pub frames: u64,
+}
+
+impl Pause {
+    /// The frame in the middle of the pause, where a chapter would start.
+    #[must_use]
+    pub fn middle(&self) -> u64 {
+        self.start + self.frames / 2
+    }
+}
+
+/// Finds the pauses in interleaved audio: runs of silence at least the
+/// minimum length that sound follows.
+pub struct PauseDetector {
+    threshold: f32,
+    channels: usize,
+    min_frames: u64,
+    /// Whether there's been any sound yet.
+    sound: bool,
+    /// The start of the silence since the last sound.
+    silence: Option<u64>,
+    pauses: Vec<Pause>,
+}
+
+impl PauseDetector {
+    /// Creates a detector for audio at `rate` with `channels` channels.
+    #[must_use]
+    pub fn new(options: &SplitOptions, rate: u32, channels: usize) -> Self {
+        Self {
+            threshold: amplitude(options.threshold),
+            channels,
+            min_frames: frames(options.min_pause, rate) as u64,
+            sound: false,
+            silence: None,
+            pauses: Vec::new(),
+        }
+    }
+
+    /// Takes the next interleaved `samples`, the first frame of which is
+    /// `position` frames from the start of the audio.
+    pub fn process(&mut self, samples: &[f32], position: u64) {
+        for (frame, position) in samples.chunks_exact(self.channels).zip(position..) {
+            if !is_loud(frame, self.threshold) {
+                if self.sound {
+                    self.silence.get_or_insert(position);
+                }
+                continue;
+            }
+            self.sound = true;
+            if let Some(start) = self.silence.take() {
+                if position - start >= self.min_frames {
+                    self.pauses.push(Pause {
+                        start,
+                        frames: position - start,
+                    });
+                }
+            }
+        }
+    }
+
+    /// The pauses found, in order. Silence at the very end isn't a pause.
+    #[must_use]
+    pub fn finish(self) -> Vec<Pause> {
+        self.pauses
+    }
+}
+
+/// Where to start chapters in the audio from frame `start` to `end`, given
+/// its `pauses`: the middles of the longest pauses, leaving every chapter at
+/// least `min_chapter` frames, and making at most `target` chapters. Returns
+/// the starts of the chapters after the first, in order.
+#[must_use]
+pub fn chapter_points(
+    pauses: &[Pause],
+    start: u64,
+    end: u64,
+    min_chapter: u64,
+    target: Option<NonZeroUsize>,
+) -> Vec<u64> {
+    let mut longest = pauses.to_vec();
+    longest.sort_by_key(|pause| std::cmp::Reverse(pause.frames));
+    let limit = target.map_or(usize::MAX, |target| target.get() - 1);
+    let mut points: Vec<u64> = Vec::new();
+    for point in longest.iter().map(Pause::middle) {
+        if points.len() == limit {
+            break;
+        }
+        let far = |other: u64| point.abs_diff(other) >= min_chapter;
+        if far(start) && far(end) && points.iter().all(|&other| far(other)) {
+            points.push(point);
+        }
+    }
+    points.sort_unstable();
+    points
+}
+
+/// How many frames of the silence `before` and `after` a join to keep, so that
+/// together they're at most `gap` frames, keeping an even share on each side
+/// where there's enough.
+#[must_use]
+pub fn split_gap(before: usize, after: usize, gap: usize) -> (usize, usize) {
+    if before + after <= gap {
+        return (before, after);
+    }
+    let keep_before = before.min((gap / 2).max(gap.saturating_sub(after)));
+    (keep_before, gap - keep_before)
+}
+
+/// The linear amplitude of a level in dB relative to full scale.
+fn amplitude(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// Whether any channel of `frame` is at or above `threshold`.
+fn is_loud(frame: &[f32], threshold: f32) -> bool {
+    frame.iter().any(|sample| sample.abs() >= threshold)
+}
+
+fn frames(duration: Duration, rate: u32) -> usize {
+    (duration.as_secs_f64() * f64::from(rate)).round() as usize
+}
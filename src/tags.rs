@@ -0,0 +1,355 @@
+//! Tags read from input files, and the book-level metadata derived from them.
+//!
+//! `ID3v2`, Vorbis comments, RIFF INFO and MP4 `ilst` tags are read through
+//! symphonia; `APEv2` tags, which symphonia doesn't support, are read from the
+//! end of the file directly. Cover art is taken from symphonia's visuals
+//! (`ID3v2` APIC frames and FLAC PICTURE blocks), and from the base64 encoded
+//! FLAC picture blocks of `METADATA_BLOCK_PICTURE` Vorbis comments.
+//!
+//! ref
+//!   <https://wiki.hydrogenaud.io/index.php?title=APEv2_specification>
+//!   <https://xiph.org/flac/format.html#metadata_block_picture>
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+};
+
+use symphonia::core::meta::{MetadataRevision, StandardTagKey, StandardVisualKey};
+use tracing::warn;
+
+use crate::{
+    cover,
+    decode::{self, Error, Probed},
+    mp4::{CoverArt, Metadata},
+};
+
+const APE_PREAMBLE: &[u8; 8] = b"APETAGEX";
+const APE_FOOTER_LEN: usize = 32;
+const ID3V1_LEN: u64 = 128;
+/// Tags larger than this are assumed to be corrupt.
+const APE_MAX_LEN: u32 = 16 * 1024 * 1024;
+/// Vorbis comment holding a base64 encoded FLAC picture block.
+const PICTURE_COMMENT: &str = "METADATA_BLOCK_PICTURE";
+/// FLAC (and `ID3v2`) picture type of a front cover.
+const FRONT_COVER: u32 = 3;
+
+/// The tags of an input file that are used by the consolidator.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tags {
+    pub title: Option<String>,
+    pub album: Option<String>,
+    pub artist: Option<String>,
+    pub album_artist: Option<String>,
+    pub composer: Option<String>,
+    pub date: Option<String>,
+    pub genre: Option<String>,
+    pub comment: Option<String>,
+    pub track: Option<u32>,
+    pub disc: Option<u32>,
+    /// The front cover, or failing that the first picture.
+    pub cover: Option<CoverArt>,
+}
+
+impl Tags {
+    /// Sets the field for `key` to `value`, unless it is already set.
+    fn fill(&mut self, key: StandardTagKey, value: &str) {
+        let value = value.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+        if value.is_empty() {
+            return;
+        }
+        let text = match key {
+            StandardTagKey::TrackTitle => &mut self.title,
+            StandardTagKey::Album => &mut self.album,
+            StandardTagKey::Artist => &mut self.artist,
+            StandardTagKey::AlbumArtist => &mut self.album_artist,
+            StandardTagKey::Composer => &mut self.composer,
+            StandardTagKey::Date | StandardTagKey::ReleaseDate | StandardTagKey::OriginalDate => {
+                &mut self.date
+            }
+            StandardTagKey::Genre => &mut self.genre,
+            StandardTagKey::Comment => &mut self.comment,
+            StandardTagKey::TrackNumber => {
+                self.track = self.track.or(leading_number(value));
+                return;
+            }
+            StandardTagKey::DiscNumber => {
+                self.disc = self.disc.or(leading_number(value));
+                return;
+            }
+            _ => return,
+        };
+        if text.is_none() {
+            *text = Some(value.to_owned());
+        }
+    }
+
+    /// Fills in any missing tags from `revision`.
+    fn merge(&mut self, revision: &MetadataRevision) {
+        for tag in revision.tags() {
+            if let Some(key) = tag.std_key {
+                self.fill(key, &tag.value.to_string());
+            }
+        }
+        if self.cover.is_none() {
+            self.cover = revision_cover(revision);
+        }
+    }
+}
+
+/// Probes `file`, returning the probe result along with the file's tags.
+///
+/// Tags stored in the container take precedence over those found before it
+/// (such as an `ID3v2` tag in front of a FLAC stream), which in turn take
+/// precedence over an `APEv2` tag at the end of the file.
+///
+/// # Errors
+/// Returns an error if the file's format isn't recognized.
+pub fn probe(mut file: File, extension: Option<&str>) -> Result<(Probed, Tags), Error> {
+    let ape = match read_ape(&mut file) {
+        Ok(ape) => ape,
+        Err(e) => {
+            warn!("Ignoring unreadable APE tag: {e}");
+            Vec::new()
+        }
+    };
+
+    let mut probed = decode::probe(file, extension)?;
+    let mut tags = Tags::default();
+    if let Some(revision) = probed.format.metadata().current() {
+        tags.merge(revision);
+    }
+    if let Some(log) = probed.metadata.as_mut() {
+        if let Some(revision) = log.metadata().current() {
+            tags.merge(revision);
+        }
+    }
+    for (key, value) in ape {
+        if let Some(key) = ape_key(&key) {
+            tags.fill(key, &value);
+        }
+    }
+    Ok((probed, tags))
+}
+
+/// Reads the tags of `file`, whose name has the extension `extension`.
+///
+/// # Errors
+/// Returns an error if the file's format isn't recognized.
+pub fn read(file: File, extension: Option<&str>) -> Result<Tags, Error> {
+    probe(file, extension).map(|(_, tags)| tags)
+}
+
+/// Derives the metadata of the whole book from the tags of its files.
+///
+/// Each field takes the value shared by the most files (earlier files win
+/// ties): the title from the album, the author from the album artist (or the
+/// artist) and the narrator from the composer.
+#[must_use]
+pub fn book_metadata(files: &[Tags]) -> Metadata {
+    let pick = |field: fn(&Tags) -> Option<&str>| most_common(files.iter().filter_map(field));
+    Metadata {
+        title: pick(|t| t.album.as_deref()),
+        author: pick(|t| t.album_artist.as_deref()).or_else(|| pick(|t| t.artist.as_deref())),
+        narrator: pick(|t| t.composer.as_deref()),
+        year: pick(|t| t.date.as_deref().and_then(year)),
+        genre: pick(|t| t.genre.as_deref()),
+        comment: pick(|t| t.comment.as_deref()),
+        ..Metadata::default()
+    }
+}
+
+/// The front cover in `revision`, or failing that its first picture that is
+/// a JPEG or PNG image.
+fn revision_cover(revision: &MetadataRevision) -> Option<CoverArt> {
+    let visuals = revision.visuals().iter().map(|v| {
+        (
+            v.usage == Some(StandardVisualKey::FrontCover),
+            v.data.to_vec(),
+        )
+    });
+    let comments = revision
+        .tags()
+        .iter()
+        .filter(|tag| tag.key.eq_ignore_ascii_case(PICTURE_COMMENT))
+        .filter_map(|tag| flac_picture(&base64(&tag.value.to_string())?))
+        .map(|(kind, data)| (kind == FRONT_COVER, data));
+
+    let mut pictures: Vec<(bool, CoverArt)> = visuals
+        .chain(comments)
+        .filter_map(|(front, data)| Some((front, cover::from_bytes(data).ok()?)))
+        .collect();
+    let i = pictures.iter().position(|&(front, _)| front).unwrap_or(0);
+    (i < pictures.len()).then(|| pictures.swap_remove(i).1)
+}
+
+/// Parses a FLAC picture block into the picture type and the image data.
+fn flac_picture(block: &[u8]) -> Option<(u32, Vec<u8>)> {
+    fn field(rest: &mut &[u8]) -> Option<usize> {
+        let (value, tail) = rest.split_first_chunk::<4>()?;
+        *rest = tail;
+        Some(u32::from_be_bytes(*value) as usize)
+    }
+    let mut rest = block;
+    let kind = u32::try_from(field(&mut rest)?).ok()?;
+    let mime_len = field(&mut rest)?;
+    rest = rest.get(mime_len..)?;
+    let description_len = field(&mut rest)?;
+    // Skip the description, then the width, height, color depth and palette
+    // size.
+    rest = rest.get(description_len + 16..)?;
+    let len = field(&mut rest)?;
+    Some((kind, rest.get(..len)?.to_vec()))
+}
+
+/// Decodes standard base64, ignoring whitespace.
+fn base64(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    let mut acc = 0u32;
+    let mut n_bits = 0;
+    for c in text.bytes().filter(|c| !c.is_ascii_whitespace()) {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => return None,
+        };
+        acc = (acc << 6) | u32::from(value);
+        n_bits += 6;
+        if n_bits >= 8 {
+            n_bits -= 8;
+            out.push((acc >> n_bits).to_be_bytes()[3]);
+            acc &= (1 << n_bits) - 1;
+        }
+    }
+    Some(out)
+}
+
+/// The value that occurs most often, preferring the earliest on ties.
+fn most_common<'a>(values: impl Iterator<Item = &'a str>) -> Option<String> {
+    let mut counts: Vec<(&str, usize)> = Vec::new();
+    let mut index = HashMap::new();
+    for value in values {
+        let i = *index.entry(value).or_insert_with(|| {
+            counts.push((value, 0));
+            counts.len() - 1
+        });
+        counts[i].1 += 1;
+    }
+    counts
+        .iter()
+        .rev()
+        .max_by_key(|&&(_, count)| count)
+        .map(|&(value, _)| value.to_owned())
+}
+
+/// The first four digit run in a date, such as the 2004 in `2004-05-06`.
+fn year(date: &str) -> Option<&str> {
+    let bytes = date.as_bytes();
+    (0..bytes.len().saturating_sub(3))
+        .find(|&i| bytes[i..i + 4].iter().all(u8::is_ascii_digit))
+        .map(|i| &date[i..i + 4])
+}
+
+/// Parses the number at the start of `value`, so that `"3/12"` is 3.
+fn leading_number(value: &str) -> Option<u32> {
+    let value = value.trim();
+    let end = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    value[..end].parse().ok()
+}
+
+fn ape_key(key: &str) -> Option<StandardTagKey> {
+    Some(match key.to_ascii_lowercase().as_str() {
+        "title" => StandardTagKey::TrackTitle,
+        "album" => StandardTagKey::Album,
+        "artist" => StandardTagKey::Artist,
+        "album artist" | "albumartist" => StandardTagKey::AlbumArtist,
+        "composer" => StandardTagKey::Composer,
+        "year" => StandardTagKey::Date,
+        "genre" => StandardTagKey::Genre,
+        "comment" => StandardTagKey::Comment,
+        "track" => StandardTagKey::TrackNumber,
+        "disc" => StandardTagKey::DiscNumber,
+        _ => return None,
+    })
+}
+
+/// Reads the text items of an `APEv2` tag at the end of `file` (possibly
+/// followed by an `ID3v1` tag), leaving the file positioned at its start.
+fn read_ape(file: &mut File) -> io::Result<Vec<(String, String)>> {
+    let items = find_ape(file);
+    file.seek(SeekFrom::Start(0))?;
+    items
+}
+
+fn find_ape(file: &mut File) -> io::Result<Vec<(String, String)>> {
+    let len = file.seek(SeekFrom::End(0))?;
+    for trailer in [0, ID3V1_LEN] {
+        let Some(footer_pos) = len.checked_sub(trailer + APE_FOOTER_LEN as u64) else {
+            continue;
+        };
+        let mut footer = [0; APE_FOOTER_LEN];
+        file.seek(SeekFrom::Start(footer_pos))?;
+        file.read_exact(&mut footer)?;
+        if &footer[..8] != APE_PREAMBLE {
+            continue;
+        }
+
+        let field =
+            |i: usize| u32::from_le_bytes([footer[i], footer[i + 1], footer[i + 2], footer[i + 3]]);
+        // The tag size includes the footer but not the optional header.
+        let size = field(12);
+        let count = field(16);
+        if size > APE_MAX_LEN || (size as usize) < APE_FOOTER_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "bad APE tag size",
+            ));
+        }
+        let items_len = u64::from(size) - APE_FOOTER_LEN as u64;
+        let Some(items_pos) = footer_pos.checked_sub(items_len) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "bad APE tag size",
+            ));
+        };
+        let mut items = vec![0; usize::try_from(items_len).unwrap_or(0)];
+        file.seek(SeekFrom::Start(items_pos))?;
+        file.read_exact(&mut items)?;
+        return Ok(parse_ape_items(&items, count));
+    }
+    Ok(Vec::new())
+}
+
+fn parse_ape_items(mut items: &[u8], count: u32) -> Vec<(String, String)> {
+    let mut parsed = Vec::new();
+    for _ in 0..count {
+        if items.len() < 8 {
+            break;
+        }
+        let value_len = u32::from_le_bytes([items[0], items[1], items[2], items[3]]) as usize;
+        let flags = u32::from_le_bytes([items[4], items[5], items[6], items[7]]);
+        let rest = &items[8..];
+        let Some(key_end) = rest.iter().position(|&b| b == 0) else {
+            break;
+        };
+        let value_start = key_end + 1;
+        let Some(value) = rest.get(value_start..value_start + value_len) else {
+            break;
+        };
+        // Bits 1-2 of the flags give the item type; 0 is UTF-8 text.
+        if flags & 0b110 == 0 {
+            let key = String::from_utf8_lossy(&rest[..key_end]).into_owned();
+            // Multiple values are separated by NUL; only the first is kept.
+            let value = value.split(|&b| b == 0).next().unwrap_or_default();
+            parsed.push((key, String::from_utf8_lossy(value).into_owned()));
+        }
+        items = &rest[value_start + value_len..];
+    }
+    parsed
+}
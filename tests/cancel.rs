@@ -0,0 +1,66 @@
+//! Cancelling a job part way through.
+use std::sync::Arc;
+
+use consolidator::{
+    cancel::CancellationToken,
+    processor::{ConsolidationJob, Error},
+    progress::{Progress, ProgressSink},
+};
+
+mod common;
+
+use common::{wav, TempDir};
+
+/// Cancels the job once an event matching `when` arrives.
+struct CancelWhen<F> {
+    token: CancellationToken,
+    when: F,
+}
+
+impl<F: Fn(&Progress) -> bool + Send + Sync> ProgressSink for CancelWhen<F> {
+    fn progress(&self, event: Progress<'_>) {
+        if (self.when)(&event) {
+            self.token.cancel();
+        }
+    }
+}
+
+fn run_cancelled(name: &str, when: impl Fn(&Progress) -> bool + Send + Sync + 'static) {
+    let dir = TempDir::new(name);
+    let inputs = [
+        dir.write("1.wav", &wav(44100, 2, 44100)),
+        dir.write("2.wav", &wav(44100, 2, 44100)),
+    ];
+    let output = dir.0.join("book.m4b");
+    let token = CancellationToken::new();
+
+    let result = ConsolidationJob::new(&output)
+        .inputs(&inputs)
+        .cancellation(token.clone())
+        .progress(Arc::new(CancelWhen { token, when }))
+        .run();
+
+    assert!(matches!(result, Err(Error::Cancelled)), "{result:?}");
+    assert!(!output.exists());
+}
+
+#[test]
+fn cancelling_while_encoding_removes_the_output() {
+    run_cancelled("cancel-encoding", |event| {
+        matches!(event, Progress::Encoded { index: 1, .. })
+    });
+}
+
+#[test]
+fn cancelling_while_finishing_removes_the_output() {
+    run_cancelled("cancel-finishing", |event| {
+        matches!(event, Progress::Finalizing)
+    });
+}
+
+#[test]
+fn cancelling_before_starting_writes_nothing() {
+    run_cancelled("cancel-start", |event| {
+        matches!(event, Progress::Started { .. })
+    });
+}
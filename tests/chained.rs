@@ -0,0 +1,275 @@
+//! Decoding across the boundaries of chained streams.
+//!
+//! The fixtures are written here rather than checked in: chained Ogg FLAC
+//! files made of uncompressed (verbatim) FLAC frames, and MP3 files made of
+//! silent frames that change format part way through.
+use std::{
+    fs::{self, File},
+    path::PathBuf,
+};
+
+use consolidator::decode::FileDecoder;
+
+mod common;
+
+use common::mp3_frame;
+
+/// Frames of audio in each FLAC frame.
+const BLOCK_SIZE: usize = 4096;
+/// Frames of audio in each MPEG-1 layer III frame.
+const MP3_FRAME_LEN: u64 = 1152;
+
+/// A temporary file that is removed when dropped.
+struct Fixture(PathBuf);
+
+impl Fixture {
+    fn new(name: &str, data: &[u8]) -> Self {
+        let path = std::env::temp_dir().join(format!("consolidator-{}-{name}", std::process::id()));
+        fs::write(&path, data).unwrap();
+        Self(path)
+    }
+
+    fn open(&self) -> FileDecoder {
+        let extension = self.0.extension().and_then(|e| e.to_str());
+        FileDecoder::open(File::open(&self.0).unwrap(), extension).unwrap()
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// What was decoded from a file: runs of chunks with the same rate and
+/// channel count, with the samples of the first channel.
+#[derive(Debug, Default)]
+struct Decoded {
+    /// `(rate, channels, frames)` of each run.
+    runs: Vec<(u32, usize, u64)>,
+    first_channel: Vec<f32>,
+}
+
+fn decode_all(decoder: &mut FileDecoder) -> Decoded {
+    let mut decoded = Decoded::default();
+    while let Some(chunk) = decoder.next_chunk().unwrap() {
+        let channels = chunk.spec.channels.count();
+        let frames = (chunk.samples.len() / channels) as u64;
+        match decoded.runs.last_mut() {
+            Some((rate, c, total)) if *rate == chunk.spec.rate && *c == channels => {
+                *total += frames;
+            }
+            _ => decoded.runs.push((chunk.spec.rate, channels, frames)),
+        }
+        decoded
+            .first_channel
+            .extend(chunk.samples.iter().step_by(channels));
+    }
+    decoded
+}
+
+/// The CRC used by Ogg pages.
+fn ogg_crc(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, &b| {
+        (0..8).fold(crc ^ (u32::from(b) << 24), |crc, _| {
+            if crc & 0x8000_0000 == 0 {
+                crc << 1
+            } else {
+                (crc << 1) ^ 0x04c1_1db7
+            }
+        })
+    })
+}
+
+fn flac_crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, &b| {
+        (0..8).fold(crc ^ b, |crc, _| {
+            if crc & 0x80 == 0 {
+                crc << 1
+            } else {
+                (crc << 1) ^ 0x07
+            }
+        })
+    })
+}
+
+fn flac_crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, &b| {
+        (0..8).fold(crc ^ (u16::from(b) << 8), |crc, _| {
+            if crc & 0x8000 == 0 {
+                crc << 1
+            } else {
+                (crc << 1) ^ 0x8005
+            }
+        })
+    })
+}
+
+/// Writes the pages of a logical Ogg stream, one packet to a page.
+struct OggStream<'a> {
+    out: &'a mut Vec<u8>,
+    serial: u32,
+    sequence: u32,
+}
+
+impl OggStream<'_> {
+    fn page(&mut self, packet: &[u8], granule: u64, first: bool, last: bool) {
+        assert!(packet.len() < 255 * 255);
+        let mut lacing = vec![255; packet.len() / 255];
+        lacing.push((packet.len() % 255) as u8);
+
+        let mut page = b"OggS\0".to_vec();
+        page.push(u8::from(first) << 1 | u8::from(last) << 2);
+        page.extend(granule.to_le_bytes());
+        page.extend(self.serial.to_le_bytes());
+        page.extend(self.sequence.to_le_bytes());
+        page.extend([0; 4]);
+        page.push(lacing.len() as u8);
+        page.extend(lacing);
+        page.extend(packet);
+        let crc = ogg_crc(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+
+        self.out.extend(page);
+        self.sequence += 1;
+    }
+}
+
+/// A test signal for the FLAC fixtures: a ramp offset by `channel`.
+fn sample(stream: usize, frame: usize, channel: usize) -> i16 {
+    ((stream * 1000 + frame * 7 + channel * 100) % 20000) as i16 - 10000
+}
+
+/// Appends a physical Ogg FLAC stream of `blocks` frames at `rate` with
+/// `channels` channels.
+fn ogg_flac_stream(out: &mut Vec<u8>, serial: u32, rate: u32, channels: u8, blocks: usize) {
+    let total = (blocks * BLOCK_SIZE) as u64;
+    let mut stream_info = Vec::new();
+    stream_info.extend((BLOCK_SIZE as u16).to_be_bytes());
+    stream_info.extend((BLOCK_SIZE as u16).to_be_bytes());
+    stream_info.extend([0; 6]);
+    // Sample rate (20 bits), channels - 1 (3), bits per sample - 1 (5) and
+    // total samples (36).
+    let packed = u64::from(rate) << 44 | u64::from(channels - 1) << 41 | 15 << 36 | total;
+    stream_info.extend(packed.to_be_bytes());
+    stream_info.extend([0; 16]);
+
+    let mut identification = b"\x7fFLAC\x01\x00\x00\x01fLaC".to_vec();
+    identification.extend([0, 0, 0, 34]);
+    identification.extend(stream_info);
+
+    // A Vorbis comment block with a vendor string and no comments.
+    let vendor = b"consolidator tests";
+    let mut comments = vec![0x84, 0, 0, (vendor.len() + 8) as u8];
+    comments.extend((vendor.len() as u32).to_le_bytes());
+    comments.extend(vendor);
+    comments.extend(0u32.to_le_bytes());
+
+    let mut stream = OggStream {
+        out,
+        serial,
+        sequence: 0,
+    };
+    stream.page(&identification, 0, true, false);
+    stream.page(&comments, 0, false, false);
+
+    let rate_code = match rate {
+        22050 => 0x6,
+        32000 => 0x8,
+        44100 => 0x9,
+        48000 => 0xa,
+        _ => panic!("no FLAC code for {rate}Hz"),
+    };
+    for block in 0..blocks {
+        // Fixed block size of 4096, independent channels of 16 bits, then
+        // the frame number.
+        let mut frame = vec![0xff, 0xf8, 0xc0 | rate_code, (channels - 1) << 4 | 0x8];
+        frame.push(block as u8);
+        frame.push(flac_crc8(&frame));
+        for channel in 0..usize::from(channels) {
+            // A verbatim subframe.
+            frame.push(0x02);
+            for i in 0..BLOCK_SIZE {
+                let frame_index = block * BLOCK_SIZE + i;
+                frame.extend(sample(serial as usize, frame_index, channel).to_be_bytes());
+            }
+        }
+        frame.extend(flac_crc16(&frame).to_be_bytes());
+        let granule = ((block + 1) * BLOCK_SIZE) as u64;
+        stream.page(&frame, granule, false, block + 1 == blocks);
+    }
+}
+
+#[test]
+fn chained_ogg_with_same_format_decodes_continuously() {
+    let mut data = Vec::new();
+    ogg_flac_stream(&mut data, 1, 44100, 2, 3);
+    ogg_flac_stream(&mut data, 2, 44100, 2, 2);
+    ogg_flac_stream(&mut data, 3, 44100, 2, 4);
+    let fixture = Fixture::new("same.ogg", &data);
+
+    let mut decoder = fixture.open();
+    let decoded = decode_all(&mut decoder);
+
+    assert_eq!(decoded.runs, [(44100, 2, 9 * BLOCK_SIZE as u64)]);
+    assert_eq!(decoder.frames(), 9 * BLOCK_SIZE as u64);
+    assert_eq!(decoder.resets(), 2);
+
+    // Every sample of every stream arrives, in order.
+    let expected: Vec<f32> = [(1, 3), (2, 2), (3, 4)]
+        .into_iter()
+        .flat_map(|(serial, blocks)| {
+            (0..blocks * BLOCK_SIZE).map(move |i| f32::from(sample(serial, i, 0)) / 32768.0)
+        })
+        .collect();
+    assert_eq!(decoded.first_channel, expected);
+}
+
+#[test]
+fn chained_ogg_with_format_change_switches_spec() {
+    let mut data = Vec::new();
+    ogg_flac_stream(&mut data, 1, 48000, 2, 2);
+    ogg_flac_stream(&mut data, 2, 22050, 1, 3);
+    let fixture = Fixture::new("change.ogg", &data);
+
+    let mut decoder = fixture.open();
+    let decoded = decode_all(&mut decoder);
+
+    assert_eq!(
+        decoded.runs,
+        [
+            (48000, 2, 2 * BLOCK_SIZE as u64),
+            (22050, 1, 3 * BLOCK_SIZE as u64)
+        ]
+    );
+    assert_eq!(decoder.resets(), 1);
+}
+
+#[test]
+fn mp3_with_sample_rate_change_decodes_across_it() {
+    let mut data = Vec::new();
+    for _ in 0..10 {
+        data.extend(mp3_frame(44100, false));
+    }
+    for _ in 0..6 {
+        data.extend(mp3_frame(32000, true));
+    }
+    for _ in 0..4 {
+        data.extend(mp3_frame(44100, false));
+    }
+    let fixture = Fixture::new("change.mp3", &data);
+
+    let mut decoder = fixture.open();
+    let decoded = decode_all(&mut decoder);
+
+    assert_eq!(
+        decoded.runs,
+        [
+            (44100, 2, 10 * MP3_FRAME_LEN),
+            (32000, 1, 6 * MP3_FRAME_LEN),
+            (44100, 2, 4 * MP3_FRAME_LEN)
+        ]
+    );
+    assert_eq!(decoder.frames(), 20 * MP3_FRAME_LEN);
+    assert_eq!(decoder.resets(), 2);
+}
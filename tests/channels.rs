@@ -0,0 +1,155 @@
+//! Mapping input channels to the output layout, and telling fake stereo from
+//! real.
+use std::f32::consts::FRAC_1_SQRT_2;
+
+use consolidator::channels::{ChannelMapper, Layout, StereoCheck};
+use symphonia::core::audio::Channels;
+
+const SURROUND_5_1: Channels = Channels::FRONT_LEFT
+    .union(Channels::FRONT_RIGHT)
+    .union(Channels::FRONT_CENTRE)
+    .union(Channels::LFE1)
+    .union(Channels::REAR_LEFT)
+    .union(Channels::REAR_RIGHT);
+
+/// The gain of each input channel in each output channel of `mapper`, one
+/// input channel at a time, found by mapping a frame for each with just
+/// that channel at full scale.
+fn gains(mapper: &ChannelMapper) -> Vec<Vec<f32>> {
+    let count = mapper.input().count();
+    (0..count)
+        .map(|channel| {
+            let mut frame = vec![0.0; count];
+            frame[channel] = 1.0;
+            let mut output = Vec::new();
+            mapper.map(&frame, &mut output);
+            output
+        })
+        .collect()
+}
+
+fn assert_gains(actual: &[Vec<f32>], expected: &[&[f32]]) {
+    assert_eq!(actual.len(), expected.len());
+    for (actual, expected) in actual.iter().zip(expected) {
+        assert_eq!(actual.len(), expected.len());
+        assert!(
+            actual
+                .iter()
+                .zip(*expected)
+                .all(|(a, e)| (a - e).abs() < 1e-6),
+            "{actual:?} isn't {expected:?}"
+        );
+    }
+}
+
+#[test]
+fn surround_is_downmixed_to_stereo_without_clipping() {
+    // Each side is left, centre and surround, scaled to sum to one.
+    let side = 1.0 + 2.0 * FRAC_1_SQRT_2;
+    let front = 1.0 / side;
+    let other = FRAC_1_SQRT_2 / side;
+
+    let mapper = ChannelMapper::new(SURROUND_5_1, Layout::Stereo);
+
+    assert_gains(
+        &gains(&mapper),
+        &[
+            &[front, 0.0],
+            &[0.0, front],
+            &[other, other],
+            &[0.0, 0.0],
+            &[other, 0.0],
+            &[0.0, other],
+        ],
+    );
+    // Every channel at full scale in phase is full scale on each side.
+    let mut output = Vec::new();
+    mapper.map(&[1.0; 6], &mut output);
+    assert!(output.iter().all(|x| (x - 1.0).abs() < 1e-6), "{output:?}");
+}
+
+#[test]
+fn surround_is_downmixed_to_mono_as_the_average_of_the_sides() {
+    let side = 1.0 + 2.0 * FRAC_1_SQRT_2;
+    let front = 0.5 / side;
+    let centre = FRAC_1_SQRT_2 / side;
+    let surround = 0.5 * FRAC_1_SQRT_2 / side;
+
+    let mapper = ChannelMapper::new(SURROUND_5_1, Layout::Mono);
+
+    assert_gains(
+        &gains(&mapper),
+        &[
+            &[front],
+            &[front],
+            &[centre],
+            &[0.0],
+            &[surround],
+            &[surround],
+        ],
+    );
+}
+
+#[test]
+fn mono_is_copied_to_both_sides() {
+    for position in [Channels::FRONT_LEFT, Channels::FRONT_CENTRE] {
+        let mapper = ChannelMapper::new(position, Layout::Stereo);
+        let mut output = Vec::new();
+
+        mapper.map(&[0.5, -0.25, 1.0], &mut output);
+
+        assert_eq!(output, [0.5, 0.5, -0.25, -0.25, 1.0, 1.0]);
+    }
+}
+
+#[test]
+fn stereo_is_kept_or_averaged() {
+    let stereo = Channels::FRONT_LEFT | Channels::FRONT_RIGHT;
+    let input = [0.5, -0.25, 1.0, 0.0];
+
+    let mut output = Vec::new();
+    ChannelMapper::new(stereo, Layout::Stereo).map(&input, &mut output);
+    assert_eq!(output, input);
+
+    output.clear();
+    ChannelMapper::new(stereo, Layout::Mono).map(&input, &mut output);
+    assert_eq!(output, [0.125, 0.5]);
+}
+
+/// The verdict of a [`StereoCheck`] on a second of 44.1kHz stereo whose
+/// channels `channels` gives at each time in seconds, fed in blocks.
+fn is_fake(channels: impl Fn(f32) -> (f32, f32)) -> bool {
+    let samples: Vec<f32> = (0..44100)
+        .flat_map(|i| {
+            let (l, r) = channels(i as f32 / 44100.0);
+            [l, r]
+        })
+        .collect();
+    let mut check = StereoCheck::default();
+    for block in samples.chunks(1000) {
+        check.add(block);
+    }
+    check.is_fake()
+}
+
+fn tone(frequency: f32, t: f32) -> f32 {
+    (std::f32::consts::TAU * frequency * t).sin() * 0.5
+}
+
+#[test]
+fn identical_channels_are_fake_stereo() {
+    assert!(is_fake(|t| (tone(440.0, t), tone(440.0, t))));
+    // Differences below -60dB don't count.
+    assert!(is_fake(|t| (tone(440.0, t), tone(440.0, t) * 1.0001)));
+    assert!(is_fake(|_| (0.0, 0.0)));
+    assert!(StereoCheck::default().is_fake());
+}
+
+#[test]
+fn inverted_or_independent_channels_are_real_stereo() {
+    assert!(!is_fake(|t| (tone(440.0, t), -tone(440.0, t))));
+    assert!(!is_fake(|t| (tone(440.0, t), tone(660.0, t))));
+    assert!(!is_fake(|t| (tone(440.0, t), 0.0)));
+    // A difference of -40dB is enough.
+    assert!(!is_fake(|t| (tone(440.0, t), tone(440.0, t) * 1.02)));
+}
@@ -0,0 +1,109 @@
+//! Fixtures shared by the integration tests.
+#![allow(dead_code)]
+use std::{fs, path::PathBuf};
+
+/// A temporary directory that is removed when dropped.
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("consolidator-{}-{name}", std::process::id()));
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn write(&self, name: &str, data: &[u8]) -> PathBuf {
+        let path = self.0.join(name);
+        fs::write(&path, data).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A 16-bit PCM WAV file of `frames` frames of a quiet ramp, inverted in the
+/// second channel so that stereo isn't taken for mono.
+pub fn wav(rate: u32, channels: u16, frames: u32) -> Vec<u8> {
+    let samples: Vec<i16> = (0..frames)
+        .map(|frame| (frame % 200) as i16 - 100)
+        .collect();
+    wav_of(rate, channels, &samples)
+}
+
+/// A 16-bit PCM WAV file of a frame for each of `samples`, inverted in the
+/// second channel.
+pub fn wav_of(rate: u32, channels: u16, samples: &[i16]) -> Vec<u8> {
+    let data_len = samples.len() as u32 * u32::from(channels) * 2;
+    let mut out = b"RIFF".to_vec();
+    out.extend((36 + data_len).to_le_bytes());
+    out.extend(b"WAVEfmt ");
+    out.extend(16u32.to_le_bytes());
+    out.extend(1u16.to_le_bytes());
+    out.extend(channels.to_le_bytes());
+    out.extend(rate.to_le_bytes());
+    out.extend((rate * u32::from(channels) * 2).to_le_bytes());
+    out.extend((channels * 2).to_le_bytes());
+    out.extend(16u16.to_le_bytes());
+    out.extend(b"data");
+    out.extend(data_len.to_le_bytes());
+    for &sample in samples {
+        for channel in 0..channels {
+            let sample = if channel == 1 { -sample } else { sample };
+            out.extend(sample.to_le_bytes());
+        }
+    }
+    out
+}
+
+/// An MPEG-1 layer III frame of silence at 128 kbit/s.
+pub fn mp3_frame(rate: u32, mono: bool) -> Vec<u8> {
+    let rate_index = match rate {
+        44100 => 0,
+        48000 => 1,
+        32000 => 2,
+        _ => panic!("not an MPEG-1 sample rate: {rate}"),
+    };
+    let len = 144 * 128_000 / rate as usize;
+    let mut frame = vec![
+        0xff,
+        0xfb,
+        0x90 | rate_index << 2,
+        if mono { 0xc0 } else { 0 },
+    ];
+    // Side information and main data of zeros decode to silence.
+    frame.resize(len, 0);
+    frame
+}
+
+/// An `ID3v2.3` tag of the text frames `frames`.
+pub fn id3v2(frames: &[(&[u8; 4], &str)]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (id, text) in frames {
+        body.extend(*id);
+        body.extend((text.len() as u32 + 1).to_be_bytes());
+        body.extend([0, 0, 0]);
+        body.extend(text.as_bytes());
+    }
+    let mut tag = b"ID3\x03\x00\x00".to_vec();
+    // Syncsafe, seven bits to a byte.
+    tag.extend((0..4).rev().map(|i| (body.len() >> (7 * i)) as u8 & 0x7f));
+    tag.extend(body);
+    tag
+}
+
+/// The LAME extension of a Xing header, as written by `encoder` (such as
+/// `LAME3.100` or `Lavc58.54`), with the encoder's `delay` and `padding` in
+/// frames. Its CRC is left zero.
+pub fn lame_tag(encoder: &[u8; 9], delay: u32, padding: u32) -> Vec<u8> {
+    let mut tag = encoder.to_vec();
+    // Revision, lowpass, ReplayGain, flags and bitrate.
+    tag.extend([0; 12]);
+    tag.extend(&((delay << 12) | padding).to_be_bytes()[1..]);
+    // Flags, gain, preset, length, audio CRC and the tag's CRC.
+    tag.extend([0; 12]);
+    tag
+}
@@ -0,0 +1,246 @@
+//! Decoding, resizing and re-encoding cover art, and refusing malformed
+//! images without panicking.
+use consolidator::{
+    cover::{self, CoverOptions, Error, Image},
+    mp4::{CoverArt, ImageFormat},
+};
+
+/// A deflate block with fixed codes, not the last, of the filtered rows 4 to
+/// 7 of [`blocks_image`].
+const FIXED_BLOCK: [u8; 61] = [
+    0x62, 0x60, 0x70, 0x38, 0x01, 0x47, 0x0e, 0x48, 0xa8, 0x01, 0x09, 0x1d, 0x40, 0x42, 0x0c, 0x0c,
+    0x01, 0x27, 0xe0, 0xc8, 0x01, 0x09, 0x35, 0x20, 0xa1, 0x03, 0x48, 0x88, 0x81, 0x21, 0xe1, 0x04,
+    0x1c, 0x39, 0x20, 0xa1, 0x06, 0x24, 0x74, 0x00, 0x09, 0x31, 0x30, 0x14, 0x9c, 0x80, 0x23, 0x07,
+    0x24, 0xd4, 0x80, 0x84, 0x0e, 0x20, 0x21, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff,
+];
+/// The last deflate block, with dynamic codes, of rows 8 to 15.
+const DYNAMIC_BLOCK: [u8; 71] = [
+    0x4d, 0xc2, 0x31, 0x11, 0x42, 0x01, 0x10, 0x43, 0xc1, 0x48, 0x8b, 0xb4, 0x48, 0x40, 0x02, 0x12,
+    0x90, 0x90, 0x32, 0xe5, 0x97, 0x80, 0x04, 0x24, 0xd0, 0xdd, 0xbc, 0x9d, 0x95, 0xb2, 0x6b, 0x0c,
+    0x16, 0xa5, 0xd7, 0xae, 0x31, 0x58, 0x94, 0xde, 0xbb, 0xc6, 0x60, 0x51, 0xfa, 0xec, 0x1a, 0x83,
+    0x45, 0xa9, 0xbb, 0xc6, 0x60, 0x51, 0x7a, 0x76, 0x8d, 0xc1, 0xa2, 0xf4, 0xdd, 0x35, 0x06, 0x8b,
+    0xd2, 0x6f, 0xd7, 0x18, 0x2c, 0xfe, 0x01,
+];
+
+/// A 16x16 image in bands of four columns, getting greener down the rows.
+fn blocks_image() -> Image {
+    image(16, 16, |x, y| [(x / 4 * 64) as u8, (y * 16) as u8, 200])
+}
+
+fn image(width: u32, height: u32, rgb: impl Fn(u32, u32) -> [u8; 3]) -> Image {
+    let pixels = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .flat_map(|(x, y)| rgb(x, y))
+        .collect();
+    Image {
+        width,
+        height,
+        pixels,
+    }
+}
+
+/// The rows of `image` as PNG image data, each unfiltered.
+fn scanlines(image: &Image) -> Vec<u8> {
+    image
+        .pixels
+        .chunks_exact(image.width as usize * 3)
+        .flat_map(|row| [0].into_iter().chain(row.iter().copied()))
+        .collect()
+}
+
+/// `data` as deflate stored blocks.
+fn stored(data: &[u8], last: bool) -> Vec<u8> {
+    let mut out = Vec::new();
+    let blocks: Vec<&[u8]> = data.chunks(0xffff).collect();
+    for (i, block) in blocks.iter().enumerate() {
+        out.push(u8::from(last && i + 1 == blocks.len()));
+        let len = block.len() as u16;
+        out.extend(len.to_le_bytes());
+        out.extend((!len).to_le_bytes());
+        out.extend(*block);
+    }
+    out
+}
+
+/// A zlib stream of the `deflate` stream that decompresses to `data`.
+fn zlib(deflate: &[u8], data: &[u8]) -> Vec<u8> {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + u32::from(byte)) % 65521;
+        b = (b + a) % 65521;
+    }
+    let mut out = vec![0x78, 0x01];
+    out.extend(deflate);
+    out.extend((b << 16 | a).to_be_bytes());
+    out
+}
+
+/// An RGB PNG image `width` by `height` whose image data is `zlib`.
+fn png(width: u32, height: u32, zlib: &[u8]) -> CoverArt {
+    let mut header = Vec::new();
+    header.extend(width.to_be_bytes());
+    header.extend(height.to_be_bytes());
+    header.extend([8, 2, 0, 0, 0]);
+    let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+    for (kind, body) in [(b"IHDR", &header[..]), (b"IDAT", zlib), (b"IEND", &[])] {
+        data.extend((body.len() as u32).to_be_bytes());
+        let start = data.len();
+        data.extend(kind);
+        data.extend(body);
+        let crc = crc32(&data[start..]);
+        data.extend(crc.to_be_bytes());
+    }
+    CoverArt {
+        format: ImageFormat::Png,
+        data,
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                crc >> 1 ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// `image` as a JPEG, by re-encoding it as a PNG that's too large.
+fn jpeg(image: &Image) -> CoverArt {
+    let data = scanlines(image);
+    let art = png(
+        image.width,
+        image.height,
+        &zlib(&stored(&data, true), &data),
+    );
+    let max_bytes = art.data.len() - 1;
+    let art = cover::fit(
+        art,
+        &CoverOptions {
+            max_bytes: Some(max_bytes),
+            ..CoverOptions::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(art.format, ImageFormat::Jpeg);
+    art
+}
+
+/// The largest difference between the samples of `a` and `b`.
+fn max_difference(a: &Image, b: &Image) -> u8 {
+    assert_eq!((a.width, a.height), (b.width, b.height));
+    a.pixels
+        .iter()
+        .zip(&b.pixels)
+        .map(|(a, b)| a.abs_diff(*b))
+        .max()
+        .unwrap_or(0)
+}
+
+#[test]
+fn png_with_stored_fixed_and_dynamic_blocks_is_decoded() {
+    let expected = blocks_image();
+    let rows = scanlines(&expected);
+    let mut deflate = stored(&rows[..4 * 49], false);
+    deflate.extend(FIXED_BLOCK);
+    deflate.extend(DYNAMIC_BLOCK);
+
+    let decoded = cover::decode(&png(16, 16, &zlib(&deflate, &rows))).unwrap();
+
+    assert_eq!(decoded, expected);
+}
+
+#[test]
+fn jpeg_round_trips_within_a_tolerance() {
+    let original = image(64, 48, |x, y| {
+        [(x * 4) as u8, (y * 5) as u8, (255 - x * 2 - y) as u8]
+    });
+
+    let decoded = cover::decode(&jpeg(&original)).unwrap();
+
+    assert!(max_difference(&decoded, &original) <= 12);
+}
+
+#[test]
+fn images_are_scaled_down_by_averaging() {
+    // Black and white pixels in a checkerboard average out to grey.
+    let checkerboard = image(64, 32, |x, y| [if (x + y) % 2 == 0 { 0 } else { 255 }; 3]);
+    let data = scanlines(&checkerboard);
+    let art = png(64, 32, &zlib(&stored(&data, true), &data));
+
+    let fitted = cover::fit(
+        art,
+        &CoverOptions {
+            max_dimension: Some(32),
+            ..CoverOptions::default()
+        },
+    )
+    .unwrap();
+
+    let decoded = cover::decode(&fitted).unwrap();
+    assert_eq!((decoded.width, decoded.height), (32, 16));
+    let grey = image(32, 16, |_, _| [128; 3]);
+    assert!(max_difference(&decoded, &grey) <= 2);
+}
+
+/// The position of the first `marker` segment in a JPEG.
+fn segment(data: &[u8], marker: u8) -> usize {
+    data.windows(2).position(|w| w == [0xff, marker]).unwrap()
+}
+
+#[test]
+fn truncated_scan_header_is_an_error() {
+    let mut art = jpeg(&blocks_image());
+    let sos = segment(&art.data, 0xda);
+    art.data.truncate(sos + 5);
+
+    assert!(matches!(cover::decode(&art), Err(Error::Malformed(_))));
+}
+
+#[test]
+fn overfull_huffman_table_is_an_error() {
+    let mut art = jpeg(&blocks_image());
+    // Three codes of length one, where there's only room for two, leaving
+    // the number of symbols the same.
+    let bits = segment(&art.data, 0xc4) + 5;
+    art.data[bits..bits + 3].copy_from_slice(&[3, 0, 3]);
+
+    assert!(matches!(cover::decode(&art), Err(Error::Malformed(_))));
+    // Fitting the image decodes it too.
+    let options = CoverOptions {
+        max_bytes: Some(1),
+        ..CoverOptions::default()
+    };
+    assert!(matches!(
+        cover::fit(art, &options),
+        Err(Error::Malformed(_))
+    ));
+}
+
+#[test]
+fn bad_zlib_header_is_an_error() {
+    let data = scanlines(&blocks_image());
+    let mut stream = zlib(&stored(&data, true), &data);
+    // Not a multiple of 31.
+    stream[1] = 0x00;
+
+    let result = cover::decode(&png(16, 16, &stream));
+
+    assert!(matches!(result, Err(Error::Malformed(_))));
+}
+
+#[test]
+fn distance_too_far_back_is_an_error() {
+    // A fixed block of a literal, then a copy from two bytes back.
+    let stream = zlib(&[0x4b, 0x04, 0x42, 0x00], b"");
+
+    let result = cover::decode(&png(16, 16, &stream));
+
+    assert!(matches!(result, Err(Error::Malformed(_))));
+}
@@ -0,0 +1,198 @@
+//! Taking chapters from cue sheets next to the inputs.
+use std::time::Duration;
+
+use consolidator::{
+    cue::{CueSheet, Track},
+    processor::{ChapterOptions, ConsolidationJob, Options},
+};
+
+mod common;
+
+use common::{wav, TempDir};
+
+const RATE: u32 = 22050;
+
+/// The title, start and duration of each chapter of the book made from `dir`.
+fn chapters(dir: &TempDir, options: &Options) -> Vec<(String, f64, f64)> {
+    let report = ConsolidationJob::directory(&dir.0, options)
+        .unwrap()
+        .run()
+        .unwrap();
+    report
+        .chapters
+        .iter()
+        .map(|chapter| (chapter.title.clone(), chapter.start, chapter.duration))
+        .collect()
+}
+
+#[test]
+fn sheets_are_parsed() {
+    let sheet = CueSheet::parse(
+        "REM GENRE Audiobook\r\n\
+         PERFORMER \"The Author\"\r\n\
+         TITLE \"The Book\"\r\n\
+         FILE \"Part 1.wav\" WAVE\r\n\
+         \x20 TRACK 01 AUDIO\r\n\
+         \x20   TITLE \"Opening\"\r\n\
+         \x20   INDEX 01 00:00:00\r\n\
+         \x20 TRACK 02 AUDIO\r\n\
+         \x20   PERFORMER Narrator\r\n\
+         \x20   INDEX 00 01:59:00\r\n\
+         FILE \"Part 2.wav\" WAVE\r\n\
+         \x20   INDEX 01 00:00:00\r\n\
+         \x20 TRACK 03 AUDIO\r\n\
+         \x20   INDEX 01 02:03:15\r\n",
+    )
+    .unwrap();
+
+    assert_eq!(sheet.title.as_deref(), Some("The Book"));
+    assert_eq!(sheet.performer.as_deref(), Some("The Author"));
+    let files: Vec<_> = sheet.files.iter().map(|file| file.name.as_str()).collect();
+    assert_eq!(files, ["Part 1.wav", "Part 2.wav"]);
+    // A track starts where its INDEX 01 is, even in the next file.
+    let titles: Vec<Vec<(String, Duration)>> = sheet
+        .files
+        .iter()
+        .map(|file| {
+            file.tracks
+                .iter()
+                .map(|track| (track.chapter_title(), track.start))
+                .collect()
+        })
+        .collect();
+    assert_eq!(
+        titles,
+        [
+            vec![("Opening".to_owned(), Duration::ZERO)],
+            vec![
+                ("Narrator".to_owned(), Duration::ZERO),
+                ("Track 03".to_owned(), Duration::from_millis(123_200)),
+            ],
+        ]
+    );
+
+    // The audio is matched by its name without the extension if need be.
+    let tracks = sheet.tracks_in("part 2.flac").unwrap();
+    assert!(tracks.continues);
+    assert_eq!(tracks.tracks[0].number, 2);
+    assert!(sheet.tracks_in("Part 3.wav").is_none());
+}
+
+#[test]
+fn titles_after_a_tracks_index_are_kept() {
+    let sheet = CueSheet::parse(
+        "FILE \"book.wav\" WAVE\n\
+         \x20 TRACK 01 AUDIO\n\
+         \x20   INDEX 01 00:00:00\n\
+         \x20   TITLE \"After\"\n\
+         \x20   PERFORMER \"Reader\"\n\
+         \x20 TRACK 02 AUDIO\n\
+         \x20   INDEX 01 00:10:00\n\
+         FILE \"more.wav\" WAVE\n\
+         \x20   TITLE \"Not the track's\"\n\
+         \x20 TRACK 03 AUDIO\n\
+         \x20   TITLE \"Before\"\n\
+         \x20   INDEX 01 00:00:00\n",
+    )
+    .unwrap();
+
+    let tracks: Vec<(Option<&str>, Option<&str>)> = sheet
+        .files
+        .iter()
+        .flat_map(|file| &file.tracks)
+        .map(|track| (track.title.as_deref(), track.performer.as_deref()))
+        .collect();
+    // A title after the next FILE is no longer the last track's.
+    assert_eq!(
+        tracks,
+        [
+            (Some("After"), Some("Reader")),
+            (None, None),
+            (Some("Before"), None)
+        ]
+    );
+    assert_eq!(sheet.title, None);
+}
+
+#[test]
+fn windows_1252_sheets_are_read() {
+    let dir = TempDir::new("cue-cp1252");
+    let path = dir.write(
+        "book.cue",
+        b"FILE \"book.wav\" WAVE\n  TRACK 01 AUDIO\n    TITLE \"Caf\xE9 \x93Noir\x94\"\n    INDEX 01 00:00:00\n",
+    );
+
+    let sheet = CueSheet::read(&path).unwrap();
+
+    assert_eq!(
+        sheet.files[0].tracks,
+        [Track {
+            number: 1,
+            title: Some("Café “Noir”".to_owned()),
+            performer: None,
+            start: Duration::ZERO,
+        }]
+    );
+}
+
+#[test]
+fn a_single_file_is_split_at_its_tracks() {
+    let dir = TempDir::new("cue-single");
+    dir.write("book.wav", &wav(RATE, 1, RATE * 6));
+    dir.write(
+        "book.cue",
+        "\u{FEFF}TITLE \"Cued\"\n\
+         FILE \"book.wav\" WAVE\n\
+         \x20 TRACK 01 AUDIO\n    TITLE \"Chapter One\"\n    INDEX 01 00:00:00\n\
+         \x20 TRACK 02 AUDIO\n    TITLE \"Chapter Two\"\n    INDEX 01 00:02:00\n\
+         \x20 TRACK 03 AUDIO\n    INDEX 01 00:04:30\n"
+            .as_bytes(),
+    );
+
+    assert_eq!(
+        chapters(&dir, &Options::default()),
+        [
+            ("Chapter One".to_owned(), 0.0, 2.0),
+            ("Chapter Two".to_owned(), 2.0, 2.4),
+            ("Track 03".to_owned(), 4.4, 1.6),
+        ]
+    );
+
+    // Unless cue sheets are ignored, when the file is one chapter.
+    let options = Options {
+        chapters: ChapterOptions {
+            cue_sheets: false,
+            ..ChapterOptions::default()
+        },
+        ..Options::default()
+    };
+    assert_eq!(chapters(&dir, &options), [("book".to_owned(), 0.0, 6.0)]);
+}
+
+#[test]
+fn tracks_run_on_across_the_files_of_a_sheet() {
+    let dir = TempDir::new("cue-multi");
+    dir.write("a.wav", &wav(RATE, 1, RATE * 3));
+    dir.write("b.wav", &wav(RATE, 1, RATE * 3));
+    dir.write("c.wav", &wav(RATE, 1, RATE));
+    dir.write(
+        "disc.cue",
+        b"FILE \"a.wav\" WAVE\n\
+          \x20 TRACK 01 AUDIO\n    TITLE One\n    INDEX 01 00:00:00\n\
+          \x20 TRACK 02 AUDIO\n    TITLE Two\n    INDEX 01 00:02:00\n\
+          FILE \"b.wav\" WAVE\n\
+          \x20 TRACK 03 AUDIO\n    TITLE Three\n    INDEX 01 00:01:00\n",
+    );
+
+    // The start of b.wav finishes track two, and c.wav, which the sheet
+    // doesn't name, is a chapter of its own.
+    assert_eq!(
+        chapters(&dir, &Options::default()),
+        [
+            ("One".to_owned(), 0.0, 2.0),
+            ("Two".to_owned(), 2.0, 2.0),
+            ("Three".to_owned(), 4.0, 2.0),
+            ("c".to_owned(), 6.0, 1.0),
+        ]
+    );
+}
@@ -0,0 +1,148 @@
+//! Finding durations without decoding, and how far they can be trusted.
+use std::{fs::File, path::Path};
+
+use consolidator::decode::{Confidence, DurationSource, FileDecoder, ProbedDuration};
+
+mod common;
+
+use common::{lame_tag, mp3_frame, wav, TempDir};
+
+/// Frames of audio in each MPEG-1 layer III frame.
+const MP3_FRAME_LEN: u32 = 1152;
+
+fn open(path: &Path) -> FileDecoder {
+    let extension = path.extension().and_then(|e| e.to_str());
+    FileDecoder::open(File::open(path).unwrap(), extension).unwrap()
+}
+
+fn decoded_seconds(path: &Path) -> f64 {
+    let mut decoder = open(path);
+    while decoder.next_chunk().unwrap().is_some() {}
+    decoder.seconds()
+}
+
+/// A stereo 44.1kHz MP3 of `frames` silent frames, after a header frame that
+/// `header` writes its tag into (just after the side information).
+fn mp3(frames: u32, header: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+    let mut data = mp3_frame(44100, false);
+    let mut tag = Vec::new();
+    header(&mut tag);
+    data[36..36 + tag.len()].copy_from_slice(&tag);
+    for _ in 0..frames {
+        data.extend(mp3_frame(44100, false));
+    }
+    data
+}
+
+fn assert_duration(
+    probed: ProbedDuration,
+    seconds: f64,
+    source: DurationSource,
+    confidence: Confidence,
+) {
+    assert_eq!((probed.source, probed.confidence), (source, confidence));
+    assert!((probed.seconds - seconds).abs() < 1e-9, "{probed:?}");
+}
+
+/// The CRC-16 of `data` that ends a LAME tag.
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |mut crc, &byte| {
+        crc ^= u16::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                crc >> 1 ^ 0xa001
+            } else {
+                crc >> 1
+            };
+        }
+        crc
+    })
+}
+
+#[test]
+fn xing_header_gives_the_duration() {
+    let dir = TempDir::new("xing");
+    let seconds = f64::from(20 * MP3_FRAME_LEN) / 44100.0;
+    let mut data = mp3(20, |tag| {
+        tag.extend(b"Xing");
+        tag.extend(1u32.to_be_bytes());
+        tag.extend(20u32.to_be_bytes());
+        tag.extend(lame_tag(b"LAME3.100", 576, 1000));
+    });
+    // The LAME tag's CRC covers the frame up to it.
+    let crc = 36 + 12 + 34;
+    let sum = crc16(&data[..crc]);
+    data[crc..crc + 2].copy_from_slice(&sum.to_be_bytes());
+    let tagged = dir.write("tagged.mp3", &data);
+    let untagged = dir.write(
+        "untagged.mp3",
+        &mp3(20, |tag| {
+            tag.extend(b"Info");
+            tag.extend(1u32.to_be_bytes());
+            tag.extend(20u32.to_be_bytes());
+        }),
+    );
+
+    // Less the encoder delay and padding, which decoding trims too.
+    let trimmed = f64::from(20 * MP3_FRAME_LEN - 576 - 1000) / 44100.0;
+    let probed = open(&tagged).into_duration().unwrap();
+    assert_duration(probed, trimmed, DurationSource::Xing, Confidence::Exact);
+    assert!((decoded_seconds(&tagged) - trimmed).abs() < 1e-9);
+    let probed = open(&untagged).into_duration().unwrap();
+    assert_duration(
+        probed,
+        seconds,
+        DurationSource::Xing,
+        Confidence::Approximate,
+    );
+}
+
+#[test]
+fn vbri_header_gives_an_approximate_duration() {
+    let dir = TempDir::new("vbri");
+    let path = dir.write(
+        "vbri.mp3",
+        &mp3(12, |tag| {
+            tag.extend(b"VBRI");
+            tag.extend(1u16.to_be_bytes());
+            tag.extend([0; 4]);
+            tag.extend(0u32.to_be_bytes());
+            tag.extend(12u32.to_be_bytes());
+        }),
+    );
+
+    let probed = open(&path).into_duration().unwrap();
+    let seconds = f64::from(12 * MP3_FRAME_LEN) / 44100.0;
+    assert_duration(
+        probed,
+        seconds,
+        DurationSource::Vbri,
+        Confidence::Approximate,
+    );
+}
+
+#[test]
+fn mp3_without_a_header_is_scanned() {
+    let dir = TempDir::new("scan");
+    let data: Vec<u8> = (0..15).flat_map(|_| mp3_frame(48000, true)).collect();
+    let path = dir.write("plain.mp3", &data);
+
+    let probed = open(&path).into_duration().unwrap();
+    let seconds = decoded_seconds(&path);
+    assert!((seconds - f64::from(15 * MP3_FRAME_LEN) / 48000.0).abs() < 1e-9);
+    assert_duration(
+        probed,
+        seconds,
+        DurationSource::PacketScan,
+        Confidence::Exact,
+    );
+}
+
+#[test]
+fn container_gives_the_duration() {
+    let dir = TempDir::new("container");
+    let path = dir.write("pcm.wav", &wav(22050, 2, 33075));
+
+    let probed = open(&path).into_duration().unwrap();
+    assert_duration(probed, 1.5, DurationSource::Container, Confidence::Exact);
+}
@@ -0,0 +1,74 @@
+//! Decoding files with packets that can't be decoded, which are replaced
+//! with silence unless errors are strict.
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use consolidator::decode::{Error, ErrorMode, FileDecoder};
+
+mod common;
+
+use common::{mp3_frame, TempDir};
+
+/// Frames of audio in each MPEG-1 layer III frame.
+const MP3_FRAME_LEN: u64 = 1152;
+
+/// 20 silent MP3 frames, of which frames 8 to 10 claim more main data than
+/// the frame holds, so they can't be decoded, though they can still be read
+/// as packets.
+fn corrupt_mp3(dir: &TempDir) -> PathBuf {
+    let data: Vec<u8> = (0..20)
+        .flat_map(|i| {
+            let mut frame = mp3_frame(44100, false);
+            if (8..11).contains(&i) {
+                frame[6..8].copy_from_slice(&[0xff, 0xf0]);
+            }
+            frame
+        })
+        .collect();
+    dir.write("corrupt.mp3", &data)
+}
+
+fn open(path: &Path, mode: ErrorMode) -> FileDecoder {
+    let mut decoder = FileDecoder::open(File::open(path).unwrap(), Some("mp3")).unwrap();
+    decoder.set_error_mode(mode);
+    decoder
+}
+
+#[test]
+fn corrupt_packets_are_replaced_with_silence() {
+    let dir = TempDir::new("errors-lenient");
+    let mut decoder = open(&corrupt_mp3(&dir), ErrorMode::Lenient);
+
+    let mut samples = Vec::new();
+    while let Some(chunk) = decoder.next_chunk().unwrap() {
+        samples.extend_from_slice(chunk.samples);
+    }
+
+    assert_eq!(decoder.skipped_packets(), 3);
+    // The silence is as long as the packets it replaces.
+    assert_eq!(decoder.frames(), 20 * MP3_FRAME_LEN);
+    assert_eq!(samples.len() as u64, 2 * 20 * MP3_FRAME_LEN);
+    assert!(samples.iter().all(|&sample| sample == 0.0));
+}
+
+#[test]
+fn corrupt_packets_are_errors_when_strict() {
+    let dir = TempDir::new("errors-strict");
+    let mut decoder = open(&corrupt_mp3(&dir), ErrorMode::Strict);
+
+    let result = loop {
+        match decoder.next_chunk() {
+            Ok(Some(_)) => {}
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+
+    assert!(
+        matches!(result, Err(Error::Decode { packet: 8, .. })),
+        "{result:?}"
+    );
+    assert_eq!(decoder.frames(), 8 * MP3_FRAME_LEN);
+}
@@ -0,0 +1,211 @@
+//! Measuring loudness, and normalizing the inputs to a target.
+use std::{f64::consts::PI, fs::File};
+
+use consolidator::{
+    decode::FileDecoder,
+    loudness::{LoudnessMeter, NormalizeMode, NormalizeOptions, NormalizeScope},
+    processor::{ConsolidationJob, LoudnessOptions},
+};
+
+mod common;
+
+use common::{wav_of, TempDir};
+
+/// `seconds` of a sine wave at `frequency` with a peak of `amplitude`.
+fn sine(rate: u32, frequency: f64, amplitude: f64, phase: f64, seconds: f64) -> Vec<f32> {
+    let frames = (seconds * f64::from(rate)) as usize;
+    (0..frames)
+        .map(|frame| {
+            let t = frame as f64 / f64::from(rate);
+            (amplitude * (2.0 * PI * frequency * t + phase).sin()) as f32
+        })
+        .collect()
+}
+
+fn interleave(samples: &[f32], channels: usize) -> Vec<f32> {
+    samples
+        .iter()
+        .flat_map(|&sample| std::iter::repeat_n(sample, channels))
+        .collect()
+}
+
+fn assert_near(actual: f64, expected: f64, tolerance: f64) {
+    assert!(
+        (actual - expected).abs() <= tolerance,
+        "{actual} isn't within {tolerance} of {expected}"
+    );
+}
+
+#[test]
+fn a_sine_at_1khz_measures_its_level() {
+    let tone = sine(48000, 997.0, 0.1, 0.0, 5.0);
+    for (channels, expected) in [(2, -20.0), (1, -23.01)] {
+        let mut meter = LoudnessMeter::new(48000, channels);
+        meter.add(&interleave(&tone, channels));
+        let loudness = meter.finish().loudness();
+        assert_near(loudness.integrated, expected, 0.05);
+        assert_near(loudness.true_peak, -20.0, 0.05);
+    }
+}
+
+#[test]
+fn true_peaks_between_samples_are_found() {
+    // Every sample of a quarter-rate sine 45 degrees out of phase is 3dB
+    // below its peak.
+    let tone = sine(48000, 12000.0, 0.5, PI / 4.0, 1.0);
+    let mut meter = LoudnessMeter::new(48000, 1);
+    meter.add(&tone);
+    assert_near(meter.finish().loudness().true_peak, -6.02, 0.5);
+}
+
+#[test]
+fn silence_has_no_loudness() {
+    let mut meter = LoudnessMeter::new(44100, 2);
+    meter.add(&[0.0; 44100 * 2]);
+    assert_eq!(meter.finish().loudness().integrated, f64::NEG_INFINITY);
+}
+
+/// Consolidates a quiet and a loud tone, normalized with `scope`.
+fn normalize(scope: NormalizeScope) -> (TempDir, consolidator::processor::ConsolidationReport) {
+    let dir = TempDir::new(&format!("normalize-{scope:?}"));
+    let pcm = |amplitude| {
+        let tone = sine(44100, 997.0, amplitude, 0.0, 3.0);
+        let samples: Vec<i16> = tone.iter().map(|&s| (s * 32767.0) as i16).collect();
+        wav_of(44100, 2, &samples)
+    };
+    let inputs = [
+        dir.write("quiet.wav", &pcm(0.05)),
+        dir.write("loud.wav", &pcm(0.2)),
+    ];
+    let report = ConsolidationJob::new(dir.0.join("book.m4b"))
+        .inputs(&inputs)
+        .loudness(LoudnessOptions {
+            measure: false,
+            normalize: Some(NormalizeOptions {
+                target: -18.0,
+                true_peak: -1.0,
+                scope,
+                ..NormalizeOptions::default()
+            }),
+        })
+        .run()
+        .unwrap();
+    (dir, report)
+}
+
+#[test]
+fn each_file_is_normalized_to_the_target() {
+    let (dir, report) = normalize(NormalizeScope::File);
+
+    let measured: Vec<_> = report
+        .files
+        .iter()
+        .map(|file| (file.loudness.unwrap().integrated, file.gain.unwrap()))
+        .collect();
+    assert_near(measured[0].0, -26.0, 0.05);
+    assert_near(measured[0].1, 8.0, 0.05);
+    assert_near(measured[1].0, -14.0, 0.05);
+    assert_near(measured[1].1, -4.0, 0.05);
+
+    // Both chapters come out at the target.
+    let output = dir.0.join("book.m4b");
+    let mut decoder = FileDecoder::open(File::open(output).unwrap(), Some("m4b")).unwrap();
+    let mut meter = LoudnessMeter::new(44100, 2);
+    while let Some(chunk) = decoder.next_chunk().unwrap() {
+        meter.add(chunk.samples);
+    }
+    assert_near(meter.finish().loudness().integrated, -18.0, 0.2);
+}
+
+#[test]
+fn the_book_is_normalized_as_a_whole() {
+    let (_dir, report) = normalize(NormalizeScope::Book);
+
+    let book = report.loudness.unwrap().integrated;
+    for file in &report.files {
+        assert_near(file.gain.unwrap(), -18.0 - book, 1e-9);
+    }
+}
+
+#[test]
+fn gain_stops_at_the_true_peak_ceiling() {
+    let options = NormalizeOptions {
+        target: -3.0,
+        ..NormalizeOptions::default()
+    };
+    let mut meter = LoudnessMeter::new(44100, 1);
+    meter.add(&sine(44100, 997.0, 0.5, 0.0, 2.0));
+    let loudness = meter.finish().loudness();
+    // A tone at -9 LUFS would need 6dB of gain, but its peak is at -6dBTP.
+    assert_near(loudness.integrated, -9.03, 0.05);
+    assert_near(options.gain(&loudness), -1.0 - loudness.true_peak, 1e-9);
+    assert_near(options.gain(&loudness), 5.0, 0.05);
+}
+
+/// The value of the freeform item `name` in the m4b file `data`.
+fn freeform(data: &[u8], name: &str) -> String {
+    let at = data
+        .windows(name.len())
+        .position(|window| window == name.as_bytes())
+        .unwrap_or_else(|| panic!("no {name} item"));
+    // The `data` box follows the name: its size and type, the type of its
+    // value and the locale.
+    let data = &data[at + name.len()..];
+    let size = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+    assert_eq!(&data[4..8], b"data");
+    String::from_utf8(data[16..size].to_vec()).unwrap()
+}
+
+#[test]
+fn the_book_can_be_tagged_with_its_gain_instead() {
+    let dir = TempDir::new("gain-tags");
+    let pcm = |amplitude| {
+        let tone = sine(44100, 997.0, amplitude, 0.0, 3.0);
+        let samples: Vec<i16> = tone.iter().map(|&s| (s * 32767.0) as i16).collect();
+        wav_of(44100, 2, &samples)
+    };
+    let inputs = [
+        dir.write("quiet.wav", &pcm(0.05)),
+        dir.write("loud.wav", &pcm(0.2)),
+    ];
+    let output = dir.0.join("book.m4b");
+    let report = ConsolidationJob::new(&output)
+        .inputs(&inputs)
+        .loudness(LoudnessOptions {
+            measure: false,
+            normalize: Some(NormalizeOptions {
+                mode: NormalizeMode::Tags,
+                ..NormalizeOptions::default()
+            }),
+        })
+        .run()
+        .unwrap();
+
+    // The audio is left alone, and each chapter measured as written.
+    assert!(report.files.iter().all(|file| file.gain.is_none()));
+    let chapters: Vec<_> = report
+        .chapters
+        .iter()
+        .map(|chapter| chapter.loudness.unwrap().integrated)
+        .collect();
+    assert_near(chapters[0], -26.0, 0.05);
+    assert_near(chapters[1], -14.0, 0.05);
+
+    let book = report.loudness.unwrap();
+    let gain = -18.0 - book.integrated;
+    let data = std::fs::read(&output).unwrap();
+    let replay_gain = freeform(&data, "REPLAYGAIN_TRACK_GAIN");
+    assert_eq!(replay_gain, format!("{gain:.2} dB"));
+    assert_eq!(freeform(&data, "REPLAYGAIN_ALBUM_GAIN"), replay_gain);
+    let peak: f64 = freeform(&data, "REPLAYGAIN_TRACK_PEAK").parse().unwrap();
+    assert_near(peak, 0.2, 0.005);
+
+    let sound_check: Vec<u32> = freeform(&data, "iTunNORM")
+        .split_whitespace()
+        .map(|value| u32::from_str_radix(value, 16).unwrap())
+        .collect();
+    assert_eq!(sound_check.len(), 10);
+    let level = 1000.0 * 10f64.powf(-gain / 10.0);
+    assert_near(f64::from(sound_check[0]), level, 1.0);
+    assert_near(f64::from(sound_check[2]), level * 2.5, 1.0);
+}
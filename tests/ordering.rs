@@ -0,0 +1,147 @@
+//! Ordering the inputs by name and by their track and disc tags.
+use std::{cmp::Ordering, path::PathBuf};
+
+use consolidator::ordering::{natural_cmp, sort, Error, OrderStrategy};
+
+mod common;
+
+use common::{id3v2, wav, TempDir};
+
+/// A short WAV file behind an `ID3v2` tag of the frames `frames`.
+fn tagged(dir: &TempDir, name: &str, frames: &[(&[u8; 4], &str)]) -> PathBuf {
+    let mut data = id3v2(frames);
+    data.extend(wav(8000, 1, 80));
+    dir.write(name, &data)
+}
+
+fn sorted(mut names: Vec<&str>) -> Vec<&str> {
+    names.sort_by(|a, b| natural_cmp(a, b));
+    names
+}
+
+#[test]
+fn numbers_in_names_sort_by_value() {
+    assert_eq!(natural_cmp("2 - x", "10 - x"), Ordering::Less);
+    assert_eq!(
+        sorted(vec!["10 - x.mp3", "2 - x.mp3", "1 - x.mp3"]),
+        ["1 - x.mp3", "2 - x.mp3", "10 - x.mp3"]
+    );
+    assert_eq!(
+        sorted(vec![
+            "Part 2 Chapter 10",
+            "Part 10 Chapter 1",
+            "Part 2 Chapter 9"
+        ]),
+        ["Part 2 Chapter 9", "Part 2 Chapter 10", "Part 10 Chapter 1"]
+    );
+}
+
+#[test]
+fn leading_zeros_and_case_only_break_ties() {
+    assert_eq!(
+        sorted(vec!["Track 10", "track 009", "Track 1", "Track 01"]),
+        ["Track 01", "Track 1", "track 009", "Track 10"]
+    );
+    assert_eq!(natural_cmp("b", "A"), Ordering::Greater);
+    assert_eq!(natural_cmp("Chapter", "chapter 1"), Ordering::Less);
+    // Equal but for case, by their bytes.
+    assert_eq!(natural_cmp("Intro", "intro"), Ordering::Less);
+    assert_eq!(natural_cmp("intro", "intro"), Ordering::Equal);
+}
+
+#[test]
+fn tags_order_tracks_across_discs() {
+    let dir = TempDir::new("ordering-discs");
+    let mut paths = vec![
+        tagged(&dir, "a.wav", &[(b"TPOS", "2/2"), (b"TRCK", "1")]),
+        tagged(&dir, "b.wav", &[(b"TPOS", "1/2"), (b"TRCK", "10/10")]),
+        tagged(&dir, "c.wav", &[(b"TPOS", "1"), (b"TRCK", "2")]),
+        dir.write("d.txt", b"not audio"),
+        tagged(&dir, "e.wav", &[(b"TPOS", "2"), (b"TRCK", "02")]),
+        dir.write("0.txt", b"not audio either"),
+    ];
+
+    sort(&mut paths, OrderStrategy::Tags).unwrap();
+
+    // Files that can't be read as audio go last, by name.
+    let names: Vec<_> = paths
+        .iter()
+        .map(|path| path.file_name().unwrap().to_str().unwrap())
+        .collect();
+    assert_eq!(
+        names,
+        ["c.wav", "b.wav", "a.wav", "e.wav", "0.txt", "d.txt"]
+    );
+}
+
+#[test]
+fn tracks_without_discs_are_all_on_one() {
+    let dir = TempDir::new("ordering-tracks");
+    let second = tagged(&dir, "a.wav", &[(b"TRCK", "2")]);
+    let first = tagged(&dir, "b.wav", &[(b"TRCK", "1")]);
+    let mut paths = vec![second.clone(), first.clone()];
+
+    sort(&mut paths, OrderStrategy::Tags).unwrap();
+
+    assert_eq!(paths, [first, second]);
+}
+
+#[test]
+fn ambiguous_tags_are_errors() {
+    let dir = TempDir::new("ordering-errors");
+    let one = tagged(&dir, "1.wav", &[(b"TPOS", "1"), (b"TRCK", "1")]);
+    let again = tagged(&dir, "2.wav", &[(b"TPOS", "1"), (b"TRCK", "1")]);
+    let no_track = tagged(&dir, "3.wav", &[(b"TPOS", "1")]);
+    let no_disc = tagged(&dir, "4.wav", &[(b"TRCK", "2")]);
+
+    let result = sort(&mut vec![again.clone(), one.clone()], OrderStrategy::Tags);
+    assert!(
+        matches!(
+            &result,
+            Err(Error::DuplicateTrack { disc: 1, track: 1, first, second })
+                if *first == one && *second == again
+        ),
+        "{result:?}"
+    );
+
+    let result = sort(
+        &mut vec![one.clone(), no_track.clone()],
+        OrderStrategy::Tags,
+    );
+    assert!(
+        matches!(&result, Err(Error::MissingTrackNumber(path)) if *path == no_track),
+        "{result:?}"
+    );
+
+    let result = sort(&mut vec![one, no_disc.clone()], OrderStrategy::Tags);
+    assert!(
+        matches!(&result, Err(Error::MissingDiscNumber(path)) if *path == no_disc),
+        "{result:?}"
+    );
+}
+
+#[test]
+fn names_sort_by_file_name() {
+    let mut paths: Vec<PathBuf> = ["dir 10/b", "dir 2/10", "a", "dir 1/2"]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+
+    sort(&mut paths, OrderStrategy::Name).unwrap();
+    assert_eq!(
+        paths,
+        ["dir 1/2", "dir 2/10", "a", "dir 10/b"].map(PathBuf::from)
+    );
+}
+
+#[test]
+fn given_order_is_kept() {
+    let mut paths: Vec<PathBuf> = ["b", "10", "a", "2"].iter().map(PathBuf::from).collect();
+    let given = paths.clone();
+
+    sort(&mut paths, OrderStrategy::Given).unwrap();
+    assert_eq!(paths, given);
+
+    sort(&mut paths, OrderStrategy::Name).unwrap();
+    assert_eq!(paths, ["2", "10", "a", "b"].map(PathBuf::from));
+}
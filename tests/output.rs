@@ -0,0 +1,308 @@
+//! Reading back the m4b file a job writes: its AAC audio, and its chapters
+//! both as a Nero `chpl` box and as a text track.
+use std::{fs::File, path::Path};
+
+use consolidator::processor::ConsolidationJob;
+use symphonia::{
+    core::{
+        audio::SampleBuffer,
+        codecs::{DecoderOptions, CODEC_TYPE_AAC},
+        errors::Error as SymphoniaError,
+        formats::FormatOptions,
+        io::MediaSourceStream,
+        meta::MetadataOptions,
+        probe::Hint,
+    },
+    default::{get_codecs, get_probe},
+};
+
+mod common;
+
+use common::{wav, wav_of, TempDir};
+
+const RATE: u32 = 44100;
+/// Frames the AAC encoder primes the stream with, and the most it pads the
+/// end with to fill the last packet.
+const PRIMING: u64 = 1024;
+const MAX_PADDING: u64 = 2048;
+
+/// The boxes in `data`, each as its type and body.
+fn boxes(mut data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut boxes = Vec::new();
+    while data.len() >= 8 {
+        let size = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+        let kind = data[4..8].try_into().unwrap();
+        let (header, size) = if size == 1 {
+            let size = u64::from_be_bytes(data[8..16].try_into().unwrap());
+            (16, size as usize)
+        } else {
+            (8, size)
+        };
+        boxes.push((kind, &data[header..size]));
+        data = &data[size..];
+    }
+    boxes
+}
+
+/// The body of the first box along `path`, each a type within the last.
+fn find<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+    let (first, rest) = path.split_first()?;
+    let body = boxes(data).into_iter().find(|(kind, _)| kind == *first)?.1;
+    if rest.is_empty() {
+        Some(body)
+    } else {
+        find(body, rest)
+    }
+}
+
+fn u32_at(data: &[u8], pos: usize) -> u32 {
+    u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap())
+}
+
+/// The starts and titles of the chapters in the `chpl` box, in 100ns units.
+fn nero_chapters(moov: &[u8]) -> Vec<(u64, String)> {
+    let chpl = find(moov, &[b"udta", b"chpl"]).expect("no chpl box");
+    // Version and flags, and a reserved word.
+    let count = chpl[8];
+    let mut pos = 9;
+    (0..count)
+        .map(|_| {
+            let start = u64::from_be_bytes(chpl[pos..pos + 8].try_into().unwrap());
+            let len = usize::from(chpl[pos + 8]);
+            let title = String::from_utf8(chpl[pos + 9..pos + 9 + len].to_vec()).unwrap();
+            pos += 9 + len;
+            (start, title)
+        })
+        .collect()
+}
+
+/// The starts and titles of the samples of the chapter text track that the
+/// audio track refers to with `tref/chap`, with the track's timescale.
+fn text_chapters(file: &[u8], moov: &[u8]) -> (u32, Vec<(u64, String)>) {
+    let traks: Vec<&[u8]> = boxes(moov)
+        .into_iter()
+        .filter(|(kind, _)| kind == b"trak")
+        .map(|(_, body)| body)
+        .collect();
+    let chap = traks
+        .iter()
+        .find_map(|trak| find(trak, &[b"tref", b"chap"]))
+        .expect("no tref/chap box");
+    let id = u32_at(chap, 0);
+    let trak = traks
+        .iter()
+        .find(|trak| {
+            let tkhd = find(trak, &[b"tkhd"]).unwrap();
+            // Version 0: version and flags, then two times.
+            u32_at(tkhd, 12) == id
+        })
+        .expect("no chapter track");
+    let mdia = find(trak, &[b"mdia"]).unwrap();
+    let timescale = u32_at(find(mdia, &[b"mdhd"]).unwrap(), 12);
+    let stbl = find(mdia, &[b"minf", b"stbl"]).unwrap();
+
+    let stts = find(stbl, &[b"stts"]).unwrap();
+    let mut durations = Vec::new();
+    for entry in 0..u32_at(stts, 4) as usize {
+        let count = u32_at(stts, 8 + 8 * entry);
+        let delta = u32_at(stts, 12 + 8 * entry);
+        durations.extend((0..count).map(|_| u64::from(delta)));
+    }
+    let stsz = find(stbl, &[b"stsz"]).unwrap();
+    let (size, count) = (u32_at(stsz, 4), u32_at(stsz, 8) as usize);
+    let sizes: Vec<usize> = (0..count)
+        .map(|i| if size == 0 { u32_at(stsz, 12 + 4 * i) } else { size } as usize)
+        .collect();
+    let offsets: Vec<usize> = match find(stbl, &[b"stco"]) {
+        Some(stco) => (0..u32_at(stco, 4) as usize)
+            .map(|i| u32_at(stco, 8 + 4 * i) as usize)
+            .collect(),
+        None => {
+            let co64 = find(stbl, &[b"co64"]).unwrap();
+            (0..u32_at(co64, 4) as usize)
+                .map(|i| u64::from_be_bytes(co64[8 + 8 * i..16 + 8 * i].try_into().unwrap()))
+                .map(|offset| offset as usize)
+                .collect()
+        }
+    };
+    // Each run of chunks from a first chunk has so many samples each.
+    let stsc = find(stbl, &[b"stsc"]).unwrap();
+    let runs: Vec<(usize, usize)> = (0..u32_at(stsc, 4) as usize)
+        .map(|i| {
+            let first = u32_at(stsc, 8 + 12 * i) as usize - 1;
+            (first, u32_at(stsc, 12 + 12 * i) as usize)
+        })
+        .collect();
+    let mut samples = Vec::new();
+    for (chunk, &offset) in offsets.iter().enumerate() {
+        let per_chunk = runs
+            .iter()
+            .rev()
+            .find(|(first, _)| *first <= chunk)
+            .unwrap()
+            .1;
+        let mut pos = offset;
+        for _ in 0..per_chunk {
+            let size = sizes[samples.len()];
+            samples.push(&file[pos..pos + size]);
+            pos += size;
+        }
+    }
+
+    let mut start = 0;
+    let chapters = samples
+        .iter()
+        .zip(durations)
+        .map(|(sample, duration)| {
+            let len = usize::from(u16::from_be_bytes([sample[0], sample[1]]));
+            let title = String::from_utf8(sample[2..2 + len].to_vec()).unwrap();
+            start += duration;
+            (start - duration, title)
+        })
+        .collect();
+    (timescale, chapters)
+}
+
+/// The codec, rate and channels of the audio in `path`, and the interleaved
+/// samples it decodes to.
+fn decode(path: &Path) -> (bool, u32, usize, Vec<f32>) {
+    let source = MediaSourceStream::new(Box::new(File::open(path).unwrap()), Default::default());
+    let mut hint = Hint::new();
+    hint.with_extension("m4b");
+    let mut format = get_probe()
+        .format(
+            &hint,
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .unwrap()
+        .format;
+    let track = format.default_track().unwrap();
+    let (id, params) = (track.id, track.codec_params.clone());
+    let mut decoder = get_codecs()
+        .make(&params, &DecoderOptions::default())
+        .unwrap();
+    let mut samples = Vec::new();
+    let mut spec = None;
+    loop {
+        match format.next_packet() {
+            // Chapter titles are packets of their own track.
+            Ok(packet) if packet.track_id() != id => {}
+            Ok(packet) => {
+                let decoded = decoder.decode(&packet).unwrap();
+                spec.get_or_insert(*decoded.spec());
+                let mut buf = SampleBuffer::new(decoded.capacity() as u64, *decoded.spec());
+                buf.copy_interleaved_ref(decoded);
+                samples.extend_from_slice(buf.samples());
+            }
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break
+            }
+            Err(e) => panic!("{e}"),
+        }
+    }
+    // The channels are only known from the decoder's configuration.
+    let spec = spec.unwrap();
+    (
+        params.codec == CODEC_TYPE_AAC,
+        spec.rate,
+        spec.channels.count(),
+        samples,
+    )
+}
+
+#[test]
+fn output_decodes_as_aac_with_both_kinds_of_chapters() {
+    let dir = TempDir::new("output");
+    let inputs = [
+        dir.write("One.wav", &wav(RATE, 2, RATE * 3 / 2)),
+        dir.write("Two.wav", &wav(RATE, 2, RATE)),
+    ];
+    let output = dir.0.join("book.m4b");
+
+    let report = ConsolidationJob::new(&output)
+        .inputs(&inputs)
+        .run()
+        .unwrap();
+    assert_eq!(report.output.as_deref(), Some(output.as_path()));
+
+    let (aac, rate, channels, samples) = decode(&output);
+    assert!(aac);
+    assert_eq!((rate, channels), (RATE, 2));
+    let frames = (samples.len() / channels) as u64;
+    // Decoding yields the priming and padding as well as the audio.
+    let presented = u64::from(RATE) * 5 / 2;
+    assert!(
+        (presented + PRIMING..=presented + PRIMING + MAX_PADDING).contains(&frames),
+        "{frames} frames"
+    );
+
+    let file = std::fs::read(&output).unwrap();
+    let moov = find(&file, &[b"moov"]).expect("no moov box");
+    let titles = ["One".to_owned(), "Two".to_owned()];
+    assert_eq!(
+        nero_chapters(moov),
+        [(0, titles[0].clone()), (15_000_000, titles[1].clone())]
+    );
+    assert_eq!(
+        text_chapters(&file, moov),
+        (
+            RATE,
+            vec![
+                (0, titles[0].clone()),
+                (u64::from(RATE) * 3 / 2, titles[1].clone())
+            ]
+        )
+    );
+}
+
+#[test]
+fn rates_too_high_for_the_sample_entry_are_left_to_the_decoder_config() {
+    let dir = TempDir::new("output-96k");
+    let input = dir.write("1.wav", &wav(96000, 1, 96000));
+    let output = dir.0.join("book.m4b");
+
+    ConsolidationJob::new(&output).input(&input).run().unwrap();
+
+    let file = std::fs::read(&output).unwrap();
+    let moov = find(&file, &[b"moov"]).unwrap();
+    let mdia = find(moov, &[b"trak", b"mdia"]).unwrap();
+    assert_eq!(u32_at(find(mdia, &[b"mdhd"]).unwrap(), 12), 96000);
+    let stsd = find(mdia, &[b"minf", b"stbl", b"stsd"]).unwrap();
+    // The entry count, then the mp4a entry, whose rate is after 24 bytes.
+    let mp4a = find(&stsd[8..], &[b"mp4a"]).unwrap();
+    assert_eq!(u32_at(mp4a, 24), 0);
+    let (aac, rate, channels, _) = decode(&output);
+    assert!(aac);
+    assert_eq!((rate, channels), (96000, 1));
+}
+
+#[test]
+fn full_scale_sine_survives_encoding() {
+    let dir = TempDir::new("output-sine");
+    let sine: Vec<i16> = (0..RATE)
+        .map(|i| {
+            let phase = 2.0 * std::f64::consts::PI * 1000.0 * f64::from(i) / f64::from(RATE);
+            (phase.sin() * f64::from(i16::MAX)) as i16
+        })
+        .collect();
+    let input = dir.write("sine.wav", &wav_of(RATE, 1, &sine));
+    let output = dir.0.join("book.m4b");
+
+    ConsolidationJob::new(&output).input(&input).run().unwrap();
+
+    let (_, rate, channels, samples) = decode(&output);
+    assert_eq!((rate, channels), (RATE, 1));
+    // Past the priming, the decoded audio lines up with the input.
+    let decoded = &samples[PRIMING as usize..];
+    assert!(decoded.len() >= sine.len());
+    let (mut signal, mut noise) = (0.0, 0.0);
+    for (&expected, &actual) in sine.iter().zip(decoded) {
+        let expected = f64::from(expected) / 32768.0;
+        signal += expected * expected;
+        noise += (f64::from(actual) - expected).powi(2);
+    }
+    let snr = 10.0 * (signal / noise).log10();
+    assert!(snr > 30.0, "SNR of {snr:.1}dB");
+}
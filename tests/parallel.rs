@@ -0,0 +1,47 @@
+//! Decoding inputs on several threads.
+use std::{fs, num::NonZeroUsize, path::PathBuf};
+
+use consolidator::processor::ConsolidationJob;
+
+mod common;
+
+use common::{wav, TempDir};
+
+#[test]
+fn output_is_the_same_whatever_the_number_of_jobs() {
+    let dir = TempDir::new("parallel");
+    let inputs: Vec<PathBuf> = [
+        ("1.wav", wav(44100, 2, 30000)),
+        ("2.wav", wav(22050, 1, 20000)),
+        ("3.wav", b"RIFF".to_vec()),
+        ("4.wav", wav(48000, 2, 50000)),
+        ("5.wav", wav(44100, 1, 1000)),
+        ("6.wav", wav(44100, 2, 70000)),
+    ]
+    .iter()
+    .map(|(name, data)| dir.write(name, data))
+    .collect();
+
+    let outputs: Vec<_> = [1, 2, 4, 8]
+        .into_iter()
+        .map(|jobs| {
+            // The title of the book comes from the name of the output.
+            let output = dir.0.join(jobs.to_string()).join("book.m4b");
+            fs::create_dir(output.parent().unwrap()).unwrap();
+            let report = ConsolidationJob::new(&output)
+                .inputs(&inputs)
+                .jobs(NonZeroUsize::new(jobs))
+                .run()
+                .unwrap();
+            let paths: Vec<_> = report.files.iter().map(|file| &file.path).collect();
+            assert_eq!(paths, inputs.iter().collect::<Vec<_>>());
+            assert_eq!(report.failures().count(), 1);
+            assert_eq!(report.chapters.len(), 5);
+            fs::read(output).unwrap()
+        })
+        .collect();
+
+    for output in &outputs[1..] {
+        assert!(*output == outputs[0]);
+    }
+}
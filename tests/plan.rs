@@ -0,0 +1,156 @@
+//! Plans of what a consolidation job would produce.
+use consolidator::{
+    mp4::Metadata,
+    ordering::OrderStrategy,
+    processor::{ConsolidationJob, PlannedCover},
+    silence::{SilenceOptions, SplitOptions},
+};
+
+mod common;
+
+use common::{wav, TempDir};
+
+#[test]
+fn plan_lists_chapters_format_and_size_without_writing() {
+    let dir = TempDir::new("plan");
+    let first = dir.write("1 - first.wav", &wav(44100, 2, 88200));
+    let broken = dir.write("2 - broken.wav", b"RIFF");
+    let last = dir.write("10 - last.wav", &wav(48000, 2, 24000));
+    dir.write("cover.jpg", b"not really a jpeg");
+    let output = dir.0.join("book.m4b");
+
+    let plan = ConsolidationJob::new(&output)
+        .inputs([&last, &broken, &first])
+        .order(OrderStrategy::Name)
+        .metadata(Metadata {
+            author: Some("Someone".to_owned()),
+            ..Metadata::default()
+        })
+        .plan()
+        .unwrap();
+
+    assert!(!output.exists());
+    let paths: Vec<_> = plan.files.iter().map(|file| &file.path).collect();
+    assert_eq!(paths, [&first, &broken, &last]);
+    assert!(plan.files[1].error.is_some());
+    assert_eq!(plan.files[2].sample_rate, Some(48000));
+
+    let chapters: Vec<_> = plan
+        .chapters
+        .iter()
+        .map(|chapter| (chapter.title.as_str(), chapter.start, chapter.duration))
+        .collect();
+    assert_eq!(
+        chapters,
+        [
+            ("1 - first", Some(0.0), Some(2.0)),
+            ("10 - last", Some(2.0), Some(0.5))
+        ]
+    );
+
+    // Both rates are as common, so the higher one is chosen.
+    assert_eq!((plan.sample_rate, plan.channels), (Some(48000), Some(2)));
+    assert_eq!(plan.bitrate, Some(128_000));
+    assert!((plan.duration - 2.5).abs() < 1e-9);
+    let size = plan.estimated_size.unwrap();
+    assert!((40_000..50_000).contains(&size), "{size}");
+
+    assert_eq!(plan.metadata.title.as_deref(), Some("book"));
+    assert_eq!(plan.metadata.author.as_deref(), Some("Someone"));
+    assert_eq!(
+        plan.cover,
+        Some(PlannedCover::File(dir.0.join("cover.jpg")))
+    );
+
+    let json = serde_json::to_value(&plan).unwrap();
+    assert_eq!(json["chapters"][1]["start"], 2.0);
+    assert_eq!(json["cover"]["source"], "file");
+    assert!(plan.to_string().contains("10 - last"));
+}
+
+#[test]
+fn plan_times_are_before_trimming_silence() {
+    let dir = TempDir::new("plan-untrimmed");
+    let input = dir.write("1.wav", &wav(44100, 2, 44100));
+    let job = ConsolidationJob::new(dir.0.join("book.m4b")).input(&input);
+
+    let plan = job.clone().plan().unwrap();
+    assert!(!plan.untrimmed);
+    assert!(!plan.to_string().contains("trimming"));
+
+    let plan = job.silence(Some(SilenceOptions::default())).plan().unwrap();
+    assert!(plan.untrimmed);
+    assert!((plan.duration - 1.0).abs() < 1e-9);
+    assert!(plan.to_string().contains("before trimming silence"));
+}
+
+#[test]
+fn chapters_to_be_split_at_pauses_are_marked_unsplit() {
+    let dir = TempDir::new("plan-unsplit");
+    let input = dir.write("1.wav", &wav(44100, 2, 44100));
+    let job = ConsolidationJob::new(dir.0.join("book.m4b")).input(&input);
+
+    let plan = job.clone().plan().unwrap();
+    assert!(!plan.chapters[0].unsplit);
+
+    let plan = job.split(Some(SplitOptions::default())).plan().unwrap();
+    assert_eq!(plan.chapters.len(), 1);
+    assert!(plan.chapters[0].unsplit);
+    assert!(plan.to_string().contains("1 *\n"));
+    assert_eq!(
+        serde_json::to_value(&plan).unwrap()["chapters"][0]["unsplit"],
+        true
+    );
+}
+
+#[test]
+fn chapters_are_planned_at_the_tracks_of_cue_sheets() {
+    let dir = TempDir::new("plan-cue");
+    let inputs = [
+        dir.write("a.wav", &wav(22050, 1, 22050 * 3)),
+        dir.write("b.wav", &wav(22050, 1, 22050 * 3)),
+        dir.write("c.wav", &wav(22050, 1, 22050)),
+    ];
+    dir.write(
+        "disc.cue",
+        b"TITLE \"The Book\"\n\
+          FILE \"a.wav\" WAVE\n\
+          \x20 TRACK 01 AUDIO\n    TITLE One\n    INDEX 01 00:00:00\n\
+          \x20 TRACK 02 AUDIO\n    TITLE Two\n    INDEX 01 00:02:00\n\
+          FILE \"b.wav\" WAVE\n\
+          \x20 TRACK 03 AUDIO\n    TITLE Three\n    INDEX 01 00:01:00\n",
+    );
+    let job = ConsolidationJob::new(dir.0.join("book.m4b")).inputs(&inputs);
+
+    let plan = job.clone().plan().unwrap();
+
+    // The start of b.wav finishes track two, just as when running.
+    let planned: Vec<_> = plan
+        .chapters
+        .iter()
+        .map(|chapter| (chapter.title.clone(), chapter.start, chapter.duration))
+        .collect();
+    assert_eq!(
+        planned,
+        [
+            ("One".to_owned(), Some(0.0), Some(2.0)),
+            ("Two".to_owned(), Some(2.0), Some(2.0)),
+            ("Three".to_owned(), Some(4.0), Some(2.0)),
+            ("c".to_owned(), Some(6.0), Some(1.0)),
+        ]
+    );
+    assert_eq!(plan.metadata.title.as_deref(), Some("The Book"));
+    let report = job.run().unwrap();
+    let run: Vec<_> = report
+        .chapters
+        .iter()
+        .map(|chapter| {
+            (
+                chapter.title.clone(),
+                Some(chapter.start),
+                Some(chapter.duration),
+            )
+        })
+        .collect();
+    assert_eq!(planned, run);
+}
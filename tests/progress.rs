@@ -0,0 +1,71 @@
+//! Progress reported while a job runs.
+use std::sync::{Arc, Mutex};
+
+use consolidator::{
+    processor::ConsolidationJob,
+    progress::{Progress, ProgressSink},
+};
+
+mod common;
+
+use common::{wav, TempDir};
+
+/// The events of a job, less those that come with every packet, and the
+/// files started, which workers get to in no particular order.
+#[derive(Default)]
+struct Recorder {
+    events: Mutex<Vec<String>>,
+    started: Mutex<Vec<(usize, Option<f64>)>>,
+    /// Seconds of the last input decoded, and of the output encoded.
+    decoded: Mutex<f64>,
+    encoded: Mutex<f64>,
+}
+
+impl ProgressSink for Recorder {
+    fn progress(&self, event: Progress<'_>) {
+        match event {
+            Progress::FileStarted {
+                index, duration, ..
+            } => self.started.lock().unwrap().push((index, duration)),
+            Progress::Decoded {
+                index: 2, seconds, ..
+            } => *self.decoded.lock().unwrap() = seconds,
+            Progress::Encoded { seconds, .. } => *self.encoded.lock().unwrap() = seconds,
+            Progress::Decoded { .. } => {}
+            event => self.events.lock().unwrap().push(format!("{event:?}")),
+        }
+    }
+}
+
+#[test]
+fn progress_is_reported_for_each_file() {
+    let dir = TempDir::new("progress");
+    let first = dir.write("1.wav", &wav(44100, 2, 44100));
+    let broken = dir.write("2.mp3", b"not audio");
+    let last = dir.write("3.wav", &wav(44100, 2, 22050));
+    let recorder = Arc::new(Recorder::default());
+
+    ConsolidationJob::new(dir.0.join("book.m4b"))
+        .inputs([&first, &broken, &last])
+        .jobs(None)
+        .progress(recorder.clone())
+        .run()
+        .unwrap();
+
+    let mut started = recorder.started.lock().unwrap().clone();
+    started.sort_by_key(|&(index, _)| index);
+    assert_eq!(started, [(0, Some(1.0)), (2, Some(0.5))]);
+    assert_eq!(
+        *recorder.events.lock().unwrap(),
+        [
+            "Started { files: 3 }",
+            "FileFinished { index: 0, failed: false }",
+            "FileFinished { index: 1, failed: true }",
+            "FileFinished { index: 2, failed: false }",
+            "Finalizing",
+            "Finished",
+        ]
+    );
+    assert!((*recorder.decoded.lock().unwrap() - 0.5).abs() < 1e-9);
+    assert!((*recorder.encoded.lock().unwrap() - 1.5).abs() < 1e-9);
+}
@@ -0,0 +1,118 @@
+//! The report returned by a consolidation job.
+use consolidator::{
+    decode::{self, ErrorMode},
+    processor::{ConsolidationJob, Error, FileStatus},
+};
+
+mod common;
+
+use common::{mp3_frame, wav, TempDir};
+
+#[test]
+fn report_records_each_file_and_chapter() {
+    let dir = TempDir::new("report");
+    let first = dir.write("one.wav", &wav(44100, 2, 44100));
+    let broken = dir.write("two.mp3", b"not audio at all");
+    let third = dir.write("three.wav", &wav(22050, 1, 11025));
+    let output = dir.0.join("book.m4b");
+
+    let report = ConsolidationJob::new(&output)
+        .inputs([&first, &broken, &third])
+        .run()
+        .unwrap();
+
+    assert_eq!(report.output.as_deref(), Some(output.as_path()));
+    assert!(output.exists());
+    assert_eq!(
+        (report.sample_rate, report.channels),
+        (Some(44100), Some(2))
+    );
+
+    // Inputs keep the order they were given in.
+    let paths: Vec<_> = report.files.iter().map(|file| &file.path).collect();
+    assert_eq!(paths, [&first, &broken, &third]);
+    let failed: Vec<_> = report.failures().map(|file| &file.path).collect();
+    assert_eq!(failed, [&broken]);
+    assert!(matches!(report.files[0].status, FileStatus::Succeeded));
+
+    let third = &report.files[2];
+    assert_eq!(third.codec, Some("pcm_s16le"));
+    assert_eq!((third.sample_rate, third.channels), (Some(22050), Some(1)));
+    assert_eq!(third.frames, 11025);
+    assert!((third.duration - 0.5).abs() < 1e-9);
+
+    let chapters: Vec<_> = report
+        .chapters
+        .iter()
+        .map(|chapter| (chapter.title.as_str(), chapter.start, chapter.duration))
+        .collect();
+    assert_eq!(chapters, [("one", 0.0, 1.0), ("three", 1.0, 0.5)]);
+    assert!((report.duration - 1.5).abs() < 1e-9);
+}
+
+#[test]
+fn report_serializes_to_json() {
+    let dir = TempDir::new("json");
+    let good = dir.write("good.wav", &wav(48000, 1, 4800));
+    let broken = dir.write("broken.wav", b"RIFF");
+
+    let report = ConsolidationJob::new(dir.0.join("book.m4b"))
+        .inputs([&good, &broken])
+        .run()
+        .unwrap();
+    let json = serde_json::to_value(&report).unwrap();
+
+    assert_eq!(json["sample_rate"], 48000);
+    assert_eq!(json["files"][0]["status"], "succeeded");
+    assert_eq!(json["files"][0]["detection"]["format"], "wav");
+    assert_eq!(json["files"][0]["detection"]["method"], "magic");
+    assert_eq!(json["files"][1]["status"], "failed");
+    assert!(json["files"][1]["error"]
+        .as_str()
+        .is_some_and(|e| !e.is_empty()));
+    assert_eq!(json["chapters"][0]["title"], "good");
+}
+
+#[test]
+fn corrupt_packets_are_replaced_with_silence_unless_strict() {
+    let dir = TempDir::new("corrupt");
+    // Frames 8 to 10 claim more main data than the frame holds, so they
+    // can't be decoded, though they can still be read as packets.
+    let data: Vec<u8> = (0..20)
+        .flat_map(|i| {
+            let mut frame = mp3_frame(44100, false);
+            if (8..11).contains(&i) {
+                frame[6..8].copy_from_slice(&[0xff, 0xf0]);
+            }
+            frame
+        })
+        .collect();
+    let input = dir.write("corrupt.mp3", &data);
+    let output = dir.0.join("book.m4b");
+
+    let report = ConsolidationJob::new(&output).input(&input).run().unwrap();
+
+    let file = &report.files[0];
+    assert!(matches!(file.status, FileStatus::Succeeded));
+    assert_eq!(file.skipped_packets, 3);
+    assert_eq!(file.warnings.len(), 1, "{:?}", file.warnings);
+    // The silence is as long as the packets it replaces.
+    assert_eq!(file.frames, 20 * 1152);
+    assert!((file.duration - 20.0 * 1152.0 / 44100.0).abs() < 1e-9);
+    assert!((report.chapters[0].duration - file.duration).abs() < 1e-9);
+
+    std::fs::remove_file(&output).unwrap();
+    let result = ConsolidationJob::new(&output)
+        .input(&input)
+        .errors(ErrorMode::Strict)
+        .run();
+
+    assert!(
+        matches!(
+            result,
+            Err(Error::Decode(decode::Error::Decode { packet: 8, .. }))
+        ),
+        "{result:?}"
+    );
+    assert!(!output.exists());
+}
@@ -0,0 +1,166 @@
+//! Accuracy of the sample-rate conversion, measured on sine sweeps.
+use std::f64::consts::PI;
+
+use consolidator::resample::{Quality, Resampler, TargetRate};
+
+/// Length of each sweep, in seconds.
+const SWEEP_SECONDS: f64 = 1.0;
+/// Output samples at each end that are left out of the measurements, as the
+/// sweeps start and stop abruptly.
+const MARGIN: usize = 1000;
+
+/// A linear sine sweep from `f0` to `f1` Hz, sampled at time `t` seconds.
+fn sweep(f0: f64, f1: f64, t: f64) -> f64 {
+    let rate = (f1 - f0) / SWEEP_SECONDS;
+    (2.0 * PI * (f0 * t + rate * t * t / 2.0)).sin()
+}
+
+/// The sweep from `f0` to `f1` Hz sampled at `rate`, at half of full scale.
+fn sweep_at(rate: u32, f0: f64, f1: f64) -> Vec<f32> {
+    let len = (SWEEP_SECONDS * f64::from(rate)) as usize;
+    (0..len)
+        .map(|n| (0.5 * sweep(f0, f1, n as f64 / f64::from(rate))) as f32)
+        .collect()
+}
+
+/// Resamples mono `input`, feeding it in uneven chunks.
+fn resample(input: &[f32], from: u32, to: u32, quality: Quality) -> Vec<f32> {
+    let mut resampler = Resampler::new(from, to, 1, quality);
+    let mut output = Vec::new();
+    for chunk in input.chunks(1000).flat_map(|c| c.chunks(c.len() / 3 + 1)) {
+        resampler.process(chunk, &mut output);
+    }
+    resampler.finish(&mut output);
+    output
+}
+
+/// Power of `samples` relative to a full-scale sine, in dB.
+fn level_db(samples: impl Iterator<Item = f64>) -> f64 {
+    let (sum, count) = samples.fold((0.0, 0), |(sum, count), x| (sum + x * x, count + 1));
+    10.0 * (sum / f64::from(count) / 0.5).log10()
+}
+
+/// Level of the energy left in the output of a sweep that lies entirely
+/// above the output's Nyquist frequency, all of which would alias.
+fn alias_level(from: u32, to: u32, quality: Quality) -> f64 {
+    let nyquist = f64::from(to) / 2.0;
+    let input = sweep_at(from, nyquist * 1.05, f64::from(from) / 2.0 * 0.99);
+    let output = resample(&input, from, to, quality);
+    level_db(
+        output[MARGIN..output.len() - MARGIN]
+            .iter()
+            .map(|&x| f64::from(x)),
+    )
+}
+
+/// Level of the difference between the output of an in-band sweep and the
+/// same sweep sampled directly at the output rate, which includes passband
+/// ripple, images and aliases.
+fn error_level(from: u32, to: u32, f1: f64, quality: Quality) -> f64 {
+    let input = sweep_at(from, 20.0, f1);
+    let expected = sweep_at(to, 20.0, f1);
+    let output = resample(&input, from, to, quality);
+    assert_eq!(output.len(), expected.len());
+    level_db(
+        output[MARGIN..output.len() - MARGIN]
+            .iter()
+            .zip(&expected[MARGIN..])
+            .map(|(&x, &y)| f64::from(x) - f64::from(y)),
+    )
+}
+
+#[test]
+fn downsampling_rejects_aliases() {
+    for (quality, limit) in [
+        (Quality::Fast, -60.0),
+        (Quality::Balanced, -90.0),
+        (Quality::Best, -120.0),
+    ] {
+        for (from, to) in [(48000, 44100), (44100, 22050), (48000, 32000)] {
+            let level = alias_level(from, to, quality);
+            assert!(
+                level < limit,
+                "{quality:?} {from}->{to}: aliases at {level:.1}dB"
+            );
+        }
+    }
+}
+
+#[test]
+fn passband_is_preserved() {
+    for (quality, flat, limit) in [
+        (Quality::Fast, 0.7, -60.0),
+        (Quality::Balanced, 0.85, -90.0),
+        (Quality::Best, 0.9, -120.0),
+    ] {
+        for (from, to) in [
+            (22050, 48000),
+            (44100, 48000),
+            (48000, 44100),
+            (32000, 44100),
+        ] {
+            let f1 = f64::from(from.min(to)) / 2.0 * flat;
+            let level = error_level(from, to, f1, quality);
+            assert!(
+                level < limit,
+                "{quality:?} {from}->{to}: error at {level:.1}dB"
+            );
+        }
+    }
+}
+
+#[test]
+fn higher_quality_is_more_accurate() {
+    let errors: Vec<f64> = [Quality::Fast, Quality::Balanced, Quality::Best]
+        .into_iter()
+        .map(|quality| error_level(44100, 48000, 15000.0, quality))
+        .collect();
+    assert!(
+        errors.windows(2).all(|pair| pair[1] < pair[0]),
+        "{errors:?}"
+    );
+}
+
+#[test]
+fn output_length_follows_the_ratio() {
+    for (from, to, frames, expected) in [
+        (44100, 48000, 44100, 48000),
+        (48000, 44100, 1000, 919),
+        (22050, 48000, 7, 15),
+        (44100, 44100, 5000, 5000),
+    ] {
+        for channels in [1, 2] {
+            let input = vec![0.25; frames * channels];
+            let mut resampler = Resampler::new(from, to, channels, Quality::Balanced);
+            let mut output = Vec::new();
+            resampler.process(&input, &mut output);
+            resampler.finish(&mut output);
+            assert_eq!(output.len(), expected * channels, "{from}->{to}");
+        }
+    }
+}
+
+#[test]
+fn channels_are_kept_apart() {
+    let left = sweep_at(44100, 100.0, 5000.0);
+    let interleaved: Vec<f32> = left.iter().flat_map(|&x| [x, -x]).collect();
+    let mut resampler = Resampler::new(44100, 48000, 2, Quality::Balanced);
+    let mut output = Vec::new();
+    resampler.process(&interleaved, &mut output);
+    resampler.finish(&mut output);
+    let mono = resample(&left, 44100, 48000, Quality::Balanced);
+    for (frame, &x) in output.chunks_exact(2).zip(&mono) {
+        assert_eq!(frame, [x, -x]);
+    }
+}
+
+#[test]
+fn target_rate_is_chosen_from_inputs() {
+    let rates = [44100, 48000, 44100, 22050, 48000, 44100];
+    assert_eq!(TargetRate::MostCommon.choose(&rates), Some(44100));
+    assert_eq!(TargetRate::Highest.choose(&rates), Some(48000));
+    assert_eq!(TargetRate::Fixed(32000).choose(&rates), Some(32000));
+    // Ties go to the higher rate.
+    assert_eq!(TargetRate::MostCommon.choose(&[22050, 48000]), Some(48000));
+    assert_eq!(TargetRate::MostCommon.choose(&[]), None);
+}
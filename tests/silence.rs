@@ -0,0 +1,173 @@
+//! Trimming the silence at the ends of the inputs, and splitting them into
+//! chapters at their pauses.
+use std::{num::NonZeroUsize, time::Duration};
+
+use consolidator::{
+    processor::ConsolidationJob,
+    silence::{SilenceOptions, SplitOptions},
+};
+
+mod common;
+
+use common::{wav_of, TempDir};
+
+const RATE: u32 = 22050;
+
+/// Audio of `parts`, each a number of seconds of silence or of sound.
+fn audio(parts: &[(bool, f64)]) -> Vec<i16> {
+    let mut samples = Vec::new();
+    for &(sound, seconds) in parts {
+        let frames = (seconds * f64::from(RATE)) as usize;
+        samples.extend((0..frames).map(|frame| match (sound, frame % 2) {
+            (false, _) => 0,
+            (true, 0) => 8000,
+            (true, _) => -8000,
+        }));
+    }
+    samples
+}
+
+#[test]
+fn silence_at_joins_is_trimmed_to_the_gap() {
+    let dir = TempDir::new("silence");
+    let inputs = [
+        // Silence within a file is kept.
+        dir.write(
+            "1.wav",
+            &wav_of(
+                RATE,
+                1,
+                &audio(&[
+                    (false, 2.0),
+                    (true, 0.5),
+                    (false, 2.0),
+                    (true, 0.5),
+                    (false, 3.0),
+                ]),
+            ),
+        ),
+        // Silence shorter than the minimum isn't trimmed.
+        dir.write(
+            "2.wav",
+            &wav_of(RATE, 1, &audio(&[(false, 0.5), (true, 1.0), (false, 2.0)])),
+        ),
+        dir.write(
+            "3.wav",
+            &wav_of(RATE, 1, &audio(&[(false, 1.5), (true, 1.0)])),
+        ),
+    ];
+
+    let report = ConsolidationJob::new(dir.0.join("book.m4b"))
+        .inputs(&inputs)
+        .silence(Some(SilenceOptions {
+            threshold: -50.0,
+            min_duration: Duration::from_secs(1),
+            gap: Duration::from_secs(1),
+        }))
+        .run()
+        .unwrap();
+
+    // Half the gap at the start and end of the book, and the whole gap at
+    // each join, made of an even share of each side where there's enough.
+    let chapters: Vec<_> = report
+        .chapters
+        .iter()
+        .map(|chapter| (chapter.start, chapter.duration))
+        .collect();
+    assert_eq!(chapters, [(0.0, 4.5), (4.5, 2.0), (6.5, 1.5)]);
+    let trimmed: Vec<_> = report
+        .files
+        .iter()
+        .map(|file| file.trimmed_silence)
+        .collect();
+    assert_eq!(trimmed, [3.5, 1.5, 1.0]);
+}
+
+#[test]
+fn silence_is_kept_unless_trimming_is_asked_for() {
+    let dir = TempDir::new("silence-kept");
+    let input = dir.write(
+        "1.wav",
+        &wav_of(RATE, 1, &audio(&[(false, 2.0), (true, 1.0), (false, 2.0)])),
+    );
+
+    let report = ConsolidationJob::new(dir.0.join("book.m4b"))
+        .input(&input)
+        .run()
+        .unwrap();
+
+    assert!((report.duration - 5.0).abs() < 1e-9);
+    assert_eq!(report.files[0].trimmed_silence, 0.0);
+}
+
+fn split(target_chapters: Option<usize>) -> Vec<(String, f64, f64)> {
+    let dir = TempDir::new(&format!("split-{target_chapters:?}"));
+    let input = dir.write(
+        "book.wav",
+        &wav_of(
+            RATE,
+            1,
+            &audio(&[
+                (true, 3.0),
+                (false, 1.0),
+                (true, 2.0),
+                (false, 3.0),
+                (true, 2.0),
+                // Too short to be a pause.
+                (false, 0.5),
+                (true, 2.0),
+            ]),
+        ),
+    );
+
+    let report = ConsolidationJob::new(dir.0.join("book.m4b"))
+        .input(&input)
+        .split(Some(SplitOptions {
+            threshold: -50.0,
+            min_pause: Duration::from_secs(1),
+            min_chapter: Duration::from_millis(1500),
+            target_chapters: target_chapters.and_then(NonZeroUsize::new),
+        }))
+        .run()
+        .unwrap();
+    report
+        .chapters
+        .into_iter()
+        .map(|chapter| (chapter.title, chapter.start, chapter.duration))
+        .collect()
+}
+
+#[test]
+fn inputs_are_split_at_their_pauses() {
+    let chapter = |n: usize, start, duration| (format!("Chapter {n}"), start, duration);
+    assert_eq!(
+        split(None),
+        [
+            chapter(1, 0.0, 3.5),
+            chapter(2, 3.5, 4.0),
+            chapter(3, 7.5, 6.0)
+        ]
+    );
+    // The longest pauses are taken first.
+    assert_eq!(split(Some(2)), [chapter(1, 0.0, 7.5), chapter(2, 7.5, 6.0)]);
+}
+
+#[test]
+fn chapters_are_exported_with_their_start_times() {
+    let dir = TempDir::new("export");
+    let inputs = [
+        dir.write("one.wav", &wav_of(RATE, 1, &audio(&[(true, 61.5)]))),
+        dir.write("two.wav", &wav_of(RATE, 1, &audio(&[(true, 1.0)]))),
+    ];
+    let report = ConsolidationJob::new(dir.0.join("book.m4b"))
+        .inputs(&inputs)
+        .run()
+        .unwrap();
+
+    let mut exported = Vec::new();
+    report.write_chapters(&mut exported).unwrap();
+    assert_eq!(
+        String::from_utf8(exported).unwrap(),
+        "00:00:00.000 one\n00:01:01.500 two\n"
+    );
+}
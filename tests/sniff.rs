@@ -0,0 +1,250 @@
+//! Identifying formats by their first bytes: ADTS streams of either id and
+//! with or without CRCs, MPEG audio behind tags and junk, files with the
+//! wrong extension, and the Xing and VBRI headers that give MP3 lengths.
+use std::{fs::File, path::Path};
+
+use consolidator::{
+    aac::{AacConfig, AacEncoder},
+    decode::{DetectionMethod, DurationSource, FileDecoder},
+};
+
+mod common;
+
+use common::{id3v2, lame_tag, mp3_frame, wav, TempDir};
+
+/// Frames of audio in each MPEG-1 layer III frame.
+const MP3_FRAME_LEN: u32 = 1152;
+/// Frames of audio in each AAC access unit.
+const AAC_FRAME_LEN: u64 = 1024;
+
+fn open(path: &Path) -> FileDecoder {
+    let extension = path.extension().and_then(|e| e.to_str());
+    FileDecoder::open(File::open(path).unwrap(), extension).unwrap()
+}
+
+/// Decodes all of `decoder`, returning the rate and channels of its audio
+/// and its frames.
+fn decode(mut decoder: FileDecoder) -> (Option<u32>, Option<usize>, u64) {
+    let mut spec = None;
+    while let Some(chunk) = decoder.next_chunk().unwrap() {
+        spec = Some(chunk.spec);
+    }
+    (
+        spec.map(|spec| spec.rate),
+        spec.map(|spec| spec.channels.count()),
+        decoder.frames(),
+    )
+}
+
+/// Half a second of a stereo tone at 44.1kHz as AAC access units.
+fn access_units() -> Vec<Vec<u8>> {
+    let mut encoder = AacEncoder::new(AacConfig {
+        sample_rate: 44100,
+        channels: 2,
+        bitrate: 128_000,
+    })
+    .unwrap();
+    let samples: Vec<f32> = (0..22050)
+        .flat_map(|i| [(i as f32 * 0.05).sin() * 0.25; 2])
+        .collect();
+    let mut units = Vec::new();
+    encoder.encode(&samples, &mut units);
+    encoder.finish(&mut units);
+    units
+}
+
+/// `unit` as an ADTS frame of stereo AAC-LC at 44.1kHz, with the MPEG-2 id
+/// if `mpeg2`, and with a (zero) CRC if `crc`.
+fn adts_frame(unit: &[u8], mpeg2: bool, crc: bool) -> Vec<u8> {
+    let header_len = if crc { 9 } else { 7 };
+    let len = header_len + unit.len();
+    let mut frame = vec![
+        0xff,
+        0xf0 | u8::from(mpeg2) << 3 | u8::from(!crc),
+        // AAC-LC, 44.1kHz, and the top bit of two channels.
+        1 << 6 | 4 << 2,
+        2 << 6 | (len >> 11) as u8,
+        (len >> 3) as u8,
+        (len << 5) as u8 | 0x1f,
+        0xfc,
+    ];
+    frame.resize(header_len, 0);
+    frame.extend(unit);
+    frame
+}
+
+#[test]
+fn adts_streams_are_read_with_either_id_and_crcs() {
+    let dir = TempDir::new("sniff-adts");
+    let units = access_units();
+    for (mpeg2, crc) in [(false, false), (false, true), (true, false), (true, true)] {
+        let mut data = Vec::new();
+        for (i, unit) in units.iter().enumerate() {
+            // Junk between frames past the first few is skipped too.
+            if i == 5 {
+                data.extend([0; 13]);
+            }
+            data.extend(adts_frame(unit, mpeg2, crc));
+        }
+        let path = dir.write("book.aac", &data);
+
+        let decoder = open(&path);
+        let detection = decoder.detection().clone();
+        assert_eq!(
+            (detection.format, detection.method, detection.skipped),
+            (Some("aac"), DetectionMethod::FrameSync, 0)
+        );
+        assert_eq!(
+            decode(decoder),
+            (Some(44100), Some(2), units.len() as u64 * AAC_FRAME_LEN),
+            "MPEG-2 id {mpeg2}, CRC {crc}"
+        );
+    }
+}
+
+#[test]
+fn mp3_is_found_behind_tags_and_junk() {
+    let dir = TempDir::new("sniff-mp3");
+    let mut data = id3v2(&[(b"TIT2", "Chapter One")]);
+    data.extend(id3v2(&[(b"TALB", "The Book")]));
+    // A frame sync that doesn't lead to another frame.
+    let junk = [
+        b"junk".as_slice(),
+        &mp3_frame(44100, false)[..100],
+        &[0; 50],
+    ]
+    .concat();
+    data.extend(&junk);
+    for _ in 0..10 {
+        data.extend(mp3_frame(44100, false));
+    }
+    let path = dir.write("book.mp3", &data);
+
+    let decoder = open(&path);
+    let detection = decoder.detection().clone();
+    assert_eq!(
+        (detection.format, detection.method, detection.skipped),
+        (Some("mp3"), DetectionMethod::FrameSync, junk.len() as u64)
+    );
+    assert_eq!(detection.extension_matches(), Some(true));
+    assert_eq!(decoder.tags().title.as_deref(), Some("Chapter One"));
+    assert_eq!(
+        decode(decoder),
+        (Some(44100), Some(2), u64::from(10 * MP3_FRAME_LEN))
+    );
+}
+
+#[test]
+fn files_are_read_by_content_whatever_their_extension() {
+    let dir = TempDir::new("sniff-extension");
+    let path = dir.write("wave.mp3", &wav(22050, 1, 22050));
+
+    let decoder = open(&path);
+    let detection = decoder.detection().clone();
+    assert_eq!(
+        (detection.format, detection.method),
+        (Some("wav"), DetectionMethod::Magic)
+    );
+    assert_eq!(detection.extension_matches(), Some(false));
+    assert_eq!(decode(decoder), (Some(22050), Some(1), 22050));
+
+    let data: Vec<u8> = (0..4).flat_map(|_| mp3_frame(48000, true)).collect();
+    let path = dir.write("mpeg.m4a", &data);
+    let decoder = open(&path);
+    assert_eq!(decoder.detection().format, Some("mp3"));
+    assert_eq!(decoder.detection().extension_matches(), Some(false));
+    assert_eq!(decode(decoder).2, u64::from(4 * MP3_FRAME_LEN));
+
+    // Neither the content nor the extension is audio.
+    let path = dir.write("notes.txt", &[0xff; 4096]);
+    let extension = path.extension().and_then(|e| e.to_str());
+    assert!(FileDecoder::open(File::open(&path).unwrap(), extension).is_err());
+}
+
+/// `frames` silent 44.1kHz MP3 frames after a header frame with `tag` at
+/// `offset`.
+fn mp3_with_header(mono: bool, offset: usize, tag: &[u8], frames: u32) -> Vec<u8> {
+    let mut data = mp3_frame(44100, mono);
+    data[offset..offset + tag.len()].copy_from_slice(tag);
+    for _ in 0..frames {
+        data.extend(mp3_frame(44100, mono));
+    }
+    data
+}
+
+/// A Xing header with the given `flags`, counting `frames`, with each of
+/// its optional fields present as flagged, and then `encoder`.
+fn xing(flags: u32, frames: u32, encoder: &[u8]) -> Vec<u8> {
+    let mut tag = b"Xing".to_vec();
+    tag.extend(flags.to_be_bytes());
+    if flags & 1 != 0 {
+        tag.extend(frames.to_be_bytes());
+    }
+    if flags & 2 != 0 {
+        tag.extend(100_000u32.to_be_bytes());
+    }
+    if flags & 4 != 0 {
+        tag.extend([0x55; 100]);
+    }
+    if flags & 8 != 0 {
+        tag.extend(50u32.to_be_bytes());
+    }
+    tag.extend(encoder);
+    tag
+}
+
+#[test]
+fn xing_and_vbri_headers_are_found_after_the_side_information() {
+    let dir = TempDir::new("sniff-vbr");
+    let lavc = lame_tag(b"Lavc58.54", 576, 1000);
+    // The decoder's own delay of 529 frames is moved from the padding to the
+    // delay, so the encoder's delay and padding are what's trimmed.
+    let cases = [
+        // Every optional field, before the encoder's tag.
+        (
+            false,
+            36,
+            xing(0xf, 8, &lavc),
+            DurationSource::Xing,
+            8 * 1152 - 1576,
+        ),
+        // The side information of a mono frame is shorter.
+        (
+            true,
+            21,
+            xing(0x1, 6, &lavc),
+            DurationSource::Xing,
+            6 * 1152 - 1576,
+        ),
+        (true, 21, xing(0x1, 6, b""), DurationSource::Xing, 6 * 1152),
+        // Without the frame count, the frames are counted.
+        (
+            false,
+            36,
+            xing(0x6, 9, &lavc),
+            DurationSource::PacketScan,
+            9 * 1152,
+        ),
+        // A VBRI header is at the same place in any frame.
+        (
+            true,
+            36,
+            [
+                b"VBRI".as_slice(),
+                &[0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 7],
+            ]
+            .concat(),
+            DurationSource::Vbri,
+            7 * 1152,
+        ),
+    ];
+    for (mono, offset, tag, source, frames) in cases {
+        let path = dir.write("vbr.mp3", &mp3_with_header(mono, offset, &tag, 9));
+
+        let probed = open(&path).into_duration().unwrap();
+
+        assert_eq!(probed.source, source, "{tag:?}");
+        let seconds = f64::from(frames) / 44100.0;
+        assert!((probed.seconds - seconds).abs() < 1e-9, "{probed:?}");
+    }
+}
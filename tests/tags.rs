@@ -0,0 +1,267 @@
+//! Reading `APEv2` tags, and cover art from the base64 picture blocks of
+//! Vorbis comments, including tags that are cut short or lie about sizes.
+use std::{fs::File, path::Path};
+
+use consolidator::{
+    mp4::{CoverArt, ImageFormat},
+    tags::{self, Tags},
+};
+
+mod common;
+
+use common::{wav, TempDir};
+
+const JPEG: &[u8] = b"\xff\xd8\xff\xe0 a front cover";
+const PNG: &[u8] = b"\x89PNG\r\n\x1a\n a back cover";
+
+fn read(path: &Path) -> Tags {
+    let extension = path.extension().and_then(|e| e.to_str());
+    tags::read(File::open(path).unwrap(), extension).unwrap()
+}
+
+/// A short WAV file followed by `tag`.
+fn wav_with(dir: &TempDir, tag: &[u8]) -> Tags {
+    let mut data = wav(8000, 1, 80);
+    data.extend(tag);
+    read(&dir.write("tagged.wav", &data))
+}
+
+/// An `APEv2` tag, without a header, of the text items `items`.
+fn ape_tag(items: &[(&str, &str)]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (key, value) in items {
+        body.extend((value.len() as u32).to_le_bytes());
+        body.extend(0u32.to_le_bytes());
+        body.extend(key.as_bytes());
+        body.push(0);
+        body.extend(value.as_bytes());
+    }
+    let mut tag = body;
+    let size = tag.len() as u32 + 32;
+    tag.extend(b"APETAGEX");
+    tag.extend(2000u32.to_le_bytes());
+    tag.extend(size.to_le_bytes());
+    tag.extend((items.len() as u32).to_le_bytes());
+    tag.extend(0u32.to_le_bytes());
+    tag.extend([0; 8]);
+    tag
+}
+
+/// The four bytes at `offset` in the footer of `tag`.
+fn footer_field(tag: &mut [u8], offset: usize) -> &mut [u8] {
+    let footer = tag.len() - 32;
+    &mut tag[footer + offset..footer + offset + 4]
+}
+
+#[test]
+fn ape_tags_are_read() {
+    let dir = TempDir::new("tags-ape");
+    let tag = ape_tag(&[
+        ("Title", "Chapter One"),
+        ("ALBUM", "The Book"),
+        ("Album Artist", "The Author"),
+        ("Composer", "The Narrator"),
+        ("Year", "2004-05-06"),
+        ("Track", "3/12"),
+        ("Disc", "2"),
+        ("Unknown", "ignored"),
+    ]);
+
+    let expected = Tags {
+        title: Some("Chapter One".to_owned()),
+        album: Some("The Book".to_owned()),
+        album_artist: Some("The Author".to_owned()),
+        composer: Some("The Narrator".to_owned()),
+        date: Some("2004-05-06".to_owned()),
+        track: Some(3),
+        disc: Some(2),
+        ..Tags::default()
+    };
+    assert_eq!(wav_with(&dir, &tag), expected);
+
+    // Also in front of an ID3v1 tag.
+    let mut id3v1 = b"TAG".to_vec();
+    id3v1.resize(128, 0);
+    assert_eq!(wav_with(&dir, &[tag, id3v1].concat()), expected);
+}
+
+#[test]
+fn ape_items_past_the_end_of_the_tag_are_dropped() {
+    let dir = TempDir::new("tags-ape-truncated");
+    let mut tag = ape_tag(&[("Title", "Kept"), ("Album", "Cut short")]);
+    // The album's value runs past the footer.
+    let album = 8 + "Title".len() + 1 + "Kept".len();
+    tag[album..album + 4].copy_from_slice(&1000u32.to_le_bytes());
+
+    let tags = wav_with(&dir, &tag);
+    assert_eq!(tags.title.as_deref(), Some("Kept"));
+    assert_eq!(tags.album, None);
+
+    // More items than the tag holds.
+    let mut tag = ape_tag(&[("Title", "Only")]);
+    footer_field(&mut tag, 16).copy_from_slice(&5u32.to_le_bytes());
+    assert_eq!(wav_with(&dir, &tag).title.as_deref(), Some("Only"));
+
+    // Items that aren't text.
+    let mut tag = ape_tag(&[("Title", "Binary"), ("Album", "Text")]);
+    tag[4] = 0b010;
+    let tags = wav_with(&dir, &tag);
+    assert_eq!((tags.title, tags.album.as_deref()), (None, Some("Text")));
+}
+
+#[test]
+fn ape_tags_larger_than_the_file_are_ignored() {
+    let dir = TempDir::new("tags-ape-oversized");
+    for size in [100_000, 64 * 1024 * 1024, 8] {
+        let mut tag = ape_tag(&[("Title", "Never read")]);
+        footer_field(&mut tag, 12).copy_from_slice(&u32::to_le_bytes(size));
+
+        // The file is still read, without the tag.
+        assert_eq!(wav_with(&dir, &tag), Tags::default(), "size {size}");
+    }
+}
+
+/// Standard base64 with padding.
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &byte)| {
+            bits | u32::from(byte) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            out.push(if i <= chunk.len() {
+                char::from(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize])
+            } else {
+                '='
+            });
+        }
+    }
+    out
+}
+
+/// A FLAC picture block of picture type `kind` holding `data`.
+fn picture(kind: u32, mime: &str, data: &[u8]) -> Vec<u8> {
+    let mut block = kind.to_be_bytes().to_vec();
+    block.extend((mime.len() as u32).to_be_bytes());
+    block.extend(mime.as_bytes());
+    block.extend(0u32.to_be_bytes());
+    // Width, height, color depth and palette size.
+    block.extend([0; 16]);
+    block.extend((data.len() as u32).to_be_bytes());
+    block.extend(data);
+    block
+}
+
+/// A FLAC stream of a frame of silence with the Vorbis comments `comments`.
+fn flac(comments: &[String]) -> Vec<u8> {
+    let mut data = b"fLaC".to_vec();
+    data.push(0);
+    data.extend([0, 0, 34]);
+    data.extend(192u16.to_be_bytes());
+    data.extend(192u16.to_be_bytes());
+    data.extend([0; 6]);
+    // 44100 Hz, mono, 16 bits, an unknown number of samples.
+    data.extend((44100u32 << 12 | 15 << 4).to_be_bytes());
+    data.extend([0; 4 + 16]);
+
+    let mut block = 0u32.to_le_bytes().to_vec();
+    block.extend((comments.len() as u32).to_le_bytes());
+    for comment in comments {
+        block.extend((comment.len() as u32).to_le_bytes());
+        block.extend(comment.as_bytes());
+    }
+    data.push(0x80 | 4);
+    data.extend(&(block.len() as u32).to_be_bytes()[1..]);
+    data.extend(block);
+    data.extend(silent_frame());
+    data
+}
+
+/// A FLAC frame of 192 frames of silence, as a constant subframe.
+fn silent_frame() -> Vec<u8> {
+    let crc = |data: &[u8], width: u32, poly: u32| {
+        let top = 1 << (width - 1);
+        data.iter().fold(0u32, |mut crc, &byte| {
+            crc ^= u32::from(byte) << (width - 8);
+            for _ in 0..8 {
+                crc = if crc & top != 0 {
+                    crc << 1 ^ poly
+                } else {
+                    crc << 1
+                };
+            }
+            crc & ((1 << width) - 1)
+        })
+    };
+    // 192 frames at 44100 Hz, mono, 16 bits, frame 0.
+    let mut frame = vec![0xff, 0xf8, 0x19, 0x08, 0x00];
+    frame.push(crc(&frame, 8, 0x07) as u8);
+    frame.extend([0, 0, 0]);
+    let crc = crc(&frame, 16, 0x8005) as u16;
+    frame.extend(crc.to_be_bytes());
+    frame
+}
+
+fn flac_cover(dir: &TempDir, comments: &[String]) -> Option<CoverArt> {
+    read(&dir.write("pictures.flac", &flac(comments))).cover
+}
+
+fn picture_comment(block: &[u8]) -> String {
+    format!("METADATA_BLOCK_PICTURE={}", base64(block))
+}
+
+#[test]
+fn front_covers_are_taken_from_picture_comments() {
+    let dir = TempDir::new("tags-pictures");
+    let back = picture_comment(&picture(4, "image/png", PNG));
+    let front = picture_comment(&picture(3, "image/jpeg", JPEG));
+
+    assert_eq!(
+        flac_cover(&dir, &[back.clone(), front]),
+        Some(CoverArt {
+            format: ImageFormat::Jpeg,
+            data: JPEG.to_vec(),
+        })
+    );
+    // Failing a front cover, the first picture.
+    assert_eq!(
+        flac_cover(&dir, &[back]).map(|art| art.format),
+        Some(ImageFormat::Png)
+    );
+}
+
+#[test]
+fn malformed_picture_comments_are_ignored() {
+    let dir = TempDir::new("tags-bad-pictures");
+    let block = picture(3, "image/jpeg", JPEG);
+    let fallback = picture_comment(&picture(4, "image/png", PNG));
+    let with_len = |offset: usize, len: u32| {
+        let mut block = block.clone();
+        block[offset..offset + 4].copy_from_slice(&len.to_be_bytes());
+        picture_comment(&block)
+    };
+    let data_len = block.len() - JPEG.len() - 4;
+
+    for (comment, why) in [
+        (picture_comment(&block[..block.len() - 1]), "truncated data"),
+        (picture_comment(&block[..10]), "truncated header"),
+        (with_len(4, u32::MAX), "oversized MIME type"),
+        (with_len(data_len, JPEG.len() as u32 + 1), "oversized data"),
+        (with_len(data_len, u32::MAX), "data length overflow"),
+        (
+            format!("METADATA_BLOCK_PICTURE=*{}", base64(&block)),
+            "bad base64",
+        ),
+        (
+            picture_comment(&picture(3, "image/gif", b"GIF89a")),
+            "not JPEG or PNG",
+        ),
+    ] {
+        assert_eq!(
+            flac_cover(&dir, &[comment, fallback.clone()]).map(|art| art.format),
+            Some(ImageFormat::Png),
+            "{why}"
+        );
+    }
+}